@@ -0,0 +1,871 @@
+//! Async versions of the functions that run zfs commands, for the tokio runtime.
+//!
+//! Loading and unloading keys, mounting and unmounting, and checking whether keys are loaded and datasets are
+//! mounted, run zfs with `tokio::process` and await it, so no thread is held while zfs runs. The other functions
+//! run their blocking counterpart from the crate root on the blocking pool of tokio, with `spawn_blocking`.
+//! The futures must be polled within a tokio runtime, and the commands start when they're first polled.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinError;
+
+use crate::config::{
+    keylocation_prompt_result, load_key_output_result, mount_failure, mounted_path_result,
+    property_output_result, unload_key_output_result, unmount_output_result,
+};
+use crate::passphrase::SecretBuffer;
+use crate::{
+    check_and_sanitize_zfs_dataset_name, check_passphrase, command_completed_events,
+    command_running_event, is_mountpoint_occupied_error, lock_dataset, mountpoint_from_mount_error,
+    parse_key_status_result, parse_mount_status_result, parse_mountpoint_property, spawn_failure,
+    CanMount, CommandFailure, CommandOutput, DatasetLock, DatasetMountedState, DatasetUsage,
+    EncryptionProperties, EncryptionStatus, KeyStatus, LoadKeyOutcome, MountStatus, Mountpoint,
+    Passphrase, Snapshot, ZfsConfig, ZfsError,
+};
+
+/// Returns the error for a blocking task that couldn't complete, or propagates its panic like a blocking call would
+fn join_error(error: JoinError) -> ZfsError {
+    match error.try_into_panic() {
+        Ok(panic) => std::panic::resume_unwind(panic),
+        Err(error) => ZfsError::SystemError(error.to_string()),
+    }
+}
+
+/// Runs the function on the blocking pool of tokio
+async fn run_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, ZfsError> + Send + 'static,
+) -> Result<T, ZfsError> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|e| Err(join_error(e)))
+}
+
+/// Takes the lock of the dataset, like the blocking functions, which wait for it on the blocking pool
+async fn lock(dataset: &str) -> Result<DatasetLock, ZfsError> {
+    let dataset = dataset.to_string();
+    tokio::task::spawn_blocking(move || lock_dataset(&dataset))
+        .await
+        .map_err(join_error)
+}
+
+/// Runs the command like ZfsConfig::run, awaiting it instead of blocking
+/// The runner of the config, if it has one, is blocking, so it runs on the blocking pool.
+async fn run(
+    config: &ZfsConfig,
+    command: Command,
+    stdin_data: &[u8],
+    spawn_error: impl FnOnce(String) -> ZfsError,
+) -> Result<CommandOutput, ZfsError> {
+    command_running_event(&command, stdin_data.len());
+
+    if let Some(runner) = config.runner.clone() {
+        let argv = crate::config::command_os_argv(&command);
+        let stdin = SecretBuffer::concat(&[stdin_data]);
+        let output = tokio::task::spawn_blocking(move || {
+            runner.run(&argv, (!stdin.is_empty()).then_some(&stdin))
+        })
+        .await
+        .map_err(join_error)?
+        .map_err(|e| config.binary_not_found(spawn_failure(&command, e, spawn_error)))?;
+
+        let output = CommandOutput {
+            status: output.status,
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        };
+        command_completed_events(&command, output.status, &output.stderr);
+
+        return Ok(output);
+    }
+
+    let mut command = tokio::process::Command::from(command);
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| config.binary_not_found(spawn_failure(command.as_std(), e, spawn_error)))?;
+
+    // stdin is written while the outputs are read, so that a command that writes a lot before reading its stdin
+    // can't block on a full pipe. It's written without buffering, so no copy of a passphrase is left in a buffer.
+    let stdin = child.stdin.take();
+    let write_stdin = async move {
+        match stdin {
+            // The pipe is closed when it's dropped, at the end of this block
+            Some(mut stdin) => stdin.write_all(stdin_data).await,
+            None => Ok(()),
+        }
+    };
+    let (written, output) = tokio::join!(write_stdin, child.wait_with_output());
+    written.map_err(|e| ZfsError::SystemError(e.to_string()))?;
+    let output = output.map_err(|e| ZfsError::SystemError(e.to_string()))?;
+
+    let output = CommandOutput {
+        status: output.status,
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+    };
+    command_completed_events(command.as_std(), output.status, &output.stderr);
+
+    Ok(output)
+}
+
+/// Runs a command made by the privileged commands of the config, like ZfsConfig::run_privileged_command
+async fn run_privileged(
+    config: &ZfsConfig,
+    command: Command,
+    stdin_data: &[u8],
+    dataset: &str,
+    spawn_error: impl FnOnce(String) -> ZfsError,
+) -> Result<CommandOutput, ZfsError> {
+    let program = config.privileged_program(&command);
+
+    let output = run(config, command, stdin_data, spawn_error).await?;
+    config.check_sudo_failure(program, &output, dataset)?;
+
+    Ok(output)
+}
+
+/// Same as ZfsConfig::get_property, for an already sanitized dataset name
+async fn get_property(
+    config: &ZfsConfig,
+    dataset: &str,
+    property: &str,
+) -> Result<Option<String>, ZfsError> {
+    let command = config.property_command(dataset, property);
+    let output = run(config, command, &[], |e| {
+        ZfsError::GetPropertyCallFailed(dataset.to_string(), property.to_string(), e)
+    })
+    .await?;
+
+    property_output_result(dataset, property, output)
+}
+
+/// Same as ZfsConfig::key_status, for an already sanitized dataset name
+async fn key_status(config: &ZfsConfig, dataset: &str) -> Result<KeyStatus, ZfsError> {
+    let command = config.key_status_command(dataset);
+    let output = run(config, command, &[], |e| {
+        ZfsError::KeyLoadedCheckFailed(dataset.to_string(), e)
+    })
+    .await?;
+
+    parse_key_status_result(
+        dataset,
+        output.status.success(),
+        &output.stdout,
+        output.stderr,
+    )
+}
+
+/// Same as ZfsConfig::mount_status, for an already sanitized dataset name
+async fn mount_status(config: &ZfsConfig, dataset: &str) -> Result<MountStatus, ZfsError> {
+    let command = config.mount_status_command();
+    let output = run(config, command, &[], |e| {
+        ZfsError::IsMountedCheckCallFailed(dataset.to_string(), e)
+    })
+    .await?;
+
+    parse_mount_status_result(
+        dataset,
+        output.status.success(),
+        &output.stdout,
+        output.stderr,
+    )
+}
+
+/// Same as ZfsConfig::load_key_detailed
+async fn load_key_detailed(
+    config: &ZfsConfig,
+    zfs_dataset: &str,
+    passphrase: &str,
+) -> Result<LoadKeyOutcome, ZfsError> {
+    let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+    check_passphrase(&dataset, passphrase)?;
+    let _lock = lock(&dataset).await?;
+
+    match key_status(config, &dataset).await?.is_loaded() {
+        Some(true) => return Ok(LoadKeyOutcome::AlreadyLoaded),
+        Some(false) => (),
+        None => return Err(ZfsError::DatasetNotFound(dataset)),
+    }
+
+    keylocation_prompt_result(
+        &dataset,
+        get_property(config, &dataset, "keylocation").await?,
+    )?;
+
+    let command = config.load_key_command(&dataset, None);
+    let stdin_data = SecretBuffer::concat(&[passphrase.as_bytes(), b"\n"]);
+    let output = run_privileged(config, command, &stdin_data, &dataset, |e| {
+        ZfsError::LoadKeyCmdFailed(dataset.to_string(), CommandFailure::spawn_failed(e))
+    })
+    .await?;
+
+    load_key_output_result(&dataset, output)
+}
+
+/// Same as ZfsConfig::unload_key
+async fn unload_key(config: &ZfsConfig, zfs_dataset: &str) -> Result<(), ZfsError> {
+    let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+    let _lock = lock(&dataset).await?;
+
+    match key_status(config, &dataset).await?.is_loaded() {
+        Some(true) => (),
+        Some(false) => return Ok(()),
+        None => return Err(ZfsError::DatasetNotFound(dataset)),
+    }
+
+    // zfs refuses to unload the key of a mounted dataset with a message that doesn't say why
+    match mount_status(config, &dataset).await?.is_mounted() {
+        Some(true) => return Err(ZfsError::CannotUnloadKeyWhileMounted(dataset)),
+        Some(false) => (),
+        None => return Err(ZfsError::DatasetNotFound(dataset)),
+    }
+    check_not_held(config, &dataset).await?;
+
+    let command = config.unload_key_command(&dataset);
+    let output = run_privileged(config, command, &[], &dataset, |e| {
+        ZfsError::UnloadKeyCmdFailed(dataset.to_string(), CommandFailure::spawn_failed(e))
+    })
+    .await?;
+
+    unload_key_output_result(&dataset, output)
+}
+
+/// Same as ZfsConfig::check_not_held, which lists the snapshots and their holds on the blocking pool
+async fn check_not_held(config: &ZfsConfig, dataset: &str) -> Result<(), ZfsError> {
+    if !config.check_holds {
+        return Ok(());
+    }
+
+    let config = config.clone();
+    let dataset = dataset.to_string();
+    run_blocking(move || config.check_not_held(&dataset)).await
+}
+
+/// Same as ZfsConfig::mount_dataset, for an already sanitized dataset name
+async fn mount(config: &ZfsConfig, dataset: &str) -> Result<(), ZfsError> {
+    if !mount_locked(config, dataset).await? {
+        return Ok(());
+    }
+
+    // The dataset is unlocked by now, so the hook can use it
+    if let Some(on_mounted) = config.on_mounted.clone() {
+        let mountpoint = get_property(config, dataset, "mountpoint").await?;
+        if let Some(Mountpoint::Path(mountpoint)) =
+            mountpoint.as_deref().map(parse_mountpoint_property)
+        {
+            let dataset = dataset.to_string();
+            run_blocking(move || {
+                on_mounted(&dataset, &mountpoint);
+                Ok(())
+            })
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs `zfs mount` while holding the lock of the dataset, like ZfsConfig::run_mount_locked
+/// Returns: Ok(false) if the dataset was already mounted, so zfs mount didn't run
+async fn mount_locked(config: &ZfsConfig, dataset: &str) -> Result<bool, ZfsError> {
+    let _lock = lock(dataset).await?;
+
+    match key_status(config, dataset).await?.is_loaded() {
+        Some(true) => (),
+        Some(false) => return Err(ZfsError::KeyNotLoadedForMount(dataset.to_string())),
+        None => return Err(ZfsError::DatasetNotFound(dataset.to_string())),
+    }
+
+    match mount_status(config, dataset).await?.is_mounted() {
+        Some(true) if config.strict_mountpoint => {
+            // The mount table is read on the blocking pool
+            let config = config.clone();
+            let dataset = dataset.to_string();
+            return run_blocking(move || config.check_mounted_at_mountpoint(&dataset))
+                .await
+                .map(|()| false);
+        }
+        Some(true) => return Ok(false),
+        Some(false) => (),
+        None => return Err(ZfsError::DatasetNotFound(dataset.to_string())),
+    }
+
+    // zfs mount refuses legacy mountpoints with a confusing message, or does nothing
+    let mountpoint = get_property(config, dataset, "mountpoint").await?;
+    if mountpoint.as_deref().map(parse_mountpoint_property) == Some(Mountpoint::Legacy) {
+        return Err(ZfsError::LegacyMountpoint(dataset.to_string()));
+    }
+    // Same for canmount=off, which some versions of zfs mount report as success
+    if get_property(config, dataset, "canmount")
+        .await?
+        .as_deref()
+        .map(str::trim)
+        == Some("off")
+    {
+        return Err(ZfsError::CanmountOff(dataset.to_string()));
+    }
+
+    let command = config.mount_command(dataset, &[]);
+    let output = run_privileged(config, command, &[], dataset, |e| {
+        ZfsError::MountCmdFailed(dataset.to_string(), CommandFailure::spawn_failed(e))
+    })
+    .await?;
+
+    if output.status.success() {
+        Ok(true)
+    } else if is_mountpoint_occupied_error(&output.stderr) {
+        let mountpoint = match mountpoint_from_mount_error(&output.stderr) {
+            Some(mountpoint) => Some(mountpoint),
+            None => get_property(config, dataset, "mountpoint")
+                .await?
+                .map(PathBuf::from),
+        };
+        Err(mount_failure(dataset, output, mountpoint))
+    } else {
+        Err(mount_failure(dataset, output, None))
+    }
+}
+
+/// Same as ZfsConfig::unmount_dataset and ZfsConfig::unmount_dataset_force, for an already sanitized dataset name
+async fn unmount(config: &ZfsConfig, dataset: &str, force: bool) -> Result<(), ZfsError> {
+    let _lock = lock(dataset).await?;
+
+    match mount_status(config, dataset).await?.is_mounted() {
+        Some(true) => (),
+        Some(false) => return Ok(()),
+        None => return Err(ZfsError::DatasetNotFound(dataset.to_string())),
+    }
+
+    let command = config.unmount_command(dataset, force);
+    let output = run_privileged(config, command, &[], dataset, |e| {
+        ZfsError::UnmountCmdFailed(dataset.to_string(), CommandFailure::spawn_failed(e))
+    })
+    .await?;
+
+    unmount_output_result(dataset, output).map(|_| ())
+}
+
+/// Async version of [`crate::zfs_load_key`]
+pub fn zfs_load_key(
+    zfs_dataset: impl AsRef<str>,
+    passphrase: impl AsRef<str>,
+) -> impl Future<Output = Result<(), ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    let passphrase = Passphrase::new(passphrase.as_ref());
+    async move {
+        load_key_detailed(&ZfsConfig::default(), &dataset, passphrase.as_ref())
+            .await
+            .map(|_| ())
+    }
+}
+
+/// Async version of [`crate::zfs_load_key_detailed`]
+pub fn zfs_load_key_detailed(
+    zfs_dataset: impl AsRef<str>,
+    passphrase: impl AsRef<str>,
+) -> impl Future<Output = Result<LoadKeyOutcome, ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    let passphrase = Passphrase::new(passphrase.as_ref());
+    async move { load_key_detailed(&ZfsConfig::default(), &dataset, passphrase.as_ref()).await }
+}
+
+/// Async version of [`crate::zfs_load_key_returning_root`]
+pub fn zfs_load_key_returning_root(
+    zfs_dataset: impl AsRef<str>,
+    passphrase: impl AsRef<str>,
+) -> impl Future<Output = Result<Option<String>, ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    let passphrase = Passphrase::new(passphrase.as_ref());
+    run_blocking(move || crate::zfs_load_key_returning_root(dataset, passphrase))
+}
+
+/// Async version of [`crate::zfs_unload_key`]
+pub fn zfs_unload_key(
+    zfs_dataset: impl AsRef<str>,
+) -> impl Future<Output = Result<(), ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    async move { unload_key(&ZfsConfig::default(), &dataset).await }
+}
+
+/// Async version of [`crate::zfs_change_key`]
+pub fn zfs_change_key(
+    zfs_dataset: impl AsRef<str>,
+    new_passphrase: impl AsRef<str>,
+) -> impl Future<Output = Result<(), ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    let new_passphrase = Passphrase::new(new_passphrase.as_ref());
+    run_blocking(move || crate::zfs_change_key(dataset, new_passphrase))
+}
+
+/// Async version of [`crate::zfs_mount_dataset`]
+pub fn zfs_mount_dataset(
+    zfs_dataset: impl AsRef<str>,
+) -> impl Future<Output = Result<(), ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    async move {
+        let dataset = check_and_sanitize_zfs_dataset_name(dataset)?;
+        mount(&ZfsConfig::default(), &dataset).await
+    }
+}
+
+/// Async version of [`crate::zfs_mount_dataset_returning_path`]
+pub fn zfs_mount_dataset_returning_path(
+    zfs_dataset: impl AsRef<str>,
+) -> impl Future<Output = Result<PathBuf, ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    async move {
+        let config = ZfsConfig::default();
+        let dataset = check_and_sanitize_zfs_dataset_name(dataset)?;
+        mount(&config, &dataset).await?;
+        mounted_path_result(
+            &dataset,
+            get_property(&config, &dataset, "mountpoint").await?,
+        )
+    }
+}
+
+/// Async version of [`crate::zfs_unmount_dataset`]
+pub fn zfs_unmount_dataset(
+    zfs_dataset: impl AsRef<str>,
+) -> impl Future<Output = Result<(), ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    async move {
+        let dataset = check_and_sanitize_zfs_dataset_name(dataset)?;
+        unmount(&ZfsConfig::default(), &dataset, false).await
+    }
+}
+
+/// Async version of [`crate::zfs_unmount_dataset_force`]
+pub fn zfs_unmount_dataset_force(
+    zfs_dataset: impl AsRef<str>,
+) -> impl Future<Output = Result<(), ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    async move {
+        let dataset = check_and_sanitize_zfs_dataset_name(dataset)?;
+        unmount(&ZfsConfig::default(), &dataset, true).await
+    }
+}
+
+/// Async version of [`crate::zfs_unlock_and_mount`]
+pub fn zfs_unlock_and_mount(
+    zfs_dataset: impl AsRef<str>,
+    passphrase: impl AsRef<str>,
+) -> impl Future<Output = Result<(), ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    let passphrase = Passphrase::new(passphrase.as_ref());
+    async move {
+        let config = ZfsConfig::default();
+        let dataset = check_and_sanitize_zfs_dataset_name(dataset)?;
+        load_key_detailed(&config, &dataset, passphrase.as_ref()).await?;
+        mount(&config, &dataset).await
+    }
+}
+
+/// Async version of [`crate::zfs_ensure_available`]
+pub fn zfs_ensure_available(
+    zfs_dataset: impl AsRef<str>,
+    passphrase: impl AsRef<str>,
+) -> impl Future<Output = Result<(), ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    let passphrase = Passphrase::new(passphrase.as_ref());
+    run_blocking(move || crate::zfs_ensure_available(dataset, passphrase))
+}
+
+/// Async version of [`crate::zfs_unmount_and_unload`]
+pub fn zfs_unmount_and_unload(
+    zfs_dataset: impl AsRef<str>,
+) -> impl Future<Output = Result<(), ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    async move {
+        let config = ZfsConfig::default();
+        let dataset = check_and_sanitize_zfs_dataset_name(dataset)?;
+        // Checked before unmounting too, so that a held dataset is left as it is
+        check_not_held(&config, &dataset).await?;
+        unmount(&config, &dataset, false).await?;
+        unload_key(&config, &dataset).await
+    }
+}
+
+/// Async version of [`crate::zfs_dataset_exists`]
+pub fn zfs_dataset_exists(
+    zfs_dataset: impl AsRef<str>,
+) -> impl Future<Output = Result<bool, ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    run_blocking(move || crate::zfs_dataset_exists(dataset))
+}
+
+/// Async version of [`crate::zfs_is_key_loaded`]
+pub fn zfs_is_key_loaded(
+    zfs_dataset: impl AsRef<str>,
+) -> impl Future<Output = Result<Option<bool>, ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    async move {
+        let dataset = check_and_sanitize_zfs_dataset_name(dataset)?;
+        key_status(&ZfsConfig::default(), &dataset)
+            .await
+            .map(KeyStatus::is_loaded)
+    }
+}
+
+/// Async version of [`crate::zfs_is_dataset_mounted`]
+pub fn zfs_is_dataset_mounted(
+    zfs_dataset: impl AsRef<str>,
+) -> impl Future<Output = Result<Option<bool>, ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    async move {
+        let dataset = check_and_sanitize_zfs_dataset_name(dataset)?;
+        mount_status(&ZfsConfig::default(), &dataset)
+            .await
+            .map(MountStatus::is_mounted)
+    }
+}
+
+/// Async version of [`crate::zfs_get_dataset_state`]
+pub fn zfs_get_dataset_state(
+    zfs_dataset: impl AsRef<str>,
+) -> impl Future<Output = Result<Option<DatasetMountedState>, ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    run_blocking(move || crate::zfs_get_dataset_state(dataset))
+}
+
+/// Async version of [`crate::zfs_get_states`]
+pub fn zfs_get_states(
+    zfs_datasets: &[impl AsRef<str>],
+) -> impl Future<Output = Result<BTreeMap<String, DatasetMountedState>, ZfsError>> + Send + 'static
+{
+    let datasets: Vec<String> = zfs_datasets
+        .iter()
+        .map(|d| d.as_ref().to_string())
+        .collect();
+    run_blocking(move || crate::zfs_get_states(&datasets))
+}
+
+/// Async version of [`crate::zfs_is_unlocked_and_mounted`]
+pub fn zfs_is_unlocked_and_mounted(
+    zfs_dataset: impl AsRef<str>,
+) -> impl Future<Output = Result<Option<(bool, bool)>, ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    run_blocking(move || crate::zfs_is_unlocked_and_mounted(dataset))
+}
+
+/// Async version of [`crate::zfs_get_encryption_properties`]
+pub fn zfs_get_encryption_properties(
+    zfs_dataset: impl AsRef<str>,
+) -> impl Future<Output = Result<Option<EncryptionProperties>, ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    run_blocking(move || crate::zfs_get_encryption_properties(dataset))
+}
+
+/// Async version of [`crate::zfs_encryption_status`]
+pub fn zfs_encryption_status(
+    zfs_dataset: impl AsRef<str>,
+) -> impl Future<Output = Result<EncryptionStatus, ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    run_blocking(move || crate::zfs_encryption_status(dataset))
+}
+
+/// Async version of [`crate::zfs_get_canmount`]
+pub fn zfs_get_canmount(
+    zfs_dataset: impl AsRef<str>,
+) -> impl Future<Output = Result<CanMount, ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    run_blocking(move || crate::zfs_get_canmount(dataset))
+}
+
+/// Async version of [`crate::zfs_get_encryption_root`]
+pub fn zfs_get_encryption_root(
+    zfs_dataset: impl AsRef<str>,
+) -> impl Future<Output = Result<Option<String>, ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    run_blocking(move || crate::zfs_get_encryption_root(dataset))
+}
+
+/// Async version of [`crate::zfs_get_dataset_usage`]
+pub fn zfs_get_dataset_usage(
+    zfs_dataset: impl AsRef<str>,
+) -> impl Future<Output = Result<Option<DatasetUsage>, ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    run_blocking(move || crate::zfs_get_dataset_usage(dataset))
+}
+
+/// Async version of [`crate::zfs_list_snapshots`]
+pub fn zfs_list_snapshots(
+    zfs_dataset: impl AsRef<str>,
+) -> impl Future<Output = Result<Vec<Snapshot>, ZfsError>> + Send + 'static {
+    let dataset = zfs_dataset.as_ref().to_string();
+    run_blocking(move || crate::zfs_list_snapshots(dataset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    /// A zfs that keeps the key and mount state of one dataset, `pool/ds`, in files of the directory FAKE_ZFS_STATE
+    const FAKE_ZFS: &str = r#"#!/bin/sh
+cd "$FAKE_ZFS_STATE" || exit 1
+state() { if [ -e "$1" ]; then echo "$2"; else echo "$3"; fi; }
+case "$1 $2" in
+  "get keystatus") printf 'pool/ds\t%s\n' "$(state key available unavailable)" ;;
+  "list -H") printf 'pool/ds\t%s\n' "$(state mounted yes no)" ;;
+  "get -H")
+    case "$5" in
+      keylocation) echo prompt ;;
+      mountpoint) echo /mnt/ds ;;
+      canmount) echo on ;;
+    esac ;;
+  "load-key pool/ds")
+    read -r passphrase
+    if [ "$passphrase" = abcdefghijklmnop ]; then touch key; else
+      echo "Key load error: Incorrect key provided for 'pool/ds'." >&2; exit 255; fi ;;
+  "unload-key pool/ds") rm key ;;
+  "mount pool/ds") touch mounted ;;
+  "umount pool/ds") rm mounted ;;
+  *) echo "unexpected arguments: $*" >&2; exit 2 ;;
+esac
+"#;
+
+    /// Returns a config that runs FAKE_ZFS, with its state in a directory of the test
+    fn fake_zfs_config(test: &str) -> (ZfsConfig, PathBuf) {
+        let dir = std::env::temp_dir().join(format!(
+            "sam-zfs-unlocker-asynchronous-{}-{test}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let zfs = dir.join("zfs");
+        std::fs::write(&zfs, FAKE_ZFS).unwrap();
+        std::fs::set_permissions(&zfs, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config = ZfsConfig {
+            zfs_path: zfs,
+            use_sudo: false,
+            env: vec![("FAKE_ZFS_STATE".to_string(), dir.display().to_string())],
+            ..Default::default()
+        };
+        (config, dir)
+    }
+
+    #[test]
+    fn tokio_process() {
+        let (config, dir) = fake_zfs_config("tokio_process");
+        let ds = "pool/ds";
+
+        block_on(async {
+            assert_eq!(
+                key_status(&config, ds).await.unwrap().is_loaded(),
+                Some(false)
+            );
+            assert!(matches!(
+                load_key_detailed(&config, ds, "the wrong passphrase").await,
+                Err(ZfsError::IncorrectPassphrase(_))
+            ));
+            assert!(matches!(
+                mount(&config, ds).await,
+                Err(ZfsError::KeyNotLoadedForMount(_))
+            ));
+            assert_eq!(
+                load_key_detailed(&config, ds, "abcdefghijklmnop")
+                    .await
+                    .unwrap(),
+                LoadKeyOutcome::Loaded
+            );
+            assert_eq!(
+                load_key_detailed(&config, ds, "abcdefghijklmnop")
+                    .await
+                    .unwrap(),
+                LoadKeyOutcome::AlreadyLoaded
+            );
+
+            mount(&config, ds).await.unwrap();
+            assert_eq!(
+                mount_status(&config, ds).await.unwrap().is_mounted(),
+                Some(true)
+            );
+            assert!(matches!(
+                unload_key(&config, ds).await,
+                Err(ZfsError::CannotUnloadKeyWhileMounted(_))
+            ));
+            unmount(&config, ds, false).await.unwrap();
+            assert_eq!(
+                mount_status(&config, ds).await.unwrap().is_mounted(),
+                Some(false)
+            );
+            unload_key(&config, ds).await.unwrap();
+            assert_eq!(
+                key_status(&config, ds).await.unwrap().is_loaded(),
+                Some(false)
+            );
+
+            assert_eq!(
+                key_status(&config, "pool/missing")
+                    .await
+                    .unwrap()
+                    .is_loaded(),
+                None
+            );
+        });
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tokio_process_events() {
+        let (config, dir) = fake_zfs_config("tokio_process_events");
+
+        let events = crate::tests::captured_events(|| {
+            block_on(async {
+                assert!(matches!(
+                    load_key_detailed(&config, "pool/ds", "the secret passphrase").await,
+                    Err(ZfsError::IncorrectPassphrase(_))
+                ));
+            })
+        });
+
+        assert!(events.contains("Running command"));
+        assert!(events.contains("load-key"));
+        assert!(events.contains("stdin_len=22"));
+        assert!(events.contains("Command failed"));
+        assert!(events.contains("Incorrect key provided"));
+        assert!(!events.contains("secret"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn blocking_task_result() {
+        assert_eq!(block_on(run_blocking(|| Ok(5))).unwrap(), 5);
+        block_on(run_blocking(|| -> Result<(), _> {
+            Err(ZfsError::SystemError("failure".to_string()))
+        }))
+        .unwrap_err();
+    }
+
+    #[test]
+    #[should_panic(expected = "panic in task")]
+    fn blocking_task_panic() {
+        block_on(run_blocking(|| -> Result<(), ZfsError> {
+            panic!("panic in task")
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn futures_are_send() {
+        fn assert_send<T: Send + 'static>(_: T) {}
+
+        assert_send(zfs_unlock_and_mount("pool/ds", "abcdefghijklmnop"));
+        assert_send(zfs_unmount_and_unload("pool/ds"));
+        assert_send(zfs_get_states(&["pool/ds"]));
+    }
+
+    #[test]
+    fn invalid_dataset_names() {
+        let ds = "pool/dataset name";
+
+        assert!(matches!(
+            block_on(zfs_load_key(ds, "abcdefghijklmnop")),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert!(matches!(
+            block_on(zfs_load_key_detailed(ds, "abcdefghijklmnop")),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert!(matches!(
+            block_on(zfs_load_key_returning_root(ds, "abcdefghijklmnop")),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert!(matches!(
+            block_on(zfs_ensure_available(ds, "abcdefghijklmnop")),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert!(matches!(
+            block_on(zfs_unlock_and_mount(ds, "abcdefghijklmnop")),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert!(matches!(
+            block_on(zfs_unload_key(ds)),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert!(matches!(
+            block_on(zfs_mount_dataset(ds)),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert!(matches!(
+            block_on(zfs_mount_dataset_returning_path(ds)),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert!(matches!(
+            block_on(zfs_unmount_dataset(ds)),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert!(matches!(
+            block_on(zfs_unmount_dataset_force(ds)),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert!(matches!(
+            block_on(zfs_unmount_and_unload(ds)),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert!(matches!(
+            block_on(zfs_dataset_exists(ds)),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert!(matches!(
+            block_on(zfs_is_key_loaded(ds)),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert!(matches!(
+            block_on(zfs_is_dataset_mounted(ds)),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert!(matches!(
+            block_on(zfs_get_dataset_state(ds)),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert!(matches!(
+            block_on(zfs_get_states(&[ds])),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert!(matches!(
+            block_on(zfs_is_unlocked_and_mounted(ds)),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert!(matches!(
+            block_on(zfs_get_encryption_root(ds)),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert!(matches!(
+            block_on(zfs_get_canmount(ds)),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert!(matches!(
+            block_on(zfs_encryption_status(ds)),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert!(matches!(
+            block_on(zfs_get_dataset_usage(ds)),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert!(matches!(
+            block_on(zfs_list_snapshots(ds)),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+    }
+}
@@ -0,0 +1,5008 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::num::NonZeroUsize;
+use std::os::fd::AsFd;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::encryption::{
+    parse_encryption_properties_result, parse_encryption_status_result, ENCRYPTION_PROPERTIES,
+    ENCRYPTION_STATUS_COLUMNS,
+};
+use crate::passphrase::SecretBuffer;
+use crate::watch::spawn_watcher;
+use crate::{
+    check_and_sanitize_key_location, check_and_sanitize_mount_option,
+    check_and_sanitize_mountpoint, check_and_sanitize_property, check_and_sanitize_property_name,
+    check_and_sanitize_zfs_dataset_name, check_and_sanitize_zfs_snapshot_name,
+    check_and_sanitize_zpool_name, check_key_for_keyformat, check_passphrase,
+    command_completed_events, command_running_event, datasets_at_mountpoint, is_dataset_busy_error,
+    is_dataset_not_found_error, is_incorrect_key_error, is_mountpoint_occupied_error,
+    is_sudo_command_not_found_error, is_sudo_password_required_error, is_transient_mount_error,
+    lock_dataset, locked_datasets, mountpoint_from_mount_error, parse_canmount_property,
+    parse_dataset_exists_result, parse_dataset_state_result, parse_dataset_states_result,
+    parse_dataset_usage_result, parse_datasets_mountpoints, parse_encrypted_dataset_line,
+    parse_encrypted_datasets, parse_encryption_root, parse_holds_result,
+    parse_inconsistent_datasets, parse_key_states, parse_key_status_result, parse_lockable_roots,
+    parse_mount_status_result, parse_mount_table_targets, parse_mountpoint,
+    parse_mountpoint_property, parse_pbkdf2iters, parse_pool_health_result,
+    parse_pool_imported_result, parse_properties_result, parse_snapshots_result, parse_sudo_list,
+    parse_unmount_order, parse_unmounted_filesystems, parse_zfs_version, retry_with_backoff,
+    run_command, run_command_to_writer, spawn_command_lines, spawn_failure, unlock_all_passphrases,
+    unlocked_datasets, CanMount, CommandFailure, CommandOutput, CommandRunner, CreateOpts,
+    DatasetMountedState, DatasetStateChange, DatasetUsage, EncryptionProperties, EncryptionStatus,
+    GracefulUnmountReport, Inconsistency, KeySource, KeyStatus, LoadKeyOutcome, MountAllReport,
+    MountStatus, Mountpoint, ParseWarnings, PoolHealth, PoolRemovalReport, RecursiveLoadKeyReport,
+    RemountOutcome, Snapshot, SshRunner, SudoCapabilities, UnmountAndUnloadMethod, WaitLimit,
+    WatchHandle, ZfsError, ZfsVersion, DATASET_STATE_COLUMNS, INCONSISTENCY_COLUMNS,
+    MAX_KEY_READ_LEN, MOUNT_TABLE_PATH, PRIVILEGED_ZFS_SUBCOMMANDS, PRIVILEGED_ZPOOL_SUBCOMMANDS,
+    SUDOERS_ZFS_PATH, SUDOERS_ZPOOL_PATH, UNMOUNT_UNLOAD_KEY_MIN_VERSION, USAGE_PROPERTIES,
+};
+
+/// What the commands that act on all datasets report instead of a dataset name, e.g., in ZfsError::CommandTimedOut
+const ALL_DATASETS: &str = "all datasets";
+
+/// A function that's called with the name and the mountpoint of a dataset after it's mounted
+pub type MountedHook = Arc<dyn Fn(&str, &Path) + Send + Sync>;
+
+/// The binaries used to run zfs commands
+/// The free functions of this crate use the default config, which finds `zfs` and `sudo` in PATH.
+#[derive(Clone)]
+pub struct ZfsConfig {
+    /// The zfs binary. It's looked up in PATH if it's not a path, or in sudo's `secure_path` when run with sudo.
+    pub zfs_path: PathBuf,
+    /// The zpool binary, looked up the same way as zfs_path
+    pub zpool_path: PathBuf,
+    /// The sudo binary, used for the commands that need privileges
+    pub sudo_path: PathBuf,
+    /// Whether the commands that need privileges are run with `sudo -n`.
+    /// Disable this if the process already has the privileges, e.g., when running as root.
+    pub use_sudo: bool,
+    /// Runs the commands instead of the system, if set
+    pub runner: Option<Arc<dyn CommandRunner>>,
+    /// Called after zfs mount mounts a dataset, e.g., by mount_dataset or unlock_and_mount, if set.
+    /// It isn't called for datasets that were already mounted, or for the ones mounted by mount_all.
+    /// The dataset isn't locked while it runs, so it can use this config on the same dataset.
+    pub on_mounted: Option<MountedHook>,
+    /// Whether mounting a dataset that's already mounted fails if it's not mounted at its mountpoint,
+    /// e.g., because it was mounted somewhere else by hand. Where it's mounted is read from the mount table
+    /// of the system, or of the host of the runner with `cat /proc/self/mounts` if there's a runner,
+    /// so nothing is checked for datasets that aren't in it.
+    pub strict_mountpoint: bool,
+    /// Environment variables set on the commands, in addition to the ones they inherit from the process.
+    /// `LC_ALL` and `LANG` are always set to `C` first, so that the output of zfs can be parsed in any locale.
+    /// sudo drops most variables unless its env_keep allows them, so these may not reach the commands it runs.
+    pub env: Vec<(String, String)>,
+    /// How sudo asks for a password, if it needs one. By default it never asks, for daemons.
+    pub sudo_prompt: SudoPrompt,
+    /// Whether unloading a key, e.g., with unload_key or unmount_and_unload, fails with ZfsError::SnapshotsHeld
+    /// if any snapshot of the dataset or its descendants has a hold, like the ones replication tools put on
+    /// snapshots while they send them. The snapshots and their holds are listed before every unload.
+    pub check_holds: bool,
+    /// The program and options that the commands needing privileges are run with, in place of sudo_path with the
+    /// options of sudo_prompt, if set, e.g., `["doas", "-n"]` where doas is used instead of sudo.
+    /// The binary, e.g., zfs, and its arguments are appended to it. It's only used if use_sudo is set.
+    pub escalation_command: Option<Vec<String>>,
+}
+
+/// How sudo gets the password of the user, for the rules that don't have NOPASSWD
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub enum SudoPrompt {
+    /// sudo is run with `-n`, so it fails instead of asking, with ZfsError::SudoNonInteractiveDenied
+    #[default]
+    NonInteractive,
+    /// sudo asks on the controlling terminal of the process, e.g., in an interactive command line tool.
+    /// sudo remembers the password for a while, so it's asked once for many commands.
+    Terminal,
+    /// sudo is run with `-A`, and runs the program as `SUDO_ASKPASS` to get the password, e.g., a graphical prompt
+    Askpass(PathBuf),
+}
+
+/// The environment variables that are set on every command, before the ones of the config
+pub(crate) const COMMAND_ENV: [(&str, &str); 2] = [("LC_ALL", "C"), ("LANG", "C")];
+
+impl std::fmt::Debug for ZfsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ZfsConfig")
+            .field("zfs_path", &self.zfs_path)
+            .field("zpool_path", &self.zpool_path)
+            .field("sudo_path", &self.sudo_path)
+            .field("use_sudo", &self.use_sudo)
+            .field("runner", &self.runner)
+            .field("on_mounted", &self.on_mounted.as_ref().map(|_| "<hook>"))
+            .field("strict_mountpoint", &self.strict_mountpoint)
+            .field("env", &self.env)
+            .field("sudo_prompt", &self.sudo_prompt)
+            .field("check_holds", &self.check_holds)
+            .field("escalation_command", &self.escalation_command)
+            .finish()
+    }
+}
+
+/// Returns whether both are unset, or are the same object
+fn same_arc<T: ?Sized>(a: &Option<Arc<T>>, b: &Option<Arc<T>>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// Configs are equal if they run the same binaries, with the same runner and hook, if any
+impl PartialEq for ZfsConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.zfs_path == other.zfs_path
+            && self.zpool_path == other.zpool_path
+            && self.sudo_path == other.sudo_path
+            && self.use_sudo == other.use_sudo
+            && same_arc(&self.runner, &other.runner)
+            && same_arc(&self.on_mounted, &other.on_mounted)
+            && self.strict_mountpoint == other.strict_mountpoint
+            && self.env == other.env
+            && self.sudo_prompt == other.sudo_prompt
+            && self.check_holds == other.check_holds
+            && self.escalation_command == other.escalation_command
+    }
+}
+
+impl Eq for ZfsConfig {}
+
+impl Default for ZfsConfig {
+    fn default() -> Self {
+        Self {
+            zfs_path: PathBuf::from("zfs"),
+            zpool_path: PathBuf::from("zpool"),
+            sudo_path: PathBuf::from("sudo"),
+            use_sudo: true,
+            runner: None,
+            on_mounted: None,
+            strict_mountpoint: false,
+            env: Vec::new(),
+            sudo_prompt: SudoPrompt::NonInteractive,
+            check_holds: false,
+            escalation_command: None,
+        }
+    }
+}
+
+impl ZfsConfig {
+    /// The default config, with the commands run on another host by ssh, as with SshRunner::new(destination)
+    /// The binaries, and sudo, are the ones of the remote host.
+    pub fn over_ssh(destination: impl Into<String>) -> Self {
+        Self {
+            runner: Some(Arc::new(SshRunner::new(destination))),
+            ..Default::default()
+        }
+    }
+
+    /// The default config, except that sudo is only used if the process isn't running as root
+    pub fn for_current_user() -> Self {
+        Self {
+            use_sudo: !is_running_as_root(),
+            ..Default::default()
+        }
+    }
+
+    /// Creates a command that runs the program with the environment of this config
+    fn command(&self, program: &Path) -> Command {
+        let mut command = Command::new(program);
+        command
+            .envs(COMMAND_ENV)
+            .envs(self.env.iter().map(|(key, value)| (key, value)));
+        command
+    }
+
+    /// Creates a command that runs zfs without privileges, for read-only queries
+    fn zfs_command(&self) -> Command {
+        self.command(&self.zfs_path)
+    }
+
+    /// Creates a command that runs zfs with sudo, unless disabled
+    fn privileged_zfs_command(&self) -> Command {
+        self.privileged_command(&self.zfs_path)
+    }
+
+    /// Creates a command that runs zpool without privileges, for read-only queries
+    fn zpool_command(&self) -> Command {
+        self.command(&self.zpool_path)
+    }
+
+    /// Creates a command that runs zpool with sudo, unless disabled
+    fn privileged_zpool_command(&self) -> Command {
+        self.privileged_command(&self.zpool_path)
+    }
+
+    fn privileged_command(&self, program: &Path) -> Command {
+        if let Some((escalation_program, options)) = self
+            .escalation_command
+            .as_ref()
+            .and_then(|escalation| escalation.split_first())
+            .filter(|_| self.use_sudo)
+        {
+            let mut command = self.command(Path::new(escalation_program));
+            command.args(options).arg(program);
+            command
+        } else if self.use_sudo {
+            let mut command = self.command(&self.sudo_path);
+            match &self.sudo_prompt {
+                SudoPrompt::NonInteractive => {
+                    command.arg("-n"); // sudo isn't interactive
+                }
+                SudoPrompt::Terminal => (),
+                SudoPrompt::Askpass(askpass) => {
+                    command.arg("-A").env("SUDO_ASKPASS", askpass);
+                }
+            }
+            command.arg(program);
+            command
+        } else {
+            self.command(program)
+        }
+    }
+
+    /// The program that commands made by privileged_command run to get privileges, like sudo
+    fn escalation_program(&self) -> &Path {
+        self.escalation_command
+            .as_ref()
+            .and_then(|escalation| escalation.first())
+            .map_or(&self.sudo_path, Path::new)
+    }
+
+    /// The binary that a command made by privileged_command runs with sudo, which is the first of its arguments
+    /// that's the zfs or the zpool binary, after whatever options sudo or the escalation command has
+    pub(crate) fn privileged_program(&self, command: &Command) -> Option<PathBuf> {
+        command
+            .get_args()
+            .find(|arg| *arg == self.zfs_path || *arg == self.zpool_path)
+            .map(PathBuf::from)
+    }
+
+    pub(crate) fn load_key_command(&self, dataset: &str, location: Option<&str>) -> Command {
+        let mut command = self.privileged_zfs_command();
+        command.arg("load-key");
+        if let Some(location) = location {
+            command.arg("-L").arg(location);
+        }
+        command.arg(dataset);
+        command
+    }
+
+    fn create_command(&self, dataset: &str, properties: &[String]) -> Command {
+        let mut command = self.privileged_zfs_command();
+        command.arg("create");
+        for property in properties {
+            command.arg("-o").arg(property);
+        }
+        command.arg(dataset);
+        command
+    }
+
+    pub(crate) fn unload_key_command(&self, dataset: &str) -> Command {
+        let mut command = self.privileged_zfs_command();
+        command.arg("unload-key").arg(dataset);
+        command
+    }
+
+    pub(crate) fn mount_command(&self, dataset: &str, options: &[String]) -> Command {
+        let mut command = self.privileged_zfs_command();
+        command.arg("mount");
+        for option in options {
+            command.arg("-o").arg(option);
+        }
+        command.arg(dataset);
+        command
+    }
+
+    pub(crate) fn unmount_command(&self, dataset: &str, force: bool) -> Command {
+        let mut command = self.privileged_zfs_command();
+        command.arg("umount");
+        if force {
+            command.arg("-f");
+        }
+        command.arg(dataset);
+        command
+    }
+
+    pub(crate) fn key_status_command(&self, dataset: &str) -> Command {
+        let mut command = self.zfs_command();
+        command
+            .arg("get")
+            .arg("keystatus")
+            .arg("-H") // No table header
+            .arg("-o")
+            .arg("name,value") // Only show two columns, dataset name and whether key is available
+            .arg(dataset); // Only get the property of this dataset
+        command
+    }
+
+    pub(crate) fn mount_status_command(&self) -> Command {
+        let mut command = self.zfs_command();
+        command
+            .arg("list")
+            .arg("-H") // No table header
+            .arg("-o")
+            .arg("name,mounted"); // Only show two columns, dataset name and whether dataset is mounted
+        command
+    }
+
+    pub(crate) fn property_command(&self, dataset: &str, property: &str) -> Command {
+        let mut command = self.zfs_command();
+        command
+            .arg("get")
+            .arg("-H") // No table header
+            .arg("-o")
+            .arg("value") // Only show the value of the property
+            .arg(property)
+            .arg(dataset);
+        command
+    }
+
+    /// Same as [`crate::zfs_plan_load_key`], with the binaries of this config
+    pub fn plan_load_key(&self, zfs_dataset: impl AsRef<str>) -> Result<Vec<String>, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        Ok(command_argv(&self.load_key_command(&dataset, None)))
+    }
+
+    /// Same as [`crate::zfs_plan_unload_key`], with the binaries of this config
+    pub fn plan_unload_key(&self, zfs_dataset: impl AsRef<str>) -> Result<Vec<String>, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        Ok(command_argv(&self.unload_key_command(&dataset)))
+    }
+
+    /// Same as [`crate::zfs_plan_mount_dataset`], with the binaries of this config
+    pub fn plan_mount_dataset(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+    ) -> Result<Vec<String>, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        Ok(command_argv(&self.mount_command(&dataset, &[])))
+    }
+
+    /// Same as [`crate::zfs_plan_unmount_dataset`], with the binaries of this config
+    pub fn plan_unmount_dataset(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+    ) -> Result<Vec<String>, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        Ok(command_argv(&self.unmount_command(&dataset, false)))
+    }
+
+    /// Runs the command with the runner of this config, or with run_command if it has none
+    fn run(
+        &self,
+        command: Command,
+        stdin_data: &[u8],
+        limit: WaitLimit,
+        dataset: &str,
+        spawn_error: impl FnOnce(String) -> ZfsError,
+    ) -> Result<CommandOutput, ZfsError> {
+        let Some(runner) = &self.runner else {
+            return run_command(command, stdin_data, limit, dataset, spawn_error)
+                .map_err(|e| self.binary_not_found(e));
+        };
+
+        if limit.is_cancelled() {
+            return Err(ZfsError::Cancelled(dataset.to_string()));
+        }
+
+        command_running_event(&command, stdin_data.len());
+        let output = runner
+            .run(
+                &command_os_argv(&command),
+                (!stdin_data.is_empty()).then_some(stdin_data),
+            )
+            .map_err(|e| self.binary_not_found(spawn_failure(&command, e, spawn_error)))?;
+
+        let output = CommandOutput {
+            status: output.status,
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        };
+        command_completed_events(&command, output.status, &output.stderr);
+
+        Ok(output)
+    }
+
+    /// Returns the lines of the stdout of the command, like spawn_command_lines,
+    /// run with the runner of this config if it has one
+    fn command_lines(
+        &self,
+        command: Command,
+        spawn_error: impl FnOnce(String) -> ZfsError,
+        failure_error: fn(String) -> ZfsError,
+    ) -> Result<Box<dyn Iterator<Item = Result<String, ZfsError>>>, ZfsError> {
+        if self.runner.is_none() {
+            return Ok(Box::new(
+                spawn_command_lines(command, spawn_error, failure_error)
+                    .map_err(|e| self.binary_not_found(e))?,
+            ));
+        }
+
+        let output = self.run(command, &[], WaitLimit::NONE, "", spawn_error)?;
+        if output.status.success() {
+            let lines = output
+                .stdout
+                .lines()
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            Ok(Box::new(lines.into_iter().map(Ok)))
+        } else {
+            Ok(Box::new(std::iter::once(Err(failure_error(output.stderr)))))
+        }
+    }
+
+    /// Tells apart a missing sudo from a missing zfs or zpool, in the error of run_command
+    pub(crate) fn binary_not_found(&self, error: ZfsError) -> ZfsError {
+        match error {
+            ZfsError::ZfsBinaryNotFound(path)
+                if self.use_sudo && path == self.escalation_program() =>
+            {
+                ZfsError::SudoBinaryNotFound(path)
+            }
+            e => e,
+        }
+    }
+
+    /// Runs a command made by privileged_zfs_command, like run_command
+    /// Returns Err(ZfsError::SudoNonInteractiveDenied) if sudo refused to run it without a password.
+    /// Returns Err(ZfsError::ZfsBinaryNotFound) if sudo couldn't find the binary to run.
+    fn run_privileged_command(
+        &self,
+        command: Command,
+        stdin_data: &[u8],
+        limit: WaitLimit,
+        dataset: &str,
+        spawn_error: impl FnOnce(String) -> ZfsError,
+    ) -> Result<CommandOutput, ZfsError> {
+        let program = self.privileged_program(&command);
+
+        let output = self.run(command, stdin_data, limit, dataset, spawn_error)?;
+        self.check_sudo_failure(program, &output, dataset)?;
+
+        Ok(output)
+    }
+
+    /// Returns the error for a privileged command that sudo refused to run, if it did
+    pub(crate) fn check_sudo_failure(
+        &self,
+        program: Option<PathBuf>,
+        output: &CommandOutput,
+        dataset: &str,
+    ) -> Result<(), ZfsError> {
+        if self.use_sudo && !output.status.success() {
+            if is_sudo_password_required_error(&output.stderr) {
+                return Err(ZfsError::SudoNonInteractiveDenied(dataset.to_string()));
+            }
+            if let Some(program) =
+                program.filter(|_| is_sudo_command_not_found_error(&output.stderr))
+            {
+                return Err(ZfsError::ZfsBinaryNotFound(program));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Same as [`crate::zfs_load_key`], with the binaries of this config
+    pub fn load_key(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        passphrase: impl AsRef<str>,
+    ) -> Result<(), ZfsError> {
+        self.load_key_detailed(zfs_dataset, passphrase).map(|_| ())
+    }
+
+    /// Same as [`crate::zfs_load_key_returning_root`], with the binaries of this config
+    pub fn load_key_returning_root(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        passphrase: impl AsRef<str>,
+    ) -> Result<Option<String>, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+        check_passphrase(&dataset, passphrase.as_ref())?;
+
+        let Some(root) = self.get_encryption_root(&dataset)? else {
+            return Ok(None);
+        };
+
+        self.run_load_key_detailed(
+            &root,
+            KeySource::Passphrase(passphrase.as_ref()),
+            WaitLimit::NONE,
+        )?;
+
+        Ok(Some(root))
+    }
+
+    /// Same as [`crate::zfs_load_key_detailed`], with the binaries of this config
+    pub fn load_key_detailed(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        passphrase: impl AsRef<str>,
+    ) -> Result<LoadKeyOutcome, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+        check_passphrase(&dataset, passphrase.as_ref())?;
+
+        self.run_load_key_detailed(
+            &dataset,
+            KeySource::Passphrase(passphrase.as_ref()),
+            WaitLimit::NONE,
+        )
+    }
+
+    /// Same as [`crate::zfs_load_key_from_keylocation`], with the binaries of this config
+    pub fn load_key_from_keylocation(&self, zfs_dataset: impl AsRef<str>) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        self.run_load_key(&dataset, KeySource::Keylocation, WaitLimit::NONE)
+    }
+
+    /// Same as [`crate::zfs_load_key_from_location`], with the binaries of this config
+    pub fn load_key_from_location(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        location: impl AsRef<str>,
+    ) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+        let location = check_and_sanitize_key_location(location)?;
+
+        self.run_load_key(&dataset, KeySource::Location(&location), WaitLimit::NONE)
+    }
+
+    /// Same as [`crate::zfs_load_key_bytes`], with the binaries of this config
+    pub fn load_key_bytes(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        key: &[u8],
+        append_newline: bool,
+    ) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+        if key.is_empty() {
+            return Err(ZfsError::EmptyPassphrase(dataset));
+        }
+
+        self.run_load_key(
+            &dataset,
+            KeySource::Stdin {
+                key,
+                append_newline,
+            },
+            WaitLimit::NONE,
+        )
+    }
+
+    /// Same as [`crate::zfs_load_key_with_timeout`], with the binaries of this config
+    pub fn load_key_with_timeout(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        passphrase: impl AsRef<str>,
+        timeout: Duration,
+    ) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+        check_passphrase(&dataset, passphrase.as_ref())?;
+
+        self.run_load_key(
+            &dataset,
+            KeySource::Passphrase(passphrase.as_ref()),
+            WaitLimit::timeout(timeout),
+        )
+    }
+
+    /// Same as [`crate::zfs_load_key_cancellable`], with the binaries of this config
+    pub fn load_key_cancellable(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        passphrase: impl AsRef<str>,
+        cancel: &AtomicBool,
+    ) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+        check_passphrase(&dataset, passphrase.as_ref())?;
+
+        self.run_load_key(
+            &dataset,
+            KeySource::Passphrase(passphrase.as_ref()),
+            WaitLimit::cancel(cancel),
+        )
+    }
+
+    /// Same as [`crate::zfs_load_key_auto`], with the binaries of this config
+    pub fn load_key_auto(&self, zfs_dataset: impl AsRef<str>, key: &[u8]) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        let keyformat = self.get_keyformat(&dataset)?;
+        self.run_load_key_for_keyformat(&dataset, &keyformat, key)
+    }
+
+    /// Same as [`crate::zfs_load_key_from_fd`], with the binaries of this config
+    pub fn load_key_from_fd(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        fd: impl AsFd,
+    ) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        // Reading from a duplicate leaves the file descriptor of the caller open
+        let file = fd
+            .as_fd()
+            .try_clone_to_owned()
+            .map(File::from)
+            .map_err(|e| ZfsError::KeyReadFailed(dataset.to_string(), e.to_string()))?;
+
+        self.run_load_key_from_reader(&dataset, file)
+    }
+
+    /// Same as [`crate::zfs_load_key_from_credential`], with the binaries of this config
+    pub fn load_key_from_credential(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        credential: impl AsRef<str>,
+    ) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+        let credential = credential.as_ref();
+
+        let credential_error = |path: &Path, reason: &str| {
+            ZfsError::KeyFileUnavailable(path.to_path_buf(), reason.to_string())
+        };
+
+        // A path would let the credential be outside of the credentials directory
+        if credential.is_empty()
+            || credential.contains('/')
+            || credential == "."
+            || credential == ".."
+        {
+            return Err(credential_error(
+                Path::new(credential),
+                "Not a valid credential name",
+            ));
+        }
+        let directory = std::env::var_os("CREDENTIALS_DIRECTORY").ok_or_else(|| {
+            credential_error(Path::new(credential), "$CREDENTIALS_DIRECTORY is not set")
+        })?;
+
+        let path = Path::new(&directory).join(credential);
+        let file = File::open(&path).map_err(|e| credential_error(&path, &e.to_string()))?;
+
+        self.run_load_key_from_reader(&dataset, file)
+    }
+
+    /// Gets the keyformat of an already sanitized dataset name
+    fn get_keyformat(&self, dataset: &str) -> Result<String, ZfsError> {
+        self.get_property(dataset, "keyformat")?
+            .ok_or_else(|| ZfsError::DatasetNotFound(dataset.to_string()))
+    }
+
+    /// Reads the key of an already sanitized dataset name from the reader, and loads it like load_key_auto
+    /// A new line at the end of the key is ignored, unless it's a raw key, of which it can be a byte.
+    fn run_load_key_from_reader(&self, dataset: &str, reader: impl Read) -> Result<(), ZfsError> {
+        let keyformat = self.get_keyformat(dataset)?;
+
+        let key = SecretBuffer::read_from(reader, MAX_KEY_READ_LEN)
+            .map_err(|e| ZfsError::KeyReadFailed(dataset.to_string(), e.to_string()))?
+            .ok_or_else(|| {
+                ZfsError::KeyReadFailed(
+                    dataset.to_string(),
+                    format!("The key is longer than {MAX_KEY_READ_LEN} bytes"),
+                )
+            })?;
+        let key = match keyformat.trim() {
+            "raw" => &key[..],
+            _ => key.strip_suffix(b"\n").unwrap_or(&key),
+        };
+
+        self.run_load_key_for_keyformat(dataset, &keyformat, key)
+    }
+
+    /// Loads the key of an already sanitized dataset name, written to zfs as the keyformat requires
+    fn run_load_key_for_keyformat(
+        &self,
+        dataset: &str,
+        keyformat: &str,
+        key: &[u8],
+    ) -> Result<(), ZfsError> {
+        let append_newline = check_key_for_keyformat(dataset, keyformat, key)?;
+
+        self.run_load_key(
+            dataset,
+            KeySource::Stdin {
+                key,
+                append_newline,
+            },
+            WaitLimit::NONE,
+        )
+    }
+
+    /// Same as [`crate::zfs_load_key_from_file`], with the binaries of this config
+    pub fn load_key_from_file(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        key_file: impl AsRef<Path>,
+    ) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+        let key_file = key_file.as_ref();
+
+        let key_file_error =
+            |reason: &str| ZfsError::KeyFileUnavailable(key_file.to_path_buf(), reason.to_string());
+
+        // zfs only accepts absolute paths in file:// URIs
+        let key_file = key_file
+            .canonicalize()
+            .map_err(|e| key_file_error(&e.to_string()))?;
+        if !key_file.is_file() {
+            return Err(key_file_error("Not a file"));
+        }
+        let key_file = key_file
+            .to_str()
+            .ok_or_else(|| key_file_error("Path is not valid UTF-8"))?;
+
+        self.run_load_key(
+            &dataset,
+            KeySource::Location(&format!("file://{key_file}")),
+            WaitLimit::NONE,
+        )
+    }
+
+    /// Same as [`crate::zfs_load_keys_bulk_with_concurrency`], with the binaries of this config
+    pub fn load_keys_bulk(
+        &self,
+        items: &[(String, String)],
+        concurrency: NonZeroUsize,
+    ) -> BTreeMap<String, Result<(), ZfsError>> {
+        let next_item = AtomicUsize::new(0);
+        let results = Mutex::new(BTreeMap::new());
+
+        std::thread::scope(|scope| {
+            for _ in 0..concurrency.get().min(items.len()) {
+                scope.spawn(|| {
+                    // Every worker takes the next item that wasn't taken yet, until none are left
+                    while let Some((dataset, passphrase)) =
+                        items.get(next_item.fetch_add(1, Ordering::Relaxed))
+                    {
+                        let result = self.load_key(dataset, passphrase);
+                        results
+                            .lock()
+                            .expect("Results lock poisoned")
+                            .insert(dataset.clone(), result);
+                    }
+                });
+            }
+        });
+
+        results.into_inner().expect("Results lock poisoned")
+    }
+
+    /// Same as [`crate::zfs_unlock_all`], with the binaries of this config
+    pub fn unlock_all(
+        &self,
+        passphrases: &BTreeMap<String, String>,
+    ) -> Result<BTreeMap<String, Result<(), ZfsError>>, ZfsError> {
+        let locked = self.list_locked_datasets()?;
+
+        Ok(unlock_all_passphrases(&locked, passphrases)
+            .into_iter()
+            .map(|(root, passphrase)| {
+                let result = match passphrase {
+                    Some(passphrase) => self.load_key(&root, passphrase),
+                    None => Err(ZfsError::NoPassphraseProvided(root.clone())),
+                };
+                (root, result)
+            })
+            .collect())
+    }
+
+    /// Same as [`crate::zfs_load_key_recursive`], with the binaries of this config
+    pub fn load_key_recursive(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        passphrase: impl AsRef<str>,
+    ) -> Result<RecursiveLoadKeyReport, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+        check_passphrase(&dataset, passphrase.as_ref())?;
+        let _lock = lock_dataset(&dataset);
+
+        let locked_before = locked_datasets(&self.list_key_states_under(&dataset)?);
+        if locked_before.is_empty() {
+            return Ok(RecursiveLoadKeyReport::default());
+        }
+
+        // Create a command to run zfs load-key
+        let mut command = self.privileged_zfs_command();
+        command.arg("load-key").arg("-r").arg(&dataset);
+
+        let stdin_data = SecretBuffer::concat(&[passphrase.as_ref().as_bytes(), b"\n"]);
+
+        let output =
+            self.run_privileged_command(command, &stdin_data, WaitLimit::NONE, &dataset, |e| {
+                ZfsError::LoadKeyCmdFailed(dataset.to_string(), CommandFailure::spawn_failed(e))
+            })?;
+
+        // -r may load only some of the keys, so check what actually changed
+        let locked_after = locked_datasets(&self.list_key_states_under(&dataset)?);
+        let report = RecursiveLoadKeyReport {
+            loaded: locked_before.difference(&locked_after).cloned().collect(),
+            failed: locked_before.intersection(&locked_after).cloned().collect(),
+        };
+
+        if !report.loaded.is_empty() || output.status.success() {
+            Ok(report)
+        } else if is_incorrect_key_error(&output.stderr) {
+            Err(ZfsError::IncorrectPassphrase(dataset.to_string()))
+        } else {
+            Err(ZfsError::LoadKeyCmdFailed(
+                dataset.to_string(),
+                output.failure(),
+            ))
+        }
+    }
+
+    /// Same as [`crate::zfs_load_key_all`], with the binaries of this config
+    pub fn load_key_all(&self) -> Result<RecursiveLoadKeyReport, ZfsError> {
+        let locked_before = locked_datasets(&self.list_key_states()?);
+        if locked_before.is_empty() {
+            return Ok(RecursiveLoadKeyReport::default());
+        }
+
+        let mut command = self.privileged_zfs_command();
+        command.arg("load-key").arg("-a");
+
+        let output =
+            self.run_privileged_command(command, &[], WaitLimit::NONE, ALL_DATASETS, |e| {
+                ZfsError::LoadKeyAllCmdFailed(CommandFailure::spawn_failed(e))
+            })?;
+
+        // -a fails if any key can't be loaded, so check what actually changed
+        let locked_after = locked_datasets(&self.list_key_states()?);
+        let report = RecursiveLoadKeyReport {
+            loaded: locked_before.difference(&locked_after).cloned().collect(),
+            failed: locked_before.intersection(&locked_after).cloned().collect(),
+        };
+
+        if !report.loaded.is_empty() || output.status.success() {
+            Ok(report)
+        } else {
+            Err(ZfsError::LoadKeyAllCmdFailed(output.failure()))
+        }
+    }
+
+    /// Lists whether the keys of all encrypted datasets are loaded
+    fn list_key_states(&self) -> Result<BTreeMap<String, bool>, ZfsError> {
+        let mut command = self.zfs_command();
+        command
+            .arg("list")
+            .arg("-H") // No table header
+            .arg("-o")
+            .arg("name,keystatus"); // Only show two columns, dataset name and whether key is available
+
+        let output = self.run(
+            command,
+            &[],
+            WaitLimit::NONE,
+            ALL_DATASETS,
+            ZfsError::ListKeyStatesCallFailed,
+        )?;
+
+        if output.status.success() {
+            parse_key_states(&output.stdout)
+        } else {
+            Err(ZfsError::ListKeyStatesCallFailed(output.stderr))
+        }
+    }
+
+    /// Lists whether the keys of an already sanitized dataset name and its encrypted descendants are loaded
+    fn list_key_states_under(&self, dataset: &str) -> Result<BTreeMap<String, bool>, ZfsError> {
+        let mut command = self.zfs_command();
+        command
+            .arg("list")
+            .arg("-r") // Include all descendants
+            .arg("-H") // No table header
+            .arg("-o")
+            .arg("name,keystatus") // Only show two columns, dataset name and whether key is available
+            .arg(dataset);
+
+        let output = self.run(command, &[], WaitLimit::NONE, dataset, |e| {
+            ZfsError::ListDescendantsCallFailed(dataset.to_string(), e)
+        })?;
+
+        if output.status.success() {
+            parse_key_states(&output.stdout)
+        } else if is_dataset_not_found_error(&output.stderr) {
+            Err(ZfsError::DatasetNotFound(dataset.to_string()))
+        } else {
+            Err(ZfsError::ListDescendantsCallFailed(
+                dataset.to_string(),
+                output.stderr,
+            ))
+        }
+    }
+
+    /// Runs `zfs load-key` for an already sanitized dataset name
+    fn run_load_key(
+        &self,
+        dataset: &str,
+        source: KeySource,
+        limit: WaitLimit,
+    ) -> Result<(), ZfsError> {
+        self.run_load_key_detailed(dataset, source, limit)
+            .map(|_| ())
+    }
+
+    /// Same as run_load_key, but returns whether zfs load-key had to run
+    fn run_load_key_detailed(
+        &self,
+        dataset: &str,
+        source: KeySource,
+        limit: WaitLimit,
+    ) -> Result<LoadKeyOutcome, ZfsError> {
+        let _lock = lock_dataset(dataset);
+
+        match self.is_key_loaded(dataset)? {
+            Some(true) => return Ok(LoadKeyOutcome::AlreadyLoaded),
+            Some(false) => (),
+            None => return Err(ZfsError::DatasetNotFound(dataset.to_string())),
+        }
+
+        if let KeySource::Passphrase(_) = source {
+            self.check_keylocation_is_prompt(dataset)?;
+        }
+
+        let location = match source {
+            KeySource::Location(location) => Some(location),
+            KeySource::Passphrase(_) | KeySource::Stdin { .. } | KeySource::Keylocation => None,
+        };
+        let command = self.load_key_command(dataset, location);
+
+        let stdin_data = match source {
+            KeySource::Passphrase(passphrase) => {
+                SecretBuffer::concat(&[passphrase.as_bytes(), b"\n"])
+            }
+            KeySource::Stdin {
+                key,
+                append_newline: true,
+            } => SecretBuffer::concat(&[key, b"\n"]),
+            KeySource::Stdin {
+                key,
+                append_newline: false,
+            } => SecretBuffer::concat(&[key]),
+            KeySource::Location(_) | KeySource::Keylocation => SecretBuffer::concat(&[]),
+        };
+
+        let output = self.run_privileged_command(command, &stdin_data, limit, dataset, |e| {
+            ZfsError::LoadKeyCmdFailed(dataset.to_string(), CommandFailure::spawn_failed(e))
+        })?;
+
+        load_key_output_result(dataset, output)
+    }
+
+    /// Same as [`crate::zfs_send_raw`], with the binaries of this config
+    pub fn send_raw(
+        &self,
+        snapshot: impl AsRef<str>,
+        mut writer: impl Write,
+    ) -> Result<u64, ZfsError> {
+        let snapshot = check_and_sanitize_zfs_snapshot_name(snapshot)?;
+
+        let mut command = self.privileged_zfs_command();
+        command.arg("send").arg("--raw").arg(&snapshot);
+
+        let program = self.privileged_program(&command);
+        let spawn_error =
+            |e| ZfsError::SendCmdFailed(snapshot.to_string(), CommandFailure::spawn_failed(e));
+        let write_error =
+            |e: std::io::Error| ZfsError::SendWriteFailed(snapshot.to_string(), e.to_string());
+
+        let (written, output) = match &self.runner {
+            Some(runner) => {
+                let output = runner
+                    .run(&command_os_argv(&command), None)
+                    .map_err(|e| self.binary_not_found(spawn_failure(&command, e, spawn_error)))?;
+                if output.status.success() {
+                    writer.write_all(&output.stdout).map_err(write_error)?;
+                }
+                (
+                    output.stdout.len() as u64,
+                    CommandOutput {
+                        status: output.status,
+                        stdout: String::new(),
+                        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+                    },
+                )
+            }
+            None => run_command_to_writer(command, &mut writer, spawn_error, |e| {
+                ZfsError::SendWriteFailed(snapshot.to_string(), e)
+            })
+            .map_err(|e| self.binary_not_found(e))?,
+        };
+        self.check_sudo_failure(program, &output, &snapshot)?;
+
+        if output.status.success() {
+            writer.flush().map_err(write_error)?;
+            Ok(written)
+        } else if is_dataset_not_found_error(&output.stderr) {
+            Err(ZfsError::DatasetNotFound(snapshot))
+        } else {
+            Err(ZfsError::SendCmdFailed(snapshot, output.failure()))
+        }
+    }
+
+    /// Same as [`crate::zfs_unload_key`], with the binaries of this config
+    pub fn unload_key(&self, zfs_dataset: impl AsRef<str>) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+        let _lock = lock_dataset(&dataset);
+
+        match self.is_key_loaded(&dataset)? {
+            Some(true) => (),
+            Some(false) => return Ok(()),
+            None => return Err(ZfsError::DatasetNotFound(dataset.to_string())),
+        }
+
+        // zfs refuses to unload the key of a mounted dataset with a message that doesn't say why
+        match self.is_dataset_mounted(&dataset)? {
+            Some(true) => return Err(ZfsError::CannotUnloadKeyWhileMounted(dataset)),
+            Some(false) => (),
+            None => return Err(ZfsError::DatasetNotFound(dataset.to_string())),
+        }
+        self.check_not_held(&dataset)?;
+
+        // Create a command to run zfs unload-key
+        let command = self.unload_key_command(&dataset);
+
+        let output = self.run_privileged_command(command, &[], WaitLimit::NONE, &dataset, |e| {
+            ZfsError::UnloadKeyCmdFailed(dataset.to_string(), CommandFailure::spawn_failed(e))
+        })?;
+
+        unload_key_output_result(&dataset, output)
+    }
+
+    /// Same as [`crate::zfs_unload_key_recursive`], with the binaries of this config
+    pub fn unload_key_recursive(&self, zfs_dataset: impl AsRef<str>) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+        let _lock = lock_dataset(&dataset);
+
+        if unlocked_datasets(&self.list_key_states_under(&dataset)?).is_empty() {
+            return Ok(());
+        }
+        self.check_not_held(&dataset)?;
+
+        let mut command = self.privileged_zfs_command();
+        command.arg("unload-key").arg("-r").arg(&dataset);
+
+        // zfs fails if any key can't be unloaded, which is reported from what's still loaded instead
+        self.run_privileged_command(command, &[], WaitLimit::NONE, &dataset, |e| {
+            ZfsError::UnloadKeyCmdFailed(dataset.to_string(), CommandFailure::spawn_failed(e))
+        })?;
+
+        let still_loaded = unlocked_datasets(&self.list_key_states_under(&dataset)?);
+        if still_loaded.is_empty() {
+            Ok(())
+        } else {
+            Err(ZfsError::KeysStillLoaded(dataset.to_string(), still_loaded))
+        }
+    }
+
+    /// Fails with ZfsError::SnapshotsHeld if check_holds is set and snapshots of an already sanitized dataset name,
+    /// or of its descendants, have holds
+    pub(crate) fn check_not_held(&self, dataset: &str) -> Result<(), ZfsError> {
+        if !self.check_holds {
+            return Ok(());
+        }
+
+        let snapshots = self
+            .list_snapshots(dataset)?
+            .into_iter()
+            .map(|snapshot| snapshot.name)
+            .collect::<Vec<_>>();
+        if snapshots.is_empty() {
+            return Ok(());
+        }
+
+        let held = self.list_holds_of(dataset, &snapshots)?;
+        if held.is_empty() {
+            Ok(())
+        } else {
+            Err(ZfsError::SnapshotsHeld(
+                dataset.to_string(),
+                held.into_keys().collect(),
+            ))
+        }
+    }
+
+    /// Same as [`crate::zfs_list_holds`], with the binaries of this config
+    pub fn list_holds(&self, snapshot: impl AsRef<str>) -> Result<Vec<String>, ZfsError> {
+        let snapshot = check_and_sanitize_zfs_snapshot_name(snapshot)?;
+
+        Ok(self
+            .list_holds_of(&snapshot, std::slice::from_ref(&snapshot))?
+            .remove(&snapshot)
+            .unwrap_or_default())
+    }
+
+    /// Lists the holds of already sanitized snapshot names, by snapshot, with label naming them in errors
+    fn list_holds_of(
+        &self,
+        label: &str,
+        snapshots: &[String],
+    ) -> Result<BTreeMap<String, Vec<String>>, ZfsError> {
+        let mut command = self.zfs_command();
+        command
+            .arg("holds")
+            .arg("-H") // No table header
+            .args(snapshots);
+
+        let output = self.run(command, &[], WaitLimit::NONE, label, |e| {
+            ZfsError::ListHoldsCallFailed(label.to_string(), e)
+        })?;
+
+        parse_holds_result(
+            label,
+            output.status.success(),
+            &output.stdout,
+            output.stderr,
+        )
+    }
+
+    /// Same as [`crate::zfs_change_key`], with the binaries of this config
+    pub fn change_key(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        new_passphrase: impl AsRef<str>,
+    ) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+        check_passphrase(&dataset, new_passphrase.as_ref())?;
+        let _lock = lock_dataset(&dataset);
+
+        match self.is_key_loaded(&dataset)? {
+            Some(true) => (),
+            Some(false) => return Err(ZfsError::KeyNotLoadedForChangeKey(dataset.to_string())),
+            None => return Err(ZfsError::DatasetNotFound(dataset.to_string())),
+        }
+
+        // Create a command to run zfs change-key
+        let mut command = self.privileged_zfs_command();
+        command.arg("change-key").arg(&dataset);
+
+        // zfs asks for the new passphrase again to confirm it, so it's written twice.
+        // If zfs doesn't ask for confirmation, the second line is ignored.
+        let line = new_passphrase.as_ref().as_bytes();
+        let stdin_data = SecretBuffer::concat(&[line, b"\n", line, b"\n"]);
+
+        let output =
+            self.run_privileged_command(command, &stdin_data, WaitLimit::NONE, &dataset, |e| {
+                ZfsError::ChangeKeyCmdFailed(dataset.to_string(), CommandFailure::spawn_failed(e))
+            })?;
+
+        // Check if the command was successful
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(ZfsError::ChangeKeyCmdFailed(
+                dataset.to_string(),
+                output.failure(),
+            ))
+        }
+    }
+
+    /// Same as [`crate::zfs_create_encrypted_dataset`], with the binaries of this config
+    pub fn create_encrypted_dataset(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        passphrase: impl AsRef<str>,
+        opts: CreateOpts,
+    ) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+        check_passphrase(&dataset, passphrase.as_ref())?;
+        let properties = opts.to_properties()?;
+        let _lock = lock_dataset(&dataset);
+
+        let command = self.create_command(&dataset, &properties);
+
+        // Like change-key, zfs asks for the new passphrase again to confirm it
+        let line = passphrase.as_ref().as_bytes();
+        let stdin_data = SecretBuffer::concat(&[line, b"\n", line, b"\n"]);
+
+        let output =
+            self.run_privileged_command(command, &stdin_data, WaitLimit::NONE, &dataset, |e| {
+                ZfsError::CreateDatasetCmdFailed(
+                    dataset.to_string(),
+                    CommandFailure::spawn_failed(e),
+                )
+            })?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(ZfsError::CreateDatasetCmdFailed(
+                dataset.to_string(),
+                output.failure(),
+            ))
+        }
+    }
+
+    /// Same as [`crate::zfs_set_property`], with the binaries of this config
+    pub fn set_property(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        property: impl AsRef<str>,
+        value: impl AsRef<str>,
+    ) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+        let property = check_and_sanitize_property(property, value)?;
+        let _lock = lock_dataset(&dataset);
+
+        let mut command = self.privileged_zfs_command();
+        command.arg("set").arg(&property).arg(&dataset);
+
+        let output = self.run_privileged_command(command, &[], WaitLimit::NONE, &dataset, |e| {
+            ZfsError::SetPropertyCmdFailed(dataset.to_string(), CommandFailure::spawn_failed(e))
+        })?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(ZfsError::SetPropertyCmdFailed(
+                dataset.to_string(),
+                output.failure(),
+            ))
+        }
+    }
+
+    /// Same as [`crate::zfs_mount_dataset`], with the binaries of this config
+    pub fn mount_dataset(&self, zfs_dataset: impl AsRef<str>) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        self.run_mount(&dataset, &[], WaitLimit::NONE)
+    }
+
+    /// Same as [`crate::zfs_mount_dataset_returning_path`], with the binaries of this config
+    pub fn mount_dataset_returning_path(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+    ) -> Result<PathBuf, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        self.run_mount(&dataset, &[], WaitLimit::NONE)?;
+
+        mounted_path_result(&dataset, self.get_property(&dataset, "mountpoint")?)
+    }
+
+    /// Same as [`crate::zfs_mount_dataset_verbose`], with the binaries of this config
+    pub fn mount_dataset_verbose(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+    ) -> Result<CommandOutput, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        self.run_mount_with_output(&dataset, &[], WaitLimit::NONE)
+    }
+
+    /// Same as [`crate::zfs_mount_dataset_with_timeout`], with the binaries of this config
+    pub fn mount_dataset_with_timeout(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        timeout: Duration,
+    ) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        self.run_mount(&dataset, &[], WaitLimit::timeout(timeout))
+    }
+
+    /// Same as [`crate::zfs_mount_dataset_cancellable`], with the binaries of this config
+    pub fn mount_dataset_cancellable(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        cancel: &AtomicBool,
+    ) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        self.run_mount(&dataset, &[], WaitLimit::cancel(cancel))
+    }
+
+    /// Same as [`crate::zfs_mount_dataset_with_retry`], with the binaries of this config
+    pub fn mount_dataset_with_retry(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        attempts: u32,
+        backoff: Duration,
+    ) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        retry_with_backoff(attempts, backoff, is_transient_mount_error, || {
+            self.run_mount(&dataset, &[], WaitLimit::NONE)
+        })
+    }
+
+    /// Same as [`crate::zfs_mount_dataset_with_options`], with the binaries of this config
+    pub fn mount_dataset_with_options(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        options: &[&str],
+    ) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+        let options = options
+            .iter()
+            .map(check_and_sanitize_mount_option)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.run_mount(&dataset, &options, WaitLimit::NONE)
+    }
+
+    /// Same as [`crate::zfs_mount_at`], with the binaries of this config
+    pub fn mount_at(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        path: &Path,
+        options: &[&str],
+    ) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+        let mut options = options
+            .iter()
+            .map(check_and_sanitize_mount_option)
+            .map(|option| match option {
+                Ok(option) if option.starts_with("mountpoint=") => {
+                    Err(ZfsError::MountOptionIsInvalid(option))
+                }
+                option => option,
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        options.push(check_and_sanitize_mountpoint(path)?);
+
+        self.run_mount(&dataset, &options, WaitLimit::NONE)
+    }
+
+    /// Runs `zfs mount` for an already sanitized dataset name and options
+    fn run_mount(
+        &self,
+        dataset: &str,
+        options: &[String],
+        limit: WaitLimit,
+    ) -> Result<(), ZfsError> {
+        self.run_mount_with_output(dataset, options, limit)
+            .map(|_| ())
+    }
+
+    /// Same as run_mount, but returns the output of zfs mount, which is empty if it didn't have to run
+    fn run_mount_with_output(
+        &self,
+        dataset: &str,
+        options: &[String],
+        limit: WaitLimit,
+    ) -> Result<CommandOutput, ZfsError> {
+        let output = match self.run_mount_locked(dataset, options, limit)? {
+            Some(output) => output,
+            None => return Ok(CommandOutput::default()),
+        };
+
+        // The dataset is unlocked by now, so the hook can use it
+        if let Some(on_mounted) = &self.on_mounted {
+            let mountpoint = self.get_property(dataset, "mountpoint")?;
+            if let Some(Mountpoint::Path(mountpoint)) =
+                mountpoint.as_deref().map(parse_mountpoint_property)
+            {
+                on_mounted(dataset, &mountpoint);
+            }
+        }
+
+        Ok(output)
+    }
+
+    /// Checks that a mounted dataset is mounted at its mountpoint, if it's in the mount table of the system
+    pub(crate) fn check_mounted_at_mountpoint(&self, dataset: &str) -> Result<(), ZfsError> {
+        let expected = match self.get_property(dataset, "mountpoint")? {
+            Some(mountpoint) => match parse_mountpoint_property(&mountpoint) {
+                Mountpoint::Path(path) => path,
+                // Only the system knows where legacy mountpoints should be
+                Mountpoint::Legacy | Mountpoint::None => return Ok(()),
+            },
+            None => return Err(ZfsError::DatasetNotFound(dataset.to_string())),
+        };
+
+        let mount_table = self.read_mount_table(dataset)?;
+        let targets = parse_mount_table_targets(&mount_table, dataset);
+
+        match targets.first() {
+            Some(actual) if !targets.contains(&expected) => {
+                Err(ZfsError::MountedAtUnexpectedLocation(
+                    dataset.to_string(),
+                    actual.clone(),
+                    expected,
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads the mount table of the system, or of the host that the runner runs the commands on if there's one
+    fn read_mount_table(&self, dataset: &str) -> Result<String, ZfsError> {
+        if self.runner.is_none() {
+            return std::fs::read_to_string(MOUNT_TABLE_PATH)
+                .map_err(|e| ZfsError::SystemError(e.to_string()));
+        }
+
+        let mut command = self.command(Path::new("cat"));
+        command.arg(MOUNT_TABLE_PATH);
+        let output = self.run(
+            command,
+            &[],
+            WaitLimit::NONE,
+            dataset,
+            ZfsError::SystemError,
+        )?;
+
+        if output.status.success() {
+            Ok(output.stdout)
+        } else {
+            Err(ZfsError::SystemError(format!(
+                "Reading {MOUNT_TABLE_PATH} failed: {}",
+                output.stderr.trim_end()
+            )))
+        }
+    }
+
+    /// Runs `zfs mount` while holding the lock of the dataset
+    /// Returns: Ok(None) if the dataset was already mounted, so zfs mount didn't run
+    fn run_mount_locked(
+        &self,
+        dataset: &str,
+        options: &[String],
+        limit: WaitLimit,
+    ) -> Result<Option<CommandOutput>, ZfsError> {
+        let _lock = lock_dataset(dataset);
+
+        match self.is_key_loaded(dataset)? {
+            Some(true) => (),
+            Some(false) => return Err(ZfsError::KeyNotLoadedForMount(dataset.to_string())),
+            None => return Err(ZfsError::DatasetNotFound(dataset.to_string())),
+        }
+
+        match self.is_dataset_mounted(dataset)? {
+            Some(true) if self.strict_mountpoint => {
+                return self.check_mounted_at_mountpoint(dataset).map(|()| None)
+            }
+            Some(true) => return Ok(None),
+            Some(false) => (),
+            None => return Err(ZfsError::DatasetNotFound(dataset.to_string())),
+        }
+
+        // zfs mount refuses legacy mountpoints with a confusing message, or does nothing
+        let mountpoint = self.get_property(dataset, "mountpoint")?;
+        if mountpoint.as_deref().map(parse_mountpoint_property) == Some(Mountpoint::Legacy) {
+            return Err(ZfsError::LegacyMountpoint(dataset.to_string()));
+        }
+        // Same for canmount=off, which some versions of zfs mount report as success
+        if self
+            .get_property(dataset, "canmount")?
+            .as_deref()
+            .map(str::trim)
+            == Some("off")
+        {
+            return Err(ZfsError::CanmountOff(dataset.to_string()));
+        }
+
+        // Create a command to run zfs mount
+        let command = self.mount_command(dataset, options);
+
+        let output = self.run_privileged_command(command, &[], limit, dataset, |e| {
+            ZfsError::MountCmdFailed(dataset.to_string(), CommandFailure::spawn_failed(e))
+        })?;
+
+        // Check if the command was successful
+        if output.status.success() {
+            Ok(Some(output))
+        } else if is_mountpoint_occupied_error(&output.stderr) {
+            let mountpoint = match mountpoint_from_mount_error(&output.stderr) {
+                Some(mountpoint) => Some(mountpoint),
+                None => self.get_property(dataset, "mountpoint")?.map(PathBuf::from),
+            };
+            Err(mount_failure(dataset, output, mountpoint))
+        } else {
+            Err(mount_failure(dataset, output, None))
+        }
+    }
+
+    /// Same as [`crate::zfs_mount_all`], with the binaries of this config
+    pub fn mount_all(&self) -> Result<MountAllReport, ZfsError> {
+        let unmounted_before = self.list_unmounted_filesystems()?;
+        if unmounted_before.is_empty() {
+            return Ok(MountAllReport::default());
+        }
+
+        let mut command = self.privileged_zfs_command();
+        command.arg("mount").arg("-a");
+
+        let output =
+            self.run_privileged_command(command, &[], WaitLimit::NONE, ALL_DATASETS, |e| {
+                ZfsError::MountAllCmdFailed(CommandFailure::spawn_failed(e))
+            })?;
+
+        // -a fails if any filesystem can't be mounted, so check what actually changed
+        let unmounted_after = self.list_unmounted_filesystems()?;
+        let report = MountAllReport {
+            mounted: unmounted_before
+                .difference(&unmounted_after)
+                .cloned()
+                .collect(),
+            failed: unmounted_before
+                .intersection(&unmounted_after)
+                .cloned()
+                .collect(),
+        };
+
+        if !report.mounted.is_empty() || output.status.success() {
+            Ok(report)
+        } else {
+            Err(ZfsError::MountAllCmdFailed(output.failure()))
+        }
+    }
+
+    /// Lists the filesystems that `zfs mount -a` would mount, but aren't mounted
+    fn list_unmounted_filesystems(&self) -> Result<BTreeSet<String>, ZfsError> {
+        let mut command = self.zfs_command();
+        command
+            .arg("list")
+            .arg("-H") // No table header
+            .arg("-t")
+            .arg("filesystem") // Volumes can't be mounted
+            .arg("-o")
+            .arg("name,mounted,canmount,mountpoint");
+
+        let output = self.run(
+            command,
+            &[],
+            WaitLimit::NONE,
+            ALL_DATASETS,
+            ZfsError::ListUnmountedDatasetsCallFailed,
+        )?;
+
+        if output.status.success() {
+            parse_unmounted_filesystems(&output.stdout)
+        } else {
+            Err(ZfsError::ListUnmountedDatasetsCallFailed(output.stderr))
+        }
+    }
+
+    /// Same as [`crate::zfs_unlock_and_mount`], with the binaries of this config
+    pub fn unlock_and_mount(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        passphrase: impl AsRef<str>,
+    ) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        self.load_key(&dataset, passphrase)?;
+        self.mount_dataset(&dataset)
+    }
+
+    /// Same as [`crate::zfs_ensure_available`], with the binaries of this config
+    pub fn ensure_available(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        passphrase: impl AsRef<str>,
+    ) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        self.unlock_and_mount(&dataset, passphrase)?;
+
+        match self.is_unlocked_and_mounted(&dataset)? {
+            Some((true, true)) => Ok(()),
+            Some(_) => Err(ZfsError::VerificationFailed(dataset)),
+            None => Err(ZfsError::DatasetNotFound(dataset)),
+        }
+    }
+
+    /// Same as [`crate::zfs_unmount_dataset`], with the binaries of this config
+    pub fn unmount_dataset(&self, zfs_dataset: impl AsRef<str>) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        self.run_unmount(&dataset, false)
+    }
+
+    /// Same as [`crate::zfs_unmount_dataset_force`], with the binaries of this config
+    pub fn unmount_dataset_force(&self, zfs_dataset: impl AsRef<str>) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        self.run_unmount(&dataset, true)
+    }
+
+    /// Same as [`crate::zfs_unmount_by_mountpoint`], with the binaries of this config
+    pub fn unmount_by_mountpoint(&self, path: &Path) -> Result<Vec<String>, ZfsError> {
+        let datasets = datasets_at_mountpoint(&self.list_datasets_mountpoints()?, path);
+        if datasets.is_empty() {
+            return Err(ZfsError::NoDatasetAtMountpoint(path.to_path_buf()));
+        }
+
+        let mut unmounted = Vec::new();
+        for (dataset, state) in self.get_states(&datasets)? {
+            if state.is_mounted {
+                self.run_unmount(&dataset, false)?;
+                unmounted.push(dataset);
+            }
+        }
+        Ok(unmounted)
+    }
+
+    /// Same as [`crate::zfs_unmount_tree`], with the binaries of this config
+    pub fn unmount_tree(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+    ) -> Result<BTreeMap<String, Result<(), ZfsError>>, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        let mut command = self.zfs_command();
+        command
+            .arg("list")
+            .arg("-r") // Include all descendants
+            .arg("-H") // No table header
+            .arg("-t")
+            .arg("filesystem") // Only filesystems can be mounted
+            .arg("-o")
+            .arg("name,mounted") // Only show two columns, dataset name and whether dataset is mounted
+            .arg(&dataset);
+
+        let output = self.run(command, &[], WaitLimit::NONE, &dataset, |e| {
+            ZfsError::ListDescendantsCallFailed(dataset.to_string(), e)
+        })?;
+
+        if !output.status.success() {
+            return if is_dataset_not_found_error(&output.stderr) {
+                Err(ZfsError::DatasetNotFound(dataset.to_string()))
+            } else {
+                Err(ZfsError::ListDescendantsCallFailed(
+                    dataset.to_string(),
+                    output.stderr,
+                ))
+            };
+        }
+
+        Ok(parse_unmount_order(&output.stdout)?
+            .into_iter()
+            .map(|descendant| {
+                let result = self.run_unmount(&descendant, false);
+                (descendant, result)
+            })
+            .collect())
+    }
+
+    /// Same as [`crate::zfs_unmount_and_unload`], with the binaries of this config
+    pub fn unmount_and_unload(&self, zfs_dataset: impl AsRef<str>) -> Result<(), ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        // Checked before unmounting too, so that a held dataset is left as it is
+        self.check_not_held(&dataset)?;
+        self.unmount_dataset(&dataset)?;
+        self.unload_key(&dataset)
+    }
+
+    /// Same as [`crate::zfs_unmount_and_unload_atomic`], with the binaries of this config
+    pub fn unmount_and_unload_atomic(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+    ) -> Result<UnmountAndUnloadMethod, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        let supports_combined = match self.zfs_version() {
+            Ok(version) => version >= UNMOUNT_UNLOAD_KEY_MIN_VERSION,
+            // Versions before `zfs version` existed don't have `zfs unmount -u` either
+            Err(ZfsError::VersionCallFailed(_)) => false,
+            Err(e) => return Err(e),
+        };
+        if !supports_combined {
+            self.unmount_and_unload(&dataset)?;
+            return Ok(UnmountAndUnloadMethod::Separate);
+        }
+        // umount -u unloads the key without unload_key
+        self.check_not_held(&dataset)?;
+
+        let mut command = self.privileged_zfs_command();
+        command.arg("umount").arg("-u").arg(&dataset);
+        let method = if self.run_unmount_command(&dataset, command)? {
+            UnmountAndUnloadMethod::Combined
+        } else {
+            UnmountAndUnloadMethod::Separate
+        };
+
+        // -u only unloads the keys of encryption roots, and nothing if the dataset was already unmounted
+        self.unload_key(&dataset)?;
+
+        Ok(method)
+    }
+
+    /// Same as [`crate::zfs_unmount_dataset_graceful`], with the binaries of this config
+    pub fn unmount_dataset_graceful(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        retries: u32,
+        delay: Duration,
+        escalate_to_force: bool,
+    ) -> Result<GracefulUnmountReport, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match self.run_unmount(&dataset, false) {
+                Ok(()) => {
+                    return Ok(GracefulUnmountReport {
+                        attempts,
+                        forced: false,
+                    })
+                }
+                Err(ZfsError::DatasetBusy(_)) if attempts <= retries => std::thread::sleep(delay),
+                Err(ZfsError::DatasetBusy(_)) if escalate_to_force => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        attempts += 1;
+        self.run_unmount(&dataset, true)?;
+
+        Ok(GracefulUnmountReport {
+            attempts,
+            forced: true,
+        })
+    }
+
+    /// Same as [`crate::zfs_remount_dataset`], with the binaries of this config
+    pub fn remount_dataset(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+    ) -> Result<RemountOutcome, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        let outcome = match self.is_dataset_mounted(&dataset)? {
+            Some(true) => {
+                match self.run_unmount(&dataset, false) {
+                    Ok(()) => (),
+                    // Something else may have unmounted it between the check and the unmount
+                    Err(ZfsError::UnmountCmdFailed(_, _))
+                        if self.is_dataset_mounted(&dataset)? == Some(false) => {}
+                    Err(e) => return Err(e),
+                }
+                RemountOutcome::Remounted
+            }
+            Some(false) => RemountOutcome::AlreadyUnmounted,
+            None => return Err(ZfsError::DatasetNotFound(dataset.to_string())),
+        };
+
+        self.run_mount(&dataset, &[], WaitLimit::NONE)?;
+
+        Ok(outcome)
+    }
+
+    /// Runs `zfs umount` for an already sanitized dataset name
+    fn run_unmount(&self, dataset: &str, force: bool) -> Result<(), ZfsError> {
+        self.run_unmount_command(dataset, self.unmount_command(dataset, force))
+            .map(|_| ())
+    }
+
+    /// Runs the unmount command for an already sanitized dataset name, if the dataset is mounted
+    /// Returns: Ok(true) if the command ran and succeeded, Ok(false) if the dataset was already unmounted
+    fn run_unmount_command(&self, dataset: &str, command: Command) -> Result<bool, ZfsError> {
+        let _lock = lock_dataset(dataset);
+
+        match self.is_dataset_mounted(dataset)? {
+            Some(true) => (),
+            Some(false) => return Ok(false),
+            None => return Err(ZfsError::DatasetNotFound(dataset.to_string())),
+        }
+
+        let output = self.run_privileged_command(command, &[], WaitLimit::NONE, dataset, |e| {
+            ZfsError::UnmountCmdFailed(dataset.to_string(), CommandFailure::spawn_failed(e))
+        })?;
+
+        unmount_output_result(dataset, output)
+    }
+
+    /// Same as [`crate::zfs_dataset_exists`], with the binaries of this config
+    pub fn dataset_exists(&self, zfs_dataset: impl AsRef<str>) -> Result<bool, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        let mut command = self.zfs_command();
+        command
+            .arg("list")
+            .arg("-H") // No table header
+            .arg("-o")
+            .arg("name") // Only show the dataset name
+            .arg(&dataset);
+
+        let output = self.run(command, &[], WaitLimit::NONE, &dataset, |e| {
+            ZfsError::DatasetExistsCheckFailed(dataset.to_string(), e)
+        })?;
+
+        parse_dataset_exists_result(&dataset, output.status.success(), output.stderr)
+    }
+
+    /// Same as [`crate::zfs_is_key_loaded`], with the binaries of this config
+    pub fn is_key_loaded(&self, zfs_dataset: impl AsRef<str>) -> Result<Option<bool>, ZfsError> {
+        self.key_status(zfs_dataset).map(KeyStatus::is_loaded)
+    }
+
+    /// Same as [`crate::zfs_key_status`], with the binaries of this config
+    pub fn key_status(&self, zfs_dataset: impl AsRef<str>) -> Result<KeyStatus, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        let command = self.key_status_command(&dataset);
+        let output = self.run(command, &[], WaitLimit::NONE, &dataset, |e| {
+            ZfsError::KeyLoadedCheckFailed(dataset.to_string(), e)
+        })?;
+
+        parse_key_status_result(
+            &dataset,
+            output.status.success(),
+            &output.stdout,
+            output.stderr,
+        )
+    }
+
+    /// Same as [`crate::zfs_is_dataset_mounted`], with the binaries of this config
+    pub fn is_dataset_mounted(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+    ) -> Result<Option<bool>, ZfsError> {
+        self.mount_status(zfs_dataset).map(MountStatus::is_mounted)
+    }
+
+    /// Same as [`crate::zfs_mount_status`], with the binaries of this config
+    pub fn mount_status(&self, zfs_dataset: impl AsRef<str>) -> Result<MountStatus, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        let command = self.mount_status_command();
+        let output = self.run(command, &[], WaitLimit::NONE, &dataset, |e| {
+            ZfsError::IsMountedCheckCallFailed(dataset.to_string(), e)
+        })?;
+
+        parse_mount_status_result(
+            &dataset,
+            output.status.success(),
+            &output.stdout,
+            output.stderr,
+        )
+    }
+
+    /// Same as [`crate::zfs_wait_until_mounted`], with the binaries of this config
+    pub fn wait_until_mounted(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        timeout: Duration,
+        poll_interval: Duration,
+    ) -> Result<bool, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.is_dataset_mounted(&dataset)? {
+                Some(true) => return Ok(true),
+                Some(false) => (),
+                None => return Err(ZfsError::DatasetNotFound(dataset.to_string())),
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(false);
+            }
+            std::thread::sleep(poll_interval.min(deadline - now));
+        }
+    }
+
+    /// Same as [`crate::zfs_get_dataset_state`], with the binaries of this config
+    pub fn get_dataset_state(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+    ) -> Result<Option<DatasetMountedState>, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        let mut command = self.zfs_command();
+        command
+            .arg("list")
+            .arg("-H") // No table header
+            .arg("-o")
+            .arg(DATASET_STATE_COLUMNS)
+            .arg(&dataset); // Only list this dataset
+
+        let output = self.run(command, &[], WaitLimit::NONE, &dataset, |e| {
+            ZfsError::DatasetStateCallFailed(dataset.to_string(), e)
+        })?;
+
+        parse_dataset_state_result(
+            &dataset,
+            output.status.success(),
+            &output.stdout,
+            output.stderr,
+        )
+    }
+
+    /// Same as [`crate::zfs_get_states`], with the binaries of this config
+    pub fn get_states(
+        &self,
+        zfs_datasets: &[impl AsRef<str>],
+    ) -> Result<BTreeMap<String, DatasetMountedState>, ZfsError> {
+        let datasets = zfs_datasets
+            .iter()
+            .map(check_and_sanitize_zfs_dataset_name)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Without any dataset, zfs would list all of them
+        if datasets.is_empty() {
+            return Ok(BTreeMap::new());
+        }
+
+        let mut command = self.zfs_command();
+        command
+            .arg("list")
+            .arg("-H") // No table header
+            .arg("-o")
+            .arg(DATASET_STATE_COLUMNS)
+            .args(&datasets); // Only list these datasets
+
+        let output = self.run(
+            command,
+            &[],
+            WaitLimit::NONE,
+            &datasets.join(", "),
+            ZfsError::DatasetStatesCallFailed,
+        )?;
+
+        parse_dataset_states_result(output.status.success(), &output.stdout, output.stderr)
+    }
+
+    /// Same as [`crate::zfs_list_properties`], with the binaries of this config
+    pub fn list_properties(
+        &self,
+        zfs_datasets: Option<&[&str]>,
+        properties: &[&str],
+    ) -> Result<Vec<BTreeMap<String, String>>, ZfsError> {
+        let properties = properties
+            .iter()
+            .map(check_and_sanitize_property_name)
+            .collect::<Result<Vec<_>, _>>()?;
+        if properties.is_empty() {
+            return Err(ZfsError::DatasetPropertyIsInvalid(String::new()));
+        }
+        let datasets = zfs_datasets
+            .map(|datasets| {
+                datasets
+                    .iter()
+                    .map(check_and_sanitize_zfs_dataset_name)
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()?;
+
+        // Without any dataset, zfs would list all of them
+        if datasets
+            .as_ref()
+            .is_some_and(|datasets| datasets.is_empty())
+        {
+            return Ok(Vec::new());
+        }
+
+        let mut command = self.zfs_command();
+        command
+            .arg("list")
+            .arg("-H") // No table header
+            .arg("-o")
+            .arg(properties.join(","))
+            .args(datasets.iter().flatten()); // Only list these datasets, if given
+
+        let output = self.run(
+            command,
+            &[],
+            WaitLimit::NONE,
+            &datasets.map_or(ALL_DATASETS.to_string(), |datasets| datasets.join(", ")),
+            ZfsError::ListPropertiesCallFailed,
+        )?;
+
+        parse_properties_result(
+            output.status.success(),
+            &output.stdout,
+            output.stderr,
+            &properties,
+        )
+    }
+
+    /// Same as [`crate::zfs_is_unlocked_and_mounted`], with the binaries of this config
+    pub fn is_unlocked_and_mounted(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+    ) -> Result<Option<(bool, bool)>, ZfsError> {
+        Ok(self
+            .get_dataset_state(zfs_dataset)?
+            .map(|state| (state.is_key_loaded, state.is_mounted)))
+    }
+
+    /// Same as [`crate::zfs_key_requires_passphrase`], with the binaries of this config
+    pub fn key_requires_passphrase(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+    ) -> Result<Option<bool>, ZfsError> {
+        let root = match self.get_encryption_root(zfs_dataset) {
+            Ok(Some(root)) => root,
+            Ok(None) | Err(ZfsError::DatasetNotFound(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        Ok(self
+            .get_encryption_properties(root)?
+            .map(|properties| properties.requires_passphrase()))
+    }
+
+    /// Same as [`crate::zfs_encryption_status`], with the binaries of this config
+    pub fn encryption_status(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+    ) -> Result<EncryptionStatus, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        let mut command = self.zfs_command();
+        command
+            .arg("list")
+            .arg("-H") // No table header
+            .arg("-o")
+            .arg(ENCRYPTION_STATUS_COLUMNS)
+            .arg(&dataset);
+
+        let output = self.run(command, &[], WaitLimit::NONE, &dataset, |e| {
+            ZfsError::GetPropertyCallFailed(
+                dataset.to_string(),
+                ENCRYPTION_STATUS_COLUMNS.to_string(),
+                e,
+            )
+        })?;
+
+        parse_encryption_status_result(
+            &dataset,
+            output.status.success(),
+            &output.stdout,
+            output.stderr,
+        )
+    }
+
+    /// Same as [`crate::zfs_get_encryption_properties`], with the binaries of this config
+    pub fn get_encryption_properties(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+    ) -> Result<Option<EncryptionProperties>, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        let mut command = self.zfs_command();
+        command
+            .arg("get")
+            .arg("-H") // No table header
+            .arg("-o")
+            .arg("property,value") // Only show two columns, property name and its value
+            .arg(ENCRYPTION_PROPERTIES)
+            .arg(&dataset);
+
+        let output = self.run(command, &[], WaitLimit::NONE, &dataset, |e| {
+            ZfsError::GetPropertyCallFailed(
+                dataset.to_string(),
+                ENCRYPTION_PROPERTIES.to_string(),
+                e,
+            )
+        })?;
+
+        parse_encryption_properties_result(
+            &dataset,
+            output.status.success(),
+            &output.stdout,
+            output.stderr,
+        )
+    }
+
+    /// Same as [`crate::zfs_get_canmount`], with the binaries of this config
+    pub fn get_canmount(&self, zfs_dataset: impl AsRef<str>) -> Result<CanMount, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        let value = self
+            .get_property(&dataset, "canmount")?
+            .ok_or_else(|| ZfsError::DatasetNotFound(dataset.to_string()))?;
+
+        parse_canmount_property(&dataset, &value)
+    }
+
+    /// Same as [`crate::zfs_set_canmount`], with the binaries of this config
+    pub fn set_canmount(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+        canmount: CanMount,
+    ) -> Result<(), ZfsError> {
+        self.set_property(zfs_dataset, "canmount", canmount.zfs_value())
+    }
+
+    /// Same as [`crate::zfs_get_encryption_root`], with the binaries of this config
+    pub fn get_encryption_root(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+    ) -> Result<Option<String>, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        let value = self
+            .get_property(&dataset, "encryptionroot")?
+            .ok_or_else(|| ZfsError::DatasetNotFound(dataset.to_string()))?;
+
+        parse_encryption_root(&dataset, &value)
+    }
+
+    /// Same as [`crate::zfs_get_dataset_usage`], with the binaries of this config
+    pub fn get_dataset_usage(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+    ) -> Result<Option<DatasetUsage>, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        let mut command = self.zfs_command();
+        command
+            .arg("get")
+            .arg("-H") // No table header
+            .arg("-p") // Exact numbers of bytes, instead of rounded sizes
+            .arg("-o")
+            .arg("property,value") // Only show two columns, property name and its value
+            .arg(USAGE_PROPERTIES)
+            .arg(&dataset);
+
+        let output = self.run(command, &[], WaitLimit::NONE, &dataset, |e| {
+            ZfsError::GetPropertyCallFailed(dataset.to_string(), USAGE_PROPERTIES.to_string(), e)
+        })?;
+
+        parse_dataset_usage_result(
+            &dataset,
+            output.status.success(),
+            &output.stdout,
+            output.stderr,
+        )
+    }
+
+    /// Same as [`crate::zfs_list_snapshots`], with the binaries of this config
+    pub fn list_snapshots(&self, zfs_dataset: impl AsRef<str>) -> Result<Vec<Snapshot>, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        let mut command = self.zfs_command();
+        command
+            .arg("list")
+            .arg("-H") // No table header
+            .arg("-p") // Exact numbers of bytes and creation times in seconds
+            .arg("-t")
+            .arg("snapshot")
+            .arg("-o")
+            .arg("name,used,referenced,creation")
+            .arg("-r") // The snapshots of the descendants too
+            .arg(&dataset);
+
+        let output = self.run(command, &[], WaitLimit::NONE, &dataset, |e| {
+            ZfsError::ListSnapshotsCallFailed(dataset.to_string(), e)
+        })?;
+
+        parse_snapshots_result(
+            &dataset,
+            output.status.success(),
+            &output.stdout,
+            output.stderr,
+        )
+    }
+
+    /// Checks that zfs reads the key of the dataset from stdin, so that a passphrase can be written to it
+    fn check_keylocation_is_prompt(&self, dataset: &str) -> Result<(), ZfsError> {
+        keylocation_prompt_result(dataset, self.get_property(dataset, "keylocation")?)
+    }
+
+    /// Reads a single property of an already sanitized dataset name
+    /// Returns: Some(value): The value of the property, as printed by zfs
+    /// Returns: None: The dataset is not found
+    /// Otherwise, an error is returned
+    fn get_property(&self, dataset: &str, property: &str) -> Result<Option<String>, ZfsError> {
+        let command = self.property_command(dataset, property);
+        let output = self.run(command, &[], WaitLimit::NONE, dataset, |e| {
+            ZfsError::GetPropertyCallFailed(dataset.to_string(), property.to_string(), e)
+        })?;
+
+        property_output_result(dataset, property, output)
+    }
+
+    /// Same as [`crate::zfs_get_pbkdf2iters`], with the binaries of this config
+    pub fn get_pbkdf2iters(&self, zfs_dataset: impl AsRef<str>) -> Result<Option<u64>, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        let value = self
+            .get_property(&dataset, "pbkdf2iters")?
+            .ok_or_else(|| ZfsError::DatasetNotFound(dataset.to_string()))?;
+        parse_pbkdf2iters(&dataset, &value)
+    }
+
+    /// Same as [`crate::zfs_list_datasets_mountpoints`], with the binaries of this config
+    pub fn list_datasets_mountpoints(&self) -> Result<BTreeMap<String, Mountpoint>, ZfsError> {
+        let mut command = self.zfs_command();
+        command
+            .arg("list")
+            .arg("-H") // No table header
+            .arg("-o")
+            .arg("name,mountpoint"); // Only show two columns, dataset name and mountpoint
+
+        let output = self.run(
+            command,
+            &[],
+            WaitLimit::NONE,
+            "",
+            ZfsError::ListDatasetsMountPointsCallFailed,
+        )?;
+
+        // Check if the command was successful
+        if output.status.success() {
+            Ok(parse_datasets_mountpoints(&output.stdout))
+        } else {
+            Err(ZfsError::ListDatasetsMountPointsCallFailed(output.stderr))
+        }
+    }
+
+    /// Same as [`crate::zfs_get_mountpoint`], with the binaries of this config
+    pub fn get_mountpoint(
+        &self,
+        zfs_dataset: impl AsRef<str>,
+    ) -> Result<Option<PathBuf>, ZfsError> {
+        let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+
+        let mut command = self.zfs_command();
+        command
+            .arg("list")
+            .arg("-H") // No table header
+            .arg("-o")
+            .arg("mountpoint") // Only show the mountpoint
+            .arg(&dataset);
+
+        let output = self.run(
+            command,
+            &[],
+            WaitLimit::NONE,
+            &dataset,
+            ZfsError::ListDatasetsMountPointsCallFailed,
+        )?;
+
+        if output.status.success() {
+            Ok(parse_mountpoint(&output.stdout))
+        } else if is_dataset_not_found_error(&output.stderr) {
+            Err(ZfsError::DatasetNotFound(dataset))
+        } else {
+            Err(ZfsError::ListDatasetsMountPointsCallFailed(output.stderr))
+        }
+    }
+
+    /// Same as [`crate::zfs_list_encrypted_datasets`], with the binaries of this config
+    pub fn list_encrypted_datasets(
+        &self,
+    ) -> Result<BTreeMap<String, DatasetMountedState>, ZfsError> {
+        self.list_encrypted_datasets_where(None, |_| true)
+            .map(|(datasets, _)| datasets)
+    }
+
+    /// Same as [`crate::zfs_find_inconsistent_datasets`], with the binaries of this config
+    pub fn find_inconsistent_datasets(&self) -> Result<Vec<Inconsistency>, ZfsError> {
+        let mut command = self.zfs_command();
+        command
+            .arg("list")
+            .arg("-H") // No table header
+            .arg("-t")
+            .arg("filesystem") // Volumes aren't mounted, so they have no mount state to compare
+            .arg("-o")
+            .arg(INCONSISTENCY_COLUMNS);
+
+        let output = self.run(
+            command,
+            &[],
+            WaitLimit::NONE,
+            ALL_DATASETS,
+            ZfsError::DatasetStatesCallFailed,
+        )?;
+
+        if output.status.success() {
+            parse_inconsistent_datasets(&output.stdout)
+        } else {
+            Err(ZfsError::DatasetStatesCallFailed(output.stderr))
+        }
+    }
+
+    /// Same as [`crate::zfs_watch`], with the binaries of this config
+    /// The watcher uses a clone of the config, so changing the config afterwards doesn't change the watcher.
+    pub fn watch(
+        &self,
+        poll_interval: Duration,
+    ) -> (std::sync::mpsc::Receiver<DatasetStateChange>, WatchHandle) {
+        spawn_watcher(self.clone(), poll_interval)
+    }
+
+    /// Same as [`crate::zfs_list_encrypted_datasets_with_warnings`], with the binaries of this config
+    pub fn list_encrypted_datasets_with_warnings(
+        &self,
+    ) -> Result<(BTreeMap<String, DatasetMountedState>, ParseWarnings), ZfsError> {
+        self.list_encrypted_datasets_where(None, |_| true)
+    }
+
+    /// Same as [`crate::zfs_list_encrypted_datasets_under`], with the binaries of this config
+    pub fn list_encrypted_datasets_under(
+        &self,
+        root: impl AsRef<str>,
+    ) -> Result<BTreeMap<String, DatasetMountedState>, ZfsError> {
+        let root = check_and_sanitize_zfs_dataset_name(root)?;
+
+        self.list_encrypted_datasets_where(Some(&root), |_| true)
+            .map(|(datasets, _)| datasets)
+    }
+
+    /// Same as [`crate::zfs_iter_encrypted_datasets`], with the binaries of this config
+    pub fn iter_encrypted_datasets(
+        &self,
+    ) -> Result<impl Iterator<Item = Result<DatasetMountedState, ZfsError>>, ZfsError> {
+        let mut command = self.zfs_command();
+        command
+            .arg("list")
+            .arg("-H") // No table header
+            .arg("-o")
+            .arg(DATASET_STATE_COLUMNS);
+
+        let lines = self.command_lines(
+            command,
+            ZfsError::ListUnmountedDatasetsCallFailed,
+            ZfsError::ListUnmountedDatasetsCallFailed,
+        )?;
+
+        Ok(lines.filter_map(|line| match line {
+            Ok(line) => parse_encrypted_dataset_line(&line),
+            Err(e) => Some(Err(e)),
+        }))
+    }
+
+    /// Same as [`crate::zfs_list_locked_datasets`], with the binaries of this config
+    pub fn list_locked_datasets(&self) -> Result<BTreeMap<String, DatasetMountedState>, ZfsError> {
+        self.list_encrypted_datasets_where(None, |state| !state.is_key_loaded)
+            .map(|(datasets, _)| datasets)
+    }
+
+    /// Same as [`crate::zfs_list_lockable_roots`], with the binaries of this config
+    pub fn list_lockable_roots(&self) -> Result<Vec<String>, ZfsError> {
+        let mut command = self.zfs_command();
+        command
+            .arg("list")
+            .arg("-H") // No table header
+            .arg("-o")
+            .arg("name,encryptionroot,keystatus"); // Dataset name, its encryption root and whether key is available
+
+        let output = self.run(
+            command,
+            &[],
+            WaitLimit::NONE,
+            ALL_DATASETS,
+            ZfsError::ListKeyStatesCallFailed,
+        )?;
+
+        if output.status.success() {
+            parse_lockable_roots(&output.stdout)
+        } else {
+            Err(ZfsError::ListKeyStatesCallFailed(output.stderr))
+        }
+    }
+
+    /// Lists the encrypted datasets that are kept, of all pools, or only an already sanitized root and its descendants
+    fn list_encrypted_datasets_where(
+        &self,
+        root: Option<&str>,
+        keep: impl Fn(&DatasetMountedState) -> bool,
+    ) -> Result<(BTreeMap<String, DatasetMountedState>, ParseWarnings), ZfsError> {
+        let mut command = self.zfs_command();
+        command.arg("list");
+        if root.is_some() {
+            command.arg("-r"); // Include all descendants
+        }
+        command
+            .arg("-H") // No table header
+            .arg("-o")
+            .arg(DATASET_STATE_COLUMNS)
+            .args(root);
+
+        let output = self.run(
+            command,
+            &[],
+            WaitLimit::NONE,
+            root.unwrap_or_default(),
+            ZfsError::ListUnmountedDatasetsCallFailed,
+        )?;
+
+        // Check if the command was successful
+        if output.status.success() {
+            parse_encrypted_datasets(&output.stdout, keep)
+        } else if let Some(root) = root.filter(|_| is_dataset_not_found_error(&output.stderr)) {
+            Err(ZfsError::DatasetNotFound(root.to_string()))
+        } else {
+            Err(ZfsError::ListUnmountedDatasetsCallFailed(output.stderr))
+        }
+    }
+
+    /// Same as [`crate::zpool_import`], with the binaries of this config
+    pub fn zpool_import(&self, pool_name: impl AsRef<str>) -> Result<(), ZfsError> {
+        let pool = check_and_sanitize_zpool_name(pool_name)?;
+
+        if self.is_pool_imported(&pool)? {
+            return Ok(());
+        }
+
+        let mut command = self.privileged_zpool_command();
+        command.arg("import").arg(&pool);
+
+        let output = self.run_privileged_command(command, &[], WaitLimit::NONE, &pool, |e| {
+            ZfsError::ZpoolImportCmdFailed(pool.to_string(), CommandFailure::spawn_failed(e))
+        })?;
+
+        // Check if the command was successful
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(ZfsError::ZpoolImportCmdFailed(
+                pool.to_string(),
+                output.failure(),
+            ))
+        }
+    }
+
+    /// Same as [`crate::zpool_export`], with the binaries of this config
+    pub fn zpool_export(&self, pool_name: impl AsRef<str>) -> Result<(), ZfsError> {
+        let pool = check_and_sanitize_zpool_name(pool_name)?;
+
+        self.run_export(&pool, false)
+    }
+
+    /// Same as [`crate::zpool_export_force`], with the binaries of this config
+    pub fn zpool_export_force(&self, pool_name: impl AsRef<str>) -> Result<(), ZfsError> {
+        let pool = check_and_sanitize_zpool_name(pool_name)?;
+
+        self.run_export(&pool, true)
+    }
+
+    /// Same as [`crate::zfs_prepare_pool_for_removal`], with the binaries of this config
+    pub fn prepare_pool_for_removal(
+        &self,
+        pool_name: impl AsRef<str>,
+    ) -> Result<PoolRemovalReport, ZfsError> {
+        let pool = check_and_sanitize_zpool_name(pool_name)?;
+
+        let mut report = PoolRemovalReport {
+            unmounted: self.unmount_tree(&pool)?,
+            keys_unloaded: None,
+            exported: None,
+        };
+        if !report.unmounted.values().all(Result::is_ok) {
+            return Ok(report);
+        }
+
+        let keys_unloaded = self.unload_key_recursive(&pool);
+        let keys_unloaded_ok = keys_unloaded.is_ok();
+        report.keys_unloaded = Some(keys_unloaded);
+        if !keys_unloaded_ok {
+            return Ok(report);
+        }
+
+        report.exported = Some(self.run_export(&pool, false));
+        Ok(report)
+    }
+
+    /// Runs `zpool export` for an already sanitized pool name
+    fn run_export(&self, pool: &str, force: bool) -> Result<(), ZfsError> {
+        if !self.is_pool_imported(pool)? {
+            return Ok(());
+        }
+
+        let mut command = self.privileged_zpool_command();
+        command.arg("export");
+        if force {
+            command.arg("-f");
+        }
+        command.arg(pool);
+
+        let output = self.run_privileged_command(command, &[], WaitLimit::NONE, pool, |e| {
+            ZfsError::ZpoolExportCmdFailed(pool.to_string(), CommandFailure::spawn_failed(e))
+        })?;
+
+        // Check if the command was successful
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(ZfsError::ZpoolExportCmdFailed(
+                pool.to_string(),
+                output.failure(),
+            ))
+        }
+    }
+
+    /// Same as [`crate::zfs_version`], with the binaries of this config
+    pub fn zfs_version(&self) -> Result<ZfsVersion, ZfsError> {
+        let mut command = self.zfs_command();
+        command.arg("version");
+
+        let output = self.run(
+            command,
+            &[],
+            WaitLimit::NONE,
+            "",
+            ZfsError::VersionCallFailed,
+        )?;
+
+        if output.status.success() {
+            parse_zfs_version(&output.stdout)
+        } else {
+            Err(ZfsError::VersionCallFailed(output.stderr))
+        }
+    }
+
+    /// Same as [`crate::zpool_status`], with the binaries of this config
+    pub fn zpool_status(&self, pool_name: impl AsRef<str>) -> Result<PoolHealth, ZfsError> {
+        let pool = check_and_sanitize_zpool_name(pool_name)?;
+
+        let mut command = self.zpool_command();
+        command
+            .arg("list")
+            .arg("-H") // No table header
+            .arg("-o")
+            .arg("health") // Only show the health of the pool
+            .arg(&pool);
+
+        let output = self.run(command, &[], WaitLimit::NONE, &pool, |e| {
+            ZfsError::PoolHealthCheckFailed(pool.to_string(), e)
+        })?;
+
+        parse_pool_health_result(
+            &pool,
+            output.status.success(),
+            &output.stdout,
+            output.stderr,
+        )
+    }
+
+    /// Checks whether an already sanitized pool name is imported
+    fn is_pool_imported(&self, pool: &str) -> Result<bool, ZfsError> {
+        let mut command = self.zpool_command();
+        command
+            .arg("list")
+            .arg("-H") // No table header
+            .arg("-o")
+            .arg("name") // Only show the pool name
+            .arg(pool);
+
+        let output = self.run(command, &[], WaitLimit::NONE, pool, |e| {
+            ZfsError::PoolImportedCheckFailed(pool.to_string(), e)
+        })?;
+
+        parse_pool_imported_result(pool, output.status.success(), output.stderr)
+    }
+
+    /// Same as [`crate::required_sudoers`], for the binaries of this config
+    /// sudoers rules need absolute paths, so if zfs_path or zpool_path isn't one, the path sudo resolves it to is used.
+    pub fn required_sudoers(&self, user: &str) -> String {
+        let zfs_path = sudoers_path(&self.zfs_path, SUDOERS_ZFS_PATH);
+        let zpool_path = sudoers_path(&self.zpool_path, SUDOERS_ZPOOL_PATH);
+
+        let zfs_rules = PRIVILEGED_ZFS_SUBCOMMANDS
+            .iter()
+            .map(|subcommand| format!("{user} ALL=(root) NOPASSWD: {zfs_path} {subcommand} *\n"));
+        let zpool_rules = PRIVILEGED_ZPOOL_SUBCOMMANDS
+            .iter()
+            .map(|subcommand| format!("{user} ALL=(root) NOPASSWD: {zpool_path} {subcommand} *\n"));
+
+        zfs_rules.chain(zpool_rules).collect()
+    }
+
+    /// Same as [`crate::zfs_check_sudo_permissions`], with the binaries of this config
+    pub fn check_sudo_permissions(&self) -> Result<SudoCapabilities, ZfsError> {
+        if !self.use_sudo {
+            return Ok(SudoCapabilities {
+                load_key: true,
+                unload_key: true,
+                mount: true,
+                umount: true,
+            });
+        }
+
+        let mut command = self.command(&self.sudo_path);
+        command.arg("-n").arg("-l");
+
+        let output = self.run(
+            command,
+            &[],
+            WaitLimit::NONE,
+            "",
+            ZfsError::SudoListCallFailed,
+        )?;
+
+        if output.status.success() {
+            let zfs_path = sudoers_path(&self.zfs_path, SUDOERS_ZFS_PATH);
+            Ok(parse_sudo_list(&output.stdout, &zfs_path))
+        } else if is_sudo_password_required_error(&output.stderr) {
+            Ok(SudoCapabilities::default())
+        } else {
+            Err(ZfsError::SudoListCallFailed(output.stderr))
+        }
+    }
+}
+
+/// The path of the binary in sudoers rules, which is the default path sudo resolves it to if it isn't absolute
+fn sudoers_path(path: &Path, default: &str) -> String {
+    if path.is_absolute() {
+        path.to_string_lossy().to_string()
+    } else {
+        default.to_string()
+    }
+}
+
+/// Returns whether the effective user of the process is root, which can run privileged commands without sudo
+fn is_running_as_root() -> bool {
+    // SAFETY: geteuid has no preconditions and can't fail
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Interprets the output of `zfs load-key` for an already sanitized dataset name
+pub(crate) fn load_key_output_result(
+    dataset: &str,
+    output: CommandOutput,
+) -> Result<LoadKeyOutcome, ZfsError> {
+    // Check if the command was successful
+    if output.status.success() {
+        Ok(LoadKeyOutcome::Loaded)
+    } else if is_incorrect_key_error(&output.stderr) {
+        Err(ZfsError::IncorrectPassphrase(dataset.to_string()))
+    } else {
+        Err(ZfsError::LoadKeyCmdFailed(
+            dataset.to_string(),
+            output.failure(),
+        ))
+    }
+}
+
+/// Interprets the output of `zfs unload-key` for an already sanitized dataset name
+pub(crate) fn unload_key_output_result(
+    dataset: &str,
+    output: CommandOutput,
+) -> Result<(), ZfsError> {
+    // Check if the command was successful
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(ZfsError::UnloadKeyCmdFailed(
+            dataset.to_string(),
+            output.failure(),
+        ))
+    }
+}
+
+/// Returns the error for a `zfs mount` that failed
+/// The mountpoint is the one that zfs said is occupied, if it did.
+pub(crate) fn mount_failure(
+    dataset: &str,
+    output: CommandOutput,
+    mountpoint: Option<PathBuf>,
+) -> ZfsError {
+    match mountpoint {
+        Some(mountpoint) => ZfsError::MountpointOccupied(dataset.to_string(), mountpoint),
+        None => ZfsError::MountCmdFailed(dataset.to_string(), output.failure()),
+    }
+}
+
+/// Returns the path where a dataset that was just mounted is mounted, from its mountpoint property
+pub(crate) fn mounted_path_result(
+    dataset: &str,
+    mountpoint: Option<String>,
+) -> Result<PathBuf, ZfsError> {
+    let mountpoint = mountpoint.ok_or_else(|| ZfsError::DatasetNotFound(dataset.to_string()))?;
+    match parse_mountpoint_property(&mountpoint) {
+        Mountpoint::Path(path) => Ok(path),
+        Mountpoint::Legacy => Err(ZfsError::LegacyMountpoint(dataset.to_string())),
+        // zfs can't mount a dataset without a mountpoint, so it must have changed since
+        Mountpoint::None => Err(ZfsError::UnexpectedPropertyValue(
+            dataset.to_string(),
+            "mountpoint".to_string(),
+            mountpoint,
+        )),
+    }
+}
+
+/// Interprets the output of `zfs umount` for an already sanitized dataset name
+/// Returns: Ok(true) if the command succeeded
+pub(crate) fn unmount_output_result(
+    dataset: &str,
+    output: CommandOutput,
+) -> Result<bool, ZfsError> {
+    // Check if the command was successful
+    if output.status.success() {
+        Ok(true)
+    } else if is_dataset_busy_error(&output.stderr) {
+        Err(ZfsError::DatasetBusy(dataset.to_string()))
+    } else {
+        Err(ZfsError::UnmountCmdFailed(
+            dataset.to_string(),
+            output.failure(),
+        ))
+    }
+}
+
+/// Interprets the output of `zfs get` for a single property of an already sanitized dataset name
+/// Returns: Ok(None) if the dataset is not found
+pub(crate) fn property_output_result(
+    dataset: &str,
+    property: &str,
+    output: CommandOutput,
+) -> Result<Option<String>, ZfsError> {
+    // Check if the command was successful
+    if output.status.success() {
+        Ok(Some(output.stdout.trim_end_matches('\n').to_string()))
+    } else if is_dataset_not_found_error(&output.stderr) {
+        Ok(None)
+    } else {
+        Err(ZfsError::GetPropertyCallFailed(
+            dataset.to_string(),
+            property.to_string(),
+            output.stderr,
+        ))
+    }
+}
+
+/// Checks that the keylocation property of the dataset, if it exists, lets zfs read the passphrase from stdin
+/// Datasets that inherit their key have keylocation=none, and are left for zfs to report on.
+pub(crate) fn keylocation_prompt_result(
+    dataset: &str,
+    keylocation: Option<String>,
+) -> Result<(), ZfsError> {
+    let keylocation = keylocation.ok_or_else(|| ZfsError::DatasetNotFound(dataset.to_string()))?;
+
+    match keylocation.as_str() {
+        "prompt" | "none" | "-" => Ok(()),
+        _ => Err(ZfsError::PassphraseProvidedButKeylocationNotPrompt(
+            dataset.to_string(),
+            keylocation,
+        )),
+    }
+}
+
+/// The program and the arguments of a command, as they're given to a CommandRunner
+pub(crate) fn command_os_argv(command: &Command) -> Vec<OsString> {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(OsString::from)
+        .collect()
+}
+
+/// The program and the arguments of a command, as they would be passed to it
+pub(crate) fn command_argv(command: &Command) -> Vec<String> {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| arg.to_string_lossy().to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        let config = ZfsConfig::default();
+        assert_eq!(command_argv(&config.zfs_command()), ["zfs"]);
+        assert_eq!(
+            command_argv(&config.privileged_zfs_command()),
+            ["sudo", "-n", "zfs"]
+        );
+
+        let config = ZfsConfig {
+            zfs_path: PathBuf::from("/usr/local/sbin/zfs"),
+            zpool_path: PathBuf::from("/usr/local/sbin/zpool"),
+            sudo_path: PathBuf::from("/usr/local/bin/sudo"),
+            use_sudo: true,
+            runner: None,
+            on_mounted: None,
+            strict_mountpoint: false,
+            env: Vec::new(),
+            sudo_prompt: SudoPrompt::NonInteractive,
+            check_holds: false,
+            escalation_command: None,
+        };
+        assert_eq!(command_argv(&config.zfs_command()), ["/usr/local/sbin/zfs"]);
+        assert_eq!(
+            command_argv(&config.privileged_zfs_command()),
+            ["/usr/local/bin/sudo", "-n", "/usr/local/sbin/zfs"]
+        );
+        assert_eq!(
+            command_argv(&config.privileged_zpool_command()),
+            ["/usr/local/bin/sudo", "-n", "/usr/local/sbin/zpool"]
+        );
+
+        let terminal_config = ZfsConfig {
+            sudo_prompt: SudoPrompt::Terminal,
+            ..config.clone()
+        };
+        assert_eq!(
+            command_argv(&terminal_config.privileged_zfs_command()),
+            ["/usr/local/bin/sudo", "/usr/local/sbin/zfs"]
+        );
+        let askpass_config = ZfsConfig {
+            sudo_prompt: SudoPrompt::Askpass(PathBuf::from("/usr/bin/ssh-askpass")),
+            ..config.clone()
+        };
+        let command = askpass_config.privileged_zfs_command();
+        assert_eq!(
+            command_argv(&command),
+            ["/usr/local/bin/sudo", "-A", "/usr/local/sbin/zfs"]
+        );
+        assert!(command.get_envs().any(|(key, value)| key == "SUDO_ASKPASS"
+            && value == Some(std::ffi::OsStr::new("/usr/bin/ssh-askpass"))));
+        assert_eq!(
+            askpass_config.privileged_program(&command),
+            Some(PathBuf::from("/usr/local/sbin/zfs"))
+        );
+
+        // The escalation command replaces sudo and its options, whatever they are
+        let doas_config = ZfsConfig {
+            escalation_command: Some(vec!["doas".to_string(), "-n".to_string()]),
+            ..askpass_config.clone()
+        };
+        let command = doas_config.privileged_zpool_command();
+        assert_eq!(
+            command_argv(&command),
+            ["doas", "-n", "/usr/local/sbin/zpool"]
+        );
+        assert!(command.get_envs().all(|(key, _)| key != "SUDO_ASKPASS"));
+        assert_eq!(doas_config.escalation_program(), Path::new("doas"));
+        let doas_config = ZfsConfig {
+            escalation_command: Some(vec![
+                "/usr/bin/doas".to_string(),
+                "-u".to_string(),
+                "root".to_string(),
+            ]),
+            ..doas_config
+        };
+        let command = doas_config.privileged_zfs_command();
+        assert_eq!(
+            command_argv(&command),
+            ["/usr/bin/doas", "-u", "root", "/usr/local/sbin/zfs"]
+        );
+        assert_eq!(
+            doas_config.privileged_program(&command),
+            Some(PathBuf::from("/usr/local/sbin/zfs"))
+        );
+        let config_without_sudo = ZfsConfig {
+            use_sudo: false,
+            ..doas_config
+        };
+        assert_eq!(
+            command_argv(&config_without_sudo.privileged_zfs_command()),
+            ["/usr/local/sbin/zfs"]
+        );
+
+        let config = ZfsConfig {
+            use_sudo: false,
+            ..config
+        };
+        assert_eq!(
+            command_argv(&config.privileged_zfs_command()),
+            ["/usr/local/sbin/zfs"]
+        );
+        // Queries never use sudo
+        assert_eq!(command_argv(&config.zfs_command()), ["/usr/local/sbin/zfs"]);
+
+        // zfs runs in the C locale, with the variables of the config set after it
+        let envs = |command: Command| {
+            command
+                .get_envs()
+                .map(|(key, value)| {
+                    (
+                        key.to_string_lossy().to_string(),
+                        value.map(|value| value.to_string_lossy().to_string()),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        // Later variables override earlier ones, and get_envs is sorted by name
+        let expected = |extra: &[(&str, &str)]| {
+            COMMAND_ENV
+                .iter()
+                .chain(extra)
+                .map(|(key, value)| (key.to_string(), Some(value.to_string())))
+                .collect::<BTreeMap<_, _>>()
+                .into_iter()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(envs(config.zfs_command()), expected(&[]));
+        assert_eq!(envs(config.privileged_zpool_command()), expected(&[]));
+        let config = ZfsConfig {
+            use_sudo: true,
+            env: vec![
+                ("ZFS_COLOR".to_string(), "0".to_string()),
+                ("LC_ALL".to_string(), "C.UTF-8".to_string()),
+            ],
+            ..config
+        };
+        assert_eq!(
+            envs(config.privileged_zfs_command()),
+            expected(&[("ZFS_COLOR", "0"), ("LC_ALL", "C.UTF-8")])
+        );
+
+        let config = ZfsConfig::for_current_user();
+        assert_eq!(config.use_sudo, !is_running_as_root());
+        assert_eq!(
+            config,
+            ZfsConfig {
+                use_sudo: config.use_sudo,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn plans() {
+        let ds = "pool/dataset";
+        let config = ZfsConfig::default();
+        assert_eq!(
+            config.plan_load_key(ds).unwrap(),
+            ["sudo", "-n", "zfs", "load-key", ds]
+        );
+        assert_eq!(
+            config.plan_unload_key(ds).unwrap(),
+            ["sudo", "-n", "zfs", "unload-key", ds]
+        );
+        assert_eq!(
+            config.plan_mount_dataset(ds).unwrap(),
+            ["sudo", "-n", "zfs", "mount", ds]
+        );
+        assert_eq!(
+            config.plan_unmount_dataset(ds).unwrap(),
+            ["sudo", "-n", "zfs", "umount", ds]
+        );
+        assert_eq!(
+            command_argv(&config.load_key_command(ds, Some("file:///keys/dataset.key"))),
+            [
+                "sudo",
+                "-n",
+                "zfs",
+                "load-key",
+                "-L",
+                "file:///keys/dataset.key",
+                ds
+            ]
+        );
+        assert_eq!(
+            command_argv(&config.mount_command(ds, &["ro".to_string(), "noatime".to_string()])),
+            ["sudo", "-n", "zfs", "mount", "-o", "ro", "-o", "noatime", ds]
+        );
+        assert_eq!(
+            command_argv(&config.create_command(
+                ds,
+                &[
+                    "encryption=on".to_string(),
+                    "mountpoint=/home/alice".to_string()
+                ]
+            )),
+            [
+                "sudo",
+                "-n",
+                "zfs",
+                "create",
+                "-o",
+                "encryption=on",
+                "-o",
+                "mountpoint=/home/alice",
+                ds
+            ]
+        );
+        assert_eq!(
+            command_argv(&config.unmount_command(ds, true)),
+            ["sudo", "-n", "zfs", "umount", "-f", ds]
+        );
+
+        let config = ZfsConfig {
+            use_sudo: false,
+            ..config
+        };
+        assert_eq!(config.plan_mount_dataset(ds).unwrap(), ["zfs", "mount", ds]);
+
+        assert!(matches!(
+            config.plan_load_key("pool/data set"),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+    }
+
+    #[test]
+    fn bulk_results() {
+        let config = ZfsConfig {
+            zfs_path: PathBuf::from("/some/non/existent/zfs"),
+            ..ZfsConfig::default()
+        };
+        let items = (0..10)
+            .map(|i| (format!("pool/dataset{i}"), "abcdefghijklmnop".to_string()))
+            .chain(std::iter::once((
+                "pool/data set".to_string(),
+                "abcdefghijklmnop".to_string(),
+            )))
+            .collect::<Vec<_>>();
+
+        for concurrency in [1, 3, 100] {
+            let results = config.load_keys_bulk(&items, NonZeroUsize::new(concurrency).unwrap());
+            assert_eq!(results.len(), items.len());
+            assert!(matches!(
+                results["pool/data set"],
+                Err(ZfsError::DatasetNameIsInvalid(_))
+            ));
+            assert!(results
+                .iter()
+                .filter(|(dataset, _)| *dataset != "pool/data set")
+                .all(|(_, result)| matches!(result, Err(ZfsError::ZfsBinaryNotFound(_)))));
+        }
+
+        assert!(config
+            .load_keys_bulk(&[], NonZeroUsize::new(4).unwrap())
+            .is_empty());
+    }
+
+    #[test]
+    fn runner_check_sudo_permissions() {
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "-n" => (
+                0,
+                "User alice may run the following commands on host:\n    \
+                 (root) NOPASSWD: /sbin/zfs load-key *, /sbin/zfs unload-key *\n",
+                "",
+            ),
+            _ => (1, "", ""),
+        });
+        let config = ZfsConfig {
+            zfs_path: PathBuf::from("/sbin/zfs"),
+            ..config_with_runner(&runner)
+        };
+
+        assert_eq!(
+            config.check_sudo_permissions().unwrap(),
+            SudoCapabilities {
+                load_key: true,
+                unload_key: true,
+                mount: false,
+                umount: false,
+            }
+        );
+        assert_eq!(runner.calls()[0].0, ["sudo", "-n", "-l"]);
+
+        let runner = MockRunner::new(|_| (1, "", "sudo: a password is required\n"));
+        assert_eq!(
+            config_with_runner(&runner)
+                .check_sudo_permissions()
+                .unwrap(),
+            SudoCapabilities::default()
+        );
+
+        let config = ZfsConfig {
+            use_sudo: false,
+            ..config_with_runner(&runner)
+        };
+        assert!(config.check_sudo_permissions().unwrap().all());
+        assert_eq!(runner.calls().len(), 1);
+    }
+
+    #[test]
+    fn sudoers_lines_for_zfs_path() {
+        let config = ZfsConfig {
+            zfs_path: PathBuf::from("/usr/local/sbin/zfs"),
+            ..ZfsConfig::default()
+        };
+        let sudoers = config.required_sudoers("alice");
+        assert!(sudoers.lines().all(|line| {
+            line.starts_with("alice ALL=(root) NOPASSWD: /usr/local/sbin/zfs ")
+                || line.starts_with("alice ALL=(root) NOPASSWD: /usr/sbin/zpool ")
+        }));
+
+        let config = ZfsConfig {
+            zpool_path: PathBuf::from("/usr/local/sbin/zpool"),
+            ..config
+        };
+        let sudoers = config.required_sudoers("alice");
+        assert!(sudoers.lines().all(|line| {
+            line.starts_with("alice ALL=(root) NOPASSWD: /usr/local/sbin/zfs ")
+                || line.starts_with("alice ALL=(root) NOPASSWD: /usr/local/sbin/zpool ")
+        }));
+        assert!(sudoers.contains("/usr/local/sbin/zpool import *\n"));
+
+        assert_eq!(
+            ZfsConfig::default().required_sudoers("alice"),
+            crate::required_sudoers("alice")
+        );
+    }
+
+    /// The argv and stdin of a command that MockRunner was given
+    type MockCall = (Vec<String>, Option<Vec<u8>>);
+
+    /// Answers every command with the output of `respond`, and records the commands it was given
+    #[derive(Debug)]
+    struct MockRunner {
+        respond: fn(&[String]) -> (i32, &'static str, &'static str),
+        calls: Mutex<Vec<MockCall>>,
+    }
+
+    impl MockRunner {
+        fn new(respond: fn(&[String]) -> (i32, &'static str, &'static str)) -> Arc<Self> {
+            Arc::new(Self {
+                respond,
+                calls: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn calls(&self) -> Vec<MockCall> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    impl CommandRunner for MockRunner {
+        fn run(
+            &self,
+            argv: &[OsString],
+            stdin: Option<&[u8]>,
+        ) -> std::io::Result<std::process::Output> {
+            use std::os::unix::process::ExitStatusExt;
+
+            let argv = argv
+                .iter()
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            let (code, stdout, stderr) = (self.respond)(&argv);
+            self.calls
+                .lock()
+                .unwrap()
+                .push((argv, stdin.map(|stdin| stdin.to_vec())));
+
+            Ok(std::process::Output {
+                status: std::process::ExitStatus::from_raw(code << 8),
+                stdout: stdout.as_bytes().to_vec(),
+                stderr: stderr.as_bytes().to_vec(),
+            })
+        }
+    }
+
+    fn config_with_runner(runner: &Arc<MockRunner>) -> ZfsConfig {
+        ZfsConfig {
+            runner: Some(runner.clone()),
+            ..ZfsConfig::default()
+        }
+    }
+
+    #[test]
+    fn runner_load_key_from_fd() {
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "get" if argv[2] == "keystatus" => (0, "pool/dataset\tunavailable\n", ""),
+            "get" => (0, "passphrase\n", ""),
+            _ => (0, "", ""),
+        });
+        let config = config_with_runner(&runner);
+
+        let key_path = std::env::temp_dir().join(format!("zfs-key-fd-{}", std::process::id()));
+        std::fs::write(&key_path, "abcdefghijklmnop\n").unwrap();
+        let key_file = File::open(&key_path).unwrap();
+        config.load_key_from_fd("pool/dataset", &key_file).unwrap();
+        std::fs::remove_file(&key_path).unwrap();
+
+        let calls = runner.calls();
+        assert_eq!(
+            calls.last().unwrap(),
+            &(
+                command_argv(Command::new("sudo").args(["-n", "zfs", "load-key", "pool/dataset"])),
+                Some(b"abcdefghijklmnop\n".to_vec())
+            )
+        );
+
+        std::fs::write(&key_path, [b'a'; 2000]).unwrap();
+        let key_file = File::open(&key_path).unwrap();
+        std::fs::remove_file(&key_path).unwrap();
+        assert!(matches!(
+            config.load_key_from_fd("pool/dataset", &key_file),
+            Err(ZfsError::KeyReadFailed(_, _))
+        ));
+        assert_eq!(runner.calls().len(), calls.len() + 1);
+
+        for credential in ["", ".", "..", "../key", "/etc/key"] {
+            assert!(matches!(
+                config.load_key_from_credential("pool/dataset", credential),
+                Err(ZfsError::KeyFileUnavailable(_, _))
+            ));
+        }
+    }
+
+    #[test]
+    fn runner_load_key_detailed() {
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "get" if argv[2] == "keystatus" => (0, "pool/dataset\tunavailable\n", ""),
+            "get" => (0, "prompt\n", ""),
+            _ => (0, "", ""),
+        });
+        assert_eq!(
+            config_with_runner(&runner)
+                .load_key_detailed("pool/dataset", "abcdefghijklmnop")
+                .unwrap(),
+            LoadKeyOutcome::Loaded
+        );
+
+        let runner = MockRunner::new(|_| (0, "pool/dataset\tavailable\n", ""));
+        assert_eq!(
+            config_with_runner(&runner)
+                .load_key_detailed("pool/dataset", "abcdefghijklmnop")
+                .unwrap(),
+            LoadKeyOutcome::AlreadyLoaded
+        );
+        assert_eq!(runner.calls().len(), 1);
+    }
+
+    #[test]
+    fn runner_load_key_returning_root() {
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "get" if argv[2] == "keystatus" => (0, "pool/enc\tunavailable\n", ""),
+            "get" if argv[5] == "encryptionroot" => (0, "pool/enc\n", ""),
+            "get" => (0, "prompt\n", ""),
+            _ => (0, "", ""),
+        });
+        assert_eq!(
+            config_with_runner(&runner)
+                .load_key_returning_root("pool/enc/child", "abcdefghijklmnop")
+                .unwrap()
+                .as_deref(),
+            Some("pool/enc")
+        );
+        let calls = runner.calls();
+        assert_eq!(
+            calls.last().unwrap().0,
+            ["sudo", "-n", "zfs", "load-key", "pool/enc"]
+        );
+
+        // Not encrypted
+        let runner = MockRunner::new(|_| (0, "-\n", ""));
+        assert_eq!(
+            config_with_runner(&runner)
+                .load_key_returning_root("pool/plain", "abcdefghijklmnop")
+                .unwrap(),
+            None
+        );
+        assert_eq!(runner.calls().len(), 1);
+    }
+
+    #[test]
+    fn runner_load_key_from_location() {
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "get" => (0, "pool/enc\tunavailable\n", ""),
+            _ => (0, "", ""),
+        });
+        let config = config_with_runner(&runner);
+        config
+            .load_key_from_location("pool/enc", "https://keys.example.com/pool/enc?v=2")
+            .unwrap();
+        let calls = runner.calls();
+        let (argv, stdin) = calls.last().unwrap();
+        assert_eq!(
+            argv,
+            &[
+                "sudo",
+                "-n",
+                "zfs",
+                "load-key",
+                "-L",
+                "https://keys.example.com/pool/enc?v=2",
+                "pool/enc"
+            ]
+        );
+        assert!(stdin.as_deref().unwrap_or_default().is_empty());
+
+        // Invalid locations never reach zfs
+        assert!(matches!(
+            config.load_key_from_location("pool/enc", "file://relative/key"),
+            Err(ZfsError::KeyLocationIsInvalid(_))
+        ));
+        assert_eq!(runner.calls().len(), calls.len());
+    }
+
+    #[test]
+    fn runner_load_key() {
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "get" if argv[2] == "keystatus" => (0, "pool/dataset\tunavailable\n", ""),
+            "get" => (0, "prompt\n", ""),
+            _ => (0, "", ""),
+        });
+        config_with_runner(&runner)
+            .load_key("pool/dataset", "abcdefghijklmnop")
+            .unwrap();
+        assert_eq!(
+            runner.calls(),
+            [
+                (
+                    command_argv(Command::new("zfs").args([
+                        "get",
+                        "keystatus",
+                        "-H",
+                        "-o",
+                        "name,value",
+                        "pool/dataset"
+                    ])),
+                    None
+                ),
+                (
+                    command_argv(Command::new("zfs").args([
+                        "get",
+                        "-H",
+                        "-o",
+                        "value",
+                        "keylocation",
+                        "pool/dataset"
+                    ])),
+                    None
+                ),
+                (
+                    command_argv(Command::new("sudo").args([
+                        "-n",
+                        "zfs",
+                        "load-key",
+                        "pool/dataset"
+                    ])),
+                    Some(b"abcdefghijklmnop\n".to_vec())
+                ),
+            ]
+        );
+
+        // Already loaded, so load-key doesn't run
+        let runner = MockRunner::new(|_| (0, "pool/dataset\tavailable\n", ""));
+        config_with_runner(&runner)
+            .load_key("pool/dataset", "abcdefghijklmnop")
+            .unwrap();
+        assert_eq!(runner.calls().len(), 1);
+
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "get" if argv[2] == "keystatus" => (0, "pool/dataset\tunavailable\n", ""),
+            "get" => (0, "prompt\n", ""),
+            _ => (
+                255,
+                "",
+                "Key load error: Incorrect key provided for 'pool/dataset'.\n",
+            ),
+        });
+        assert!(matches!(
+            config_with_runner(&runner).load_key("pool/dataset", "abcdefghijklmnop"),
+            Err(ZfsError::IncorrectPassphrase(_))
+        ));
+
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "get" if argv[2] == "keystatus" => (0, "pool/dataset\tunavailable\n", ""),
+            "get" => (0, "prompt\n", ""),
+            _ => (1, "", "sudo: a password is required\n"),
+        });
+        assert!(matches!(
+            config_with_runner(&runner).load_key("pool/dataset", "abcdefghijklmnop"),
+            Err(ZfsError::SudoNonInteractiveDenied(_))
+        ));
+
+        // The key is read from a file, so the passphrase isn't written to zfs
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "get" if argv[2] == "keystatus" => (0, "pool/dataset\tunavailable\n", ""),
+            "get" => (0, "file:///keys/dataset.key\n", ""),
+            _ => (0, "", ""),
+        });
+        assert!(matches!(
+            config_with_runner(&runner).load_key("pool/dataset", "abcdefghijklmnop"),
+            Err(ZfsError::PassphraseProvidedButKeylocationNotPrompt(_, location))
+                if location == "file:///keys/dataset.key"
+        ));
+        assert_eq!(runner.calls().len(), 2);
+
+        config_with_runner(&runner)
+            .load_key_from_keylocation("pool/dataset")
+            .unwrap();
+        assert_eq!(
+            runner.calls().last().unwrap(),
+            &(
+                command_argv(Command::new("sudo").args(["-n", "zfs", "load-key", "pool/dataset"])),
+                None
+            )
+        );
+    }
+
+    #[test]
+    fn runner_load_key_recursive() {
+        static LOADED: AtomicBool = AtomicBool::new(false);
+        let runner = MockRunner::new(|argv| {
+            match argv[1].as_str() {
+            "list" if LOADED.load(Ordering::SeqCst) => (
+                0,
+                "pool/home\tavailable\npool/home/alice\tavailable\npool/home/bob\tunavailable\n",
+                "",
+            ),
+            "list" => (
+                0,
+                "pool/home\tunavailable\npool/home/alice\tunavailable\npool/home/bob\tunavailable\n",
+                "",
+            ),
+            _ => {
+                LOADED.store(true, Ordering::SeqCst);
+                (
+                    1,
+                    "",
+                    "Key load error: Incorrect key provided for 'pool/home/bob'.\n2 / 3 key(s) successfully loaded\n",
+                )
+            }
+        }
+        });
+        assert_eq!(
+            config_with_runner(&runner)
+                .load_key_recursive("pool/home", "abcdefghijklmnop")
+                .unwrap(),
+            RecursiveLoadKeyReport {
+                loaded: ["pool/home".to_string(), "pool/home/alice".to_string()].into(),
+                failed: ["pool/home/bob".to_string()].into(),
+            }
+        );
+        let calls = runner.calls();
+        assert_eq!(
+            calls[1],
+            (
+                command_argv(Command::new("sudo").args([
+                    "-n",
+                    "zfs",
+                    "load-key",
+                    "-r",
+                    "pool/home"
+                ])),
+                Some(b"abcdefghijklmnop\n".to_vec())
+            )
+        );
+        assert_eq!(calls.len(), 3);
+
+        // When no key is loaded, the failure is reported as an error
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" => (
+                0,
+                "pool/home\tunavailable\npool/home/alice\tunavailable\n",
+                "",
+            ),
+            _ => (
+                1,
+                "",
+                "Key load error: Incorrect key provided for 'pool/home'.\n",
+            ),
+        });
+        assert!(matches!(
+            config_with_runner(&runner).load_key_recursive("pool/home", "abcdefghijklmnop"),
+            Err(ZfsError::IncorrectPassphrase(_))
+        ));
+    }
+
+    #[test]
+    fn runner_change_key() {
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "get" => (0, "pool/dataset\tavailable\n", ""),
+            _ => (0, "", ""),
+        });
+        config_with_runner(&runner)
+            .change_key("pool/dataset", "new passphrase 1234")
+            .unwrap();
+        assert_eq!(
+            runner.calls().last().unwrap(),
+            &(
+                command_argv(Command::new("sudo").args([
+                    "-n",
+                    "zfs",
+                    "change-key",
+                    "pool/dataset"
+                ])),
+                Some(b"new passphrase 1234\nnew passphrase 1234\n".to_vec())
+            )
+        );
+
+        let runner = MockRunner::new(|_| (0, "pool/dataset\tunavailable\n", ""));
+        assert!(matches!(
+            config_with_runner(&runner).change_key("pool/dataset", "new passphrase 1234"),
+            Err(ZfsError::KeyNotLoadedForChangeKey(_))
+        ));
+        assert_eq!(runner.calls().len(), 1);
+    }
+
+    #[test]
+    fn runner_create_encrypted_dataset() {
+        let runner = MockRunner::new(|_| (0, "", ""));
+        config_with_runner(&runner)
+            .create_encrypted_dataset("pool/home/alice", "abcdefghijklmnop", CreateOpts::default())
+            .unwrap();
+        assert_eq!(
+            runner.calls(),
+            [(
+                command_argv(Command::new("sudo").args([
+                    "-n",
+                    "zfs",
+                    "create",
+                    "-o",
+                    "encryption=on",
+                    "-o",
+                    "keyformat=passphrase",
+                    "-o",
+                    "keylocation=prompt",
+                    "pool/home/alice"
+                ])),
+                Some(b"abcdefghijklmnop\nabcdefghijklmnop\n".to_vec())
+            )]
+        );
+
+        let runner = MockRunner::new(|_| {
+            (
+                1,
+                "",
+                "cannot create 'pool/home/alice': dataset already exists\n",
+            )
+        });
+        assert!(matches!(
+            config_with_runner(&runner).create_encrypted_dataset(
+                "pool/home/alice",
+                "abcdefghijklmnop",
+                CreateOpts::default()
+            ),
+            Err(ZfsError::CreateDatasetCmdFailed(dataset, _)) if dataset == "pool/home/alice"
+        ));
+    }
+
+    #[test]
+    fn runner_mount() {
+        let runner = MockRunner::new(|_| (0, "pool/dataset\tunavailable\n", ""));
+        assert!(matches!(
+            config_with_runner(&runner).mount_dataset("pool/dataset"),
+            Err(ZfsError::KeyNotLoadedForMount(_))
+        ));
+        assert_eq!(runner.calls().len(), 1);
+
+        let runner = MockRunner::new(|_| {
+            (
+                1,
+                "",
+                "cannot open 'pool/dataset': dataset does not exist\n",
+            )
+        });
+        assert!(matches!(
+            config_with_runner(&runner).mount_dataset("pool/dataset"),
+            Err(ZfsError::DatasetNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn runner_mount_strict_mountpoint() {
+        fn respond(
+            argv: &[String],
+            mount_table: &'static str,
+        ) -> (i32, &'static str, &'static str) {
+            match argv[0].as_str() {
+                "cat" if argv[1] == "/proc/self/mounts" => (0, mount_table, ""),
+                _ => match argv[1].as_str() {
+                    "get" if argv[2] == "keystatus" => (0, "pool/dataset\tavailable\n", ""),
+                    "list" => (0, "pool/dataset\tyes\n", ""),
+                    "get" => (0, "/pool/dataset\n", ""),
+                    _ => (1, "", "unexpected command\n"),
+                },
+            }
+        }
+
+        let runner =
+            MockRunner::new(|argv| respond(argv, "pool/dataset /mnt/elsewhere zfs rw,xattr 0 0\n"));
+        let config = ZfsConfig {
+            strict_mountpoint: true,
+            ..config_with_runner(&runner)
+        };
+        assert!(matches!(
+            config.mount_dataset("pool/dataset"),
+            Err(ZfsError::MountedAtUnexpectedLocation(dataset, actual, expected))
+                if dataset == "pool/dataset"
+                    && actual == Path::new("/mnt/elsewhere")
+                    && expected == Path::new("/pool/dataset")
+        ));
+        assert_eq!(
+            runner.calls().last().unwrap().0,
+            ["cat", "/proc/self/mounts"]
+        );
+
+        let runner =
+            MockRunner::new(|argv| respond(argv, "pool/dataset /pool/dataset zfs rw,xattr 0 0\n"));
+        let config = ZfsConfig {
+            strict_mountpoint: true,
+            ..config_with_runner(&runner)
+        };
+        config.mount_dataset("pool/dataset").unwrap();
+        // zfs mount doesn't run for a dataset that's already mounted where it should be
+        assert!(runner.calls().iter().all(|(argv, _)| argv[0] != "sudo"));
+
+        let runner = MockRunner::new(|argv| match argv[0].as_str() {
+            "cat" => (1, "", "cat: /proc/self/mounts: No such file or directory\n"),
+            _ => respond(argv, ""),
+        });
+        let config = ZfsConfig {
+            strict_mountpoint: true,
+            ..config_with_runner(&runner)
+        };
+        assert!(matches!(
+            config.mount_dataset("pool/dataset"),
+            Err(ZfsError::SystemError(message)) if message.contains("No such file")
+        ));
+    }
+
+    #[test]
+    fn runner_send_raw() {
+        let runner = MockRunner::new(|_| (0, "stream", ""));
+        let mut stream = Vec::new();
+        assert_eq!(
+            config_with_runner(&runner)
+                .send_raw("pool/dataset@snap", &mut stream)
+                .unwrap(),
+            6
+        );
+        assert_eq!(stream, b"stream");
+        assert_eq!(
+            runner.calls()[0].0,
+            ["sudo", "-n", "zfs", "send", "--raw", "pool/dataset@snap"]
+        );
+
+        let runner = MockRunner::new(|_| {
+            (
+                1,
+                "",
+                "cannot open 'pool/dataset@snap': dataset does not exist\n",
+            )
+        });
+        assert!(matches!(
+            config_with_runner(&runner).send_raw("pool/dataset@snap", Vec::new()),
+            Err(ZfsError::DatasetNotFound(_))
+        ));
+        assert!(matches!(
+            config_with_runner(&runner).send_raw("pool/dataset", Vec::new()),
+            Err(ZfsError::SnapshotNameIsInvalid(_))
+        ));
+        assert_eq!(runner.calls().len(), 1);
+    }
+
+    #[test]
+    fn runner_unmount_and_unload_atomic() {
+        let unmounts = |runner: &MockRunner| {
+            runner
+                .calls()
+                .into_iter()
+                .map(|(argv, _)| argv)
+                .filter(|argv| argv.contains(&"umount".to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "version" => (0, "zfs-2.2.2-1\nzfs-kmod-2.2.2-1\n", ""),
+            "list" => (0, "pool/dataset\tyes\n", ""),
+            _ => (0, "pool/dataset\tunavailable\n", ""),
+        });
+        assert_eq!(
+            config_with_runner(&runner)
+                .unmount_and_unload_atomic("pool/dataset")
+                .unwrap(),
+            UnmountAndUnloadMethod::Combined
+        );
+        assert_eq!(
+            unmounts(&runner),
+            [["sudo", "-n", "zfs", "umount", "-u", "pool/dataset"]]
+        );
+
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "version" => (0, "zfs-2.1.5-1\nzfs-kmod-2.1.5-1\n", ""),
+            "list" => (0, "pool/dataset\tyes\n", ""),
+            _ => (0, "pool/dataset\tunavailable\n", ""),
+        });
+        assert_eq!(
+            config_with_runner(&runner)
+                .unmount_and_unload_atomic("pool/dataset")
+                .unwrap(),
+            UnmountAndUnloadMethod::Separate
+        );
+        assert_eq!(
+            unmounts(&runner),
+            [["sudo", "-n", "zfs", "umount", "pool/dataset"]]
+        );
+    }
+
+    #[test]
+    fn runner_unload_key() {
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" => (0, "pool/dataset\tyes\n", ""),
+            _ => (0, "pool/dataset\tavailable\n", ""),
+        });
+        assert!(matches!(
+            config_with_runner(&runner).unload_key("pool/dataset"),
+            Err(ZfsError::CannotUnloadKeyWhileMounted(_))
+        ));
+        assert!(runner.calls().iter().all(|(argv, _)| argv[0] == "zfs"));
+
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" => (0, "pool/dataset\tno\n", ""),
+            _ => (0, "pool/dataset\tavailable\n", ""),
+        });
+        config_with_runner(&runner)
+            .unload_key("pool/dataset")
+            .unwrap();
+        assert_eq!(
+            runner.calls().last().unwrap().0,
+            ["sudo", "-n", "zfs", "unload-key", "pool/dataset"]
+        );
+    }
+
+    #[test]
+    fn runner_unload_key_held() {
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" if argv.contains(&"snapshot".to_string()) => (
+                0,
+                "pool/dataset@a\t0\t0\t1700000000\n\
+                 pool/dataset/child@b\t0\t0\t1700000000\n",
+                "",
+            ),
+            "list" => (0, "pool/dataset\tno\n", ""),
+            "holds" => (
+                0,
+                "pool/dataset/child@b\tsyncoid\tThu Jan  4 10:00 2024\n",
+                "",
+            ),
+            _ => (0, "pool/dataset\tavailable\n", ""),
+        });
+        let config = ZfsConfig {
+            check_holds: true,
+            ..config_with_runner(&runner)
+        };
+
+        assert!(matches!(
+            config.unload_key("pool/dataset"),
+            Err(ZfsError::SnapshotsHeld(dataset, held))
+                if dataset == "pool/dataset" && held == ["pool/dataset/child@b"]
+        ));
+        assert!(matches!(
+            config.unmount_and_unload("pool/dataset"),
+            Err(ZfsError::SnapshotsHeld(..))
+        ));
+        assert!(runner.calls().iter().all(|(argv, _)| argv[0] == "zfs"));
+        assert!(runner.calls().iter().any(
+            |(argv, _)| argv[1..] == ["holds", "-H", "pool/dataset@a", "pool/dataset/child@b"]
+        ));
+
+        assert_eq!(
+            config.list_holds("pool/dataset/child@b").unwrap(),
+            ["syncoid"]
+        );
+        assert!(config
+            .list_holds("pool/dataset/child@a")
+            .unwrap()
+            .is_empty());
+
+        // Holds aren't checked by default
+        config_with_runner(&runner)
+            .unload_key("pool/dataset")
+            .unwrap();
+        assert_eq!(
+            runner.calls().last().unwrap().0,
+            ["sudo", "-n", "zfs", "unload-key", "pool/dataset"]
+        );
+    }
+
+    #[test]
+    fn runner_unload_key_recursive() {
+        static UNLOADED: AtomicBool = AtomicBool::new(false);
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" if UNLOADED.load(Ordering::SeqCst) => (
+                0,
+                "pool/home\tunavailable\npool/home/alice\tunavailable\n",
+                "",
+            ),
+            "list" => (0, "pool/home\tavailable\npool/home/alice\tavailable\n", ""),
+            _ => {
+                UNLOADED.store(true, Ordering::SeqCst);
+                (0, "", "")
+            }
+        });
+        config_with_runner(&runner)
+            .unload_key_recursive("pool/home")
+            .unwrap();
+        assert_eq!(
+            privileged_calls(&runner),
+            [command_argv(Command::new("sudo").args([
+                "-n",
+                "zfs",
+                "unload-key",
+                "-r",
+                "pool/home"
+            ]))]
+        );
+
+        // zfs fails for the key it couldn't unload, which is reported from the keys still loaded
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" => (
+                0,
+                "pool/home\tavailable\npool/home/alice\tunavailable\n",
+                "",
+            ),
+            _ => (1, "", "Key unload error: 'pool/home' is busy.\n"),
+        });
+        assert!(matches!(
+            config_with_runner(&runner).unload_key_recursive("pool/home"),
+            Err(ZfsError::KeysStillLoaded(dataset, still_loaded))
+                if dataset == "pool/home" && still_loaded == ["pool/home".to_string()].into()
+        ));
+
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" => (0, "pool/home\tavailable\n", ""),
+            _ => (1, "", "sudo: a password is required\n"),
+        });
+        assert!(matches!(
+            config_with_runner(&runner).unload_key_recursive("pool/home"),
+            Err(ZfsError::SudoNonInteractiveDenied(_))
+        ));
+
+        // Nothing is loaded, so unload-key doesn't run
+        let runner = MockRunner::new(|_| (0, "pool/home\tunavailable\n", ""));
+        config_with_runner(&runner)
+            .unload_key_recursive("pool/home")
+            .unwrap();
+        assert_eq!(runner.calls().len(), 1);
+    }
+
+    #[test]
+    fn runner_mount_hook() {
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "get" if argv.contains(&"keystatus".to_string()) => {
+                (0, "pool/dataset\tavailable\n", "")
+            }
+            "list" => (0, "pool/dataset\tno\n", ""),
+            "get" => (0, "/mnt/my data\n", ""),
+            _ => (0, "", ""),
+        });
+        let mounted = Arc::new(Mutex::new(Vec::new()));
+        let hook_mounted = mounted.clone();
+        let config = ZfsConfig {
+            on_mounted: Some(Arc::new(move |dataset: &str, mountpoint: &Path| {
+                hook_mounted
+                    .lock()
+                    .unwrap()
+                    .push((dataset.to_string(), mountpoint.to_path_buf()));
+            })),
+            ..config_with_runner(&runner)
+        };
+
+        config.mount_dataset("pool/dataset").unwrap();
+        assert_eq!(
+            *mounted.lock().unwrap(),
+            [("pool/dataset".to_string(), PathBuf::from("/mnt/my data"))]
+        );
+        assert_ne!(config, config_with_runner(&runner));
+
+        // Already mounted, so the hook isn't called again
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" => (0, "pool/dataset\tyes\n", ""),
+            _ => (0, "pool/dataset\tavailable\n", ""),
+        });
+        ZfsConfig {
+            runner: Some(runner.clone()),
+            ..config
+        }
+        .mount_dataset("pool/dataset")
+        .unwrap();
+        assert_eq!(mounted.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn runner_unlock_and_mount() {
+        static LOADED: AtomicBool = AtomicBool::new(false);
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "get" if argv[2] == "keystatus" && LOADED.load(Ordering::SeqCst) => {
+                (0, "pool/dataset\tavailable\n", "")
+            }
+            "get" if argv[2] == "keystatus" => (0, "pool/dataset\tunavailable\n", ""),
+            "get" if argv[5] == "keylocation" => (0, "prompt\n", ""),
+            "get" if argv[5] == "mountpoint" => (0, "/pool/dataset\n", ""),
+            "get" => (0, "on\n", ""),
+            "list" => (0, "pool/dataset\tno\n", ""),
+            _ => {
+                if argv[3] == "load-key" {
+                    LOADED.store(true, Ordering::SeqCst);
+                }
+                (0, "", "")
+            }
+        });
+        config_with_runner(&runner)
+            .unlock_and_mount("pool/dataset", "abcdefghijklmnop")
+            .unwrap();
+        let privileged = runner
+            .calls()
+            .into_iter()
+            .filter(|(argv, _)| argv[0] == "sudo")
+            .collect::<Vec<_>>();
+        assert_eq!(
+            privileged,
+            [
+                (
+                    command_argv(Command::new("sudo").args([
+                        "-n",
+                        "zfs",
+                        "load-key",
+                        "pool/dataset"
+                    ])),
+                    Some(b"abcdefghijklmnop\n".to_vec())
+                ),
+                (
+                    command_argv(Command::new("sudo").args(["-n", "zfs", "mount", "pool/dataset"])),
+                    None
+                ),
+            ]
+        );
+
+        // The key isn't loaded, so it isn't mounted
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "get" if argv[2] == "keystatus" => (0, "pool/dataset\tunavailable\n", ""),
+            "get" => (0, "prompt\n", ""),
+            _ => (
+                255,
+                "",
+                "Key load error: Incorrect key provided for 'pool/dataset'.\n",
+            ),
+        });
+        assert!(matches!(
+            config_with_runner(&runner).unlock_and_mount("pool/dataset", "abcdefghijklmnop"),
+            Err(ZfsError::IncorrectPassphrase(_))
+        ));
+        assert!(runner
+            .calls()
+            .iter()
+            .all(|(argv, _)| !argv.iter().any(|arg| arg == "mount")));
+    }
+
+    #[test]
+    fn runner_ensure_available() {
+        // The state is queried with list before mounting, and with get once both commands succeeded
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "get" if argv[2] == "keystatus" => (0, "pool/dataset\tavailable\n", ""),
+            "list" if argv.contains(&"name,mounted".to_string()) => (0, "pool/dataset\tyes\n", ""),
+            "list" => (
+                0,
+                "pool/dataset\tno\tavailable\tpool/dataset\t/pool/dataset\n",
+                "",
+            ),
+            _ => (0, "", ""),
+        });
+        assert!(matches!(
+            config_with_runner(&runner).ensure_available("pool/dataset", "abcdefghijklmnop"),
+            Err(ZfsError::VerificationFailed(dataset)) if dataset == "pool/dataset"
+        ));
+
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "get" if argv[2] == "keystatus" => (0, "pool/dataset\tavailable\n", ""),
+            "list" if argv.contains(&"name,mounted".to_string()) => (0, "pool/dataset\tyes\n", ""),
+            "list" => (
+                0,
+                "pool/dataset\tyes\tavailable\tpool/dataset\t/pool/dataset\n",
+                "",
+            ),
+            _ => (0, "", ""),
+        });
+        config_with_runner(&runner)
+            .ensure_available("pool/dataset", "abcdefghijklmnop")
+            .unwrap();
+        // Already unlocked and mounted, so nothing is run but the queries
+        assert!(runner
+            .calls()
+            .iter()
+            .all(|(argv, _)| !argv.iter().any(|arg| arg == "load-key" || arg == "mount")));
+    }
+
+    #[test]
+    fn runner_key_requires_passphrase() {
+        let runner = MockRunner::new(|argv| match argv.last().unwrap().as_str() {
+            "pool/plain" => (0, "-\n", ""),
+            "pool/missing" => (
+                1,
+                "",
+                "cannot open 'pool/missing': dataset does not exist\n",
+            ),
+            _ if argv[5] == "encryptionroot" => (0, "pool/enc\n", ""),
+            _ => (
+                0,
+                "encryption\taes-256-gcm\n\
+                 keyformat\tpassphrase\n\
+                 keylocation\tfile:///keys/pool.key\n\
+                 keystatus\tunavailable\n\
+                 pbkdf2iters\t350000\n",
+                "",
+            ),
+        });
+        let config = config_with_runner(&runner);
+
+        // The keylocation is read from the encryption root, as the child inherits it
+        assert_eq!(
+            config.key_requires_passphrase("pool/enc/child").unwrap(),
+            Some(false)
+        );
+        assert_eq!(runner.calls()[1].0.last().unwrap(), "pool/enc");
+        assert_eq!(config.key_requires_passphrase("pool/plain").unwrap(), None);
+        assert_eq!(
+            config.key_requires_passphrase("pool/missing").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn runner_unmount_by_mountpoint() {
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" if argv[4] == "name,mountpoint" => (
+                0,
+                "rpool/ROOT/a\t/\n\
+                 rpool/ROOT/b\t/\n\
+                 tank\t/tank\n",
+                "",
+            ),
+            "list" if argv[4] == DATASET_STATE_COLUMNS => (
+                0,
+                "rpool/ROOT/a\tno\t-\t-\t/\n\
+                 rpool/ROOT/b\tyes\t-\t-\t/\n",
+                "",
+            ),
+            "list" => (0, "rpool/ROOT/b\tyes\n", ""),
+            _ => (0, "", ""),
+        });
+        let config = config_with_runner(&runner);
+
+        assert_eq!(
+            config.unmount_by_mountpoint(Path::new("/")).unwrap(),
+            ["rpool/ROOT/b"]
+        );
+        assert_eq!(
+            runner.calls().last().unwrap().0,
+            ["sudo", "-n", "zfs", "umount", "rpool/ROOT/b"]
+        );
+
+        assert!(matches!(
+            config.unmount_by_mountpoint(Path::new("/tank/home")),
+            Err(ZfsError::NoDatasetAtMountpoint(path)) if path == Path::new("/tank/home")
+        ));
+    }
+
+    #[test]
+    fn runner_get_mountpoint() {
+        let runner = MockRunner::new(|argv| match argv[5].as_str() {
+            "pool/dataset" => (0, "/mnt/my data\n", ""),
+            "pool/legacy" => (0, "legacy\n", ""),
+            "pool/none" => (0, "none\n", ""),
+            _ => (
+                1,
+                "",
+                "cannot open 'pool/missing': dataset does not exist\n",
+            ),
+        });
+        let config = config_with_runner(&runner);
+
+        assert_eq!(
+            config.get_mountpoint("pool/dataset").unwrap(),
+            Some(PathBuf::from("/mnt/my data"))
+        );
+        assert_eq!(
+            runner.calls()[0].0,
+            ["zfs", "list", "-H", "-o", "mountpoint", "pool/dataset"]
+        );
+        assert_eq!(config.get_mountpoint("pool/legacy").unwrap(), None);
+        assert_eq!(config.get_mountpoint("pool/none").unwrap(), None);
+        assert!(matches!(
+            config.get_mountpoint("pool/missing"),
+            Err(ZfsError::DatasetNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn runner_mount_returning_path() {
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" => (0, "pool/dataset\tyes\n", ""),
+            "get" if argv.contains(&"keystatus".to_string()) => {
+                (0, "pool/dataset\tavailable\n", "")
+            }
+            _ => (0, "/mnt/my data\n", ""),
+        });
+        assert_eq!(
+            config_with_runner(&runner)
+                .mount_dataset_returning_path("pool/dataset")
+                .unwrap(),
+            PathBuf::from("/mnt/my data")
+        );
+
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" => (0, "pool/dataset\tyes\n", ""),
+            "get" if argv.contains(&"keystatus".to_string()) => {
+                (0, "pool/dataset\tavailable\n", "")
+            }
+            _ => (0, "legacy\n", ""),
+        });
+        assert!(matches!(
+            config_with_runner(&runner).mount_dataset_returning_path("pool/dataset"),
+            Err(ZfsError::LegacyMountpoint(_))
+        ));
+    }
+
+    #[test]
+    fn runner_mount_verbose() {
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "get" if argv[2] == "keystatus" => (0, "pool/dataset\tavailable\n", ""),
+            "get" if argv[5] == "mountpoint" => (0, "/pool/dataset\n", ""),
+            "get" => (0, "on\n", ""),
+            "list" => (0, "pool/dataset\tno\n", ""),
+            _ => (0, "mounted\n", "warning: mountpoint is not empty\n"),
+        });
+        let output = config_with_runner(&runner)
+            .mount_dataset_verbose("pool/dataset")
+            .unwrap();
+        assert_eq!(output.stdout, "mounted\n");
+        assert_eq!(output.stderr, "warning: mountpoint is not empty\n");
+        assert_eq!(
+            runner.calls().last().unwrap().0,
+            command_argv(Command::new("sudo").args(["-n", "zfs", "mount", "pool/dataset"]))
+        );
+
+        // Already mounted, so zfs mount doesn't run
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "get" => (0, "pool/dataset\tavailable\n", ""),
+            _ => (0, "pool/dataset\tyes\n", ""),
+        });
+        let output = config_with_runner(&runner)
+            .mount_dataset_verbose("pool/dataset")
+            .unwrap();
+        assert!(output.stdout.is_empty() && output.stderr.is_empty());
+        assert!(privileged_calls(&runner).is_empty());
+    }
+
+    #[test]
+    fn runner_mount_legacy() {
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "get" if argv.contains(&"keystatus".to_string()) => {
+                (0, "pool/dataset\tavailable\n", "")
+            }
+            "list" => (0, "pool/dataset\tno\n", ""),
+            "get" => (0, "legacy\n", ""),
+            _ => (0, "", ""),
+        });
+        assert!(matches!(
+            config_with_runner(&runner).mount_dataset("pool/dataset"),
+            Err(ZfsError::LegacyMountpoint(_))
+        ));
+        assert!(runner.calls().iter().all(|(argv, _)| argv[0] == "zfs"));
+    }
+
+    #[test]
+    fn runner_mount_canmount_off() {
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "get" if argv[2] == "keystatus" => (0, "pool/dataset\tavailable\n", ""),
+            "list" => (0, "pool/dataset\tno\n", ""),
+            "get" if argv[5] == "canmount" => (0, "off\n", ""),
+            "get" => (0, "/pool/dataset\n", ""),
+            _ => (0, "", ""),
+        });
+        let config = config_with_runner(&runner);
+
+        assert!(matches!(
+            config.mount_dataset("pool/dataset"),
+            Err(ZfsError::CanmountOff(dataset)) if dataset == "pool/dataset"
+        ));
+        assert!(runner.calls().iter().all(|(argv, _)| argv[0] == "zfs"));
+        assert_eq!(config.get_canmount("pool/dataset").unwrap(), CanMount::Off);
+
+        config
+            .set_canmount("pool/dataset", CanMount::NoAuto)
+            .unwrap();
+        assert_eq!(
+            runner.calls().last().unwrap().0,
+            [
+                "sudo",
+                "-n",
+                "zfs",
+                "set",
+                "canmount=noauto",
+                "pool/dataset"
+            ]
+        );
+    }
+
+    #[test]
+    fn runner_mount_at() {
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "get" if argv[2] == "keystatus" => (0, "pool/dataset\tavailable\n", ""),
+            "list" => (0, "pool/dataset\tno\n", ""),
+            "get" => (0, "/pool/dataset\n", ""),
+            _ => (0, "", ""),
+        });
+        config_with_runner(&runner)
+            .mount_at("pool/dataset", Path::new("/mnt/recovery"), &["ro"])
+            .unwrap();
+        assert_eq!(
+            runner.calls().last().unwrap().0,
+            [
+                "sudo",
+                "-n",
+                "zfs",
+                "mount",
+                "-o",
+                "ro",
+                "-o",
+                "mountpoint=/mnt/recovery",
+                "pool/dataset"
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_sudo_binary() {
+        let config = ZfsConfig {
+            sudo_path: PathBuf::from("/some/non/existent/sudo"),
+            ..ZfsConfig::default()
+        };
+        assert!(matches!(
+            config.run_privileged_command(
+                config.privileged_zfs_command(),
+                &[],
+                WaitLimit::NONE,
+                "pool/dataset",
+                ZfsError::SystemError
+            ),
+            Err(ZfsError::SudoBinaryNotFound(path)) if path == config.sudo_path
+        ));
+        let doas_config = ZfsConfig {
+            escalation_command: Some(vec!["/some/non/existent/doas".to_string()]),
+            ..ZfsConfig::default()
+        };
+        assert!(matches!(
+            doas_config.run_privileged_command(
+                doas_config.privileged_zfs_command(),
+                &[],
+                WaitLimit::NONE,
+                "pool/dataset",
+                ZfsError::SystemError
+            ),
+            Err(ZfsError::SudoBinaryNotFound(path)) if path == Path::new("/some/non/existent/doas")
+        ));
+
+        let runner = MockRunner::new(|_| (1, "", "sudo: zfs: command not found\n"));
+        assert!(matches!(
+            config_with_runner(&runner).set_property("pool/dataset", "compression", "lz4"),
+            Err(ZfsError::ZfsBinaryNotFound(path)) if path == Path::new("zfs")
+        ));
+    }
+
+    #[test]
+    fn runner_set_property() {
+        let runner = MockRunner::new(|_| (0, "", ""));
+        config_with_runner(&runner)
+            .set_property("pool/dataset", "keylocation", "file:///root/dataset.key")
+            .unwrap();
+        assert_eq!(
+            runner.calls(),
+            [(
+                command_argv(Command::new("sudo").args([
+                    "-n",
+                    "zfs",
+                    "set",
+                    "keylocation=file:///root/dataset.key",
+                    "pool/dataset"
+                ])),
+                None
+            )]
+        );
+
+        let runner = MockRunner::new(|_| {
+            (
+                1,
+                "",
+                "cannot set property for 'pool/dataset': invalid property 'nope'\n",
+            )
+        });
+        assert!(matches!(
+            config_with_runner(&runner).set_property("pool/dataset", "nope", "1"),
+            Err(ZfsError::SetPropertyCmdFailed(_, _))
+        ));
+        assert!(matches!(
+            config_with_runner(&runner).set_property("pool/dataset", "compression", "lz4 -f"),
+            Err(ZfsError::DatasetPropertyIsInvalid(_))
+        ));
+        assert_eq!(runner.calls().len(), 1);
+    }
+
+    #[test]
+    fn runner_unmount() {
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" => (0, "pool/dataset\tyes\n", ""),
+            _ => (1, "", "umount: /pool/dataset: target is busy.\n"),
+        });
+        assert!(matches!(
+            config_with_runner(&runner).unmount_dataset("pool/dataset"),
+            Err(ZfsError::DatasetBusy(_))
+        ));
+
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" => (0, "pool/dataset\tyes\n", ""),
+            _ => (0, "", ""),
+        });
+        config_with_runner(&runner)
+            .unmount_dataset_force("pool/dataset")
+            .unwrap();
+        assert_eq!(
+            runner.calls().last().unwrap().0,
+            command_argv(Command::new("sudo").args(["-n", "zfs", "umount", "-f", "pool/dataset"]))
+        );
+    }
+
+    /// The argv of every umount that `runner` was given
+    fn umount_calls(runner: &MockRunner) -> Vec<Vec<String>> {
+        runner
+            .calls()
+            .into_iter()
+            .map(|(argv, _)| argv)
+            .filter(|argv| argv.iter().any(|arg| arg == "umount"))
+            .collect()
+    }
+
+    #[test]
+    fn runner_unmount_graceful_busy_then_success() {
+        static UNMOUNTS: AtomicUsize = AtomicUsize::new(0);
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" => (0, "pool/dataset\tyes\n", ""),
+            _ => match UNMOUNTS.fetch_add(1, Ordering::SeqCst) {
+                0 => (1, "", "umount: /pool/dataset: target is busy.\n"),
+                _ => (0, "", ""),
+            },
+        });
+        assert_eq!(
+            config_with_runner(&runner)
+                .unmount_dataset_graceful("pool/dataset", 3, Duration::ZERO, true)
+                .unwrap(),
+            GracefulUnmountReport {
+                attempts: 2,
+                forced: false
+            }
+        );
+        let unmount =
+            command_argv(Command::new("sudo").args(["-n", "zfs", "umount", "pool/dataset"]));
+        assert_eq!(umount_calls(&runner), vec![unmount.clone(), unmount]);
+    }
+
+    #[test]
+    fn runner_unmount_graceful_forced() {
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" => (0, "pool/dataset\tyes\n", ""),
+            _ if argv.contains(&"-f".to_string()) => (0, "", ""),
+            _ => (1, "", "umount: /pool/dataset: target is busy.\n"),
+        });
+        assert_eq!(
+            config_with_runner(&runner)
+                .unmount_dataset_graceful("pool/dataset", 2, Duration::ZERO, true)
+                .unwrap(),
+            GracefulUnmountReport {
+                attempts: 4,
+                forced: true
+            }
+        );
+        let unmount =
+            command_argv(Command::new("sudo").args(["-n", "zfs", "umount", "pool/dataset"]));
+        let forced_unmount =
+            command_argv(Command::new("sudo").args(["-n", "zfs", "umount", "-f", "pool/dataset"]));
+        assert_eq!(
+            umount_calls(&runner),
+            vec![
+                unmount.clone(),
+                unmount.clone(),
+                unmount.clone(),
+                forced_unmount
+            ]
+        );
+
+        // Without escalating, the last busy error is returned
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" => (0, "pool/dataset\tyes\n", ""),
+            _ => (1, "", "umount: /pool/dataset: target is busy.\n"),
+        });
+        assert!(matches!(
+            config_with_runner(&runner).unmount_dataset_graceful(
+                "pool/dataset",
+                2,
+                Duration::ZERO,
+                false
+            ),
+            Err(ZfsError::DatasetBusy(_))
+        ));
+        assert_eq!(
+            umount_calls(&runner),
+            vec![unmount.clone(), unmount.clone(), unmount]
+        );
+    }
+
+    #[test]
+    fn runner_unmount_graceful_forced_failure() {
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" => (0, "pool/dataset\tyes\n", ""),
+            _ if argv.contains(&"-f".to_string()) => {
+                (1, "", "cannot unmount 'pool/dataset': permission denied\n")
+            }
+            _ => (1, "", "umount: /pool/dataset: target is busy.\n"),
+        });
+        assert!(matches!(
+            config_with_runner(&runner).unmount_dataset_graceful(
+                "pool/dataset",
+                0,
+                Duration::ZERO,
+                true
+            ),
+            Err(ZfsError::UnmountCmdFailed(_, _))
+        ));
+        assert_eq!(
+            umount_calls(&runner),
+            vec![
+                command_argv(Command::new("sudo").args(["-n", "zfs", "umount", "pool/dataset"])),
+                command_argv(Command::new("sudo").args([
+                    "-n",
+                    "zfs",
+                    "umount",
+                    "-f",
+                    "pool/dataset"
+                ]))
+            ]
+        );
+    }
+
+    /// The argv of every command that `runner` ran with sudo
+    fn privileged_calls(runner: &MockRunner) -> Vec<Vec<String>> {
+        runner
+            .calls()
+            .into_iter()
+            .map(|(argv, _)| argv)
+            .filter(|argv| argv[0] == "sudo")
+            .collect()
+    }
+
+    #[test]
+    fn runner_remount() {
+        static UNMOUNTED: AtomicBool = AtomicBool::new(false);
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "get" if argv[2] == "keystatus" => (0, "pool/dataset\tavailable\n", ""),
+            "get" if argv[5] == "mountpoint" => (0, "/pool/dataset\n", ""),
+            "get" => (0, "on\n", ""),
+            "list" if UNMOUNTED.load(Ordering::SeqCst) => (0, "pool/dataset\tno\n", ""),
+            "list" => (0, "pool/dataset\tyes\n", ""),
+            _ => {
+                if argv[3] == "umount" {
+                    UNMOUNTED.store(true, Ordering::SeqCst);
+                }
+                (0, "", "")
+            }
+        });
+        assert_eq!(
+            config_with_runner(&runner)
+                .remount_dataset("pool/dataset")
+                .unwrap(),
+            RemountOutcome::Remounted
+        );
+        assert_eq!(
+            privileged_calls(&runner),
+            [
+                command_argv(Command::new("sudo").args(["-n", "zfs", "umount", "pool/dataset"])),
+                command_argv(Command::new("sudo").args(["-n", "zfs", "mount", "pool/dataset"])),
+            ]
+        );
+
+        // Already unmounted, so it's only mounted
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "get" if argv[2] == "keystatus" => (0, "pool/dataset\tavailable\n", ""),
+            "get" if argv[5] == "mountpoint" => (0, "/pool/dataset\n", ""),
+            "get" => (0, "on\n", ""),
+            "list" => (0, "pool/dataset\tno\n", ""),
+            _ => (0, "", ""),
+        });
+        assert_eq!(
+            config_with_runner(&runner)
+                .remount_dataset("pool/dataset")
+                .unwrap(),
+            RemountOutcome::AlreadyUnmounted
+        );
+        assert_eq!(
+            privileged_calls(&runner),
+            [command_argv(Command::new("sudo").args([
+                "-n",
+                "zfs",
+                "mount",
+                "pool/dataset"
+            ]))]
+        );
+    }
+
+    #[test]
+    fn runner_zpool_import_export() {
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" => (1, "", "cannot open 'usb': no such pool\n"),
+            _ => (0, "", ""),
+        });
+        let config = config_with_runner(&runner);
+        config.zpool_import("usb").unwrap();
+        assert_eq!(
+            privileged_calls(&runner),
+            [command_argv(
+                Command::new("sudo").args(["-n", "zpool", "import", "usb"])
+            )]
+        );
+        // Not imported, so there's nothing to export
+        config.zpool_export("usb").unwrap();
+        assert_eq!(privileged_calls(&runner).len(), 1);
+
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" => (0, "usb\n", ""),
+            _ => (0, "", ""),
+        });
+        let config = config_with_runner(&runner);
+        // Already imported, so there's nothing to import
+        config.zpool_import("usb").unwrap();
+        config.zpool_export("usb").unwrap();
+        config.zpool_export_force("usb").unwrap();
+        assert_eq!(
+            privileged_calls(&runner),
+            [
+                command_argv(Command::new("sudo").args(["-n", "zpool", "export", "usb"])),
+                command_argv(Command::new("sudo").args(["-n", "zpool", "export", "-f", "usb"])),
+            ]
+        );
+
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" => (1, "", "cannot open 'usb': no such pool\n"),
+            _ => (1, "", "cannot import 'usb': no such pool available\n"),
+        });
+        assert!(matches!(
+            config_with_runner(&runner).zpool_import("usb"),
+            Err(ZfsError::ZpoolImportCmdFailed(pool, _)) if pool == "usb"
+        ));
+
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" => (0, "usb\n", ""),
+            _ => (1, "", "cannot export 'usb': pool is busy\n"),
+        });
+        assert!(matches!(
+            config_with_runner(&runner).zpool_export("usb"),
+            Err(ZfsError::ZpoolExportCmdFailed(pool, _)) if pool == "usb"
+        ));
+    }
+
+    #[test]
+    fn runner_prepare_pool_for_removal() {
+        let runner = MockRunner::new(|argv| match (argv[0].as_str(), argv[1].as_str()) {
+            ("zfs", "list") if argv[4] == "-t" => (0, "usb\tyes\nusb/backup\tyes\n", ""),
+            ("zfs", "list") if argv[2] == "-r" => (0, "usb\t-\nusb/backup\tunavailable\n", ""),
+            ("zfs", "list") => (0, "usb\tyes\nusb/backup\tyes\n", ""),
+            _ => (0, "", ""),
+        });
+        let report = config_with_runner(&runner)
+            .prepare_pool_for_removal("usb")
+            .unwrap();
+        assert!(report.is_complete());
+        assert_eq!(
+            report.unmounted.keys().collect::<Vec<_>>(),
+            ["usb", "usb/backup"]
+        );
+
+        let privileged = runner
+            .calls()
+            .into_iter()
+            .map(|(argv, _)| argv)
+            .filter(|argv| argv[0] == "sudo")
+            .collect::<Vec<_>>();
+        assert_eq!(
+            privileged,
+            [
+                ["sudo", "-n", "zfs", "umount", "usb/backup"],
+                ["sudo", "-n", "zfs", "umount", "usb"],
+                ["sudo", "-n", "zpool", "export", "usb"],
+            ]
+        );
+
+        // Nothing else is done while a filesystem is still mounted
+        let runner = MockRunner::new(|argv| match (argv[0].as_str(), argv[1].as_str()) {
+            ("zfs", "list") if argv[4] == "-t" => (0, "usb\tyes\nusb/backup\tyes\n", ""),
+            ("zfs", "list") => (0, "usb\tyes\nusb/backup\tyes\n", ""),
+            ("sudo", _) if argv.last().unwrap() == "usb/backup" => {
+                (1, "", "umount: /usb/backup: target is busy.\n")
+            }
+            _ => (1, "", "cannot unmount '/usb': pool or dataset is busy\n"),
+        });
+        let report = config_with_runner(&runner)
+            .prepare_pool_for_removal("usb")
+            .unwrap();
+        assert!(!report.is_complete());
+        assert!(matches!(
+            report.unmounted["usb/backup"],
+            Err(ZfsError::DatasetBusy(_))
+        ));
+        assert!(report.keys_unloaded.is_none());
+        assert!(report.exported.is_none());
+
+        let runner = MockRunner::new(|_| (0, "usb\n", ""));
+        config_with_runner(&runner)
+            .zpool_export_force("usb")
+            .unwrap();
+        assert_eq!(
+            runner.calls().last().unwrap().0,
+            ["sudo", "-n", "zpool", "export", "-f", "usb"]
+        );
+    }
+
+    #[test]
+    fn runner_list_properties() {
+        let runner = MockRunner::new(|_| (0, "pool/a\toff\n", ""));
+        let config = config_with_runner(&runner);
+        let rows = config
+            .list_properties(Some(&["pool/a"]), &["name", "compression"])
+            .unwrap();
+        assert_eq!(rows[0]["compression"], "off");
+        config.list_properties(None, &["name"]).unwrap();
+        assert_eq!(
+            runner.calls()[0].0,
+            ["zfs", "list", "-H", "-o", "name,compression", "pool/a"]
+        );
+        assert_eq!(runner.calls()[1].0, ["zfs", "list", "-H", "-o", "name"]);
+
+        assert!(config
+            .list_properties(Some(&[]), &["name"])
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            config.list_properties(None, &[]),
+            Err(ZfsError::DatasetPropertyIsInvalid(_))
+        ));
+        assert!(matches!(
+            config.list_properties(None, &["name,mounted"]),
+            Err(ZfsError::DatasetPropertyIsInvalid(_))
+        ));
+        assert_eq!(runner.calls().len(), 2);
+    }
+
+    #[test]
+    fn runner_dataset_exists() {
+        let runner = MockRunner::new(|_| (0, "pool/a\n", ""));
+        assert!(config_with_runner(&runner)
+            .dataset_exists("pool/a")
+            .unwrap());
+        assert_eq!(
+            runner.calls()[0].0,
+            command_argv(Command::new("zfs").args(["list", "-H", "-o", "name", "pool/a"]))
+        );
+
+        let runner = MockRunner::new(|_| (1, "", "cannot open 'pool/a': dataset does not exist\n"));
+        assert!(!config_with_runner(&runner)
+            .dataset_exists("pool/a")
+            .unwrap());
+
+        let runner = MockRunner::new(|_| (1, "", "internal error: out of memory\n"));
+        assert!(matches!(
+            config_with_runner(&runner).dataset_exists("pool/a"),
+            Err(ZfsError::DatasetExistsCheckFailed(dataset, _)) if dataset == "pool/a"
+        ));
+    }
+
+    #[test]
+    fn runner_get_states() {
+        let runner = MockRunner::new(|_| (0, "pool/a\tyes\tavailable\tpool/a\t/a\n", ""));
+        let config = config_with_runner(&runner);
+        assert_eq!(config.get_states(&["pool/a", " pool/b "]).unwrap().len(), 1);
+        assert_eq!(
+            runner.calls()[0].0,
+            [
+                "zfs",
+                "list",
+                "-H",
+                "-o",
+                DATASET_STATE_COLUMNS,
+                "pool/a",
+                "pool/b"
+            ]
+        );
+
+        assert!(config.get_states(&[] as &[&str]).unwrap().is_empty());
+        assert!(matches!(
+            config.get_states(&["pool/a", "pool/b c"]),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert_eq!(runner.calls().len(), 1);
+    }
+
+    #[test]
+    fn runner_is_unlocked_and_mounted() {
+        type Respond = fn(&[String]) -> (i32, &'static str, &'static str);
+        let cases: [(Respond, (bool, bool)); 4] = [
+            (
+                |_| (0, "pool/a\tno\tunavailable\tpool/a\t/a\n", ""),
+                (false, false),
+            ),
+            (
+                |_| (0, "pool/a\tno\tavailable\tpool/a\t/a\n", ""),
+                (true, false),
+            ),
+            (
+                |_| (0, "pool/a\tyes\tunavailable\tpool/a\t/a\n", ""),
+                (false, true),
+            ),
+            (
+                |_| (0, "pool/a\tyes\tavailable\tpool/a\t/a\n", ""),
+                (true, true),
+            ),
+        ];
+        for (respond, expected) in cases {
+            let runner = MockRunner::new(respond);
+            assert_eq!(
+                config_with_runner(&runner)
+                    .is_unlocked_and_mounted("pool/a")
+                    .unwrap(),
+                Some(expected)
+            );
+        }
+
+        let runner = MockRunner::new(|_| (1, "", "cannot open 'pool/a': dataset does not exist\n"));
+        assert_eq!(
+            config_with_runner(&runner)
+                .is_unlocked_and_mounted("pool/a")
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn runner_unmount_tree() {
+        let runner = MockRunner::new(|argv| match argv[1].as_str() {
+            "list" if argv[2] == "-r" => (0, "pool/a\tyes\npool/a/b\tyes\npool/a/c\tno\n", ""),
+            "list" => (0, "pool/a\tyes\npool/a/b\tyes\n", ""),
+            _ => (0, "", ""),
+        });
+        let results = config_with_runner(&runner).unmount_tree("pool/a").unwrap();
+        assert_eq!(results.keys().collect::<Vec<_>>(), ["pool/a", "pool/a/b"]);
+        assert!(results.values().all(Result::is_ok));
+
+        let unmounted = runner
+            .calls()
+            .into_iter()
+            .filter(|(argv, _)| argv[0] == "sudo")
+            .map(|(argv, _)| argv.last().unwrap().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(unmounted, ["pool/a/b", "pool/a"]);
+    }
+
+    #[test]
+    fn runner_wait_until_mounted() {
+        let poll_interval = Duration::from_millis(1);
+
+        let runner = MockRunner::new(|_| (0, "pool/dataset\tyes\n", ""));
+        assert!(config_with_runner(&runner)
+            .wait_until_mounted("pool/dataset", Duration::from_secs(10), poll_interval)
+            .unwrap());
+        assert_eq!(runner.calls().len(), 1);
+
+        let runner = MockRunner::new(|_| (0, "pool/dataset\tno\n", ""));
+        assert!(!config_with_runner(&runner)
+            .wait_until_mounted("pool/dataset", Duration::from_millis(20), poll_interval)
+            .unwrap());
+        assert!(runner.calls().len() > 1);
+
+        let runner = MockRunner::new(|_| (0, "pool/other\tyes\n", ""));
+        assert!(matches!(
+            config_with_runner(&runner).wait_until_mounted(
+                "pool/dataset",
+                Duration::from_secs(10),
+                poll_interval
+            ),
+            Err(ZfsError::DatasetNotFound(_))
+        ));
+        assert_eq!(runner.calls().len(), 1);
+    }
+
+    #[test]
+    fn runner_list_encrypted_datasets() {
+        let runner = MockRunner::new(|_| {
+            (
+                0,
+                "pool\tyes\t-\t-\t/pool\n\
+                 pool/enc\tno\tunavailable\tpool/enc\t/pool/enc\n",
+                "",
+            )
+        });
+        let datasets = config_with_runner(&runner)
+            .iter_encrypted_datasets()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(datasets.len(), 1);
+        assert_eq!(datasets[0].dataset_name, "pool/enc");
+
+        let runner = MockRunner::new(|_| (1, "", "permission denied\n"));
+        let mut datasets = config_with_runner(&runner)
+            .iter_encrypted_datasets()
+            .unwrap();
+        assert!(matches!(
+            datasets.next(),
+            Some(Err(ZfsError::ListUnmountedDatasetsCallFailed(_)))
+        ));
+        assert!(datasets.next().is_none());
+    }
+
+    #[test]
+    fn runner_find_inconsistent_datasets() {
+        let runner = MockRunner::new(|_| {
+            (
+                0,
+                "pool/enc\tyes\tunavailable\tpool/enc\npool/enc/child\tno\tunavailable\tpool/enc\n",
+                "",
+            )
+        });
+        let config = config_with_runner(&runner);
+
+        assert_eq!(
+            config.find_inconsistent_datasets().unwrap(),
+            [Inconsistency::MountedWithoutKey {
+                dataset: "pool/enc".to_string()
+            }]
+        );
+        assert_eq!(
+            runner.calls()[0].0,
+            [
+                "zfs",
+                "list",
+                "-H",
+                "-t",
+                "filesystem",
+                "-o",
+                INCONSISTENCY_COLUMNS
+            ]
+        );
+
+        let runner = MockRunner::new(|_| (1, "", "internal error\n"));
+        assert!(matches!(
+            config_with_runner(&runner).find_inconsistent_datasets(),
+            Err(ZfsError::DatasetStatesCallFailed(_))
+        ));
+    }
+
+    #[test]
+    fn runner_watch() {
+        static POLLS: AtomicUsize = AtomicUsize::new(0);
+        let runner = MockRunner::new(|_| match POLLS.fetch_add(1, Ordering::SeqCst) {
+            0 => (0, "tank/home\tno\tunavailable\ttank/home\t/home\n", ""),
+            1 => (1, "", "internal error: the watcher skips this poll\n"),
+            2 => (0, "tank/home\tno\tavailable\ttank/home\t/home\n", ""),
+            _ => (0, "tank/home\tyes\tavailable\ttank/home\t/home\n", ""),
+        });
+        let config = config_with_runner(&runner);
+
+        let (changes, handle) = config.watch(Duration::from_millis(1));
+        let timeout = Duration::from_secs(10);
+        let unlocked = changes.recv_timeout(timeout).unwrap();
+        assert_eq!(unlocked.dataset, "tank/home");
+        let (old, new) = (unlocked.old_state.unwrap(), unlocked.new_state.unwrap());
+        assert!(!old.is_key_loaded && !old.is_mounted);
+        assert!(new.is_key_loaded && !new.is_mounted);
+        let mounted = changes.recv_timeout(timeout).unwrap();
+        assert!(mounted.new_state.unwrap().is_mounted);
+        assert!(runner
+            .calls()
+            .iter()
+            .all(|(argv, _)| argv[..2] == ["zfs", "list"]));
+
+        // Once stopped, the watcher drops its sender, which ends the receiver
+        handle.stop();
+        assert!(changes.recv().is_err());
+    }
+
+    #[test]
+    fn runner_list_encrypted_datasets_under() {
+        let runner = MockRunner::new(|argv| match argv.last().unwrap().as_str() {
+            "tank/tenants/acme" => (
+                0,
+                "tank/tenants/acme\tyes\tavailable\ttank/tenants/acme\t/acme\n\
+                 tank/tenants/acme/home\tno\tunavailable\ttank/tenants/acme/home\t/acme/home\n",
+                "",
+            ),
+            _ => (
+                1,
+                "",
+                "cannot open 'tank/tenants/nobody': dataset does not exist\n",
+            ),
+        });
+        let config = config_with_runner(&runner);
+
+        let datasets = config
+            .list_encrypted_datasets_under("tank/tenants/acme")
+            .unwrap();
+        assert_eq!(
+            datasets.keys().collect::<Vec<_>>(),
+            ["tank/tenants/acme", "tank/tenants/acme/home"]
+        );
+        assert_eq!(
+            runner.calls()[0].0,
+            [
+                "zfs",
+                "list",
+                "-r",
+                "-H",
+                "-o",
+                DATASET_STATE_COLUMNS,
+                "tank/tenants/acme"
+            ]
+        );
+
+        assert!(matches!(
+            config.list_encrypted_datasets_under("tank/tenants/nobody"),
+            Err(ZfsError::DatasetNotFound(root)) if root == "tank/tenants/nobody"
+        ));
+        assert!(matches!(
+            config.list_encrypted_datasets_under("tank/../acme"),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+        assert_eq!(runner.calls().len(), 2);
+    }
+
+    #[test]
+    fn missing_zfs_binary() {
+        let config = ZfsConfig {
+            zfs_path: PathBuf::from("/some/non/existent/zfs"),
+            ..ZfsConfig::default()
+        };
+        assert!(matches!(
+            config.is_key_loaded("pool/dataset"),
+            Err(ZfsError::ZfsBinaryNotFound(path)) if path == config.zfs_path
+        ));
+        // Raw keys can be anything, even bytes that would be an empty passphrase
+        assert!(matches!(
+            config.load_key_bytes("pool/dataset", &[b' '; 32], false),
+            Err(ZfsError::ZfsBinaryNotFound(_))
+        ));
+        assert!(matches!(
+            config.dataset_exists("pool/dataset"),
+            Err(ZfsError::ZfsBinaryNotFound(_))
+        ));
+        assert!(matches!(
+            config.load_key_all(),
+            Err(ZfsError::ZfsBinaryNotFound(_))
+        ));
+        assert!(matches!(
+            config.mount_all(),
+            Err(ZfsError::ZfsBinaryNotFound(_))
+        ));
+        assert!(matches!(
+            config.list_datasets_mountpoints(),
+            Err(ZfsError::ZfsBinaryNotFound(_))
+        ));
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn runner_command_events() {
+        let runner = MockRunner::new(|_| {
+            (
+                255,
+                "",
+                "Key load error: Incorrect key provided for 'pool/dataset'.\n",
+            )
+        });
+        let config = config_with_runner(&runner);
+
+        let events = crate::tests::captured_events(|| {
+            let command = config.load_key_command("pool/dataset", None);
+            let output = config
+                .run(
+                    command,
+                    b"the secret passphrase\n",
+                    WaitLimit::NONE,
+                    "pool/dataset",
+                    ZfsError::SystemError,
+                )
+                .unwrap();
+            assert_eq!(output.status.code(), Some(255));
+        });
+
+        assert!(events.contains("Running command"));
+        assert!(events.contains("load-key"));
+        assert!(events.contains("stdin_len=22"));
+        assert!(events.contains("Command failed"));
+        assert!(events.contains("Incorrect key provided"));
+        assert!(!events.contains("secret"));
+    }
+}
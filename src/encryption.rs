@@ -0,0 +1,454 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    is_dataset_not_found_error, parse_encryption_root, parse_key_available_state,
+    parse_pbkdf2iters, split_output_line, ZfsError,
+};
+
+/// The properties that are queried by zfs_get_encryption_properties, in the order they're passed to zfs
+pub(crate) const ENCRYPTION_PROPERTIES: &str =
+    "encryption,keyformat,keylocation,keystatus,pbkdf2iters";
+
+/// The columns that are listed by zfs_encryption_status, in the order they're passed to zfs
+pub(crate) const ENCRYPTION_STATUS_COLUMNS: &str = "encryption,keystatus,encryptionroot";
+
+/// Whether a dataset exists and is encrypted, e.g., to explain why it's missing from zfs_list_encrypted_datasets
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EncryptionStatus {
+    NotFound,
+    Unencrypted,
+    Encrypted {
+        key_loaded: bool,
+        /// The dataset whose key has to be loaded to unlock this one, which may be the dataset itself
+        encryption_root: String,
+    },
+}
+
+/// Interprets the result of `zfs list -H -o <ENCRYPTION_STATUS_COLUMNS> <dataset>`
+pub(crate) fn parse_encryption_status_result(
+    dataset: &str,
+    success: bool,
+    stdout: &str,
+    stderr: String,
+) -> Result<EncryptionStatus, ZfsError> {
+    if !success {
+        return if is_dataset_not_found_error(&stderr) {
+            Ok(EncryptionStatus::NotFound)
+        } else {
+            Err(ZfsError::GetPropertyCallFailed(
+                dataset.to_string(),
+                ENCRYPTION_STATUS_COLUMNS.to_string(),
+                stderr,
+            ))
+        };
+    }
+
+    let v = stdout
+        .lines()
+        .next()
+        .map(split_output_line)
+        .unwrap_or_default();
+    if v.len() < 3 {
+        return Err(ZfsError::GetPropertyCallFailed(
+            dataset.to_string(),
+            ENCRYPTION_STATUS_COLUMNS.to_string(),
+            format!("Unexpected output of zfs list: {}", stdout.trim_end()),
+        ));
+    }
+
+    if v[0].trim() == "off" {
+        return Ok(EncryptionStatus::Unencrypted);
+    }
+
+    let encryption_root = parse_encryption_root(dataset, v[2])?.ok_or_else(|| {
+        ZfsError::UnexpectedPropertyValue(
+            dataset.to_string(),
+            "encryptionroot".to_string(),
+            v[2].to_string(),
+        )
+    })?;
+
+    Ok(EncryptionStatus::Encrypted {
+        key_loaded: parse_key_available_state(v[1])?,
+        encryption_root,
+    })
+}
+
+/// The encryption algorithm of a dataset
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EncryptionType {
+    Off,
+    Aes128Ccm,
+    Aes192Ccm,
+    Aes256Ccm,
+    Aes128Gcm,
+    Aes192Gcm,
+    Aes256Gcm,
+}
+
+impl EncryptionType {
+    /// The value of the encryption property for this algorithm, as zfs shows and accepts it
+    pub(crate) fn zfs_value(self) -> &'static str {
+        match self {
+            EncryptionType::Off => "off",
+            EncryptionType::Aes128Ccm => "aes-128-ccm",
+            EncryptionType::Aes192Ccm => "aes-192-ccm",
+            EncryptionType::Aes256Ccm => "aes-256-ccm",
+            EncryptionType::Aes128Gcm => "aes-128-gcm",
+            EncryptionType::Aes192Gcm => "aes-192-gcm",
+            EncryptionType::Aes256Gcm => "aes-256-gcm",
+        }
+    }
+}
+
+/// The format of the key of an encrypted dataset
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum KeyFormat {
+    Passphrase,
+    Raw,
+    Hex,
+}
+
+/// Where zfs gets the key of an encryption root from when it's loaded
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum KeyLocation {
+    /// The key is read from stdin, or asked for interactively
+    Prompt,
+    /// The key is read from the URI, e.g., `file:///path/to/key`
+    Uri(String),
+}
+
+/// The encryption properties of a dataset, as returned by zfs_get_encryption_properties
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EncryptionProperties {
+    pub encryption: EncryptionType,
+    /// None if the dataset isn't encrypted
+    pub keyformat: Option<KeyFormat>,
+    /// None if the dataset isn't encrypted, or isn't an encryption root and inherits its key
+    pub keylocation: Option<KeyLocation>,
+    /// Always true if the dataset isn't encrypted, as it doesn't need a key
+    pub is_key_loaded: bool,
+    /// The number of PBKDF2 iterations used to derive the key from the passphrase
+    /// None if the key isn't a passphrase
+    pub pbkdf2iters: Option<u64>,
+}
+
+impl EncryptionProperties {
+    /// Whether loading the key needs a passphrase from the user, i.e., its keyformat is passphrase and its
+    /// keylocation is prompt. Raw and hex keys at prompt aren't passphrases, so they're false like keys in files.
+    /// The keylocation is only set on encryption roots, so this is false for the datasets that inherit their key.
+    pub fn requires_passphrase(&self) -> bool {
+        self.keyformat == Some(KeyFormat::Passphrase)
+            && self.keylocation == Some(KeyLocation::Prompt)
+    }
+}
+
+/// Interprets the result of `zfs get -H -o property,value <ENCRYPTION_PROPERTIES> <dataset>`
+pub(crate) fn parse_encryption_properties_result(
+    dataset: &str,
+    success: bool,
+    stdout: &str,
+    stderr: String,
+) -> Result<Option<EncryptionProperties>, ZfsError> {
+    if !success {
+        return if is_dataset_not_found_error(&stderr) {
+            Ok(None)
+        } else {
+            Err(ZfsError::GetPropertyCallFailed(
+                dataset.to_string(),
+                ENCRYPTION_PROPERTIES.to_string(),
+                stderr,
+            ))
+        };
+    }
+
+    let values = stdout
+        .lines()
+        .map(split_output_line)
+        .filter(|v| v.len() >= 2)
+        .map(|v| (v[0], v[1]))
+        .collect::<BTreeMap<&str, &str>>();
+
+    let get = |property: &str| {
+        values.get(property).copied().ok_or_else(|| {
+            ZfsError::GetPropertyCallFailed(
+                dataset.to_string(),
+                property.to_string(),
+                "Property is missing from the output of zfs".to_string(),
+            )
+        })
+    };
+    let unexpected = |property: &str, value: &str| {
+        ZfsError::UnexpectedPropertyValue(
+            dataset.to_string(),
+            property.to_string(),
+            value.to_string(),
+        )
+    };
+
+    let encryption = match get("encryption")? {
+        "off" => EncryptionType::Off,
+        "aes-128-ccm" => EncryptionType::Aes128Ccm,
+        "aes-192-ccm" => EncryptionType::Aes192Ccm,
+        "aes-256-ccm" => EncryptionType::Aes256Ccm,
+        "aes-128-gcm" => EncryptionType::Aes128Gcm,
+        "aes-192-gcm" => EncryptionType::Aes192Gcm,
+        "aes-256-gcm" => EncryptionType::Aes256Gcm,
+        value => return Err(unexpected("encryption", value)),
+    };
+
+    if encryption == EncryptionType::Off {
+        return Ok(Some(EncryptionProperties {
+            encryption,
+            keyformat: None,
+            keylocation: None,
+            is_key_loaded: true,
+            pbkdf2iters: None,
+        }));
+    }
+
+    let keyformat = match get("keyformat")? {
+        "passphrase" => KeyFormat::Passphrase,
+        "raw" => KeyFormat::Raw,
+        "hex" => KeyFormat::Hex,
+        value => return Err(unexpected("keyformat", value)),
+    };
+
+    let keylocation = match get("keylocation")? {
+        "none" => None,
+        "prompt" => Some(KeyLocation::Prompt),
+        value => Some(KeyLocation::Uri(value.to_string())),
+    };
+
+    let is_key_loaded = parse_key_available_state(get("keystatus")?)?;
+
+    let pbkdf2iters = parse_pbkdf2iters(dataset, get("pbkdf2iters")?)?;
+
+    Ok(Some(EncryptionProperties {
+        encryption,
+        keyformat: Some(keyformat),
+        keylocation,
+        is_key_loaded,
+        pbkdf2iters,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialized_properties() {
+        let properties = EncryptionProperties {
+            encryption: EncryptionType::Aes256Gcm,
+            keyformat: Some(KeyFormat::Passphrase),
+            keylocation: Some(KeyLocation::Uri("file:///etc/zfs/key".to_string())),
+            is_key_loaded: false,
+            pbkdf2iters: Some(350000),
+        };
+        let json = serde_json::to_string(&properties).unwrap();
+        assert!(json.contains(r#""pbkdf2iters":350000"#));
+        assert_eq!(
+            serde_json::from_str::<EncryptionProperties>(&json).unwrap(),
+            properties
+        );
+
+        let status = EncryptionStatus::Encrypted {
+            key_loaded: true,
+            encryption_root: "pool/enc".to_string(),
+        };
+        let json = serde_json::to_string(&status).unwrap();
+        assert_eq!(
+            serde_json::from_str::<EncryptionStatus>(&json).unwrap(),
+            status
+        );
+    }
+
+    #[test]
+    fn encryption_status_result() {
+        let f = parse_encryption_status_result;
+        let ds = "pool/enc/child";
+
+        assert_eq!(
+            f(
+                ds,
+                true,
+                "aes-256-gcm\tunavailable\tpool/enc\n",
+                String::new()
+            )
+            .unwrap(),
+            EncryptionStatus::Encrypted {
+                key_loaded: false,
+                encryption_root: "pool/enc".to_string(),
+            }
+        );
+        assert_eq!(
+            f(
+                ds,
+                true,
+                "aes-256-gcm\tavailable\tpool/enc/child\n",
+                String::new()
+            )
+            .unwrap(),
+            EncryptionStatus::Encrypted {
+                key_loaded: true,
+                encryption_root: "pool/enc/child".to_string(),
+            }
+        );
+        assert_eq!(
+            f(ds, true, "off\t-\t-\n", String::new()).unwrap(),
+            EncryptionStatus::Unencrypted
+        );
+        assert_eq!(
+            f(
+                ds,
+                false,
+                "",
+                "cannot open 'pool/enc/child': dataset does not exist\n".to_string()
+            )
+            .unwrap(),
+            EncryptionStatus::NotFound
+        );
+
+        assert!(matches!(
+            f(ds, true, "aes-256-gcm\tavailable\t-\n", String::new()),
+            Err(ZfsError::UnexpectedPropertyValue(..))
+        ));
+        assert!(matches!(
+            f(ds, true, "aes-256-gcm\tmaybe\tpool/enc\n", String::new()),
+            Err(ZfsError::UnexpectedStateForKey(_))
+        ));
+        assert!(matches!(
+            f(ds, true, "", String::new()),
+            Err(ZfsError::GetPropertyCallFailed(..))
+        ));
+        assert!(matches!(
+            f(ds, false, "", "permission denied\n".to_string()),
+            Err(ZfsError::GetPropertyCallFailed(..))
+        ));
+    }
+
+    #[test]
+    fn requires_passphrase() {
+        let properties = EncryptionProperties {
+            encryption: EncryptionType::Aes256Gcm,
+            keyformat: Some(KeyFormat::Passphrase),
+            keylocation: Some(KeyLocation::Prompt),
+            is_key_loaded: false,
+            pbkdf2iters: Some(350000),
+        };
+        assert!(properties.requires_passphrase());
+        assert!(!EncryptionProperties {
+            keylocation: Some(KeyLocation::Uri("file:///keys/pool.key".to_string())),
+            ..properties.clone()
+        }
+        .requires_passphrase());
+        assert!(!EncryptionProperties {
+            keyformat: Some(KeyFormat::Hex),
+            ..properties.clone()
+        }
+        .requires_passphrase());
+        assert!(!EncryptionProperties {
+            keylocation: None,
+            ..properties
+        }
+        .requires_passphrase());
+    }
+
+    #[test]
+    fn encryption_properties_result() {
+        let f = parse_encryption_properties_result;
+        let ds = "pool/dataset";
+
+        let output = "encryption\taes-256-gcm\n\
+                      keyformat\tpassphrase\n\
+                      keylocation\tprompt\n\
+                      keystatus\tavailable\n\
+                      pbkdf2iters\t350000\n";
+        assert_eq!(
+            f(ds, true, output, String::new()).unwrap(),
+            Some(EncryptionProperties {
+                encryption: EncryptionType::Aes256Gcm,
+                keyformat: Some(KeyFormat::Passphrase),
+                keylocation: Some(KeyLocation::Prompt),
+                is_key_loaded: true,
+                pbkdf2iters: Some(350000),
+            })
+        );
+
+        let output = "encryption\taes-128-ccm\n\
+                      keyformat\traw\n\
+                      keylocation\tfile:///keys/my key\n\
+                      keystatus\tunavailable\n\
+                      pbkdf2iters\t0\n";
+        assert_eq!(
+            f(ds, true, output, String::new()).unwrap(),
+            Some(EncryptionProperties {
+                encryption: EncryptionType::Aes128Ccm,
+                keyformat: Some(KeyFormat::Raw),
+                keylocation: Some(KeyLocation::Uri("file:///keys/my key".to_string())),
+                is_key_loaded: false,
+                pbkdf2iters: None,
+            })
+        );
+
+        // A child that inherits the key of its encryption root
+        let output = "encryption\taes-256-gcm\n\
+                      keyformat\thex\n\
+                      keylocation\tnone\n\
+                      keystatus\tavailable\n\
+                      pbkdf2iters\t0\n";
+        assert_eq!(
+            f(ds, true, output, String::new())
+                .unwrap()
+                .unwrap()
+                .keylocation,
+            None
+        );
+
+        let output = "encryption\toff\n\
+                      keyformat\tnone\n\
+                      keylocation\tnone\n\
+                      keystatus\t-\n\
+                      pbkdf2iters\t0\n";
+        assert_eq!(
+            f(ds, true, output, String::new()).unwrap(),
+            Some(EncryptionProperties {
+                encryption: EncryptionType::Off,
+                keyformat: None,
+                keylocation: None,
+                is_key_loaded: true,
+                pbkdf2iters: None,
+            })
+        );
+
+        assert_eq!(
+            f(
+                ds,
+                false,
+                "",
+                "cannot open 'pool/dataset': dataset does not exist\n".to_string()
+            )
+            .unwrap(),
+            None
+        );
+
+        f(ds, false, "", "permission denied\n".to_string()).unwrap_err();
+        f(ds, true, "encryption\tchacha\n", String::new()).unwrap_err();
+        f(ds, true, "encryption\taes-256-gcm\n", String::new()).unwrap_err();
+        f(
+            ds,
+            true,
+            "encryption\taes-256-gcm\nkeyformat\tpassphrase\nkeylocation\tprompt\n\
+             keystatus\tavailable\npbkdf2iters\tmany\n",
+            String::new(),
+        )
+        .unwrap_err();
+    }
+}
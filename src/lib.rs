@@ -1,9 +1,37 @@
 use std::collections::BTreeMap;
-use std::io::BufWriter;
+use std::collections::BTreeSet;
+use std::io::BufRead;
+use std::io::BufReader;
 use std::io::Read;
 use std::io::Write;
+use std::os::fd::AsFd;
+use std::path::Path;
 use std::path::PathBuf;
+use std::process::Child;
+use std::process::ChildStdout;
 use std::process::Command;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+use std::time::Instant;
+
+mod config;
+mod encryption;
+mod passphrase;
+mod runner;
+mod watch;
+
+#[cfg(feature = "tokio")]
+pub mod asynchronous;
+
+pub use config::{MountedHook, SudoPrompt, ZfsConfig};
+pub use encryption::{
+    EncryptionProperties, EncryptionStatus, EncryptionType, KeyFormat, KeyLocation,
+};
+pub use passphrase::Passphrase;
+pub use runner::{CommandRunner, SshRunner};
+pub use watch::{DatasetStateChange, WatchHandle};
 
 #[derive(thiserror::Error, Debug)]
 pub enum ZfsError {
@@ -15,33 +43,477 @@ pub enum ZfsError {
     UnexpectedStateForKey(String),
     #[error("Command returned unexpected state for mount, other than 'yes' and 'no': {0}")]
     UnexpectedStateForMount(String),
-    #[error("Command to check whether dataset {0} is mounted failed: {1}")]
+    #[error("Command to check whether dataset {0} is mounted failed: {}", summarize_stderr(.1))]
     IsMountedCheckCallFailed(String, String),
-    #[error("Command to list datasets mount points failed: {0}")]
+    #[error("Command to get the state of dataset {0} failed: {}", summarize_stderr(.1))]
+    DatasetStateCallFailed(String, String),
+    #[error("Command to get the states of datasets failed: {}", summarize_stderr(.0))]
+    DatasetStatesCallFailed(String),
+    #[error("Command to list the properties of datasets failed: {}", summarize_stderr(.0))]
+    ListPropertiesCallFailed(String),
+    #[error("Command to list the sudo permissions failed: {}", summarize_stderr(.0))]
+    SudoListCallFailed(String),
+    #[error("Command to get the version of zfs failed: {}", summarize_stderr(.0))]
+    VersionCallFailed(String),
+    #[error("Command to list datasets mount points failed: {}", summarize_stderr(.0))]
     ListDatasetsMountPointsCallFailed(String),
-    #[error("Command to list unmounted datasets failed: {0}")]
+    #[error("Command to list unmounted datasets failed: {}", summarize_stderr(.0))]
     ListUnmountedDatasetsCallFailed(String),
-    #[error("Command to check whether key for dataset {0} is loaded failed: {1}")]
+    #[error("Command to list the key states of datasets failed: {}", summarize_stderr(.0))]
+    ListKeyStatesCallFailed(String),
+    #[error("Command to list descendants of dataset {0} failed: {}", summarize_stderr(.1))]
+    ListDescendantsCallFailed(String, String),
+    #[error("Command to list snapshots of dataset {0} failed: {}", summarize_stderr(.1))]
+    ListSnapshotsCallFailed(String, String),
+    #[error("Command to check whether dataset {0} exists failed: {}", summarize_stderr(.1))]
+    DatasetExistsCheckFailed(String, String),
+    #[error("Command to check whether key for dataset {0} is loaded failed: {}", summarize_stderr(.1))]
     KeyLoadedCheckFailed(String, String),
     #[error("Load key command for dataset {0} failed: {1}")]
-    LoadKeyCmdFailed(String, String),
+    LoadKeyCmdFailed(String, CommandFailure),
+    #[error("Load key command for all datasets failed: {0}")]
+    LoadKeyAllCmdFailed(CommandFailure),
+    #[error("A passphrase was provided for dataset {0}, but its keylocation is {1}, not prompt")]
+    PassphraseProvidedButKeylocationNotPrompt(String, String),
+    #[error("No passphrase was provided for dataset {0}")]
+    NoPassphraseProvided(String),
+    #[error("Incorrect passphrase or key for dataset {0}")]
+    IncorrectPassphrase(String),
+    #[error("Key file {0:?} cannot be used: {1}")]
+    KeyFileUnavailable(PathBuf, String),
+    #[error("Reading the key for dataset {0} failed: {1}")]
+    KeyReadFailed(String, String),
+    #[error("Snapshot name is invalid: {0}")]
+    SnapshotNameIsInvalid(String),
+    #[error("Send command for snapshot {0} failed: {1}")]
+    SendCmdFailed(String, CommandFailure),
+    #[error("Writing the stream of snapshot {0} failed: {1}")]
+    SendWriteFailed(String, String),
     #[error("Unload key command for dataset {0} failed: {1}")]
-    UnloadKeyCmdFailed(String, String),
+    UnloadKeyCmdFailed(String, CommandFailure),
+    #[error("Command to list the holds of snapshot {0} failed: {}", summarize_stderr(.1))]
+    ListHoldsCallFailed(String, String),
+    #[error("Key of dataset {0} is not unloaded, as these snapshots of it are held, e.g., by a send: {1:?}")]
+    SnapshotsHeld(String, Vec<String>),
+    #[error("Dataset {0} is mounted, so its key cannot be unloaded; unmount it first")]
+    CannotUnloadKeyWhileMounted(String),
+    #[error("Keys of dataset {0} or its descendants are still loaded after unload-key: {1:?}")]
+    KeysStillLoaded(String, BTreeSet<String>),
+    #[error("Change key command for dataset {0} failed: {1}")]
+    ChangeKeyCmdFailed(String, CommandFailure),
+    #[error("Key must be loaded before change-key for dataset {0}")]
+    KeyNotLoadedForChangeKey(String),
+    #[error("Create command for dataset {0} failed: {1}")]
+    CreateDatasetCmdFailed(String, CommandFailure),
+    #[error("Set command for dataset {0} failed: {1}")]
+    SetPropertyCmdFailed(String, CommandFailure),
     #[error("Key must be loaded before mount for dataset {0}")]
     KeyNotLoadedForMount(String),
     #[error("Mount command for dataset {0} failed: {1}")]
-    MountCmdFailed(String, String),
+    MountCmdFailed(String, CommandFailure),
+    #[error("Mount command for all datasets failed: {0}")]
+    MountAllCmdFailed(CommandFailure),
+    #[error(
+        "Mountpoint {1:?} of dataset {0} is already occupied by another filesystem or is not empty"
+    )]
+    MountpointOccupied(String, PathBuf),
+    #[error("Dataset {0} is mounted at {1:?} instead of its mountpoint {2:?}")]
+    MountedAtUnexpectedLocation(String, PathBuf, PathBuf),
+    #[error("Dataset {0} has canmount=off, so it cannot be mounted")]
+    CanmountOff(String),
+    #[error("Dataset {0} has a legacy mountpoint, so it must be mounted with the system mount command or fstab")]
+    LegacyMountpoint(String),
+    #[error("Dataset {0} is not unlocked and mounted, though the commands to unlock and mount it succeeded")]
+    VerificationFailed(String),
     #[error("Unmount command for dataset {0} failed: {1}")]
-    UnmountCmdFailed(String, String),
+    UnmountCmdFailed(String, CommandFailure),
+    #[error("No dataset has the mountpoint {0:?}")]
+    NoDatasetAtMountpoint(PathBuf),
+    #[error("Dataset {0} is busy and cannot be unmounted")]
+    DatasetBusy(String),
+    #[error("sudo requires a password to run zfs for dataset {0}; the command must be authorized with visudo")]
+    SudoNonInteractiveDenied(String),
+    #[error("Command for dataset {0} did not complete within {1:?}")]
+    CommandTimedOut(String, Duration),
+    #[error("Command for dataset {0} was cancelled")]
+    Cancelled(String),
     #[error("Dataset name is invalid: {0}")]
     DatasetNameIsInvalid(String),
+    #[error("zfs binary {0:?} not found, check that zfs is installed and in PATH")]
+    ZfsBinaryNotFound(PathBuf),
+    #[error("sudo binary {0:?} not found, check that sudo is installed, or disable use_sudo if running as root")]
+    SudoBinaryNotFound(PathBuf),
+    #[error("Pool name is invalid: {0}")]
+    PoolNameIsInvalid(String),
+    #[error("Pool {0} not found")]
+    PoolNotFound(String),
+    #[error("Command to check whether pool {0} is imported failed: {}", summarize_stderr(.1))]
+    PoolImportedCheckFailed(String, String),
+    #[error("Command to get the health of pool {0} failed: {}", summarize_stderr(.1))]
+    PoolHealthCheckFailed(String, String),
+    #[error("Import command for pool {0} failed: {1}")]
+    ZpoolImportCmdFailed(String, CommandFailure),
+    #[error("Export command for pool {0} failed: {1}")]
+    ZpoolExportCmdFailed(String, CommandFailure),
+    #[error("Mountpoint is invalid: {0:?}")]
+    MountpointIsInvalid(PathBuf),
+    #[error("Mount option is invalid: {0}")]
+    MountOptionIsInvalid(String),
+    #[error(
+        "Key location is invalid, it must be prompt or a file://, https:// or http:// URI: {0}"
+    )]
+    KeyLocationIsInvalid(String),
+    #[error("Dataset property is invalid: {0}")]
+    DatasetPropertyIsInvalid(String),
+    #[error("Command to get property {1} of dataset {0} failed: {}", summarize_stderr(.2))]
+    GetPropertyCallFailed(String, String, String),
+    #[error("Dataset {0} has an unexpected value for property {1}: {2}")]
+    UnexpectedPropertyValue(String, String, String),
+    #[error("Passphrase for dataset {0} is empty")]
+    EmptyPassphrase(String),
+    #[error("Dataset {0} has an unsupported keyformat: {1}")]
+    UnexpectedKeyFormat(String, String),
+    #[error("Raw key for dataset {0} must be exactly 32 bytes, but {1} bytes were provided")]
+    InvalidRawKeyLength(String, usize),
+    #[error("Hex key for dataset {0} must be exactly 64 hexadecimal characters")]
+    InvalidHexKey(String),
+}
+
+impl ZfsError {
+    /// The full stderr of the zfs command that failed, for the errors that have it
+    /// Their messages only have a summary of it, see summarize_stderr.
+    pub fn stderr(&self) -> Option<&str> {
+        match self {
+            ZfsError::IsMountedCheckCallFailed(_, stderr)
+            | ZfsError::DatasetStateCallFailed(_, stderr)
+            | ZfsError::DatasetStatesCallFailed(stderr)
+            | ZfsError::ListPropertiesCallFailed(stderr)
+            | ZfsError::SudoListCallFailed(stderr)
+            | ZfsError::VersionCallFailed(stderr)
+            | ZfsError::ListDatasetsMountPointsCallFailed(stderr)
+            | ZfsError::ListUnmountedDatasetsCallFailed(stderr)
+            | ZfsError::ListKeyStatesCallFailed(stderr)
+            | ZfsError::ListDescendantsCallFailed(_, stderr)
+            | ZfsError::ListSnapshotsCallFailed(_, stderr)
+            | ZfsError::ListHoldsCallFailed(_, stderr)
+            | ZfsError::DatasetExistsCheckFailed(_, stderr)
+            | ZfsError::KeyLoadedCheckFailed(_, stderr)
+            | ZfsError::PoolImportedCheckFailed(_, stderr)
+            | ZfsError::PoolHealthCheckFailed(_, stderr)
+            | ZfsError::GetPropertyCallFailed(_, _, stderr) => Some(stderr),
+            ZfsError::LoadKeyCmdFailed(_, failure)
+            | ZfsError::LoadKeyAllCmdFailed(failure)
+            | ZfsError::UnloadKeyCmdFailed(_, failure)
+            | ZfsError::ChangeKeyCmdFailed(_, failure)
+            | ZfsError::CreateDatasetCmdFailed(_, failure)
+            | ZfsError::SetPropertyCmdFailed(_, failure)
+            | ZfsError::MountCmdFailed(_, failure)
+            | ZfsError::MountAllCmdFailed(failure)
+            | ZfsError::UnmountCmdFailed(_, failure)
+            | ZfsError::ZpoolImportCmdFailed(_, failure)
+            | ZfsError::ZpoolExportCmdFailed(_, failure)
+            | ZfsError::SendCmdFailed(_, failure) => Some(&failure.stderr),
+            _ => None,
+        }
+    }
+
+    /// The message of the error with URLs, like file:// and https:// keylocations, and anything that looks like
+    /// a hex key replaced by `<redacted>`, for logs that shouldn't have them
+    /// The message is otherwise the same as Display. The full stderr is still available from stderr().
+    pub fn redacted_display(&self) -> String {
+        redact_secrets(&self.to_string())
+    }
+}
+
+/// What redact_secrets replaces secrets with
+const REDACTED: &str = "<redacted>";
+
+/// The shortest run of hexadecimal characters that's taken as a key, which is the length of a 128 bit key
+const MIN_REDACTED_HEX_LEN: usize = 32;
+
+/// Replaces the URLs and hex keys in the text, keeping everything else, including whitespace and quotes
+/// around them, as is
+fn redact_secrets(text: &str) -> String {
+    let is_separator =
+        |c: char| c.is_whitespace() || matches!(c, '\'' | '"' | '(' | ')' | ',' | ';');
+
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while !rest.is_empty() {
+        let token_len = rest.find(is_separator).unwrap_or(rest.len());
+        let (token, after) = rest.split_at(token_len);
+
+        let url_start = token.find("://").map(|index| {
+            token[..index]
+                .rfind(|c: char| !c.is_ascii_alphanumeric())
+                .map_or(0, |i| i + 1)
+        });
+        let hex = token.trim_end_matches(['.', ':']);
+        match url_start {
+            Some(start) => {
+                result.push_str(&token[..start]);
+                result.push_str(REDACTED);
+            }
+            None if hex.len() >= MIN_REDACTED_HEX_LEN
+                && hex.chars().all(|c| c.is_ascii_hexdigit()) =>
+            {
+                result.push_str(REDACTED);
+                result.push_str(&token[hex.len()..]);
+            }
+            None => result.push_str(token),
+        }
+
+        let separator_len = after
+            .find(|c: char| !is_separator(c))
+            .unwrap_or(after.len());
+        result.push_str(&after[..separator_len]);
+        rest = &after[separator_len..];
+    }
+    result
+}
+
+/// The longest summary of stderr that's put in the message of an error, in characters
+const MAX_STDERR_SUMMARY_LEN: usize = 200;
+
+/// Summarizes the stderr of a command in a single line for the message of an error
+/// zfs puts the most relevant line first, e.g., "cannot mount 'pool/dataset': ...", so that's the one kept,
+/// with a mark if anything is left out. The full text is still available from ZfsError::stderr.
+fn summarize_stderr(stderr: &str) -> String {
+    let mut lines = stderr
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty());
+    let first_line = lines.next().unwrap_or_default();
+
+    let mut summary = first_line
+        .chars()
+        .take(MAX_STDERR_SUMMARY_LEN)
+        .collect::<String>();
+    if summary.len() < first_line.len() || lines.next().is_some() {
+        summary.push_str(" [...]");
+    }
+    summary
+}
+
+/// How a zfs command that changes the state of a dataset failed
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CommandFailure {
+    /// The exit code of the command, None if it couldn't be started or was killed by a signal
+    pub code: Option<i32>,
+    /// The stderr of the command, or the reason it couldn't be started
+    pub stderr: String,
+}
+
+impl CommandFailure {
+    fn spawn_failed(error: String) -> Self {
+        Self {
+            code: None,
+            stderr: error,
+        }
+    }
+}
+
+impl std::fmt::Display for CommandFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let stderr = summarize_stderr(&self.stderr);
+        match self.code {
+            Some(code) => write!(f, "{stderr} (exit code {code})"),
+            None => write!(f, "{stderr}"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DatasetMountedState {
     pub dataset_name: String,
     pub is_mounted: bool,
     pub is_key_loaded: bool,
+    /// None if the mountpoint is `none` or `legacy`, i.e., zfs doesn't mount the dataset itself
+    pub mountpoint: Option<PathBuf>,
+    /// Whether the key of the dataset is its own, instead of inherited from its encryption root.
+    /// Always false for unencrypted datasets.
+    pub is_encryption_root: bool,
+}
+
+/// A state of an encrypted dataset that zfs shouldn't allow, found by zfs_find_inconsistent_datasets
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Inconsistency {
+    /// The dataset is mounted, but its key isn't loaded, so its files can't be read
+    MountedWithoutKey { dataset: String },
+    /// The key of the dataset is loaded and the one of its encryption root isn't, or the other way around,
+    /// though the dataset uses the key of its encryption root
+    KeyStatusDiffersFromRoot {
+        dataset: String,
+        encryption_root: String,
+        is_key_loaded: bool,
+        is_root_key_loaded: bool,
+    },
+    /// The encryption root of the dataset isn't listed by zfs, or isn't encrypted
+    InvalidEncryptionRoot {
+        dataset: String,
+        encryption_root: String,
+    },
+}
+
+/// Formats the states of datasets, like the ones of zfs_list_encrypted_datasets, as a table for the command line,
+/// with a header and a line per dataset, and columns separated by two spaces like zfs list prints them
+/// The names are padded to the longest one, so the other columns are aligned. The table is returned, not printed.
+pub fn format_datasets_table(datasets: &BTreeMap<String, DatasetMountedState>) -> String {
+    const HEADER: [&str; 3] = ["NAME", "MOUNTED", "KEY LOADED"];
+    let yes_no = |value: bool| if value { "yes" } else { "no" };
+
+    let name_width = datasets
+        .keys()
+        .map(|name| name.chars().count())
+        .chain(std::iter::once(HEADER[0].len()))
+        .max()
+        .unwrap_or_default();
+    let row = |name: &str, mounted: &str, key_loaded: &str| {
+        format!(
+            "{name:<name_width$}  {mounted:<mounted_width$}  {key_loaded}\n",
+            mounted_width = HEADER[1].len()
+        )
+    };
+
+    std::iter::once(row(HEADER[0], HEADER[1], HEADER[2]))
+        .chain(
+            datasets.iter().map(|(name, state)| {
+                row(name, yes_no(state.is_mounted), yes_no(state.is_key_loaded))
+            }),
+        )
+        .collect()
+}
+
+/// The columns of `zfs list` that DatasetMountedState is parsed from: the dataset name, whether it's mounted,
+/// whether its key is available, its encryption root and its mountpoint, which is last as it may have spaces
+const DATASET_STATE_COLUMNS: &str = "name,mounted,keystatus,encryptionroot,mountpoint";
+
+/// Returns whether the encryptionroot column of a dataset is the dataset itself
+fn is_own_encryption_root(dataset: &str, encryption_root: Option<&str>) -> bool {
+    encryption_root.is_some_and(|root| root.trim() == dataset)
+}
+
+/// The mountpoint property of a dataset
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Mountpoint {
+    /// zfs mounts the dataset at this path
+    Path(PathBuf),
+    /// The dataset is mounted by the system, with the mount command or fstab, not by zfs
+    Legacy,
+    /// The dataset isn't mounted anywhere, including volumes, which can't be mounted
+    None,
+}
+
+impl Mountpoint {
+    /// The path that zfs mounts the dataset at, if any
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Mountpoint::Path(path) => Some(path),
+            Mountpoint::Legacy | Mountpoint::None => None,
+        }
+    }
+}
+
+/// The canmount property of a dataset, i.e., whether it can be mounted with zfs mount, and by `zfs mount -a`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CanMount {
+    /// The dataset is mounted by zfs mount, and by `zfs mount -a`, e.g., at boot
+    On,
+    /// The dataset can't be mounted, e.g., a parent that only holds properties for its children
+    Off,
+    /// The dataset is only mounted explicitly, by zfs mount, not by `zfs mount -a`
+    NoAuto,
+}
+
+impl CanMount {
+    /// The value of the canmount property, as zfs shows and accepts it
+    fn zfs_value(self) -> &'static str {
+        match self {
+            CanMount::On => "on",
+            CanMount::Off => "off",
+            CanMount::NoAuto => "noauto",
+        }
+    }
+}
+
+/// Gets the canmount property of a dataset
+/// Returns: Err(ZfsError::DatasetNotFound) if the dataset is not found
+/// Otherwise, an error is returned
+pub fn zfs_get_canmount(zfs_dataset: impl AsRef<str>) -> Result<CanMount, ZfsError> {
+    ZfsConfig::default().get_canmount(zfs_dataset)
+}
+
+/// Sets the canmount property of a dataset, like zfs_set_property
+/// The command `zfs set canmount=<value> <dataset-name>` should be authorized with visudo.
+pub fn zfs_set_canmount(zfs_dataset: impl AsRef<str>, canmount: CanMount) -> Result<(), ZfsError> {
+    ZfsConfig::default().set_canmount(zfs_dataset, canmount)
+}
+
+/// Parses the canmount property, where `-` is what zfs shows for volumes, which can't be mounted
+fn parse_canmount_property(dataset: &str, canmount: &str) -> Result<CanMount, ZfsError> {
+    match canmount.trim() {
+        "on" => Ok(CanMount::On),
+        "off" | "-" => Ok(CanMount::Off),
+        "noauto" => Ok(CanMount::NoAuto),
+        value => Err(ZfsError::UnexpectedPropertyValue(
+            dataset.to_string(),
+            "canmount".to_string(),
+            value.to_string(),
+        )),
+    }
+}
+
+/// Parses the mountpoint property, where `-` is what zfs shows for volumes
+fn parse_mountpoint_property(mountpoint: &str) -> Mountpoint {
+    match mountpoint.trim_end_matches('\n') {
+        "legacy" => Mountpoint::Legacy,
+        "" | "none" | "-" => Mountpoint::None,
+        mountpoint => Mountpoint::Path(PathBuf::from(mountpoint)),
+    }
+}
+
+/// The mount table of the system, with the filesystems that are mounted and where
+const MOUNT_TABLE_PATH: &str = "/proc/self/mounts";
+
+/// Parses a mount table like /proc/self/mounts, returning where the zfs dataset is mounted
+/// Spaces, tabs, new lines and backslashes in paths are escaped in octal, e.g., `\040` for a space.
+fn parse_mount_table_targets(mount_table: &str, dataset: &str) -> Vec<PathBuf> {
+    mount_table
+        .lines()
+        .map(|line| line.split(' ').collect::<Vec<_>>())
+        .filter(|v| v.len() >= 3 && v[2] == "zfs" && unescape_mount_table_field(v[0]) == dataset)
+        .map(|v| PathBuf::from(unescape_mount_table_field(v[1])))
+        .collect()
+}
+
+fn unescape_mount_table_field(field: &str) -> String {
+    let mut result = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(index) = rest.find('\\') {
+        result.push_str(&rest[..index]);
+        let escaped = rest.get(index + 1..index + 4);
+        match escaped.and_then(|code| u8::from_str_radix(code, 8).ok()) {
+            Some(byte) => {
+                result.push(char::from(byte));
+                rest = &rest[index + 4..];
+            }
+            None => {
+                result.push('\\');
+                rest = &rest[index + 1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Parses the mountpoint property, where `none`, `legacy` and `-` (for volumes) mean there's no mountpoint
+fn parse_mountpoint(mountpoint: &str) -> Option<PathBuf> {
+    match parse_mountpoint_property(mountpoint) {
+        Mountpoint::Path(path) => Some(path),
+        Mountpoint::Legacy | Mountpoint::None => None,
+    }
 }
 
 fn parse_key_available_state(state: impl AsRef<str>) -> Result<bool, ZfsError> {
@@ -62,671 +534,4662 @@ fn parse_dataset_mounted_state(state: impl AsRef<str>) -> Result<bool, ZfsError>
     }
 }
 
+/// The datasets that are being changed by an operation of this process
+static BUSY_DATASETS: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+/// Notified whenever a dataset is removed from BUSY_DATASETS
+static DATASET_UNLOCKED: Condvar = Condvar::new();
+
+/// Keeps other threads of this process from changing a dataset until it's dropped
+struct DatasetLock {
+    dataset: String,
+}
+
+impl Drop for DatasetLock {
+    fn drop(&mut self) {
+        BUSY_DATASETS
+            .lock()
+            .expect("Dataset locks poisoned")
+            .remove(&self.dataset);
+        DATASET_UNLOCKED.notify_all();
+    }
+}
+
+/// Waits until no other thread of this process is changing the dataset, then locks it
+/// This serializes the checks of the state of a dataset with the command that changes it, so that
+/// two threads don't both find a key unloaded and both run load-key. Datasets are locked by name only,
+/// so a dataset and its descendants can still be changed at the same time, e.g., with load-key -r.
+/// Other processes, including other instances of this crate, aren't affected by this lock.
+fn lock_dataset(dataset: &str) -> DatasetLock {
+    let mut locked = BUSY_DATASETS.lock().expect("Dataset locks poisoned");
+    while locked.contains(dataset) {
+        locked = DATASET_UNLOCKED
+            .wait(locked)
+            .expect("Dataset locks poisoned");
+    }
+    locked.insert(dataset.to_string());
+
+    DatasetLock {
+        dataset: dataset.to_string(),
+    }
+}
+
+/// The symbols that are allowed in the parts of dataset names, besides alphanumerics
+const ALLOWED_SYMBOLS: [char; 4] = ['-', '_', '.', ':'];
+
+/// The longest dataset name that zfs accepts, in bytes (ZFS_MAX_DATASET_NAME_LEN, minus the terminating null)
+const MAX_DATASET_NAME_LEN: usize = 255;
+
+/// The number of slashes at which zfs rejects a dataset name for being nested too deeply,
+/// which is the default of the zfs_max_dataset_nesting module parameter
+const MAX_DATASET_NESTING: usize = 50;
+
+/// Returns the dataset name in the form the functions of this crate pass it to zfs, trimmed of whitespace
+/// The names returned by the list functions come straight from zfs, so they're already in this form,
+/// and names given by users should be normalized to be looked up in the maps returned by those functions.
+/// Returns: Err(ZfsError::DatasetNameIsInvalid) if the name isn't valid
+pub fn normalize_dataset_name(zfs_dataset: impl AsRef<str>) -> Result<String, ZfsError> {
+    check_and_sanitize_zfs_dataset_name(zfs_dataset)
+}
+
 /// Note that the sanitization's purpose is not to perfectly mimic ZFS specs.
 /// The purpose is to prevent any kind of possible injection of commands.
 fn check_and_sanitize_zfs_dataset_name(zfs_dataset: impl AsRef<str>) -> Result<String, ZfsError> {
-    const ALLOWED_SYMBOLS: [char; 4] = ['-', '_', '.', ':'];
-
     let dataset = zfs_dataset.as_ref().trim();
 
+    // Every part is shorter than the whole name, so the limit of the whole name covers the parts too
+    if dataset.len() > MAX_DATASET_NAME_LEN {
+        return Err(ZfsError::DatasetNameIsInvalid(format!(
+            "{dataset} (longer than {MAX_DATASET_NAME_LEN} bytes)"
+        )));
+    }
+    if dataset.matches('/').count() >= MAX_DATASET_NESTING {
+        return Err(ZfsError::DatasetNameIsInvalid(format!(
+            "{dataset} (nested deeper than {MAX_DATASET_NESTING} levels)"
+        )));
+    }
+
     let check_func = |part: &str| {
         part.chars()
             .all(|c| c.is_ascii_alphanumeric() || ALLOWED_SYMBOLS.contains(&c))
             && part.chars().all(|c| !c.is_whitespace())
             && !part.is_empty()
-            && !part.starts_with(&ALLOWED_SYMBOLS) // Can only begin with an alphanumeric
+            && !part.starts_with(ALLOWED_SYMBOLS) // Can only begin with an alphanumeric
     };
 
-    // Check the whole name, then the individual parts
-    check_func(dataset);
+    // The whole name can additionally have slashes between its parts, and begins with the pool name,
+    // which has to begin with a letter
+    let check_whole_func = |name: &str| {
+        name.chars()
+            .all(|c| c.is_ascii_alphanumeric() || ALLOWED_SYMBOLS.contains(&c) || c == '/')
+            && name.starts_with(|c: char| c.is_ascii_alphabetic())
+            && !name.ends_with('/')
+    };
 
-    if !dataset.split('/').all(|part| check_func(part)) {
+    // Check the whole name, then the individual parts
+    if !check_whole_func(dataset) || !dataset.split('/').all(check_func) {
         Err(ZfsError::DatasetNameIsInvalid(dataset.to_string()))
     } else {
         Ok(dataset.to_string())
     }
 }
 
-/// Attempts to load-key for ZFS dataset
-/// Returns: Ok(()) if the key is successfully loaded OR already loaded
-/// Returns: Error if dataset not found or some other system error occurred.
-/// The command `zfs load-key <dataset-name>` should be authorized with visudo.
-pub fn zfs_load_key(
-    zfs_dataset: impl AsRef<str>,
-    passphrase: impl AsRef<str>,
-) -> Result<(), ZfsError> {
-    let passphrase = passphrase.as_ref();
-    let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
-
-    match zfs_is_key_loaded(&dataset)? {
-        Some(loaded) => match loaded {
-            true => return Ok(()),
-            false => (),
-        },
-        None => return Err(ZfsError::DatasetNotFound(dataset.to_string())),
-    }
-
-    // Create a command to run zfs load-key
-    let mut child = Command::new("sudo")
-        .arg("-n") // sudo isn't interactive
-        .arg("zfs")
-        .arg("load-key")
-        .arg(&dataset)
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()
-        .map_err(|e| ZfsError::LoadKeyCmdFailed(dataset.to_string(), e.to_string()))?;
-
-    // Get the stdin of the zfs command
-    if let Some(mut stdin) = child.stdin.take() {
-        // Write the key to stdin
-        let mut writer = BufWriter::new(&mut stdin);
-        writeln!(writer, "{}", passphrase).map_err(|e| ZfsError::SystemError(e.to_string()))?;
-        writer
-            .flush()
-            .map_err(|e| ZfsError::SystemError(e.to_string()))?;
-    }
-
-    // Capture the stdout handle of the child process
-    let mut stdout = child.stdout.take().expect("Failed to capture stdout");
-    let mut stderr = child.stderr.take().expect("Failed to capture stderr");
+/// Checks a snapshot name, which is a dataset name followed by exactly one `@` and the name of the snapshot
+/// The name of the snapshot is checked like a part of a dataset name. Like dataset names, the purpose is to
+/// prevent injection, so bookmarks (`#`) and other characters that zfs may accept are rejected.
+fn check_and_sanitize_zfs_snapshot_name(snapshot: impl AsRef<str>) -> Result<String, ZfsError> {
+    let snapshot = snapshot.as_ref().trim();
+    let invalid = || ZfsError::SnapshotNameIsInvalid(snapshot.to_string());
 
-    // Read stdout/stderr to a string
-    let mut stdout_string = String::new();
-    stdout
-        .read_to_string(&mut stdout_string)
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
-    let mut stderr_string = String::new();
-    stderr
-        .read_to_string(&mut stderr_string)
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
+    let (dataset, name) = snapshot.split_once('@').ok_or_else(invalid)?;
+    check_and_sanitize_zfs_dataset_name(dataset).map_err(|_| invalid())?;
 
-    // Wait for the zfs command to complete
-    let status = child
-        .wait()
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
+    let is_valid_name = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || ALLOWED_SYMBOLS.contains(&c))
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric());
 
-    // Check if the command was successful
-    if status.success() {
-        Ok(())
+    if is_valid_name && snapshot.len() <= MAX_DATASET_NAME_LEN {
+        Ok(snapshot.to_string())
     } else {
-        Err(ZfsError::LoadKeyCmdFailed(
-            dataset.to_string(),
-            stderr_string,
-        ))
+        Err(invalid())
     }
 }
 
-/// Attempts to load-key for ZFS dataset
-/// Returns: Ok(()) if the key is successfully unloaded OR already unloaded
-/// Returns: Error if dataset not found or some other system error occurred.
-/// The command `zfs unload-key <dataset-name>` should be authorized with visudo.
-pub fn zfs_unload_key(zfs_dataset: impl AsRef<str>) -> Result<(), ZfsError> {
-    let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+/// Checks a pool name, which is a dataset name without any slashes
+fn check_and_sanitize_zpool_name(pool_name: impl AsRef<str>) -> Result<String, ZfsError> {
+    let pool = pool_name.as_ref().trim();
 
-    match zfs_is_key_loaded(&dataset)? {
-        Some(loaded) => match loaded {
-            true => (),
-            false => return Ok(()),
-        },
-        None => return Err(ZfsError::DatasetNotFound(dataset.to_string())),
+    if pool.contains('/') {
+        return Err(ZfsError::PoolNameIsInvalid(pool.to_string()));
     }
 
-    // Create a command to run zfs load-key
-    let mut child = Command::new("sudo")
-        .arg("-n") // sudo isn't interactive
-        .arg("zfs")
-        .arg("unload-key")
-        .arg(&dataset)
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()
-        .map_err(|e| ZfsError::UnloadKeyCmdFailed(dataset.to_string(), e.to_string()))?;
-
-    // Capture the stdout handle of the child process
-    let mut stdout = child.stdout.take().expect("Failed to capture stdout");
-    let mut stderr = child.stderr.take().expect("Failed to capture stderr");
+    check_and_sanitize_zfs_dataset_name(pool)
+        .map_err(|_| ZfsError::PoolNameIsInvalid(pool.to_string()))
+}
 
-    // Read stdout/stderr to a string
-    let mut stdout_string = String::new();
-    stdout
-        .read_to_string(&mut stdout_string)
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
-    let mut stderr_string = String::new();
-    stderr
-        .read_to_string(&mut stderr_string)
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
+/// Checks a temporary mountpoint, which must be an absolute path other than `/`, whose parts are checked like the parts
+/// of dataset names, so that it can't end the `mountpoint=` option it's passed in, e.g., with a comma
+/// Returns: The `mountpoint=<path>` option to pass to zfs mount
+fn check_and_sanitize_mountpoint(path: &Path) -> Result<String, ZfsError> {
+    let invalid = || ZfsError::MountpointIsInvalid(path.to_path_buf());
+    let path_str = path.to_str().ok_or_else(invalid)?;
 
-    // Wait for the zfs command to complete
-    let status = child
-        .wait()
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
+    let mut parts = path_str.split('/');
+    let is_valid = parts.next() == Some("")
+        && path_str.len() > 1
+        && parts.all(|part| {
+            part.starts_with(|c: char| c.is_ascii_alphanumeric())
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || ALLOWED_SYMBOLS.contains(&c))
+        });
 
-    // Check if the command was successful
-    if status.success() {
-        Ok(())
+    if is_valid {
+        Ok(format!("mountpoint={path_str}"))
     } else {
-        Err(ZfsError::UnloadKeyCmdFailed(
-            dataset.to_string(),
-            stderr_string,
-        ))
+        Err(invalid())
     }
 }
 
-/// Mounts a ZFS dataset
-/// Returns Ok(()) if successfully mounted or already mounted
-/// Returns Err otherwise
-/// The command `zfs mount <dataset-name>` should be authorized with visudo.
-pub fn zfs_mount_dataset(zfs_dataset: impl AsRef<str>) -> Result<(), ZfsError> {
-    let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+/// Checks a keylocation given to `zfs load-key -L`, which must be `prompt` or a URI of a scheme that zfs reads keys
+/// from, i.e., a file:// URI with an absolute path, or an https:// or http:// URL with a host
+/// URLs can have the characters of dataset names, '/', and the ones of query strings, but no spaces or quotes.
+fn check_and_sanitize_key_location(location: impl AsRef<str>) -> Result<String, ZfsError> {
+    const SCHEMES: [&str; 3] = ["file://", "https://", "http://"];
+    const URL_SYMBOLS: [char; 7] = ['?', '=', '&', '%', '~', '+', '@'];
 
-    match zfs_is_key_loaded(&dataset)? {
-        Some(loaded) => match loaded {
-            true => (),
-            false => return Err(ZfsError::KeyNotLoadedForMount(dataset.to_string())),
-        },
-        None => return Err(ZfsError::DatasetNotFound(dataset.to_string())),
-    }
+    let location = location.as_ref().trim();
+    let is_valid = location == "prompt"
+        || SCHEMES.iter().any(|scheme| {
+            location.strip_prefix(scheme).is_some_and(|rest| {
+                // file:// URIs are followed by an absolute path, the others by a host
+                (*scheme == "file://") == rest.starts_with('/')
+                    && rest.len() > 1
+                    && rest.chars().all(|c| {
+                        c.is_ascii_alphanumeric()
+                            || ALLOWED_SYMBOLS.contains(&c)
+                            || URL_SYMBOLS.contains(&c)
+                            || c == '/'
+                    })
+            })
+        });
 
-    match zfs_is_dataset_mounted(&dataset)? {
-        Some(mounted) => match mounted {
-            true => return Ok(()),
-            false => (),
-        },
-        None => return Err(ZfsError::DatasetNotFound(dataset.to_string())),
+    if is_valid {
+        Ok(location.to_string())
+    } else {
+        Err(ZfsError::KeyLocationIsInvalid(location.to_string()))
     }
+}
 
-    // Create a command to run zfs load-key
-    let mut child = Command::new("sudo")
-        .arg("-n") // sudo isn't interactive
-        .arg("zfs")
-        .arg("mount")
-        .arg(&dataset)
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()
-        .map_err(|e| ZfsError::MountCmdFailed(dataset.to_string(), e.to_string()))?;
-
-    // Capture the stdout handle of the child process
-    let mut stdout = child.stdout.take().expect("Failed to capture stdout");
-    let mut stderr = child.stderr.take().expect("Failed to capture stderr");
-
-    // Read stdout/stderr to a string
-    let mut stdout_string = String::new();
-    stdout
-        .read_to_string(&mut stdout_string)
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
-    let mut stderr_string = String::new();
-    stderr
-        .read_to_string(&mut stderr_string)
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
+/// Checks a mount option, like `ro` or `context=system_u:object_r:fs_t:s0`, the same way dataset names are checked
+/// Options can additionally have '=' and ',', but can't begin with a symbol, so they can't be taken as flags.
+fn check_and_sanitize_mount_option(option: impl AsRef<str>) -> Result<String, ZfsError> {
+    let option = option.as_ref().trim();
 
-    // Wait for the zfs command to complete
-    let status = child
-        .wait()
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
+    let is_valid = option
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || ALLOWED_SYMBOLS.contains(&c) || c == '=' || c == ',')
+        && option.starts_with(|c: char| c.is_ascii_alphanumeric());
 
-    // Check if the command was successful
-    if status.success() {
-        Ok(())
+    if is_valid {
+        Ok(option.to_string())
     } else {
-        Err(ZfsError::MountCmdFailed(dataset.to_string(), stderr_string))
+        Err(ZfsError::MountOptionIsInvalid(option.to_string()))
     }
 }
 
-/// Unmounts a ZFS dataset
-/// Returns: Ok(()) on success or if is already mounted
-/// Returns: Err otherwise.
-/// The command `zfs unmount <dataset-name>` should be authorized with visudo.
-pub fn zfs_unmount_dataset(zfs_dataset: impl AsRef<str>) -> Result<(), ZfsError> {
-    let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
+/// Checks a property of a dataset, like `compression=lz4`, and returns it as `name=value`
+/// Names are checked like mount options. Values may also have '/', for paths, but nothing else beyond that.
+fn check_and_sanitize_property(
+    name: impl AsRef<str>,
+    value: impl AsRef<str>,
+) -> Result<String, ZfsError> {
+    let name = name.as_ref().trim();
+    let value = value.as_ref().trim();
+    let property = format!("{name}={value}");
 
-    match zfs_is_dataset_mounted(&dataset)? {
-        Some(mounted) => match mounted {
-            true => (),
-            false => return Ok(()),
-        },
-        None => return Err(ZfsError::DatasetNotFound(dataset.to_string())),
+    if is_valid_property_name(name) && is_valid_property_value(value) {
+        Ok(property)
+    } else {
+        Err(ZfsError::DatasetPropertyIsInvalid(property))
     }
+}
 
-    // Create a command to run zfs load-key
-    let mut child = Command::new("sudo")
-        .arg("-n") // sudo isn't interactive
-        .arg("zfs")
-        .arg("umount")
-        .arg(&dataset)
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()
-        .map_err(|e| ZfsError::UnmountCmdFailed(dataset.to_string(), e.to_string()))?;
-
-    // Capture the stdout handle of the child process
-    let mut stdout = child.stdout.take().expect("Failed to capture stdout");
-    let mut stderr = child.stderr.take().expect("Failed to capture stderr");
+/// Checks a property of a dataset to be created, like check_and_sanitize_property
+/// The properties that zfs_create_encrypted_dataset sets itself can't be given here.
+fn check_and_sanitize_dataset_property(
+    name: impl AsRef<str>,
+    value: impl AsRef<str>,
+) -> Result<String, ZfsError> {
+    const RESERVED_PROPERTIES: [&str; 4] = ["encryption", "keyformat", "keylocation", "mountpoint"];
 
-    // Read stdout/stderr to a string
-    let mut stdout_string = String::new();
-    stdout
-        .read_to_string(&mut stdout_string)
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
-    let mut stderr_string = String::new();
-    stderr
-        .read_to_string(&mut stderr_string)
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
+    let property = check_and_sanitize_property(&name, &value)?;
+    if RESERVED_PROPERTIES.contains(&name.as_ref().trim()) {
+        Err(ZfsError::DatasetPropertyIsInvalid(property))
+    } else {
+        Ok(property)
+    }
+}
 
-    // Wait for the zfs command to complete
-    let status = child
-        .wait()
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
+/// Checks the name of a property to be read, e.g., the columns of zfs_list_properties
+fn check_and_sanitize_property_name(name: impl AsRef<str>) -> Result<String, ZfsError> {
+    let name = name.as_ref().trim();
 
-    // Check if the command was successful
-    if status.success() {
-        Ok(())
+    if is_valid_property_name(name) {
+        Ok(name.to_string())
     } else {
-        Err(ZfsError::UnmountCmdFailed(
-            dataset.to_string(),
-            stderr_string,
-        ))
+        Err(ZfsError::DatasetPropertyIsInvalid(name.to_string()))
     }
 }
 
-/// Checks whether key is loaded
-/// Returns: Some(true): Key is available/loaded and/or doesn't need it
-/// Returns: Some(false): Key is not loaded
-/// Returns: None: The dataset is not found
-/// Otherwise, an error is returned
-pub fn zfs_is_key_loaded(zfs_dataset: impl AsRef<str>) -> Result<Option<bool>, ZfsError> {
-    let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
-
-    // Create a command to run zfs load-key
-    let mut child = Command::new("zfs")
-        .arg("get")
-        .arg("keystatus")
-        .arg("-H") // No table header
-        .arg("-o")
-        .arg("name,value") // Only show two columns, dataset name and whether key is available
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()
-        .map_err(|e| ZfsError::KeyLoadedCheckFailed(dataset.to_string(), e.to_string()))?;
+fn is_valid_property_name(name: &str) -> bool {
+    name.chars()
+        .all(|c| c.is_ascii_alphanumeric() || ALLOWED_SYMBOLS.contains(&c))
+        && name.starts_with(|c: char| c.is_ascii_alphabetic())
+}
 
-    // Capture the stdout handle of the child process
-    let mut stdout = child.stdout.take().expect("Failed to capture stdout");
-    let mut stderr = child.stderr.take().expect("Failed to capture stderr");
+fn is_valid_property_value(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ALLOWED_SYMBOLS.contains(&c) || c == '/')
+}
 
-    // Read stdout/stderr to a string
-    let mut stdout_string = String::new();
-    stdout
-        .read_to_string(&mut stdout_string)
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
-    let mut stderr_string = String::new();
-    stderr
-        .read_to_string(&mut stderr_string)
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
+/// The properties of a dataset created by zfs_create_encrypted_dataset, other than its key
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct CreateOpts {
+    /// Where the dataset is mounted, which must be an absolute path. None inherits it from the parent.
+    pub mountpoint: Option<PathBuf>,
+    /// The encryption algorithm. None uses the default of zfs for `encryption=on`.
+    pub encryption: Option<EncryptionType>,
+    /// Any other properties, as (name, value) pairs passed to zfs with `-o name=value`
+    pub properties: Vec<(String, String)>,
+}
 
-    // Wait for the zfs command to complete
-    let status = child
-        .wait()
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
+impl CreateOpts {
+    /// Returns every property of the dataset, ready to be passed with `-o`, after checking them
+    fn to_properties(&self) -> Result<Vec<String>, ZfsError> {
+        let encryption = match self.encryption {
+            None => "on",
+            Some(EncryptionType::Off) => {
+                return Err(ZfsError::DatasetPropertyIsInvalid(
+                    "encryption=off".to_string(),
+                ))
+            }
+            Some(encryption) => encryption.zfs_value(),
+        };
 
-    // Check if the command was successful
-    if status.success() {
-        let lines = stdout_string.lines();
-        let datasets_results = lines
-            .into_iter()
-            .map(|l| l.split_whitespace().collect::<Vec<_>>())
-            .filter(|v| v.len() >= 2)
-            .map(|v| (v[0], v[1]))
-            .collect::<BTreeMap<&str, &str>>();
-        match datasets_results.get(&*dataset) {
-            Some(is_key_available) => parse_key_available_state(is_key_available).map(Some),
-            None => Ok(None),
+        let mut properties = vec![
+            format!("encryption={encryption}"),
+            "keyformat=passphrase".to_string(),
+            "keylocation=prompt".to_string(),
+        ];
+
+        if let Some(mountpoint) = &self.mountpoint {
+            let property = format!("mountpoint={}", mountpoint.display());
+            match mountpoint.to_str() {
+                Some(path) if mountpoint.is_absolute() && is_valid_property_value(path) => {
+                    properties.push(property)
+                }
+                _ => return Err(ZfsError::DatasetPropertyIsInvalid(property)),
+            }
         }
-    } else {
-        Err(ZfsError::KeyLoadedCheckFailed(
-            dataset.to_string(),
-            stderr_string,
-        ))
+
+        for (name, value) in &self.properties {
+            properties.push(check_and_sanitize_dataset_property(name, value)?);
+        }
+
+        Ok(properties)
     }
 }
 
-/// Checks whether a dataset is mounted
-/// Returns: Some(true): The dataset is mounted
-/// Returns: Some(false): The dataset is not mounted
-/// Returns: None: The dataset is not found
-/// Otherwise, an error is returned
-pub fn zfs_is_dataset_mounted(zfs_dataset: impl AsRef<str>) -> Result<Option<bool>, ZfsError> {
-    let dataset = check_and_sanitize_zfs_dataset_name(zfs_dataset)?;
-
-    // Create a command to run zfs load-key
-    let mut child = Command::new("zfs")
-        .arg("list")
-        .arg("-H") // No table header
-        .arg("-o")
-        .arg("name,mounted") // Only show two columns, dataset name and whether dataset is mounted
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()
-        .map_err(|e| ZfsError::IsMountedCheckCallFailed(dataset.to_string(), e.to_string()))?;
-
-    // Capture the stdout handle of the child process
-    let mut stdout = child.stdout.take().expect("Failed to capture stdout");
-    let mut stderr = child.stderr.take().expect("Failed to capture stderr");
+/// Creates a new dataset that's encrypted with the given passphrase, with `zfs create`
+/// zfs loads the key of the new dataset and mounts it, if it has a mountpoint.
+/// Returns: Ok(()) if the dataset is created
+/// Returns: Err(ZfsError::EmptyPassphrase) if the passphrase is empty or only whitespace, without running zfs
+/// Returns: Err(ZfsError::DatasetPropertyIsInvalid) if a property in the options isn't valid, without running zfs
+/// Returns: Err(ZfsError::CreateDatasetCmdFailed) if zfs fails, e.g., if the dataset already exists
+/// The command `zfs create <options> <dataset-name>` should be authorized with visudo.
+pub fn zfs_create_encrypted_dataset(
+    zfs_dataset: impl AsRef<str>,
+    passphrase: impl AsRef<str>,
+    opts: CreateOpts,
+) -> Result<(), ZfsError> {
+    ZfsConfig::default().create_encrypted_dataset(zfs_dataset, passphrase, opts)
+}
+
+/// Sets a property of a ZFS dataset, with `zfs set <property>=<value>`, e.g., keylocation after placing a key file
+/// Returns: Ok(()) if the property is set
+/// Returns: Err(ZfsError::DatasetPropertyIsInvalid) if the name or the value of the property isn't valid, without running zfs
+/// Returns: Err(ZfsError::SetPropertyCmdFailed) if zfs fails, e.g., if the property can't be set
+/// The command `zfs set <property>=<value> <dataset-name>` should be authorized with visudo.
+pub fn zfs_set_property(
+    zfs_dataset: impl AsRef<str>,
+    property: impl AsRef<str>,
+    value: impl AsRef<str>,
+) -> Result<(), ZfsError> {
+    ZfsConfig::default().set_property(zfs_dataset, property, value)
+}
+
+/// Attempts to load-key for ZFS dataset
+/// Returns: Ok(()) if the key is successfully loaded OR already loaded
+/// Returns: Err(ZfsError::EmptyPassphrase) if the passphrase is empty or only whitespace, without running zfs
+/// Returns: Err(ZfsError::IncorrectPassphrase) if zfs rejects the passphrase as the wrong one
+/// Returns: Err(ZfsError::PassphraseProvidedButKeylocationNotPrompt) if the keylocation of the dataset isn't prompt,
+/// e.g., a file:// or https:// URI, as zfs would read the key from there instead; use zfs_load_key_from_keylocation
+/// Returns: Error if dataset not found or some other system error occurred.
+/// The command `zfs load-key <dataset-name>` should be authorized with visudo.
+pub fn zfs_load_key(
+    zfs_dataset: impl AsRef<str>,
+    passphrase: impl AsRef<str>,
+) -> Result<(), ZfsError> {
+    ZfsConfig::default().load_key(zfs_dataset, passphrase)
+}
+
+/// What zfs_load_key_detailed did to load the key of a dataset
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LoadKeyOutcome {
+    /// The key was already loaded, so zfs load-key wasn't run
+    AlreadyLoaded,
+    /// The key was loaded by zfs load-key
+    Loaded,
+}
+
+/// Same as zfs_load_key, but tells whether the key was loaded now or already loaded
+/// Returns: Ok(LoadKeyOutcome::Loaded) if the key is successfully loaded
+/// Returns: Ok(LoadKeyOutcome::AlreadyLoaded) if the key was already loaded, without running zfs load-key
+/// Returns: Err like zfs_load_key otherwise.
+/// The command `zfs load-key <dataset-name>` should be authorized with visudo.
+pub fn zfs_load_key_detailed(
+    zfs_dataset: impl AsRef<str>,
+    passphrase: impl AsRef<str>,
+) -> Result<LoadKeyOutcome, ZfsError> {
+    ZfsConfig::default().load_key_detailed(zfs_dataset, passphrase)
+}
+
+/// Loads the key of the encryption root of a dataset, which unlocks the root and all the datasets that inherit
+/// its key, and returns the name of the root, e.g., to update the state of all of them after unlocking a child
+/// The errors of loading the key, like ZfsError::IncorrectPassphrase, name the encryption root.
+/// Returns: Ok(Some(root)) if the key of the root is loaded, including when it already was
+/// Returns: Ok(None) if the dataset isn't encrypted, without loading anything
+/// Returns: Err like zfs_load_key otherwise.
+/// The command `zfs load-key <dataset-name>` should be authorized with visudo.
+pub fn zfs_load_key_returning_root(
+    zfs_dataset: impl AsRef<str>,
+    passphrase: impl AsRef<str>,
+) -> Result<Option<String>, ZfsError> {
+    ZfsConfig::default().load_key_returning_root(zfs_dataset, passphrase)
+}
+
+/// Attempts to load-key for ZFS dataset, letting zfs read the key from the dataset's keylocation, e.g., a file:// URI
+/// Nothing is written to the stdin of zfs, so this fails for datasets with keylocation=prompt.
+/// Returns: Ok(()) if the key is successfully loaded OR already loaded
+/// Returns: Error if dataset not found or some other system error occurred.
+/// The command `zfs load-key <dataset-name>` should be authorized with visudo.
+pub fn zfs_load_key_from_keylocation(zfs_dataset: impl AsRef<str>) -> Result<(), ZfsError> {
+    ZfsConfig::default().load_key_from_keylocation(zfs_dataset)
+}
+
+/// Attempts to load-key for ZFS dataset, writing the key to zfs exactly as given, e.g., the 32 bytes of a raw key
+/// A new line is written after the key only if `append_newline` is true, as zfs expects for passphrases.
+/// Unlike zfs_load_key_auto, the key isn't checked against the keyformat of the dataset.
+/// Returns: Ok(()) if the key is successfully loaded OR already loaded
+/// Returns: Err(ZfsError::EmptyPassphrase) if the key is empty, without running zfs
+/// Returns: Error if dataset not found or some other system error occurred.
+/// The command `zfs load-key <dataset-name>` should be authorized with visudo.
+pub fn zfs_load_key_bytes(
+    zfs_dataset: impl AsRef<str>,
+    key: &[u8],
+    append_newline: bool,
+) -> Result<(), ZfsError> {
+    ZfsConfig::default().load_key_bytes(zfs_dataset, key, append_newline)
+}
+
+/// Attempts to load-key for ZFS dataset, giving up on the load-key command if it doesn't complete within the timeout
+/// Returns: Err(ZfsError::CommandTimedOut) if the load-key command had to be killed
+/// Note that sudo can't pass the kill to zfs, so zfs may keep running after the timeout.
+/// Otherwise, behaves like zfs_load_key.
+/// The command `zfs load-key <dataset-name>` should be authorized with visudo.
+pub fn zfs_load_key_with_timeout(
+    zfs_dataset: impl AsRef<str>,
+    passphrase: impl AsRef<str>,
+    timeout: Duration,
+) -> Result<(), ZfsError> {
+    ZfsConfig::default().load_key_with_timeout(zfs_dataset, passphrase, timeout)
+}
+
+/// Attempts to load-key for ZFS dataset, giving up on the load-key command as soon as `cancel` is set, e.g., by another thread
+/// Returns: Err(ZfsError::Cancelled) if the load-key command had to be killed, or `cancel` was set before it started
+/// Note that sudo can't pass the kill to zfs, so zfs may keep running after it's cancelled.
+/// Otherwise, behaves like zfs_load_key.
+/// The command `zfs load-key <dataset-name>` should be authorized with visudo.
+pub fn zfs_load_key_cancellable(
+    zfs_dataset: impl AsRef<str>,
+    passphrase: impl AsRef<str>,
+    cancel: &AtomicBool,
+) -> Result<(), ZfsError> {
+    ZfsConfig::default().load_key_cancellable(zfs_dataset, passphrase, cancel)
+}
+
+/// Attempts to load-key for ZFS dataset, choosing how the key is fed to zfs from the dataset's keyformat
+/// For keyformat=passphrase, the key is written followed by a new line, like zfs_load_key
+/// For keyformat=raw and keyformat=hex, the key is written verbatim, and must be 32 bytes
+/// or 64 hexadecimal characters, respectively.
+/// Returns: Ok(()) if the key is successfully loaded OR already loaded
+/// Returns: Error if dataset not found, the key doesn't fit the keyformat, or some other system error occurred.
+/// The command `zfs load-key <dataset-name>` should be authorized with visudo.
+pub fn zfs_load_key_auto(zfs_dataset: impl AsRef<str>, key: &[u8]) -> Result<(), ZfsError> {
+    ZfsConfig::default().load_key_auto(zfs_dataset, key)
+}
+
+/// Attempts to load-key for ZFS dataset, reading the key from the given file instead of the dataset's keylocation
+/// The key file has to be in the dataset's keyformat. It's read by zfs itself, so its content only has to be
+/// readable by the user zfs runs as, but this process must be able to see that the file exists.
+/// Returns: Ok(()) if the key is successfully loaded OR already loaded
+/// Returns: Err(ZfsError::KeyFileUnavailable) if the key file doesn't exist or isn't a file
+/// Returns: Error if dataset not found or some other system error occurred.
+/// The command `zfs load-key -L file://<key-file> <dataset-name>` should be authorized with visudo.
+pub fn zfs_load_key_from_file(
+    zfs_dataset: impl AsRef<str>,
+    key_file: impl AsRef<Path>,
+) -> Result<(), ZfsError> {
+    ZfsConfig::default().load_key_from_file(zfs_dataset, key_file)
+}
+
+/// Attempts to load-key for ZFS dataset, reading the key from the given location instead of the dataset's
+/// keylocation, e.g., a key server, without changing the keylocation property
+/// The location is passed to zfs as is. Nothing is written to the stdin of zfs, so with `prompt`, zfs fails
+/// unless the key can be read otherwise; zfs_load_key loads passphrases.
+/// Returns: Ok(()) if the key is successfully loaded OR already loaded
+/// Returns: Err(ZfsError::KeyLocationIsInvalid) if the location isn't prompt or a file://, https:// or http:// URI,
+/// or has characters that aren't allowed, without running zfs
+/// Returns: Error if dataset not found, zfs can't get the key from the location, or some other system error occurred.
+/// The command `zfs load-key -L <location> <dataset-name>` should be authorized with visudo.
+pub fn zfs_load_key_from_location(
+    zfs_dataset: impl AsRef<str>,
+    location: impl AsRef<str>,
+) -> Result<(), ZfsError> {
+    ZfsConfig::default().load_key_from_location(zfs_dataset, location)
+}
+
+/// Attempts to load-key for ZFS dataset, reading the key from a file descriptor, e.g., a pipe or a credential file
+/// The key is read until the end of the file, and is written to zfs like zfs_load_key_auto would write it,
+/// except that a new line at the end of a passphrase or a hex key is ignored. It's wiped from memory after use.
+/// The file descriptor isn't closed.
+/// Returns: Ok(()) if the key is successfully loaded OR already loaded
+/// Returns: Err(ZfsError::KeyReadFailed) if reading fails, or the key is longer than 1024 bytes
+/// Returns: Error if dataset not found, the key doesn't fit the keyformat, or some other system error occurred.
+/// The command `zfs load-key <dataset-name>` should be authorized with visudo.
+pub fn zfs_load_key_from_fd(zfs_dataset: impl AsRef<str>, fd: impl AsFd) -> Result<(), ZfsError> {
+    ZfsConfig::default().load_key_from_fd(zfs_dataset, fd)
+}
+
+/// Attempts to load-key for ZFS dataset, reading the key from a systemd credential, e.g., one given to the service
+/// with LoadCredential= or LoadCredentialEncrypted=. The credential is the file with the given name in
+/// `$CREDENTIALS_DIRECTORY`, and is read like zfs_load_key_from_fd reads its key.
+/// Returns: Ok(()) if the key is successfully loaded OR already loaded
+/// Returns: Err(ZfsError::KeyFileUnavailable) if `$CREDENTIALS_DIRECTORY` isn't set, or the credential can't be opened
+/// Returns: Err otherwise, like zfs_load_key_from_fd.
+/// The command `zfs load-key <dataset-name>` should be authorized with visudo.
+pub fn zfs_load_key_from_credential(
+    zfs_dataset: impl AsRef<str>,
+    credential: impl AsRef<str>,
+) -> Result<(), ZfsError> {
+    ZfsConfig::default().load_key_from_credential(zfs_dataset, credential)
+}
+
+/// Attempts to load-key for many ZFS datasets in parallel, each with its own passphrase
+/// At most as many load-key commands as the available parallelism of the machine run at a time.
+/// Returns: The result of loading the key of every dataset, as zfs_load_key would return it.
+///          A failure for one dataset doesn't prevent loading the others.
+/// The command `zfs load-key <dataset-name>` should be authorized with visudo.
+pub fn zfs_load_keys_bulk(items: &[(String, String)]) -> BTreeMap<String, Result<(), ZfsError>> {
+    let concurrency = std::thread::available_parallelism().unwrap_or(std::num::NonZeroUsize::MIN);
+
+    ZfsConfig::default().load_keys_bulk(items, concurrency)
+}
+
+/// Same as zfs_load_keys_bulk, but with at most `concurrency` load-key commands running at a time
+/// The command `zfs load-key <dataset-name>` should be authorized with visudo.
+pub fn zfs_load_keys_bulk_with_concurrency(
+    items: &[(String, String)],
+    concurrency: std::num::NonZeroUsize,
+) -> BTreeMap<String, Result<(), ZfsError>> {
+    ZfsConfig::default().load_keys_bulk(items, concurrency)
+}
+
+/// Loads the keys of all the locked encrypted datasets, with the passphrases given by dataset name
+/// A key is loaded for its encryption root, so the passphrase of a dataset that inherits its key can be given
+/// under its own name or the name of its encryption root, and the datasets that inherit it are unlocked with it.
+/// Returns: Ok(results) with the result of loading the key of every locked encryption root, where the roots without
+///          a passphrase are skipped with Err(ZfsError::NoPassphraseProvided)
+/// Returns: Error if the locked datasets can't be listed.
+/// The command `zfs load-key <dataset-name>` should be authorized with visudo.
+pub fn zfs_unlock_all(
+    passphrases: &BTreeMap<String, String>,
+) -> Result<BTreeMap<String, Result<(), ZfsError>>, ZfsError> {
+    ZfsConfig::default().unlock_all(passphrases)
+}
+
+/// Finds the passphrase for every encryption root of the locked datasets, in the way zfs_unlock_all does
+/// The encryption root of a dataset is its closest locked ancestor, or itself, that's an encryption root,
+/// as datasets are locked together with their encryption root.
+fn unlock_all_passphrases<'a>(
+    locked: &BTreeMap<String, DatasetMountedState>,
+    passphrases: &'a BTreeMap<String, String>,
+) -> BTreeMap<String, Option<&'a str>> {
+    let passphrases = passphrases
+        .iter()
+        .map(|(dataset, passphrase)| (dataset.trim(), passphrase.as_str()))
+        .collect::<BTreeMap<_, _>>();
+
+    let encryption_root = |dataset: &str| {
+        let mut ancestor = dataset;
+        loop {
+            if locked
+                .get(ancestor)
+                .is_some_and(|state| state.is_encryption_root)
+            {
+                return ancestor.to_string();
+            }
+            match ancestor.rsplit_once('/') {
+                Some((parent, _)) => ancestor = parent,
+                None => return dataset.to_string(),
+            }
+        }
+    };
+
+    let mut roots = BTreeMap::new();
+    for dataset in locked.keys() {
+        let passphrase = passphrases.get(dataset.as_str()).copied();
+        let root_passphrase = roots.entry(encryption_root(dataset)).or_insert(None);
+        *root_passphrase = root_passphrase.or(passphrase);
+    }
+    // The passphrase given for the root itself wins over the ones given for the datasets under it
+    for (root, passphrase) in roots.iter_mut() {
+        if let Some(root_passphrase) = passphrases.get(root.as_str()) {
+            *passphrase = Some(*root_passphrase);
+        }
+    }
+    roots
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct RecursiveLoadKeyReport {
+    /// The encrypted datasets whose keys were loaded by this call
+    pub loaded: BTreeSet<String>,
+    /// The encrypted datasets whose keys are still not loaded, e.g., descendant encryption roots with a different key
+    pub failed: BTreeSet<String>,
+}
+
+/// Attempts to load-key for ZFS dataset and all its descendants, using `zfs load-key -r`
+/// The passphrase is written once, so it loads the key of the encryption root and all the descendants that inherit it.
+/// Descendants that are encryption roots themselves get their keys loaded only if zfs can read them from their keylocation.
+/// Returns: Ok(report) with the datasets whose keys got loaded, and the ones that failed, if any key was loaded,
+///          or if all keys were already loaded.
+/// Returns: Error if no key could be loaded, the dataset is not found, or some other system error occurred.
+/// The command `zfs load-key -r <dataset-name>` should be authorized with visudo.
+pub fn zfs_load_key_recursive(
+    zfs_dataset: impl AsRef<str>,
+    passphrase: impl AsRef<str>,
+) -> Result<RecursiveLoadKeyReport, ZfsError> {
+    ZfsConfig::default().load_key_recursive(zfs_dataset, passphrase)
+}
+
+/// Attempts to load the keys of all encrypted datasets with `zfs load-key -a`
+/// zfs reads every key from the keylocation of its encryption root, so this only loads keys whose keylocation
+/// is a file or URI. Keys with keylocation=prompt can't be loaded this way, and are reported as failed.
+/// Returns: Ok(report) with the datasets whose keys got loaded, and the ones still not loaded, if any key was loaded,
+///          or if all keys were already loaded.
+/// Returns: Error if no key could be loaded or some other system error occurred.
+/// The command `zfs load-key -a` should be authorized with visudo.
+pub fn zfs_load_key_all() -> Result<RecursiveLoadKeyReport, ZfsError> {
+    ZfsConfig::default().load_key_all()
+}
+
+fn locked_datasets(key_states: &BTreeMap<String, bool>) -> BTreeSet<String> {
+    key_states
+        .iter()
+        .filter(|(_, is_key_loaded)| !**is_key_loaded)
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+fn unlocked_datasets(key_states: &BTreeMap<String, bool>) -> BTreeSet<String> {
+    key_states
+        .iter()
+        .filter(|(_, is_key_loaded)| **is_key_loaded)
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// Parses the output of `zfs list -H -o name,keystatus`, skipping unencrypted datasets
+fn parse_key_states(output: &str) -> Result<BTreeMap<String, bool>, ZfsError> {
+    output
+        .lines()
+        .map(split_output_line)
+        .filter(|v| v.len() >= 2)
+        .filter(|v| v[1].trim() != "-") // Filter unencrypted datasets
+        .map(|v| Ok((v[0].to_string(), parse_key_available_state(v[1])?)))
+        .collect()
+}
+
+/// Where zfs load-key gets the key from
+enum KeySource<'a> {
+    /// The passphrase is written to the stdin of zfs followed by a new line,
+    /// which is only done if the keylocation of the dataset is prompt
+    Passphrase(&'a str),
+    /// The key is written to the stdin of zfs
+    Stdin { key: &'a [u8], append_newline: bool },
+    /// The key is read by zfs from the given keylocation, overriding the one of the dataset
+    Location(&'a str),
+    /// The key is read by zfs from the keylocation of the dataset
+    Keylocation,
+}
+
+/// Checks that the passphrase isn't empty or only whitespace, which zfs would reject with an unclear error
+fn check_passphrase(dataset: &str, passphrase: &str) -> Result<(), ZfsError> {
+    if passphrase.trim().is_empty() {
+        Err(ZfsError::EmptyPassphrase(dataset.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+/// The most bytes that are read as a key, e.g., by zfs_load_key_from_fd
+/// zfs itself doesn't accept passphrases longer than 512 bytes.
+const MAX_KEY_READ_LEN: usize = 1024;
+
+/// Checks that the key can be used with the given keyformat
+/// Returns whether a new line should be written after the key
+fn check_key_for_keyformat(dataset: &str, keyformat: &str, key: &[u8]) -> Result<bool, ZfsError> {
+    const RAW_KEY_LENGTH: usize = 32;
+
+    match keyformat.trim() {
+        "passphrase" => {
+            if key.iter().all(|c| c.is_ascii_whitespace()) {
+                Err(ZfsError::EmptyPassphrase(dataset.to_string()))
+            } else {
+                Ok(true)
+            }
+        }
+        "raw" => {
+            if key.len() == RAW_KEY_LENGTH {
+                Ok(false)
+            } else {
+                Err(ZfsError::InvalidRawKeyLength(
+                    dataset.to_string(),
+                    key.len(),
+                ))
+            }
+        }
+        "hex" => {
+            if key.len() == 2 * RAW_KEY_LENGTH && key.iter().all(|c| c.is_ascii_hexdigit()) {
+                Ok(false)
+            } else {
+                Err(ZfsError::InvalidHexKey(dataset.to_string()))
+            }
+        }
+        _ => Err(ZfsError::UnexpectedKeyFormat(
+            dataset.to_string(),
+            keyformat.to_string(),
+        )),
+    }
+}
+
+/// The outputs of a command that ran to completion
+/// Commands that succeed may still print warnings to stderr.
+#[derive(Debug, Clone, Default)]
+pub struct CommandOutput {
+    status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    /// Turns the output of a command that didn't succeed into the error payload
+    fn failure(self) -> CommandFailure {
+        CommandFailure {
+            code: self.status.code(),
+            stderr: self.stderr,
+        }
+    }
+}
+
+/// When run_command stops waiting for a command and kills it
+#[derive(Debug, Clone, Copy, Default)]
+struct WaitLimit<'a> {
+    /// How long the command may take
+    timeout: Option<Duration>,
+    /// Set by another thread to stop the command
+    cancel: Option<&'a AtomicBool>,
+}
+
+impl<'a> WaitLimit<'a> {
+    /// Waits until the command completes
+    const NONE: WaitLimit<'static> = WaitLimit {
+        timeout: None,
+        cancel: None,
+    };
+
+    fn timeout(timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            cancel: None,
+        }
+    }
+
+    fn cancel(cancel: &'a AtomicBool) -> Self {
+        Self {
+            timeout: None,
+            cancel: Some(cancel),
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel
+            .is_some_and(|cancel| cancel.load(Ordering::SeqCst))
+    }
+}
+
+/// Emits a debug event with the `tracing` feature, and does nothing without it
+/// The arguments aren't evaluated without the feature.
+macro_rules! debug_event {
+    ($($event:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($event)*);
+    };
+}
+
+/// Emits an error event with the `tracing` feature, and does nothing without it, like debug_event
+macro_rules! error_event {
+    ($($event:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::error!($($event)*);
+    };
+}
+
+/// Emits the event of a command that is about to run, with its argv
+/// Only the length of stdin is in the event, as it can be a passphrase or a key.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+fn command_running_event(command: &Command, stdin_len: usize) {
+    debug_event!(argv = ?config::command_argv(command), stdin_len, "Running command");
+}
+
+/// Emits the events of a command that completed: its exit status, and its stderr if it failed
+/// The data written to the stdin of commands, like passphrases, is never part of the events.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+fn command_completed_events(command: &Command, status: ExitStatus, stderr: &str) {
+    debug_event!(argv = ?config::command_argv(command), %status, "Command completed");
+    if !status.success() {
+        error_event!(argv = ?config::command_argv(command), %status, stderr, "Command failed");
+    }
+}
+
+/// Spawns the command, writes stdin_data to its stdin, then waits for it to complete
+/// stdout and stderr are read while waiting, so a command can't get stuck writing to a full pipe.
+/// If a timeout is given and the command takes longer, it's killed and CommandTimedOut is returned.
+/// If a cancel flag is given and gets set, the command is killed and Cancelled is returned.
+/// The error of spawning the command is mapped with spawn_error.
+fn run_command(
+    mut command: Command,
+    stdin_data: &[u8],
+    limit: WaitLimit,
+    dataset: &str,
+    spawn_error: impl FnOnce(String) -> ZfsError,
+) -> Result<CommandOutput, ZfsError> {
+    const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    if limit.is_cancelled() {
+        return Err(ZfsError::Cancelled(dataset.to_string()));
+    }
+
+    command_running_event(&command, stdin_data.len());
+    let mut child = command
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| spawn_failure(&command, e, spawn_error))?;
+
+    // Read stdout/stderr to strings in the background, before writing stdin, so that a command
+    // that writes a lot before reading its stdin can't block on a full pipe while stdin is written
+    let stdout =
+        read_to_string_in_background(child.stdout.take().expect("Failed to capture stdout"));
+    let stderr =
+        read_to_string_in_background(child.stderr.take().expect("Failed to capture stderr"));
+
+    // Get the stdin of the command, which gets closed at the end of this block.
+    // It's written without buffering, so no copy of a passphrase is left in a buffer.
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(stdin_data)
+            .map_err(|e| ZfsError::SystemError(e.to_string()))?;
+    }
+
+    // Wait for the command to complete
+    let status = if limit.timeout.is_none() && limit.cancel.is_none() {
+        child.wait()
+    } else {
+        let deadline = limit
+            .timeout
+            .map(|timeout| (timeout, Instant::now() + timeout));
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break Ok(status),
+                Ok(None) => (),
+                Err(e) => break Err(e),
+            }
+            let now = Instant::now();
+            let error = if limit.is_cancelled() {
+                Some(ZfsError::Cancelled(dataset.to_string()))
+            } else {
+                match deadline {
+                    Some((timeout, deadline)) if now >= deadline => {
+                        Some(ZfsError::CommandTimedOut(dataset.to_string(), timeout))
+                    }
+                    _ => None,
+                }
+            };
+            if let Some(error) = error {
+                debug_event!(argv = ?config::command_argv(&command), %error, "Killing command");
+                // The readers are left behind, as the pipes may be kept open by the command's children
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(error);
+            }
+            let poll_interval = match deadline {
+                Some((_, deadline)) => POLL_INTERVAL.min(deadline - now),
+                None => POLL_INTERVAL,
+            };
+            std::thread::sleep(poll_interval);
+        }
+    }
+    .map_err(|e| ZfsError::SystemError(e.to_string()))?;
+
+    let join = |reader: std::thread::JoinHandle<std::io::Result<String>>| {
+        reader
+            .join()
+            .expect("Output reader thread panicked")
+            .map_err(|e| ZfsError::SystemError(e.to_string()))
+    };
+
+    let output = CommandOutput {
+        status,
+        stdout: join(stdout)?,
+        stderr: join(stderr)?,
+    };
+    command_completed_events(&command, output.status, &output.stderr);
+
+    Ok(output)
+}
+
+/// Runs the command to completion, copying its stdout to the writer as it's written
+/// Returns: The number of bytes copied, and the output of the command, without its stdout
+/// If writing fails, the command is killed, and the error is mapped with write_error.
+fn run_command_to_writer(
+    mut command: Command,
+    writer: &mut dyn Write,
+    spawn_error: impl FnOnce(String) -> ZfsError,
+    write_error: impl FnOnce(String) -> ZfsError,
+) -> Result<(u64, CommandOutput), ZfsError> {
+    command_running_event(&command, 0);
+    let mut child = command
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| spawn_failure(&command, e, spawn_error))?;
+
+    let mut stdout = child.stdout.take().expect("Failed to capture stdout");
+    let stderr =
+        read_to_string_in_background(child.stderr.take().expect("Failed to capture stderr"));
+
+    let written = match std::io::copy(&mut stdout, writer) {
+        Ok(written) => written,
+        Err(e) => {
+            debug_event!(argv = ?config::command_argv(&command), error = %e, "Killing command");
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(write_error(e.to_string()));
+        }
+    };
+
+    let status = child
+        .wait()
+        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
+    let stderr = stderr
+        .join()
+        .expect("Output reader thread panicked")
+        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
+    command_completed_events(&command, status, &stderr);
+
+    Ok((
+        written,
+        CommandOutput {
+            status,
+            stdout: String::new(),
+            stderr,
+        },
+    ))
+}
+
+/// The lines of the stdout of a running command, read as the command writes them
+/// After the last line, the command's exit status is checked, and failures are yielded as the last item.
+/// If the iterator is dropped before the end, the command is killed.
+struct CommandLines {
+    command: Command,
+    child: Child,
+    lines: std::io::Lines<BufReader<ChildStdout>>,
+    stderr: Option<std::thread::JoinHandle<std::io::Result<String>>>,
+    failure_error: fn(String) -> ZfsError,
+    done: bool,
+}
+
+impl Iterator for CommandLines {
+    type Item = Result<String, ZfsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.lines.next() {
+            Some(Ok(line)) => Some(Ok(line)),
+            Some(Err(e)) => {
+                self.done = true;
+                Some(Err(ZfsError::SystemError(e.to_string())))
+            }
+            None => {
+                self.done = true;
+                self.finish().err().map(Err)
+            }
+        }
+    }
+}
+
+impl CommandLines {
+    /// Waits for the command, whose stdout is done, and checks that it succeeded
+    fn finish(&mut self) -> Result<(), ZfsError> {
+        let status = self
+            .child
+            .wait()
+            .map_err(|e| ZfsError::SystemError(e.to_string()))?;
+
+        let stderr = match self.stderr.take() {
+            Some(reader) => reader
+                .join()
+                .expect("Output reader thread panicked")
+                .map_err(|e| ZfsError::SystemError(e.to_string()))?,
+            None => String::new(),
+        };
+        command_completed_events(&self.command, status, &stderr);
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err((self.failure_error)(stderr))
+        }
+    }
+}
+
+impl Drop for CommandLines {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// Spawns the command, and returns an iterator over the lines of its stdout
+/// The error of spawning the command is mapped with spawn_error, and its stderr if it fails with failure_error.
+fn spawn_command_lines(
+    mut command: Command,
+    spawn_error: impl FnOnce(String) -> ZfsError,
+    failure_error: fn(String) -> ZfsError,
+) -> Result<CommandLines, ZfsError> {
+    command_running_event(&command, 0);
+    let mut child = command
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| spawn_failure(&command, e, spawn_error))?;
+
+    let stdout = child.stdout.take().expect("Failed to capture stdout");
+    let stderr =
+        read_to_string_in_background(child.stderr.take().expect("Failed to capture stderr"));
+
+    Ok(CommandLines {
+        command,
+        child,
+        lines: BufReader::new(stdout).lines(),
+        stderr: Some(stderr),
+        failure_error,
+        done: false,
+    })
+}
+
+/// Returns the error for a command that couldn't be started
+/// A missing binary gets ZfsError::ZfsBinaryNotFound, which the config turns into SudoBinaryNotFound for sudo.
+fn spawn_failure(
+    command: &Command,
+    error: std::io::Error,
+    spawn_error: impl FnOnce(String) -> ZfsError,
+) -> ZfsError {
+    if error.kind() == std::io::ErrorKind::NotFound {
+        ZfsError::ZfsBinaryNotFound(PathBuf::from(command.get_program()))
+    } else {
+        spawn_error(error.to_string())
+    }
+}
+
+/// Returns whether the stderr of sudo says that it can't find the command, e.g., because it's not in its `secure_path`
+fn is_sudo_command_not_found_error(stderr: &str) -> bool {
+    stderr.starts_with("sudo: ") && stderr.contains("command not found")
+}
+
+fn read_to_string_in_background(
+    mut pipe: impl Read + Send + 'static,
+) -> std::thread::JoinHandle<std::io::Result<String>> {
+    std::thread::spawn(move || {
+        let mut result = String::new();
+        pipe.read_to_string(&mut result)?;
+        Ok(result)
+    })
+}
+
+/// Sends a snapshot as a raw stream, with `zfs send --raw`, writing the stream to the writer as zfs produces it
+/// The data of encrypted datasets stays encrypted in the stream, so it can be stored elsewhere without the key,
+/// and the key doesn't have to be loaded.
+/// Returns: Ok(bytes) with the number of bytes of the stream that were written
+/// Returns: Err(ZfsError::SnapshotNameIsInvalid) if the snapshot isn't `<dataset>@<name>`, without running zfs
+/// Returns: Err(ZfsError::SendWriteFailed) if writing to the writer fails, after which zfs send is killed
+/// Returns: Error if the snapshot not found or some other system error occurred.
+/// The command `zfs send --raw <snapshot>` should be authorized with visudo.
+pub fn zfs_send_raw(snapshot: impl AsRef<str>, writer: impl Write) -> Result<u64, ZfsError> {
+    ZfsConfig::default().send_raw(snapshot, writer)
+}
+
+/// Attempts to unload-key for ZFS dataset
+/// Returns: Ok(()) if the key is successfully unloaded OR already unloaded
+/// Returns: Err(ZfsError::CannotUnloadKeyWhileMounted) if the dataset is mounted, without running zfs unload-key;
+/// use zfs_unmount_and_unload to unmount it first
+/// Returns: Error if dataset not found or some other system error occurred.
+/// The command `zfs unload-key <dataset-name>` should be authorized with visudo.
+pub fn zfs_unload_key(zfs_dataset: impl AsRef<str>) -> Result<(), ZfsError> {
+    ZfsConfig::default().unload_key(zfs_dataset)
+}
+
+/// Attempts to unload-key for ZFS dataset and all its descendants, using `zfs unload-key -r`
+/// zfs can't unload the key of a dataset that's mounted or busy, and the keys that it can't unload keep
+/// the keys of their encryption roots loaded too.
+/// Returns: Ok(()) if all the keys are unloaded OR already unloaded
+/// Returns: Err(ZfsError::KeysStillLoaded) with the datasets whose keys are still loaded after unload-key
+/// Returns: Error if dataset not found or some other system error occurred.
+/// The command `zfs unload-key -r <dataset-name>` should be authorized with visudo.
+pub fn zfs_unload_key_recursive(zfs_dataset: impl AsRef<str>) -> Result<(), ZfsError> {
+    ZfsConfig::default().unload_key_recursive(zfs_dataset)
+}
+
+/// Changes the passphrase of an encrypted ZFS dataset, whose key has to be loaded
+/// The dataset should have keyformat=passphrase and keylocation=prompt, so zfs reads the new passphrase from stdin.
+/// Returns: Ok(()) if the passphrase is successfully changed
+/// Returns: Err(ZfsError::KeyNotLoadedForChangeKey) if the key is not loaded
+/// Returns: Error if dataset not found or some other system error occurred.
+/// The command `zfs change-key <dataset-name>` should be authorized with visudo.
+pub fn zfs_change_key(
+    zfs_dataset: impl AsRef<str>,
+    new_passphrase: impl AsRef<str>,
+) -> Result<(), ZfsError> {
+    ZfsConfig::default().change_key(zfs_dataset, new_passphrase)
+}
+
+/// Mounts a ZFS dataset
+/// Returns Ok(()) if successfully mounted or already mounted
+/// Returns Err(ZfsError::MountpointOccupied) if another filesystem is mounted at the mountpoint, or it's not empty
+/// Returns Err(ZfsError::LegacyMountpoint) if the mountpoint is `legacy`, without running zfs mount
+/// Returns Err(ZfsError::MountedAtUnexpectedLocation) if the dataset is already mounted somewhere other than
+/// its mountpoint, only with a ZfsConfig that has strict_mountpoint set
+/// Returns Err otherwise
+/// The command `zfs mount <dataset-name>` should be authorized with visudo.
+pub fn zfs_mount_dataset(zfs_dataset: impl AsRef<str>) -> Result<(), ZfsError> {
+    ZfsConfig::default().mount_dataset(zfs_dataset)
+}
+
+/// Mounts a ZFS dataset, giving up on the mount command if it doesn't complete within the timeout
+/// Returns Err(ZfsError::CommandTimedOut) if the mount command had to be killed
+/// Note that sudo can't pass the kill to zfs, so zfs may keep running after the timeout.
+/// Otherwise, behaves like zfs_mount_dataset.
+/// The command `zfs mount <dataset-name>` should be authorized with visudo.
+pub fn zfs_mount_dataset_with_timeout(
+    zfs_dataset: impl AsRef<str>,
+    timeout: Duration,
+) -> Result<(), ZfsError> {
+    ZfsConfig::default().mount_dataset_with_timeout(zfs_dataset, timeout)
+}
+
+/// Mounts a ZFS dataset, like zfs_mount_dataset, and returns the path it's mounted at
+/// Returns: Ok(path) with the mountpoint of the dataset if it's successfully mounted OR already mounted
+/// Returns: Err(ZfsError::LegacyMountpoint) if the mountpoint is `legacy`, even if the dataset is mounted,
+/// as the path is then only known to the system
+/// Returns: Errors like zfs_mount_dataset otherwise
+/// The command `zfs mount <dataset-name>` should be authorized with visudo.
+pub fn zfs_mount_dataset_returning_path(zfs_dataset: impl AsRef<str>) -> Result<PathBuf, ZfsError> {
+    ZfsConfig::default().mount_dataset_returning_path(zfs_dataset)
+}
+
+/// Mounts a ZFS dataset, like zfs_mount_dataset, and returns what zfs printed even when it succeeds
+/// Returns: Ok(output) with the stdout and stderr of zfs mount if it succeeded, which may have warnings,
+///          or with both empty if the dataset was already mounted and zfs mount didn't run
+/// Returns: Errors like zfs_mount_dataset
+/// The command `zfs mount <dataset-name>` should be authorized with visudo.
+pub fn zfs_mount_dataset_verbose(zfs_dataset: impl AsRef<str>) -> Result<CommandOutput, ZfsError> {
+    ZfsConfig::default().mount_dataset_verbose(zfs_dataset)
+}
+
+/// Mounts a ZFS dataset, giving up on the mount command as soon as `cancel` is set, e.g., by another thread
+/// Returns: Err(ZfsError::Cancelled) if the mount command had to be killed, or `cancel` was set before it started
+/// Note that sudo can't pass the kill to zfs, so zfs may keep running after it's cancelled.
+/// Otherwise, behaves like zfs_mount_dataset.
+/// The command `zfs mount <dataset-name>` should be authorized with visudo.
+pub fn zfs_mount_dataset_cancellable(
+    zfs_dataset: impl AsRef<str>,
+    cancel: &AtomicBool,
+) -> Result<(), ZfsError> {
+    ZfsConfig::default().mount_dataset_cancellable(zfs_dataset, cancel)
+}
+
+/// Mounts a ZFS dataset, retrying if zfs mount fails in a way that may be temporary, e.g., right after a pool import
+/// The dataset is mounted up to `attempts` times, waiting `backoff` before the first retry, and twice as long before
+/// every retry after it. Errors that retrying can't fix, like ZfsError::DatasetNotFound or ZfsError::KeyNotLoadedForMount,
+/// are returned right away.
+/// Returns: Ok(()) if successfully mounted or already mounted
+/// Returns: The error of the last attempt if all attempts fail
+/// The command `zfs mount <dataset-name>` should be authorized with visudo.
+pub fn zfs_mount_dataset_with_retry(
+    zfs_dataset: impl AsRef<str>,
+    attempts: u32,
+    backoff: Duration,
+) -> Result<(), ZfsError> {
+    ZfsConfig::default().mount_dataset_with_retry(zfs_dataset, attempts, backoff)
+}
+
+/// Returns whether a mount failed in a way that may succeed if it's retried
+fn is_transient_mount_error(error: &ZfsError) -> bool {
+    matches!(
+        error,
+        ZfsError::MountCmdFailed(_, _) | ZfsError::CommandTimedOut(_, _)
+    )
+}
+
+/// Runs `operation` up to `attempts` times, at least once, as long as it fails with errors for which `is_transient`
+/// returns true. The wait before retrying starts at `backoff` and doubles with every retry.
+fn retry_with_backoff<T>(
+    attempts: u32,
+    backoff: Duration,
+    is_transient: impl Fn(&ZfsError) -> bool,
+    mut operation: impl FnMut() -> Result<T, ZfsError>,
+) -> Result<T, ZfsError> {
+    let mut delay = backoff;
+    let mut attempt = 1;
+    loop {
+        match operation() {
+            Err(e) if attempt < attempts && is_transient(&e) => {
+                std::thread::sleep(delay);
+                delay = delay.saturating_mul(2);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Mounts a ZFS dataset with the given temporary mount options, e.g., `ro` or `noatime`
+/// Every option is passed with its own `-o`. Options are checked, like dataset names, before anything is run.
+/// The options are not applied if the dataset is already mounted.
+/// Returns Err(ZfsError::MountOptionIsInvalid) if an option has characters that aren't allowed
+/// Otherwise, behaves like zfs_mount_dataset.
+/// The command `zfs mount -o <options> <dataset-name>` should be authorized with visudo.
+pub fn zfs_mount_dataset_with_options(
+    zfs_dataset: impl AsRef<str>,
+    options: &[&str],
+) -> Result<(), ZfsError> {
+    ZfsConfig::default().mount_dataset_with_options(zfs_dataset, options)
+}
+
+/// Mounts a ZFS dataset at another path than its mountpoint, e.g., for recovery when its mountpoint can't be used,
+/// with `zfs mount -o mountpoint=<path>`, which doesn't change the mountpoint property.
+/// The path is only used until the dataset is unmounted. The options are passed like zfs_mount_dataset_with_options.
+/// Nothing is done if the dataset is already mounted, wherever it's mounted.
+/// Returns Err(ZfsError::MountpointIsInvalid) if the path isn't absolute, is `/`, or has characters that aren't allowed
+/// Returns Err(ZfsError::MountOptionIsInvalid) if an option is invalid, or sets the mountpoint itself
+/// Otherwise, behaves like zfs_mount_dataset.
+/// The command `zfs mount -o <options> -o mountpoint=<path> <dataset-name>` should be authorized with visudo.
+pub fn zfs_mount_at(
+    zfs_dataset: impl AsRef<str>,
+    path: &Path,
+    options: &[&str],
+) -> Result<(), ZfsError> {
+    ZfsConfig::default().mount_at(zfs_dataset, path, options)
+}
+
+/// Returns whether the stderr of zfs mount says that the mountpoint is taken by something else
+fn is_mountpoint_occupied_error(stderr: &str) -> bool {
+    const OCCUPIED_MESSAGES: [&str; 2] = ["filesystem already mounted", "is not empty"];
+
+    OCCUPIED_MESSAGES.iter().any(|msg| stderr.contains(msg))
+}
+
+/// Extracts the mountpoint from zfs mount errors in the form "cannot mount '/path': reason"
+/// Returns None if the error doesn't mention a path, e.g., when it mentions the dataset name instead
+fn mountpoint_from_mount_error(stderr: &str) -> Option<PathBuf> {
+    let (_, rest) = stderr.split_once("cannot mount '")?;
+    let (path, _) = rest.split_once("':")?;
+    path.starts_with('/').then(|| PathBuf::from(path))
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct MountAllReport {
+    /// The filesystems that were mounted by this call
+    pub mounted: BTreeSet<String>,
+    /// The filesystems that zfs should mount but are still not mounted, e.g., because their keys aren't loaded
+    pub failed: BTreeSet<String>,
+}
+
+/// Attempts to mount all filesystems that zfs mounts on its own, with `zfs mount -a`
+/// Only filesystems with canmount=on and a mountpoint other than none or legacy are mounted.
+/// Filesystems whose keys aren't loaded are skipped by zfs, and are reported as failed.
+/// Returns: Ok(report) with the filesystems that got mounted, and the ones still not mounted, if any was mounted,
+///          or if all were already mounted.
+/// Returns: Error if no filesystem could be mounted or some other system error occurred.
+/// The command `zfs mount -a` should be authorized with visudo.
+pub fn zfs_mount_all() -> Result<MountAllReport, ZfsError> {
+    ZfsConfig::default().mount_all()
+}
+
+/// Parses the output of `zfs list -H -t filesystem -o name,mounted,canmount,mountpoint`,
+/// keeping the filesystems that `zfs mount -a` would mount, but aren't mounted
+fn parse_unmounted_filesystems(output: &str) -> Result<BTreeSet<String>, ZfsError> {
+    let mut unmounted = BTreeSet::new();
+    for v in output
+        .lines()
+        .map(split_output_line)
+        .filter(|v| v.len() >= 4)
+    {
+        if !parse_dataset_mounted_state(v[1])?
+            && v[2].trim() == "on"
+            && parse_mountpoint(v[3]).is_some()
+        {
+            unmounted.insert(v[0].to_string());
+        }
+    }
+    Ok(unmounted)
+}
+
+/// Loads the key of a ZFS dataset, then mounts it
+/// Returns: Ok(()) if the dataset ends up mounted, including when the key was already loaded or it was already mounted
+/// Returns: The error of zfs_load_key or zfs_mount_dataset, whichever failed first
+/// The commands `zfs load-key <dataset-name>` and `zfs mount <dataset-name>` should be authorized with visudo.
+pub fn zfs_unlock_and_mount(
+    zfs_dataset: impl AsRef<str>,
+    passphrase: impl AsRef<str>,
+) -> Result<(), ZfsError> {
+    ZfsConfig::default().unlock_and_mount(zfs_dataset, passphrase)
+}
+
+/// Loads the key of a ZFS dataset and mounts it like zfs_unlock_and_mount, then checks with zfs that both took,
+/// e.g., in case a command exits with 0 without changing anything
+/// Returns: Ok(()) if the key is loaded and the dataset is mounted afterwards
+/// Returns: Err(ZfsError::VerificationFailed) if the commands succeeded, but the key isn't loaded or it isn't mounted
+/// Returns: The error of zfs_unlock_and_mount otherwise
+/// The commands `zfs load-key <dataset-name>` and `zfs mount <dataset-name>` should be authorized with visudo.
+pub fn zfs_ensure_available(
+    zfs_dataset: impl AsRef<str>,
+    passphrase: impl AsRef<str>,
+) -> Result<(), ZfsError> {
+    ZfsConfig::default().ensure_available(zfs_dataset, passphrase)
+}
+
+/// Unmounts a ZFS dataset
+/// Returns: Ok(()) on success or if is already mounted
+/// Returns: Err(ZfsError::DatasetBusy) if the dataset is in use.
+/// Returns: Err otherwise.
+/// The command `zfs unmount <dataset-name>` should be authorized with visudo.
+pub fn zfs_unmount_dataset(zfs_dataset: impl AsRef<str>) -> Result<(), ZfsError> {
+    ZfsConfig::default().unmount_dataset(zfs_dataset)
+}
+
+/// Unmounts a ZFS dataset by force, with `zfs unmount -f`, even if it's in use
+/// Processes that have files open in the dataset lose access to them.
+/// Returns: Ok(()) on success or if is already unmounted
+/// Returns: Err(ZfsError::DatasetBusy) if the dataset is in use in a way that even a forced unmount can't handle
+/// Returns: Err otherwise.
+/// The command `zfs unmount -f <dataset-name>` should be authorized with visudo.
+pub fn zfs_unmount_dataset_force(zfs_dataset: impl AsRef<str>) -> Result<(), ZfsError> {
+    ZfsConfig::default().unmount_dataset_force(zfs_dataset)
+}
+
+/// Unmounts the dataset whose mountpoint is the path, e.g., for tools that only give the path
+/// Only a mountpoint that's exactly the path matches, so the parents and children of the path are never unmounted.
+/// If several datasets have the path as mountpoint, e.g., boot environments, all of them that are mounted
+/// are unmounted.
+/// Returns: Ok(datasets) with the datasets that were unmounted, which is empty if none of them was mounted
+/// Returns: Err(ZfsError::NoDatasetAtMountpoint) if no dataset has the path as mountpoint
+/// Returns: The error of zfs_unmount_dataset for the first dataset that fails to unmount otherwise
+/// The command `zfs unmount <dataset-name>` should be authorized with visudo.
+pub fn zfs_unmount_by_mountpoint(path: &Path) -> Result<Vec<String>, ZfsError> {
+    ZfsConfig::default().unmount_by_mountpoint(path)
+}
+
+/// Returns the datasets whose mountpoint is the path, from the mountpoints of zfs_list_datasets_mountpoints
+/// Paths are compared by their components, so trailing and repeated slashes don't matter.
+fn datasets_at_mountpoint(mountpoints: &BTreeMap<String, Mountpoint>, path: &Path) -> Vec<String> {
+    mountpoints
+        .iter()
+        .filter(|(_, mountpoint)| mountpoint.path() == Some(path))
+        .map(|(dataset, _)| dataset.clone())
+        .collect()
+}
+
+/// Unmounts a ZFS dataset and all its descendant filesystems, children before their parents
+/// Filesystems that aren't mounted are skipped. A failure doesn't stop the others from being unmounted,
+/// but the parents of a filesystem that's still mounted will fail too.
+/// Returns: Ok(results) with the result of unmounting each filesystem that was mounted
+/// Returns: Err(ZfsError::DatasetNotFound) if the dataset is not found
+/// Returns: Error if the descendants can't be listed.
+/// The command `zfs unmount <dataset-name>` should be authorized with visudo.
+pub fn zfs_unmount_tree(
+    zfs_dataset: impl AsRef<str>,
+) -> Result<BTreeMap<String, Result<(), ZfsError>>, ZfsError> {
+    ZfsConfig::default().unmount_tree(zfs_dataset)
+}
+
+/// Parses the output of `zfs list -r -H -t filesystem -o name,mounted <dataset>`,
+/// into the mounted filesystems in the order they can be unmounted, deepest first
+fn parse_unmount_order(output: &str) -> Result<Vec<String>, ZfsError> {
+    let mut mounted = Vec::new();
+    for v in output
+        .lines()
+        .map(split_output_line)
+        .filter(|v| v.len() >= 2)
+    {
+        if parse_dataset_mounted_state(v[1])? {
+            mounted.push(v[0].to_string());
+        }
+    }
+
+    // Stable, so siblings stay in the order zfs listed them
+    mounted.sort_by_key(|name| std::cmp::Reverse(name.matches('/').count()));
+    Ok(mounted)
+}
+
+/// The first version of zfs with `zfs unmount -u`, which unloads the key with the unmount
+const UNMOUNT_UNLOAD_KEY_MIN_VERSION: ZfsVersion = ZfsVersion::new(2, 2, 0);
+
+/// How zfs_unmount_and_unload_atomic unmounted a dataset and unloaded its key
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum UnmountAndUnloadMethod {
+    /// With a single `zfs unmount -u`
+    Combined,
+    /// With `zfs unmount`, then `zfs unload-key`, because zfs is too old for -u, or the dataset wasn't mounted
+    Separate,
+}
+
+/// Unmounts a ZFS dataset and unloads its key, with a single `zfs unmount -u` if zfs is new enough (2.2.0),
+/// so the dataset isn't left unmounted with its key loaded in between. Older versions of zfs get
+/// zfs_unmount_and_unload.
+/// Returns: Ok(method) with how it was done, if the key ends up unloaded
+/// Returns: Errors like zfs_unmount_and_unload
+/// The commands `zfs unmount <dataset-name>`, `zfs unmount -u <dataset-name>` and `zfs unload-key <dataset-name>`
+/// should be authorized with visudo.
+pub fn zfs_unmount_and_unload_atomic(
+    zfs_dataset: impl AsRef<str>,
+) -> Result<UnmountAndUnloadMethod, ZfsError> {
+    ZfsConfig::default().unmount_and_unload_atomic(zfs_dataset)
+}
+
+/// Unmounts a ZFS dataset, then unloads its key
+/// Returns: Ok(()) if the key ends up unloaded, including when it was already unmounted or the key was already unloaded
+/// Returns: The error of zfs_unmount_dataset or zfs_unload_key, whichever failed first
+/// The commands `zfs unmount <dataset-name>` and `zfs unload-key <dataset-name>` should be authorized with visudo.
+pub fn zfs_unmount_and_unload(zfs_dataset: impl AsRef<str>) -> Result<(), ZfsError> {
+    ZfsConfig::default().unmount_and_unload(zfs_dataset)
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct GracefulUnmountReport {
+    /// The number of unmount commands that were run, including the forced one
+    pub attempts: u32,
+    /// Whether the dataset had to be unmounted by force
+    pub forced: bool,
+}
+
+/// Unmounts a ZFS dataset, retrying while it's busy
+/// The unmount is retried up to `retries` times, waiting `delay` before each retry,
+/// as long as it fails with ZfsError::DatasetBusy. If the dataset is still busy after that,
+/// and `escalate_to_force` is set, a forced unmount is done.
+/// Returns: Ok(report) on success or if is already unmounted
+/// Returns: Err(ZfsError::DatasetBusy) if still busy after the retries and forcing isn't allowed
+/// Returns: Err otherwise.
+/// The commands `zfs unmount <dataset-name>` and `zfs unmount -f <dataset-name>` should be authorized with visudo.
+pub fn zfs_unmount_dataset_graceful(
+    zfs_dataset: impl AsRef<str>,
+    retries: u32,
+    delay: Duration,
+    escalate_to_force: bool,
+) -> Result<GracefulUnmountReport, ZfsError> {
+    ZfsConfig::default().unmount_dataset_graceful(zfs_dataset, retries, delay, escalate_to_force)
+}
+
+/// What zfs_remount_dataset did to mount the dataset again
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RemountOutcome {
+    /// The dataset wasn't mounted, so it was only mounted
+    AlreadyUnmounted,
+    /// The dataset was unmounted, then mounted
+    Remounted,
+}
+
+/// Unmounts a ZFS dataset if it's mounted, then mounts it
+/// If the dataset gets unmounted by something else while it's being unmounted, it's just mounted.
+/// Returns: Ok(outcome) if the dataset ends up mounted
+/// Returns: Err(ZfsError::DatasetBusy) if the dataset is in use, and it's left mounted
+/// Returns: Err otherwise.
+/// The commands `zfs unmount <dataset-name>` and `zfs mount <dataset-name>` should be authorized with visudo.
+pub fn zfs_remount_dataset(zfs_dataset: impl AsRef<str>) -> Result<RemountOutcome, ZfsError> {
+    ZfsConfig::default().remount_dataset(zfs_dataset)
+}
+
+/// Returns whether the stderr of zfs load-key says that the key doesn't match the one of the dataset
+/// zfs also starts other failures with "Key load error", like a missing key file, so only the mismatch is checked.
+fn is_incorrect_key_error(stderr: &str) -> bool {
+    stderr.contains("Incorrect key provided")
+}
+
+/// Returns whether the stderr of zfs unmount says that the dataset is in use
+fn is_dataset_busy_error(stderr: &str) -> bool {
+    const BUSY_MESSAGES: [&str; 4] = [
+        "target is busy",
+        "device busy",
+        "dataset is busy",
+        "Device or resource busy",
+    ];
+
+    BUSY_MESSAGES.iter().any(|msg| stderr.contains(msg))
+}
+
+/// Checks whether a dataset exists, regardless of the state of its key or whether it's mounted
+/// Returns: Ok(true) if the dataset exists
+/// Returns: Ok(false) if zfs says the dataset doesn't exist
+/// Otherwise, an error is returned
+pub fn zfs_dataset_exists(zfs_dataset: impl AsRef<str>) -> Result<bool, ZfsError> {
+    ZfsConfig::default().dataset_exists(zfs_dataset)
+}
+
+/// Interprets the result of `zfs list -H -o name <dataset>`
+fn parse_dataset_exists_result(
+    dataset: &str,
+    success: bool,
+    stderr: String,
+) -> Result<bool, ZfsError> {
+    if success {
+        Ok(true)
+    } else if is_dataset_not_found_error(&stderr) {
+        Ok(false)
+    } else {
+        Err(ZfsError::DatasetExistsCheckFailed(
+            dataset.to_string(),
+            stderr,
+        ))
+    }
+}
+
+/// Checks whether key is loaded
+/// Returns: Some(true): Key is available/loaded and/or doesn't need it
+/// Returns: Some(false): Key is not loaded
+/// Returns: None: The dataset is not found
+/// Otherwise, an error is returned
+pub fn zfs_is_key_loaded(zfs_dataset: impl AsRef<str>) -> Result<Option<bool>, ZfsError> {
+    ZfsConfig::default().is_key_loaded(zfs_dataset)
+}
+
+/// Interprets the result of `zfs get keystatus -H -o name,value <dataset>`
+fn parse_key_status_result(
+    dataset: &str,
+    success: bool,
+    stdout: &str,
+    stderr: String,
+) -> Result<KeyStatus, ZfsError> {
+    // Check if the command was successful
+    if success {
+        let datasets_results = stdout
+            .lines()
+            .map(split_output_line)
+            .filter(|v| v.len() >= 2)
+            .map(|v| (v[0], v[1]))
+            .collect::<BTreeMap<&str, &str>>();
+        match datasets_results.get(dataset).map(|state| state.trim()) {
+            // Unencrypted datasets have no key status
+            Some("-") | Some("none") => Ok(KeyStatus::NotEncrypted),
+            Some(is_key_available) => match parse_key_available_state(is_key_available)? {
+                true => Ok(KeyStatus::Available),
+                false => Ok(KeyStatus::Unavailable),
+            },
+            None => Ok(KeyStatus::DatasetNotFound),
+        }
+    } else if is_dataset_not_found_error(&stderr) {
+        Ok(KeyStatus::DatasetNotFound)
+    } else {
+        Err(ZfsError::KeyLoadedCheckFailed(dataset.to_string(), stderr))
+    }
+}
+
+/// Whether the key of a dataset is loaded
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum KeyStatus {
+    Available,
+    Unavailable,
+    /// The dataset isn't encrypted, so it doesn't need a key
+    NotEncrypted,
+    DatasetNotFound,
+}
+
+impl KeyStatus {
+    /// The status in the form returned by zfs_is_key_loaded
+    fn is_loaded(self) -> Option<bool> {
+        match self {
+            KeyStatus::Available | KeyStatus::NotEncrypted => Some(true),
+            KeyStatus::Unavailable => Some(false),
+            KeyStatus::DatasetNotFound => None,
+        }
+    }
+}
+
+/// Whether a dataset is mounted
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MountStatus {
+    Mounted,
+    Unmounted,
+    DatasetNotFound,
+}
+
+impl MountStatus {
+    /// The status in the form returned by zfs_is_dataset_mounted
+    fn is_mounted(self) -> Option<bool> {
+        match self {
+            MountStatus::Mounted => Some(true),
+            MountStatus::Unmounted => Some(false),
+            MountStatus::DatasetNotFound => None,
+        }
+    }
+}
+
+/// Gets whether the key of a dataset is loaded
+/// Returns: KeyStatus::DatasetNotFound if the dataset is not found
+/// Otherwise, an error is returned if the check fails
+pub fn zfs_key_status(zfs_dataset: impl AsRef<str>) -> Result<KeyStatus, ZfsError> {
+    ZfsConfig::default().key_status(zfs_dataset)
+}
+
+/// Gets whether a dataset is mounted
+/// Returns: MountStatus::DatasetNotFound if the dataset is not found
+/// Otherwise, an error is returned if the check fails
+pub fn zfs_mount_status(zfs_dataset: impl AsRef<str>) -> Result<MountStatus, ZfsError> {
+    ZfsConfig::default().mount_status(zfs_dataset)
+}
+
+/// Interprets the result of `zfs list -H -o name,mounted`
+fn parse_mount_status_result(
+    dataset: &str,
+    success: bool,
+    stdout: &str,
+    stderr: String,
+) -> Result<MountStatus, ZfsError> {
+    // Check if the command was successful
+    if success {
+        let datasets_results = stdout
+            .lines()
+            .map(split_output_line)
+            .filter(|v| v.len() >= 2)
+            .map(|v| (v[0], v[1]))
+            .collect::<BTreeMap<&str, &str>>();
+        match datasets_results.get(dataset) {
+            Some(is_dataset_mounted) => match *is_dataset_mounted {
+                "yes" => Ok(MountStatus::Mounted),
+                "no" => Ok(MountStatus::Unmounted),
+                _ => Err(ZfsError::UnexpectedStateForMount(
+                    is_dataset_mounted.to_string(),
+                )),
+            },
+            None => Ok(MountStatus::DatasetNotFound),
+        }
+    } else {
+        Err(ZfsError::IsMountedCheckCallFailed(
+            dataset.to_string(),
+            stderr,
+        ))
+    }
+}
+
+/// Checks whether a dataset is mounted
+/// Returns: Some(true): The dataset is mounted
+/// Returns: Some(false): The dataset is not mounted
+/// Returns: None: The dataset is not found
+/// Otherwise, an error is returned
+pub fn zfs_is_dataset_mounted(zfs_dataset: impl AsRef<str>) -> Result<Option<bool>, ZfsError> {
+    ZfsConfig::default().is_dataset_mounted(zfs_dataset)
+}
+
+/// Waits until a dataset is mounted, checking whether it is every `poll_interval`, for mounts that show up
+/// in zfs shortly after the mount command completes
+/// Returns: Ok(true) if the dataset is mounted within the timeout
+/// Returns: Ok(false) if it's still not mounted when the timeout elapses
+/// Returns: Err(ZfsError::DatasetNotFound) as soon as the dataset is not found
+/// Otherwise, an error is returned
+pub fn zfs_wait_until_mounted(
+    zfs_dataset: impl AsRef<str>,
+    timeout: Duration,
+    poll_interval: Duration,
+) -> Result<bool, ZfsError> {
+    ZfsConfig::default().wait_until_mounted(zfs_dataset, timeout, poll_interval)
+}
+
+/// Gets whether a dataset is mounted and whether its key is loaded, with a single zfs call
+/// For datasets that aren't encrypted, is_key_loaded is true, as they don't need a key.
+/// Returns: Some(state): The state of the dataset
+/// Returns: None: The dataset is not found
+/// Otherwise, an error is returned
+pub fn zfs_get_dataset_state(
+    zfs_dataset: impl AsRef<str>,
+) -> Result<Option<DatasetMountedState>, ZfsError> {
+    ZfsConfig::default().get_dataset_state(zfs_dataset)
+}
+
+/// Gets whether the key of a dataset is loaded and whether it's mounted, as one snapshot from a single zfs call,
+/// so that the two values can't disagree because the dataset changed between two calls.
+/// A loaded key doesn't mean the dataset is mounted; it can be unlocked but not mounted.
+/// For datasets that aren't encrypted, the key is reported as loaded, as they don't need a key.
+/// Returns: Some((key_loaded, mounted)): The state of the dataset
+/// Returns: None: The dataset is not found
+/// Otherwise, an error is returned
+pub fn zfs_is_unlocked_and_mounted(
+    zfs_dataset: impl AsRef<str>,
+) -> Result<Option<(bool, bool)>, ZfsError> {
+    ZfsConfig::default().is_unlocked_and_mounted(zfs_dataset)
+}
+
+/// Parses the columns of a line of `zfs list -H -o <DATASET_STATE_COLUMNS>`, which has at least 3 of them
+fn parse_dataset_state_row(v: &[&str]) -> Result<DatasetMountedState, ZfsError> {
+    Ok(DatasetMountedState {
+        dataset_name: v[0].to_string(),
+        is_mounted: parse_dataset_mounted_state(v[1])?,
+        // Unencrypted datasets don't need a key
+        is_key_loaded: v[2].trim() == "-" || parse_key_available_state(v[2])?,
+        mountpoint: v.get(4).and_then(|mountpoint| parse_mountpoint(mountpoint)),
+        is_encryption_root: is_own_encryption_root(v[0], v.get(3).copied()),
+    })
+}
+
+/// Gets the states of many datasets like zfs_get_dataset_state, with a single zfs call
+/// Returns: The states of the datasets, by name, where datasets that are not found are missing
+/// Returns: Error if a name is invalid, without running zfs, or if zfs fails for another reason
+pub fn zfs_get_states(
+    zfs_datasets: &[impl AsRef<str>],
+) -> Result<BTreeMap<String, DatasetMountedState>, ZfsError> {
+    ZfsConfig::default().get_states(zfs_datasets)
+}
+
+/// Returns whether every error in the stderr of a zfs command that got many datasets is a missing dataset
+/// A failure without any error isn't one, as zfs would have said which dataset is missing.
+fn is_only_missing_datasets_error(stderr: &str) -> bool {
+    !stderr.trim().is_empty()
+        && stderr
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .all(is_dataset_not_found_error)
+}
+
+/// Lists the given properties of datasets, with one map from property name to value for each row of `zfs list`
+/// The values are as zfs prints them in scripted mode, e.g., `-` for properties that don't apply to a dataset.
+/// Add `name` to the properties to know which dataset each row is for.
+/// Returns: The properties of all datasets if `zfs_datasets` is None, or of the given ones, where datasets that are
+/// not found are missing
+/// Returns: Err(ZfsError::DatasetPropertyIsInvalid) if a property name is invalid, or none is given, without running zfs
+/// Returns: Error if a dataset name is invalid, or zfs fails for another reason
+pub fn zfs_list_properties(
+    zfs_datasets: Option<&[&str]>,
+    properties: &[&str],
+) -> Result<Vec<BTreeMap<String, String>>, ZfsError> {
+    ZfsConfig::default().list_properties(zfs_datasets, properties)
+}
+
+/// Interprets the result of `zfs list -H -o <properties> [<dataset>...]`
+/// Values are split on tabs only, so they can have spaces. Rows without a value for every property are skipped.
+fn parse_properties_result(
+    success: bool,
+    stdout: &str,
+    stderr: String,
+    properties: &[String],
+) -> Result<Vec<BTreeMap<String, String>>, ZfsError> {
+    if !success && !is_only_missing_datasets_error(&stderr) {
+        return Err(ZfsError::ListPropertiesCallFailed(stderr));
+    }
+
+    Ok(stdout
+        .lines()
+        .map(|line| line.split('\t').collect::<Vec<_>>())
+        .filter(|values| values.len() == properties.len())
+        .map(|values| {
+            properties
+                .iter()
+                .cloned()
+                .zip(values.into_iter().map(str::to_string))
+                .collect()
+        })
+        .collect())
+}
+
+/// Interprets the result of `zfs list -H -o <DATASET_STATE_COLUMNS> <dataset>...`
+/// zfs lists the datasets that exist even if others don't, and fails with an error for each missing one.
+fn parse_dataset_states_result(
+    success: bool,
+    stdout: &str,
+    stderr: String,
+) -> Result<BTreeMap<String, DatasetMountedState>, ZfsError> {
+    if !success && !is_only_missing_datasets_error(&stderr) {
+        return Err(ZfsError::DatasetStatesCallFailed(stderr));
+    }
+
+    stdout
+        .lines()
+        .map(split_output_line)
+        .filter(|v| v.len() >= 3)
+        .map(|v| parse_dataset_state_row(&v).map(|state| (state.dataset_name.clone(), state)))
+        .collect()
+}
+
+/// Interprets the result of `zfs list -H -o <DATASET_STATE_COLUMNS> <dataset>`
+fn parse_dataset_state_result(
+    dataset: &str,
+    success: bool,
+    stdout: &str,
+    stderr: String,
+) -> Result<Option<DatasetMountedState>, ZfsError> {
+    if success {
+        let row = stdout
+            .lines()
+            .map(split_output_line)
+            .filter(|v| v.len() >= 3)
+            .find(|v| v[0] == dataset);
+        row.map(|v| parse_dataset_state_row(&v)).transpose()
+    } else if is_dataset_not_found_error(&stderr) {
+        Ok(None)
+    } else {
+        Err(ZfsError::DatasetStateCallFailed(
+            dataset.to_string(),
+            stderr,
+        ))
+    }
+}
+
+/// Gets whether the key of a dataset is a passphrase that has to be entered, or can be loaded without asking anything,
+/// e.g., from a file:// keylocation, so that a UI can show a password field or an unlock button
+/// The keyformat and keylocation are the ones of the encryption root, from which the dataset inherits its key.
+/// Returns: Some(true): The keyformat is passphrase and the keylocation is prompt
+/// Returns: Some(false): The key is loaded from its keylocation, or isn't a passphrase
+/// Returns: None: The dataset is not found, or isn't encrypted
+/// Otherwise, an error is returned
+pub fn zfs_key_requires_passphrase(zfs_dataset: impl AsRef<str>) -> Result<Option<bool>, ZfsError> {
+    ZfsConfig::default().key_requires_passphrase(zfs_dataset)
+}
+
+/// Gets whether a dataset exists, is encrypted, and whether its key is loaded, from a single zfs call,
+/// e.g., to explain why a dataset is missing from zfs_list_encrypted_datasets
+/// Returns: Ok(status) with EncryptionStatus::NotFound if the dataset is not found
+/// Otherwise, an error is returned
+pub fn zfs_encryption_status(zfs_dataset: impl AsRef<str>) -> Result<EncryptionStatus, ZfsError> {
+    ZfsConfig::default().encryption_status(zfs_dataset)
+}
+
+/// Gets the encryption, keyformat, keylocation, keystatus and pbkdf2iters properties of a dataset
+/// Returns: Some(properties): The properties of the dataset, where encryption is EncryptionType::Off if it's not encrypted
+/// Returns: None: The dataset is not found
+/// Otherwise, an error is returned
+pub fn zfs_get_encryption_properties(
+    zfs_dataset: impl AsRef<str>,
+) -> Result<Option<EncryptionProperties>, ZfsError> {
+    ZfsConfig::default().get_encryption_properties(zfs_dataset)
+}
+
+/// Gets the encryption root of a dataset, which is the dataset whose key has to be loaded to unlock it
+/// An encryption root is its own encryption root.
+/// Returns: Some(root): The name of the encryption root
+/// Returns: None: The dataset isn't encrypted
+/// Returns: Err(ZfsError::DatasetNotFound) if the dataset is not found
+/// Otherwise, an error is returned
+pub fn zfs_get_encryption_root(zfs_dataset: impl AsRef<str>) -> Result<Option<String>, ZfsError> {
+    ZfsConfig::default().get_encryption_root(zfs_dataset)
+}
+
+/// Parses the value of the encryptionroot property, checking that it's a dataset name like the ones given by users
+fn parse_encryption_root(dataset: &str, value: &str) -> Result<Option<String>, ZfsError> {
+    match value.trim() {
+        "" | "-" => Ok(None),
+        root => check_and_sanitize_zfs_dataset_name(root)
+            .map(Some)
+            .map_err(|_| {
+                ZfsError::UnexpectedPropertyValue(
+                    dataset.to_string(),
+                    "encryptionroot".to_string(),
+                    value.to_string(),
+                )
+            }),
+    }
+}
+
+/// The space used by a dataset, and available to it, in bytes
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct DatasetUsage {
+    /// The space used by the dataset and all its descendants
+    pub used: u64,
+    /// The space available to the dataset and all its descendants
+    pub available: u64,
+    /// The space of the data that's accessible by the dataset, which may be shared with other datasets
+    pub referenced: u64,
+}
+
+/// The properties that are queried by zfs_get_dataset_usage, in the order they're passed to zfs
+const USAGE_PROPERTIES: &str = "used,available,referenced";
+
+/// Gets the used, available and referenced space of a dataset, in exact bytes
+/// Returns: Some(usage): The space of the dataset
+/// Returns: None: The dataset is not found
+/// Otherwise, an error is returned
+pub fn zfs_get_dataset_usage(
+    zfs_dataset: impl AsRef<str>,
+) -> Result<Option<DatasetUsage>, ZfsError> {
+    ZfsConfig::default().get_dataset_usage(zfs_dataset)
+}
+
+/// Interprets the result of `zfs get -Hp -o property,value <USAGE_PROPERTIES> <dataset>`
+fn parse_dataset_usage_result(
+    dataset: &str,
+    success: bool,
+    stdout: &str,
+    stderr: String,
+) -> Result<Option<DatasetUsage>, ZfsError> {
+    if !success {
+        return if is_dataset_not_found_error(&stderr) {
+            Ok(None)
+        } else {
+            Err(ZfsError::GetPropertyCallFailed(
+                dataset.to_string(),
+                USAGE_PROPERTIES.to_string(),
+                stderr,
+            ))
+        };
+    }
+
+    let values = stdout
+        .lines()
+        .map(split_output_line)
+        .filter(|v| v.len() >= 2)
+        .map(|v| (v[0], v[1]))
+        .collect::<BTreeMap<&str, &str>>();
+
+    let get = |property: &str| -> Result<u64, ZfsError> {
+        let value = values.get(property).ok_or_else(|| {
+            ZfsError::GetPropertyCallFailed(
+                dataset.to_string(),
+                property.to_string(),
+                "Property is missing from the output of zfs".to_string(),
+            )
+        })?;
+        value.trim().parse::<u64>().map_err(|_| {
+            ZfsError::UnexpectedPropertyValue(
+                dataset.to_string(),
+                property.to_string(),
+                value.to_string(),
+            )
+        })
+    };
+
+    Ok(Some(DatasetUsage {
+        used: get("used")?,
+        available: get("available")?,
+        referenced: get("referenced")?,
+    }))
+}
+
+/// A snapshot of a dataset, as listed by `zfs list -t snapshot`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Snapshot {
+    /// The full name of the snapshot, e.g., `pool/dataset@snapshot`
+    pub name: String,
+    /// The space used only by the snapshot, in bytes
+    pub used: u64,
+    /// The space of the data that's accessible by the snapshot, in bytes
+    pub referenced: u64,
+    /// The time the snapshot was created, in seconds since the Unix epoch
+    pub creation: u64,
+}
+
+/// Lists the snapshots of a dataset and all its descendants, in the order zfs lists them
+/// Returns: The snapshots, which is empty if the dataset has none
+/// Returns: Err(ZfsError::DatasetNotFound) if the dataset is not found
+/// Otherwise, an error is returned
+pub fn zfs_list_snapshots(zfs_dataset: impl AsRef<str>) -> Result<Vec<Snapshot>, ZfsError> {
+    ZfsConfig::default().list_snapshots(zfs_dataset)
+}
+
+/// Lists the tags of the holds of a snapshot, e.g., the ones that replication tools put on snapshots they send,
+/// with `zfs holds`. A snapshot with holds can't be destroyed.
+/// Returns: The tags, which is empty if the snapshot has no holds
+/// Returns: Err(ZfsError::DatasetNotFound) if the snapshot is not found
+/// Otherwise, an error is returned
+pub fn zfs_list_holds(snapshot: impl AsRef<str>) -> Result<Vec<String>, ZfsError> {
+    ZfsConfig::default().list_holds(snapshot)
+}
+
+/// Interprets the result of `zfs holds -H <snapshot>...`, whose lines are the snapshot, the tag and the time of the hold
+/// Returns: The tags of the snapshots that have holds, by snapshot
+fn parse_holds_result(
+    label: &str,
+    success: bool,
+    stdout: &str,
+    stderr: String,
+) -> Result<BTreeMap<String, Vec<String>>, ZfsError> {
+    if !success {
+        return if is_dataset_not_found_error(&stderr) {
+            Err(ZfsError::DatasetNotFound(label.to_string()))
+        } else {
+            Err(ZfsError::ListHoldsCallFailed(label.to_string(), stderr))
+        };
+    }
+
+    let mut holds = BTreeMap::<String, Vec<String>>::new();
+    for v in stdout
+        .lines()
+        .map(split_output_line)
+        .filter(|v| v.len() >= 2)
+    {
+        holds
+            .entry(v[0].to_string())
+            .or_default()
+            .push(v[1].to_string());
+    }
+    Ok(holds)
+}
+
+/// Interprets the result of `zfs list -Hp -t snapshot -o name,used,referenced,creation -r <dataset>`
+fn parse_snapshots_result(
+    dataset: &str,
+    success: bool,
+    stdout: &str,
+    stderr: String,
+) -> Result<Vec<Snapshot>, ZfsError> {
+    if !success {
+        return if is_dataset_not_found_error(&stderr) {
+            Err(ZfsError::DatasetNotFound(dataset.to_string()))
+        } else {
+            Err(ZfsError::ListSnapshotsCallFailed(
+                dataset.to_string(),
+                stderr,
+            ))
+        };
+    }
+
+    let unexpected = |line: &str| {
+        ZfsError::ListSnapshotsCallFailed(
+            dataset.to_string(),
+            format!("Unexpected line in the output of zfs: {line}"),
+        )
+    };
+
+    stdout
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let values = split_output_line(line);
+            if values.len() != 4 {
+                return Err(unexpected(line));
+            }
+            let number = |value: &str| value.trim().parse::<u64>().map_err(|_| unexpected(line));
+            Ok(Snapshot {
+                name: values[0].to_string(),
+                used: number(values[1])?,
+                referenced: number(values[2])?,
+                creation: number(values[3])?,
+            })
+        })
+        .collect()
+}
+
+/// Returns whether the stderr of `sudo -n` says that it needs a password to run the command,
+/// which means that the command isn't authorized with visudo for the user
+fn is_sudo_password_required_error(stderr: &str) -> bool {
+    const PASSWORD_REQUIRED_MESSAGES: [&str; 4] = [
+        "sudo: a password is required",
+        "sudo: a terminal is required",
+        "sudo: no tty present",
+        "doas: Authorization required", // doas -n, for a rule without nopass
+    ];
+
+    PASSWORD_REQUIRED_MESSAGES
+        .iter()
+        .any(|msg| stderr.contains(msg))
+}
+
+/// Returns whether the stderr of a zfs command says that the dataset doesn't exist
+fn is_dataset_not_found_error(stderr: &str) -> bool {
+    stderr.contains("dataset does not exist")
+}
+
+/// Gets the number of PBKDF2 iterations that derive the key of the dataset from its passphrase,
+/// e.g., to find the datasets that were created with a low iteration count
+/// Returns: Some(iterations) if the key is a passphrase
+/// Returns: None if the key isn't a passphrase, or the dataset isn't encrypted
+/// Returns: Err(ZfsError::DatasetNotFound) if the dataset doesn't exist
+pub fn zfs_get_pbkdf2iters(zfs_dataset: impl AsRef<str>) -> Result<Option<u64>, ZfsError> {
+    ZfsConfig::default().get_pbkdf2iters(zfs_dataset)
+}
+
+/// Parses the pbkdf2iters property, which zfs shows as 0 for keys that aren't passphrases,
+/// and as - for datasets that aren't encrypted
+fn parse_pbkdf2iters(dataset: &str, value: &str) -> Result<Option<u64>, ZfsError> {
+    match value.trim() {
+        "0" | "-" => Ok(None),
+        value => value.parse().map(Some).map_err(|_| {
+            ZfsError::GetPropertyCallFailed(
+                dataset.to_string(),
+                "pbkdf2iters".to_string(),
+                format!("Unexpected value: {value}"),
+            )
+        }),
+    }
+}
+
+/// Gets the mountpoint of one dataset, without listing all of them like zfs_list_datasets_mountpoints
+/// Returns: Some(path): The path where the dataset is mounted when it's mounted by zfs
+/// Returns: None: The mountpoint is none or legacy, so zfs doesn't mount it
+/// Returns: Err(ZfsError::DatasetNotFound) if the dataset is not found
+/// Otherwise, an error is returned
+pub fn zfs_get_mountpoint(zfs_dataset: impl AsRef<str>) -> Result<Option<PathBuf>, ZfsError> {
+    ZfsConfig::default().get_mountpoint(zfs_dataset)
+}
+
+/// Lists the mountpoint property of all datasets, by dataset name
+/// The names are as zfs prints them, so look them up with names passed through normalize_dataset_name.
+pub fn zfs_list_datasets_mountpoints() -> Result<BTreeMap<String, Mountpoint>, ZfsError> {
+    ZfsConfig::default().list_datasets_mountpoints()
+}
+
+/// Lists the state of all encrypted datasets, by dataset name
+/// The names are as zfs prints them, so look them up with names passed through normalize_dataset_name.
+/// Lines of the output of zfs that don't have all columns are skipped; zfs_list_encrypted_datasets_with_warnings
+/// returns them.
+pub fn zfs_list_encrypted_datasets() -> Result<BTreeMap<String, DatasetMountedState>, ZfsError> {
+    ZfsConfig::default().list_encrypted_datasets()
+}
+
+/// Lists the state of the encrypted datasets like zfs_list_encrypted_datasets, but only of root and its descendants,
+/// e.g., the datasets of one tenant in a pool shared by many, without listing the whole pool
+/// Returns: Err(ZfsError::DatasetNotFound) if root doesn't exist
+pub fn zfs_list_encrypted_datasets_under(
+    root: impl AsRef<str>,
+) -> Result<BTreeMap<String, DatasetMountedState>, ZfsError> {
+    ZfsConfig::default().list_encrypted_datasets_under(root)
+}
+
+/// Checks the mount and key states of all encrypted filesystems against each other, e.g., for monitoring,
+/// and reports the ones that shouldn't happen, like a dataset that's mounted while its key isn't loaded,
+/// or whose key is loaded while the one of its encryption root isn't
+/// Returns: Ok(inconsistencies), empty if everything is consistent
+/// Returns: Err(ZfsError::DatasetStatesCallFailed) if zfs fails
+pub fn zfs_find_inconsistent_datasets() -> Result<Vec<Inconsistency>, ZfsError> {
+    ZfsConfig::default().find_inconsistent_datasets()
+}
+
+/// Watches the encrypted datasets, e.g., to update a tray icon when a dataset is unlocked by another program
+/// A thread lists them like zfs_list_encrypted_datasets every poll_interval, and sends to the receiver a change
+/// for every dataset that was mounted, unmounted, unlocked or locked, or that appeared or disappeared, since the
+/// previous poll. The first poll only records the states; list them to get the states the watching starts from.
+/// Polls that fail are skipped. The watcher runs until the handle is stopped or dropped, or the receiver is dropped.
+pub fn zfs_watch(
+    poll_interval: Duration,
+) -> (std::sync::mpsc::Receiver<DatasetStateChange>, WatchHandle) {
+    ZfsConfig::default().watch(poll_interval)
+}
+
+/// What was skipped while parsing the output of zfs, e.g., lines of a dataset that was destroyed while zfs listed it
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ParseWarnings {
+    /// The lines that didn't have all the expected columns
+    pub skipped_lines: Vec<String>,
+}
+
+impl ParseWarnings {
+    /// Whether nothing was skipped
+    pub fn is_empty(&self) -> bool {
+        self.skipped_lines.is_empty()
+    }
+}
+
+/// Lists the state of all encrypted datasets like zfs_list_encrypted_datasets, with the lines it skipped
+pub fn zfs_list_encrypted_datasets_with_warnings(
+) -> Result<(BTreeMap<String, DatasetMountedState>, ParseWarnings), ZfsError> {
+    ZfsConfig::default().list_encrypted_datasets_with_warnings()
+}
+
+/// Lists the encrypted datasets like zfs_list_encrypted_datasets, as zfs prints them
+/// The output of zfs is parsed line by line while it runs, so it's never held in memory as a whole.
+/// If zfs fails, the last item is the error. Dropping the iterator early kills zfs.
+pub fn zfs_iter_encrypted_datasets(
+) -> Result<impl Iterator<Item = Result<DatasetMountedState, ZfsError>>, ZfsError> {
+    ZfsConfig::default().iter_encrypted_datasets()
+}
+
+/// Lists the encrypted datasets whose keys are not loaded, i.e., the ones that can be unlocked
+pub fn zfs_list_locked_datasets() -> Result<BTreeMap<String, DatasetMountedState>, ZfsError> {
+    ZfsConfig::default().list_locked_datasets()
+}
+
+/// Lists the encryption roots whose keys are not loaded, i.e., the datasets that need a passphrase to be unlocked
+/// Unlike zfs_list_locked_datasets, datasets that inherit their key from one of these roots aren't listed.
+pub fn zfs_list_lockable_roots() -> Result<Vec<String>, ZfsError> {
+    ZfsConfig::default().list_lockable_roots()
+}
+
+/// Parses the output of `zfs list -H -o name,encryptionroot,keystatus`,
+/// keeping the encryption roots whose keys are unavailable
+fn parse_lockable_roots(output: &str) -> Result<Vec<String>, ZfsError> {
+    let mut roots = Vec::new();
+    for v in output
+        .lines()
+        .map(split_output_line)
+        .filter(|v| v.len() >= 3)
+    {
+        if v[0] == v[1] && !parse_key_available_state(v[2])? {
+            roots.push(v[0].to_string());
+        }
+    }
+    Ok(roots)
+}
+
+/// Splits a line of zfs output in scripted mode (-H) into its columns
+/// zfs separates columns with tabs, which lets values like mountpoints contain spaces.
+/// Lines without any tabs are split on whitespace.
+fn split_output_line(line: &str) -> Vec<&str> {
+    if line.contains('\t') {
+        line.split('\t').collect()
+    } else {
+        line.split_whitespace().collect()
+    }
+}
+
+/// Parses the output of `zfs list -H -o name,mountpoint`
+fn parse_datasets_mountpoints(output: &str) -> BTreeMap<String, Mountpoint> {
+    output
+        .lines()
+        .map(split_output_line)
+        .filter(|v| v.len() >= 2)
+        .map(|v| (v[0].to_string(), parse_mountpoint_property(v[1])))
+        .collect()
+}
+
+/// Parses the output of `zfs list -H -o <DATASET_STATE_COLUMNS>`, skipping unencrypted datasets
+/// Only the datasets for which `keep` returns true are kept.
+/// The lines that are skipped because they're malformed are returned with the datasets.
+fn parse_encrypted_datasets(
+    output: &str,
+    keep: impl Fn(&DatasetMountedState) -> bool,
+) -> Result<(BTreeMap<String, DatasetMountedState>, ParseWarnings), ZfsError> {
+    let mut warnings = ParseWarnings::default();
+    let datasets = output
+        .lines()
+        .filter(|line| {
+            let is_malformed = !line.trim().is_empty() && split_output_line(line).len() < 3;
+            if is_malformed {
+                warnings.skipped_lines.push(line.to_string());
+            }
+            !is_malformed
+        })
+        .filter_map(parse_encrypted_dataset_line)
+        .filter(|state| state.as_ref().map_or(true, &keep))
+        .map(|state| state.map(|state| (state.dataset_name.clone(), state)))
+        .collect::<Result<_, _>>()?;
+    Ok((datasets, warnings))
+}
+
+/// Parses a line of the output of `zfs list -H -o <DATASET_STATE_COLUMNS>`
+/// Returns None for unencrypted datasets and lines that don't have all columns.
+fn parse_encrypted_dataset_line(line: &str) -> Option<Result<DatasetMountedState, ZfsError>> {
+    let v = split_output_line(line);
+    if v.len() < 3 || v[2].trim() == "-" {
+        return None;
+    }
+
+    let state = || {
+        Ok(DatasetMountedState {
+            dataset_name: v[0].to_string(),
+            is_mounted: parse_dataset_mounted_state(v[1])?,
+            is_key_loaded: parse_key_available_state(v[2])?,
+            mountpoint: v.get(4).and_then(|mountpoint| parse_mountpoint(mountpoint)),
+            is_encryption_root: is_own_encryption_root(v[0], v.get(3).copied()),
+        })
+    };
+    Some(state())
+}
+
+/// The columns of the datasets that zfs_find_inconsistent_datasets compares
+const INCONSISTENCY_COLUMNS: &str = "name,mounted,keystatus,encryptionroot";
+
+/// Finds the inconsistencies in the output of `zfs list -H -t filesystem -o <INCONSISTENCY_COLUMNS>`,
+/// in the order of the dataset names. Lines that don't have all columns are skipped.
+fn parse_inconsistent_datasets(output: &str) -> Result<Vec<Inconsistency>, ZfsError> {
+    // The key state is None for unencrypted datasets
+    let mut datasets = BTreeMap::new();
+    for v in output
+        .lines()
+        .map(split_output_line)
+        .filter(|v| v.len() >= 4)
+    {
+        let is_key_loaded = match v[2].trim() {
+            "-" => None,
+            state => Some(parse_key_available_state(state)?),
+        };
+        let is_mounted = parse_dataset_mounted_state(v[1])?;
+        datasets.insert(v[0], (is_mounted, is_key_loaded, v[3].trim()));
+    }
+
+    let mut inconsistencies = Vec::new();
+    for (&dataset, &(is_mounted, is_key_loaded, encryption_root)) in &datasets {
+        let Some(is_key_loaded) = is_key_loaded else {
+            continue;
+        };
+        if is_mounted && !is_key_loaded {
+            inconsistencies.push(Inconsistency::MountedWithoutKey {
+                dataset: dataset.to_string(),
+            });
+        }
+        if encryption_root == dataset || encryption_root == "-" {
+            continue;
+        }
+        match datasets.get(encryption_root) {
+            Some(&(_, Some(is_root_key_loaded), _)) if is_root_key_loaded != is_key_loaded => {
+                inconsistencies.push(Inconsistency::KeyStatusDiffersFromRoot {
+                    dataset: dataset.to_string(),
+                    encryption_root: encryption_root.to_string(),
+                    is_key_loaded,
+                    is_root_key_loaded,
+                })
+            }
+            Some(&(_, Some(_), _)) => (),
+            _ => inconsistencies.push(Inconsistency::InvalidEncryptionRoot {
+                dataset: dataset.to_string(),
+                encryption_root: encryption_root.to_string(),
+            }),
+        }
+    }
+    Ok(inconsistencies)
+}
+
+/// Imports a ZFS pool, after which the keys of its datasets can be loaded with zfs_load_key
+/// Returns: Ok(()) if the pool is successfully imported OR already imported
+/// Returns: Error if the pool can't be found or some other system error occurred.
+/// The command `zpool import <pool-name>` should be authorized with visudo.
+pub fn zpool_import(pool_name: impl AsRef<str>) -> Result<(), ZfsError> {
+    ZfsConfig::default().zpool_import(pool_name)
+}
+
+/// Exports a ZFS pool, which unmounts all its datasets
+/// Returns: Ok(()) if the pool is successfully exported OR not imported
+/// Returns: Error if the pool is busy or some other system error occurred.
+/// The command `zpool export <pool-name>` should be authorized with visudo.
+pub fn zpool_export(pool_name: impl AsRef<str>) -> Result<(), ZfsError> {
+    ZfsConfig::default().zpool_export(pool_name)
+}
+
+/// Exports a ZFS pool by force, with `zpool export -f`, which unmounts all its datasets even if they're in use
+/// Returns: Ok(()) if the pool is successfully exported OR not imported
+/// Returns: Error if the pool can't be exported even by force, or some other system error occurred.
+/// The command `zpool export -f <pool-name>` should be authorized with visudo.
+pub fn zpool_export_force(pool_name: impl AsRef<str>) -> Result<(), ZfsError> {
+    ZfsConfig::default().zpool_export_force(pool_name)
+}
+
+/// What zfs_prepare_pool_for_removal did, step by step
+/// A step is None if it was skipped because an earlier step failed.
+#[derive(Debug)]
+pub struct PoolRemovalReport {
+    /// The result of unmounting each filesystem of the pool that was mounted, like zfs_unmount_tree
+    pub unmounted: BTreeMap<String, Result<(), ZfsError>>,
+    /// The result of unloading the keys of all the datasets of the pool, like zfs_unload_key_recursive
+    pub keys_unloaded: Option<Result<(), ZfsError>>,
+    /// The result of exporting the pool, like zpool_export
+    pub exported: Option<Result<(), ZfsError>>,
+}
+
+impl PoolRemovalReport {
+    /// Returns whether every step succeeded, so the drive of the pool can be removed
+    pub fn is_complete(&self) -> bool {
+        self.unmounted.values().all(Result::is_ok)
+            && matches!(self.keys_unloaded, Some(Ok(())))
+            && matches!(self.exported, Some(Ok(())))
+    }
+}
+
+/// Prepares a ZFS pool for its drive to be removed, by unmounting all its filesystems, children first,
+/// then unloading all its keys, then exporting it
+/// The export isn't forced, so anything that still uses the pool makes it fail instead of losing data.
+/// Returns: Ok(report) with the result of every step, which stops at the first step that fails
+/// Returns: Error if the pool name is invalid, or its datasets can't be listed, e.g., because it's not imported
+/// The commands `zfs unmount <dataset-name>`, `zfs unload-key -r <pool-name>` and `zpool export <pool-name>`
+/// should be authorized with visudo.
+pub fn zfs_prepare_pool_for_removal(
+    pool_name: impl AsRef<str>,
+) -> Result<PoolRemovalReport, ZfsError> {
+    ZfsConfig::default().prepare_pool_for_removal(pool_name)
+}
+
+/// Interprets the result of `zpool list -H -o name <pool>`
+fn parse_pool_imported_result(pool: &str, success: bool, stderr: String) -> Result<bool, ZfsError> {
+    if success {
+        Ok(true)
+    } else if stderr.contains("no such pool") {
+        Ok(false)
+    } else {
+        Err(ZfsError::PoolImportedCheckFailed(pool.to_string(), stderr))
+    }
+}
+
+/// The health of a ZFS pool, as shown by zpool
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PoolHealth {
+    Online,
+    /// A device failed, but the pool still works with the redundancy left
+    Degraded,
+    /// The pool can't be used, as too many of its devices failed
+    Faulted,
+    Offline,
+    Removed,
+    Unavail,
+    /// I/O to the pool is stopped after failures, until it's cleared
+    Suspended,
+}
+
+/// Gets the health of an imported ZFS pool, e.g., to refuse unlocking its datasets if it's faulted
+/// Returns: Ok(health) with the health of the pool
+/// Returns: Err(ZfsError::PoolNotFound) if the pool isn't imported
+/// Otherwise, an error is returned
+pub fn zpool_status(pool_name: impl AsRef<str>) -> Result<PoolHealth, ZfsError> {
+    ZfsConfig::default().zpool_status(pool_name)
+}
+
+/// Interprets the result of `zpool list -H -o health <pool>`
+fn parse_pool_health_result(
+    pool: &str,
+    success: bool,
+    stdout: &str,
+    stderr: String,
+) -> Result<PoolHealth, ZfsError> {
+    if !success {
+        return if stderr.contains("no such pool") {
+            Err(ZfsError::PoolNotFound(pool.to_string()))
+        } else {
+            Err(ZfsError::PoolHealthCheckFailed(pool.to_string(), stderr))
+        };
+    }
+
+    match stdout.trim() {
+        "ONLINE" => Ok(PoolHealth::Online),
+        "DEGRADED" => Ok(PoolHealth::Degraded),
+        "FAULTED" => Ok(PoolHealth::Faulted),
+        "OFFLINE" => Ok(PoolHealth::Offline),
+        "REMOVED" => Ok(PoolHealth::Removed),
+        "UNAVAIL" => Ok(PoolHealth::Unavail),
+        "SUSPENDED" => Ok(PoolHealth::Suspended),
+        value => Err(ZfsError::UnexpectedPropertyValue(
+            pool.to_string(),
+            "health".to_string(),
+            value.to_string(),
+        )),
+    }
+}
+
+/// The version of the zfs userland tools, which are ordered from oldest to newest
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ZfsVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl ZfsVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl std::fmt::Display for ZfsVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Gets the version of the zfs userland tools, with `zfs version`, e.g., to check whether a flag is supported
+/// The version of the kernel module may differ from it until the machine is rebooted after an upgrade.
+/// Returns: Ok(version) with the version of zfs
+/// Returns: Error if zfs fails, e.g., because it's older than 0.8, or its output can't be parsed
+pub fn zfs_version() -> Result<ZfsVersion, ZfsError> {
+    ZfsConfig::default().zfs_version()
+}
+
+/// Parses the output of `zfs version`, whose first line is like `zfs-2.1.5-1ubuntu6~22.04.1`,
+/// followed by the version of the kernel module, like `zfs-kmod-2.1.5-1ubuntu6~22.04.1`
+fn parse_zfs_version(output: &str) -> Result<ZfsVersion, ZfsError> {
+    let unexpected = || {
+        ZfsError::VersionCallFailed(format!(
+            "Unexpected output of zfs version: {}",
+            output.trim_end()
+        ))
+    };
+
+    let version = output
+        .lines()
+        .filter(|line| !line.starts_with("zfs-kmod-"))
+        .find_map(|line| line.trim().strip_prefix("zfs-"))
+        .ok_or_else(unexpected)?;
+
+    // The release and the git revision follow the version, e.g., 2.2.0-rc4 or 2.1.4-FreeBSD_g52bad4f23
+    let version = version.split(['-', '_']).next().unwrap_or_default();
+    let mut numbers = version.split('.').map(|n| n.parse::<u32>());
+    match (
+        numbers.next(),
+        numbers.next(),
+        numbers.next(),
+        numbers.next(),
+    ) {
+        (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch)), None) => {
+            Ok(ZfsVersion::new(major, minor, patch))
+        }
+        _ => Err(unexpected()),
+    }
+}
+
+/// Returns the command that zfs_load_key would run, as program followed by arguments, without running anything
+/// The passphrase isn't part of it, as it's written to the stdin of the command.
+/// The command is returned even if it wouldn't run, e.g., because the key is already loaded.
+pub fn zfs_plan_load_key(zfs_dataset: impl AsRef<str>) -> Result<Vec<String>, ZfsError> {
+    ZfsConfig::default().plan_load_key(zfs_dataset)
+}
+
+/// Returns the command that zfs_unload_key would run, as program followed by arguments, without running anything
+/// The command is returned even if it wouldn't run, e.g., because the key is already unloaded.
+pub fn zfs_plan_unload_key(zfs_dataset: impl AsRef<str>) -> Result<Vec<String>, ZfsError> {
+    ZfsConfig::default().plan_unload_key(zfs_dataset)
+}
+
+/// Returns the command that zfs_mount_dataset would run, as program followed by arguments, without running anything
+/// The command is returned even if it wouldn't run, e.g., because the dataset is already mounted.
+pub fn zfs_plan_mount_dataset(zfs_dataset: impl AsRef<str>) -> Result<Vec<String>, ZfsError> {
+    ZfsConfig::default().plan_mount_dataset(zfs_dataset)
+}
+
+/// Returns the command that zfs_unmount_dataset would run, as program followed by arguments, without running anything
+/// The command is returned even if it wouldn't run, e.g., because the dataset is already unmounted.
+pub fn zfs_plan_unmount_dataset(zfs_dataset: impl AsRef<str>) -> Result<Vec<String>, ZfsError> {
+    ZfsConfig::default().plan_unmount_dataset(zfs_dataset)
+}
+
+/// The path of the zfs binary as sudo resolves it through its `secure_path`.
+/// sudoers rules must name the absolute path of the command they permit.
+const SUDOERS_ZFS_PATH: &str = "/usr/sbin/zfs";
+
+/// The zfs subcommands that this crate runs with `sudo -n`.
+const PRIVILEGED_ZFS_SUBCOMMANDS: [&str; 8] = [
+    "load-key",
+    "unload-key",
+    "change-key",
+    "mount",
+    "umount",
+    "create",
+    "set",
+    "send",
+];
+
+/// The path of the zpool binary as sudo resolves it through its `secure_path`.
+const SUDOERS_ZPOOL_PATH: &str = "/usr/sbin/zpool";
+
+/// The zpool subcommands that this crate runs with `sudo -n`.
+const PRIVILEGED_ZPOOL_SUBCOMMANDS: [&str; 2] = ["import", "export"];
+
+/// Returns the sudoers lines that allow the given user to run the privileged commands
+/// of this crate without a password, one rule per line, ready to be pasted into visudo.
+/// Note that the user name is not validated. It's written to the output as is.
+pub fn required_sudoers(user: &str) -> String {
+    ZfsConfig::default().required_sudoers(user)
+}
+
+/// The zfs subcommands that the user of the process can run as root with sudo, without a password
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct SudoCapabilities {
+    pub load_key: bool,
+    pub unload_key: bool,
+    pub mount: bool,
+    pub umount: bool,
+}
+
+impl SudoCapabilities {
+    /// Whether all the subcommands needed to unlock and lock datasets can be run
+    pub fn all(&self) -> bool {
+        self.load_key && self.unload_key && self.mount && self.umount
+    }
+}
+
+/// Checks which zfs subcommands the user of the process can run with sudo without a password, with `sudo -n -l`,
+/// e.g., to validate the rules of required_sudoers when installing, instead of failing when unlocking
+/// The rules are matched against the zfs binary as sudo resolves it. Rules with wildcards in the path aren't matched.
+/// sudo is run even if the config has an escalation_command, as other programs, like doas, can't list their rules.
+/// Returns: Ok(capabilities) with all of them allowed if the config doesn't use sudo,
+///          and none of them if sudo requires a password even to list the rules
+/// Returns: Error if sudo fails otherwise
+pub fn zfs_check_sudo_permissions() -> Result<SudoCapabilities, ZfsError> {
+    ZfsConfig::default().check_sudo_permissions()
+}
+
+/// Parses the output of `sudo -l`, whose rules follow a line like `User alice may run the following commands on host:`,
+/// each like `(root) NOPASSWD: /usr/sbin/zfs load-key *, /usr/sbin/zfs mount *`
+/// Runas users and tags carry over to the following commands of a rule, like in sudoers, and later rules override
+/// earlier ones, so a negated command, like `!/usr/sbin/zfs mount *`, denies what came before it.
+fn parse_sudo_list(output: &str, zfs_path: &str) -> SudoCapabilities {
+    let mut capabilities = SudoCapabilities::default();
+    let rules = output
+        .lines()
+        .skip_while(|line| !line.contains(" may run the following commands on "))
+        .skip(1)
+        .take_while(|line| !line.trim().is_empty());
+
+    for rule in rules {
+        let mut as_root = true;
+        let mut no_password = false;
+        for entry in split_sudo_rule(rule.trim()) {
+            let mut entry = entry.trim();
+            if let Some(runas) = entry.strip_prefix('(') {
+                let Some((users, rest)) = runas.split_once(')') else {
+                    break;
+                };
+                let runas_user = users.split(':').next().unwrap_or_default();
+                as_root = runas_user
+                    .split(',')
+                    .any(|user| matches!(user.trim(), "root" | "ALL"));
+                entry = rest.trim_start();
+            }
+            while let Some((tag, rest)) = entry.split_once(':') {
+                if tag.is_empty() || !tag.chars().all(|c| c.is_ascii_uppercase() || c == '_') {
+                    break;
+                }
+                match tag {
+                    "NOPASSWD" => no_password = true,
+                    "PASSWD" => no_password = false,
+                    _ => (),
+                }
+                entry = rest.trim_start();
+            }
+
+            let (allowed, command) = match entry.strip_prefix('!') {
+                Some(command) => (false, command.trim_start()),
+                None => (as_root && no_password, entry),
+            };
+            let mut words = command.split_whitespace();
+            let subcommand = match (words.next(), words.next()) {
+                (Some("ALL"), _) => None,
+                (Some(path), subcommand) if path == zfs_path => subcommand,
+                _ => continue,
+            };
+            // is_none_or would need Rust 1.82
+            #[allow(clippy::unnecessary_map_or)]
+            let set = |name: &str, capability: &mut bool| {
+                if subcommand.map_or(true, |subcommand| subcommand == name) {
+                    *capability = allowed;
+                }
+            };
+            set("load-key", &mut capabilities.load_key);
+            set("unload-key", &mut capabilities.unload_key);
+            set("mount", &mut capabilities.mount);
+            set("umount", &mut capabilities.umount);
+        }
+    }
+
+    capabilities
+}
+
+/// Splits a rule of `sudo -l` at the commas between its commands, but not at the ones in the runas list
+fn split_sudo_rule(rule: &str) -> Vec<&str> {
+    let mut entries = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    for (index, c) in rule.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                entries.push(&rule[start..index]);
+                start = index + 1;
+            }
+            _ => (),
+        }
+    }
+    entries.push(&rule[start..]);
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn basic() {
+        // Feel free to update these entries to your machine's entries to test
+        let hostname = "pitests";
+        let ds_name = "SamRandomPool/EncryptedDataset1";
+        let passphrase = "abcdefghijklmnop";
+        let mount_point = "/SamRandomPoolEncryptedDS1";
+
+        if hostname::get().unwrap().to_string_lossy().to_lowercase() == hostname.to_lowercase() {
+            // Try with a non-existent database
+            assert_eq!(zfs_is_key_loaded("some_random_stuff").unwrap(), None);
+
+            // Unmount, before messing with the key
+            zfs_unmount_dataset(ds_name).unwrap();
+
+            // Ensure the key is unloaded and db is unmounted, load it, then unload it
+            zfs_unload_key(ds_name).unwrap();
+            assert_eq!(zfs_is_key_loaded(ds_name).unwrap(), Some(false));
+            assert!(
+                !zfs_list_encrypted_datasets()
+                    .unwrap()
+                    .get(ds_name)
+                    .unwrap()
+                    .is_key_loaded
+            );
+            zfs_load_key(ds_name, passphrase).unwrap();
+            assert_eq!(zfs_is_key_loaded(ds_name).unwrap(), Some(true));
+            assert!(
+                zfs_list_encrypted_datasets()
+                    .unwrap()
+                    .get(ds_name)
+                    .unwrap()
+                    .is_key_loaded
+            );
+            zfs_unload_key(ds_name).unwrap();
+            assert!(
+                !zfs_list_encrypted_datasets()
+                    .unwrap()
+                    .get(ds_name)
+                    .unwrap()
+                    .is_key_loaded
+            );
+            assert_eq!(zfs_is_key_loaded(ds_name).unwrap(), Some(false));
+
+            zfs_load_key(ds_name, passphrase).unwrap();
+            assert!(
+                zfs_list_encrypted_datasets()
+                    .unwrap()
+                    .get(ds_name)
+                    .unwrap()
+                    .is_key_loaded
+            );
+            assert_eq!(zfs_is_key_loaded(ds_name).unwrap(), Some(true));
+
+            zfs_unmount_dataset(ds_name).unwrap();
+            assert_eq!(zfs_is_dataset_mounted(ds_name).unwrap(), Some(false));
+            assert!(
+                !zfs_list_encrypted_datasets()
+                    .unwrap()
+                    .get(ds_name)
+                    .unwrap()
+                    .is_mounted
+            );
+            zfs_mount_dataset(ds_name).unwrap();
+            assert_eq!(zfs_is_dataset_mounted(ds_name).unwrap(), Some(true));
+            assert!(
+                zfs_list_encrypted_datasets()
+                    .unwrap()
+                    .get(ds_name)
+                    .unwrap()
+                    .is_mounted
+            );
+            zfs_unmount_dataset(ds_name).unwrap();
+            assert_eq!(zfs_is_dataset_mounted(ds_name).unwrap(), Some(false));
+            assert!(
+                !zfs_list_encrypted_datasets()
+                    .unwrap()
+                    .get(ds_name)
+                    .unwrap()
+                    .is_mounted
+            );
+
+            zfs_unload_key(ds_name).unwrap();
+            assert_eq!(zfs_is_key_loaded(ds_name).unwrap(), Some(false));
+
+            let mount_points = zfs_list_datasets_mountpoints().unwrap();
+            assert_eq!(
+                mount_points.get(ds_name).unwrap(),
+                &Mountpoint::Path(PathBuf::from(mount_point)),
+            );
+        } else {
+            let err = "WARNING: No tests were run. Update the tests to test on your machine.";
+            println!("{}", err);
+            eprintln!("{}", err);
+        }
+    }
+
+    #[test]
+    fn test_valid_zfs_dataset_names() {
+        let f = check_and_sanitize_zfs_dataset_name;
+
+        f("pool/dataset1").unwrap();
+        f("pool/dataset_2").unwrap();
+        f("pool.dataset/dataset-3").unwrap();
+        f("pool.dataset/dataset:3").unwrap();
+        f("pool:1/dataset.with.multiple.levels").unwrap();
+        f(" pool:1/dataset.with.multiple.levels").unwrap();
+        f(" pool:1/dataset.with.multiple.levels  ").unwrap();
+    }
+
+    #[test]
+    fn normalized_dataset_names() {
+        let listed = parse_datasets_mountpoints("pool/dataset\t/mnt/dataset\n");
+        let name = normalize_dataset_name("  pool/dataset\n").unwrap();
+        assert_eq!(name, "pool/dataset");
+        assert_eq!(listed[&name].path(), Some(Path::new("/mnt/dataset")));
+
+        assert!(matches!(
+            normalize_dataset_name("pool/data set"),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_zfs_dataset_names() {
+        let f = check_and_sanitize_zfs_dataset_name;
+
+        f("").unwrap_err();
+        f("_R").unwrap_err();
+        f("-R").unwrap_err();
+        f(":R").unwrap_err();
+        f(".R").unwrap_err();
+        f(" _R").unwrap_err();
+        f(" -R").unwrap_err();
+        f(" :R").unwrap_err();
+        f(" .R").unwrap_err();
+        f("pool/_R").unwrap_err();
+        f("pool/-R").unwrap_err();
+        f("pool/:R").unwrap_err();
+        f("pool/.R").unwrap_err();
+        f("pool/ _R").unwrap_err();
+        f("pool/ -R").unwrap_err();
+        f("pool/ :R").unwrap_err();
+        f("pool/ .R").unwrap_err();
+        f("pool/dataset name").unwrap_err();
+        f("pool/dataset!").unwrap_err();
+        f("pool/dataset@name").unwrap_err();
+        f("pool//dataset").unwrap_err();
+        f("pool/ dataset").unwrap_err();
+    }
+
+    #[test]
+    fn test_invalid_whole_zfs_dataset_names() {
+        let f = check_and_sanitize_zfs_dataset_name;
+
+        // Every part is valid on its own, but the name as a whole isn't
+        f("1pool/dataset").unwrap_err();
+        f("1pool").unwrap_err();
+        f("/pool/dataset").unwrap_err();
+        f("pool/dataset/").unwrap_err();
+        f("pool/dataset//").unwrap_err();
+        f("/").unwrap_err();
+        f(" /pool/dataset ").unwrap_err();
+
+        f("pool1/1dataset").unwrap();
+        f("p/d").unwrap();
+    }
+
+    #[test]
+    fn test_too_long_zfs_dataset_names() {
+        let f = check_and_sanitize_zfs_dataset_name;
+
+        let name = format!("pool/{}", "a".repeat(250));
+        assert_eq!(name.len(), MAX_DATASET_NAME_LEN);
+        f(&name).unwrap();
+
+        let name = format!("pool/{}", "a".repeat(295));
+        assert_eq!(name.len(), 300);
+        assert!(matches!(
+            f(&name),
+            Err(ZfsError::DatasetNameIsInvalid(e)) if e.contains("longer than 255 bytes")
+        ));
+
+        // 49 slashes is as deep as zfs allows
+        let name = format!("pool{}", "/d".repeat(49));
+        f(&name).unwrap();
+
+        let name = format!("pool{}", "/d".repeat(50));
+        assert!(matches!(
+            f(&name),
+            Err(ZfsError::DatasetNameIsInvalid(e)) if e.contains("nested deeper than 50 levels")
+        ));
+    }
+
+    #[test]
+    fn zpool_names() {
+        assert_eq!(check_and_sanitize_zpool_name("tank").unwrap(), "tank");
+        assert_eq!(check_and_sanitize_zpool_name(" tank2 ").unwrap(), "tank2");
+        assert_eq!(check_and_sanitize_zpool_name("my-pool").unwrap(), "my-pool");
+
+        for name in [
+            "",
+            "tank/dataset",
+            "tank/",
+            "1tank",
+            "-tank",
+            "tank pool",
+            "tank;reboot",
+        ] {
+            assert!(matches!(
+                check_and_sanitize_zpool_name(name),
+                Err(ZfsError::PoolNameIsInvalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn pool_imported_result() {
+        let f = parse_pool_imported_result;
+
+        assert!(f("tank", true, String::new()).unwrap());
+        assert!(!f(
+            "tank",
+            false,
+            "cannot open 'tank': no such pool\n".to_string()
+        )
+        .unwrap());
+        f("tank", false, "permission denied\n".to_string()).unwrap_err();
+    }
+
+    #[test]
+    fn unmount_order() {
+        let output = "pool/a\tyes\n\
+                      pool/a/b\tyes\n\
+                      pool/a/b/c\tyes\n\
+                      pool/a/d\tno\n\
+                      pool/a/e\tyes\n";
+        assert_eq!(
+            parse_unmount_order(output).unwrap(),
+            ["pool/a/b/c", "pool/a/b", "pool/a/e", "pool/a"]
+        );
+        assert_eq!(parse_unmount_order("").unwrap(), Vec::<String>::new());
+        assert!(matches!(
+            parse_unmount_order("pool/a\tmaybe\n"),
+            Err(ZfsError::UnexpectedStateForMount(_))
+        ));
+    }
+
+    #[test]
+    fn zfs_version_output() {
+        assert_eq!(
+            parse_zfs_version("zfs-2.1.5-1ubuntu6~22.04.1\nzfs-kmod-2.1.5-1ubuntu6~22.04.1\n")
+                .unwrap(),
+            ZfsVersion::new(2, 1, 5)
+        );
+        assert_eq!(
+            parse_zfs_version("zfs-2.2.0-rc4\nzfs-kmod-2.1.12-1\n").unwrap(),
+            ZfsVersion::new(2, 2, 0)
+        );
+        assert_eq!(
+            parse_zfs_version("zfs-2.1.4-FreeBSD_g52bad4f23\nzfs-kmod-2.1.4-FreeBSD_g52bad4f23\n")
+                .unwrap(),
+            ZfsVersion::new(2, 1, 4)
+        );
+        // Only the kernel module is listed if the userland tools can't tell their version
+        parse_zfs_version("zfs-kmod-2.1.5-1\n").unwrap_err();
+        parse_zfs_version("").unwrap_err();
+        parse_zfs_version("zfs-2.1\n").unwrap_err();
+        parse_zfs_version("zfs-two\n").unwrap_err();
+
+        assert!(ZfsVersion::new(2, 1, 5) < ZfsVersion::new(2, 2, 0));
+        assert!(ZfsVersion::new(0, 8, 6) < ZfsVersion::new(2, 0, 0));
+        assert!(ZfsVersion::new(2, 1, 10) > ZfsVersion::new(2, 1, 9));
+        assert_eq!(ZfsVersion::new(2, 1, 5).to_string(), "2.1.5");
+    }
+
+    #[test]
+    fn pool_health_result() {
+        let f = parse_pool_health_result;
+
+        assert_eq!(
+            f("tank", true, "ONLINE\n", String::new()).unwrap(),
+            PoolHealth::Online
+        );
+        assert_eq!(
+            f("tank", true, "DEGRADED\n", String::new()).unwrap(),
+            PoolHealth::Degraded
+        );
+        assert_eq!(
+            f("tank", true, "FAULTED\n", String::new()).unwrap(),
+            PoolHealth::Faulted
+        );
+        assert!(matches!(
+            f(
+                "tank",
+                false,
+                "",
+                "cannot open 'tank': no such pool\n".to_string()
+            ),
+            Err(ZfsError::PoolNotFound(_))
+        ));
+        assert!(matches!(
+            f("tank", false, "", "permission denied\n".to_string()),
+            Err(ZfsError::PoolHealthCheckFailed(_, _))
+        ));
+        assert!(matches!(
+            f("tank", true, "MELTING\n", String::new()),
+            Err(ZfsError::UnexpectedPropertyValue(_, _, _))
+        ));
+    }
+
+    #[test]
+    fn dataset_locks() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let released = AtomicBool::new(false);
+        let lock = lock_dataset("locktest/dataset1");
+
+        std::thread::scope(|scope| {
+            // Another dataset isn't blocked
+            scope
+                .spawn(|| drop(lock_dataset("locktest/dataset2")))
+                .join()
+                .unwrap();
+
+            let waiter = scope.spawn(|| {
+                let _lock = lock_dataset("locktest/dataset1");
+                assert!(released.load(Ordering::SeqCst));
+            });
+
+            std::thread::sleep(Duration::from_millis(50));
+            released.store(true, Ordering::SeqCst);
+            drop(lock);
+            waiter.join().unwrap();
+        });
+
+        drop(lock_dataset("locktest/dataset1"));
+    }
+
+    #[test]
+    fn retries() {
+        let transient = || {
+            ZfsError::MountCmdFailed(
+                "pool/dataset".to_string(),
+                CommandFailure::spawn_failed(String::new()),
+            )
+        };
+        let backoff = Duration::from_millis(1);
+
+        // Succeeds on the third attempt
+        let mut calls = 0;
+        let result = retry_with_backoff(5, backoff, is_transient_mount_error, || {
+            calls += 1;
+            if calls < 3 {
+                Err(transient())
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        // Gives up after all attempts, with the last error
+        let mut calls = 0;
+        let result: Result<(), _> =
+            retry_with_backoff(4, backoff, is_transient_mount_error, || {
+                calls += 1;
+                Err(transient())
+            });
+        assert!(matches!(result, Err(ZfsError::MountCmdFailed(_, _))));
+        assert_eq!(calls, 4);
+
+        // Permanent errors aren't retried
+        let mut calls = 0;
+        let result: Result<(), _> =
+            retry_with_backoff(4, backoff, is_transient_mount_error, || {
+                calls += 1;
+                Err(ZfsError::KeyNotLoadedForMount("pool/dataset".to_string()))
+            });
+        assert!(matches!(result, Err(ZfsError::KeyNotLoadedForMount(_))));
+        assert_eq!(calls, 1);
+
+        // Zero attempts still runs once
+        let mut calls = 0;
+        let result: Result<(), _> =
+            retry_with_backoff(0, backoff, is_transient_mount_error, || {
+                calls += 1;
+                Err(transient())
+            });
+        result.unwrap_err();
+        assert_eq!(calls, 1);
+
+        assert!(!is_transient_mount_error(&ZfsError::DatasetNotFound(
+            "pool/dataset".to_string()
+        )));
+        assert!(!is_transient_mount_error(&ZfsError::MountpointOccupied(
+            "pool/dataset".to_string(),
+            PathBuf::from("/mnt")
+        )));
+        assert!(is_transient_mount_error(&ZfsError::CommandTimedOut(
+            "pool/dataset".to_string(),
+            Duration::from_secs(1)
+        )));
+    }
+
+    #[test]
+    fn properties() {
+        assert_eq!(
+            check_and_sanitize_property("keylocation", "file:///root/keys/pool.key").unwrap(),
+            "keylocation=file:///root/keys/pool.key"
+        );
+        assert_eq!(
+            check_and_sanitize_property(" com.example:owner ", " alice ").unwrap(),
+            "com.example:owner=alice"
+        );
+
+        for (name, value) in [
+            ("", "lz4"),
+            ("compression", ""),
+            ("-o", "lz4"),
+            ("compression=lz4", "lz4"),
+            ("compression", "lz4 pool/other"),
+            ("compression", "lz4;reboot"),
+            ("keylocation", "https://example.com/key?id=1"),
+        ] {
+            assert!(matches!(
+                check_and_sanitize_property(name, value),
+                Err(ZfsError::DatasetPropertyIsInvalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn create_opts() {
+        assert_eq!(
+            CreateOpts::default().to_properties().unwrap(),
+            [
+                "encryption=on",
+                "keyformat=passphrase",
+                "keylocation=prompt"
+            ]
+        );
+
+        let opts = CreateOpts {
+            mountpoint: Some(PathBuf::from("/home/alice")),
+            encryption: Some(EncryptionType::Aes256Gcm),
+            properties: vec![
+                ("compression".to_string(), "lz4".to_string()),
+                ("com.example:owner".to_string(), "alice".to_string()),
+            ],
+        };
+        assert_eq!(
+            opts.to_properties().unwrap(),
+            [
+                "encryption=aes-256-gcm",
+                "keyformat=passphrase",
+                "keylocation=prompt",
+                "mountpoint=/home/alice",
+                "compression=lz4",
+                "com.example:owner=alice"
+            ]
+        );
+
+        let invalid_opts = [
+            CreateOpts {
+                encryption: Some(EncryptionType::Off),
+                ..Default::default()
+            },
+            CreateOpts {
+                mountpoint: Some(PathBuf::from("home/alice")),
+                ..Default::default()
+            },
+            CreateOpts {
+                mountpoint: Some(PathBuf::from("/home/alice smith")),
+                ..Default::default()
+            },
+            CreateOpts {
+                properties: vec![("keyformat".to_string(), "raw".to_string())],
+                ..Default::default()
+            },
+            CreateOpts {
+                properties: vec![("mountpoint".to_string(), "/mnt".to_string())],
+                ..Default::default()
+            },
+            CreateOpts {
+                properties: vec![("-o".to_string(), "lz4".to_string())],
+                ..Default::default()
+            },
+            CreateOpts {
+                properties: vec![("compression".to_string(), String::new())],
+                ..Default::default()
+            },
+            CreateOpts {
+                properties: vec![("compression".to_string(), "lz4 -f".to_string())],
+                ..Default::default()
+            },
+        ];
+        for opts in invalid_opts {
+            assert!(matches!(
+                opts.to_properties(),
+                Err(ZfsError::DatasetPropertyIsInvalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn mount_options() {
+        let f = check_and_sanitize_mount_option;
+
+        assert_eq!(f("ro").unwrap(), "ro");
+        assert_eq!(f(" noatime ").unwrap(), "noatime");
+        assert_eq!(f("ro,noexec").unwrap(), "ro,noexec");
+        assert_eq!(
+            f("context=system_u:object_r:fs_t:s0").unwrap(),
+            "context=system_u:object_r:fs_t:s0"
+        );
+
+        f("").unwrap_err();
+        f("-f").unwrap_err();
+        f(",ro").unwrap_err();
+        f("ro noexec").unwrap_err();
+        f("ro;reboot").unwrap_err();
+        f("ro$(reboot)").unwrap_err();
+        f("mountpoint=/tmp").unwrap_err();
+
+        assert!(matches!(
+            zfs_mount_dataset_with_options("pool/dataset", &["ro", "-f"]),
+            Err(ZfsError::MountOptionIsInvalid(_))
+        ));
+    }
+
+    #[test]
+    fn canmount_property() {
+        let f = |value: &str| parse_canmount_property("pool/dataset", value);
+
+        assert_eq!(f("on\n").unwrap(), CanMount::On);
+        assert_eq!(f("off").unwrap(), CanMount::Off);
+        assert_eq!(f("-").unwrap(), CanMount::Off);
+        assert_eq!(f("noauto").unwrap(), CanMount::NoAuto);
+        assert!(matches!(
+            f("maybe"),
+            Err(ZfsError::UnexpectedPropertyValue(..))
+        ));
+    }
+
+    #[test]
+    fn key_locations() {
+        for location in [
+            "prompt",
+            " prompt ",
+            "file:///root/keys/pool.key",
+            "https://keys.example.com/pool/enc",
+            "https://keys.example.com:8443/key?dataset=pool%2Fenc&version=2",
+            "http://10.0.0.1/~admin/key",
+        ] {
+            assert_eq!(
+                check_and_sanitize_key_location(location).unwrap(),
+                location.trim()
+            );
+        }
+
+        for location in [
+            "",
+            "Prompt",
+            "promptly",
+            "-L",
+            "/root/keys/pool.key",
+            "file://",
+            "file://root/keys/pool.key",
+            "file:///",
+            "https://",
+            "https:///key",
+            "ftp://keys.example.com/key",
+            "https://keys.example.com/a key",
+            "https://keys.example.com/key;reboot",
+            "https://keys.example.com/$(id)",
+            "https://keys.example.com/'key'",
+            "https://keys.example.com/key\nprompt",
+        ] {
+            assert!(matches!(
+                check_and_sanitize_key_location(location),
+                Err(ZfsError::KeyLocationIsInvalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn temporary_mountpoints() {
+        let f = |path: &str| check_and_sanitize_mountpoint(Path::new(path));
+
+        assert_eq!(f("/mnt/recovery").unwrap(), "mountpoint=/mnt/recovery");
+        assert_eq!(
+            f("/mnt/pool_1.old:2").unwrap(),
+            "mountpoint=/mnt/pool_1.old:2"
+        );
+
+        f("/").unwrap_err();
+        f("").unwrap_err();
+        f("mnt/recovery").unwrap_err();
+        f("/mnt/recovery/").unwrap_err();
+        f("/mnt//recovery").unwrap_err();
+        f("/mnt/../etc").unwrap_err();
+        f("/mnt/.hidden").unwrap_err();
+        f("/mnt/a,exec").unwrap_err();
+        f("/mnt/my recovery").unwrap_err();
+        f("/mnt/$(reboot)").unwrap_err();
+
+        assert!(matches!(
+            zfs_mount_at("pool/dataset", Path::new("/mnt/a,b"), &[]),
+            Err(ZfsError::MountpointIsInvalid(_))
+        ));
+        assert!(matches!(
+            zfs_mount_at("pool/dataset", Path::new("/mnt/a"), &["mountpoint=x"]),
+            Err(ZfsError::MountOptionIsInvalid(_))
+        ));
+    }
+
+    /// The characters of the generated names, mostly the ones that matter to the sanitizers, with some that
+    /// shells interpret
+    const NAME_CHARS: &[char] = &[
+        'a', 'Z', 'q', '0', '9', '-', '_', '.', ':', '/', '/', '@', '#', ' ', '\t', '\n', '$', '`',
+        ';', '\'', '"', '|', '&', '*', '=', ',', '\\', '\0', 'é', '\u{202e}',
+    ];
+
+    /// Names of NAME_CHARS, which are mostly rejected, and names shaped like datasets, which are often accepted,
+    /// so that the properties are checked on both sides of the sanitizers
+    fn names() -> impl Strategy<Value = String> {
+        prop_oneof![
+            proptest::collection::vec(proptest::sample::select(NAME_CHARS), 0..24)
+                .prop_map(String::from_iter),
+            "[a-zA-Z0-9][a-zA-Z0-9_.:-]{0,6}(/[a-zA-Z0-9_.:-]{1,6}){0,3}(@[a-z0-9_.:-]{1,6})?",
+        ]
+    }
+
+    fn is_safe_part(part: &str) -> bool {
+        part.starts_with(|c: char| c.is_ascii_alphanumeric())
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || ALLOWED_SYMBOLS.contains(&c))
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(4096))]
+
+        #[test]
+        fn random_dataset_names(name in names()) {
+            if let Ok(dataset) = check_and_sanitize_zfs_dataset_name(&name) {
+                prop_assert_eq!(&dataset, name.trim());
+                prop_assert!(dataset.starts_with(|c: char| c.is_ascii_alphabetic()));
+                prop_assert!(dataset.split('/').all(is_safe_part));
+                prop_assert!(check_and_sanitize_zpool_name(&name).is_ok() != dataset.contains('/'));
+            } else {
+                prop_assert!(check_and_sanitize_zpool_name(&name).is_err());
+            }
+        }
+
+        #[test]
+        fn random_snapshot_names(name in names()) {
+            let snapshot = format!("pool/{name}");
+            if let Ok(snapshot) = check_and_sanitize_zfs_snapshot_name(&snapshot) {
+                let (dataset, name) = snapshot.split_once('@').unwrap();
+                prop_assert!(check_and_sanitize_zfs_dataset_name(dataset).is_ok());
+                prop_assert!(is_safe_part(name));
+            }
+        }
+
+        #[test]
+        fn random_mount_options(name in names()) {
+            if let Ok(option) = check_and_sanitize_mount_option(&name) {
+                prop_assert!(!option.starts_with('-'));
+                prop_assert!(option.split([',', '=']).all(|part| part.is_empty()
+                    || part
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || ALLOWED_SYMBOLS.contains(&c))));
+            }
+        }
+    }
+
+    #[test]
+    fn snapshot_names() {
+        let f = check_and_sanitize_zfs_snapshot_name;
+
+        assert_eq!(
+            f(" pool/dataset@daily-2024.01.01 ").unwrap(),
+            "pool/dataset@daily-2024.01.01"
+        );
+        assert_eq!(f("pool/ds@snap").unwrap(), "pool/ds@snap");
+        f("pool@snap:1").unwrap();
+
+        f("pool/ds@").unwrap_err();
+        f("pool/ds@a@b").unwrap_err();
+        f("pool/ds#bookmark").unwrap_err();
+        f("pool/ds@snap#bookmark").unwrap_err();
+        f("pool/dataset").unwrap_err();
+        f("pool/dataset@").unwrap_err();
+        f("@snap").unwrap_err();
+        f("pool/dataset@-snap").unwrap_err();
+        f("pool/dataset@snap@again").unwrap_err();
+        f("pool/dataset@snap name").unwrap_err();
+        f("pool/dataset@snap;rm").unwrap_err();
+        f("pool/dataset@snap/child").unwrap_err();
+        f(&format!("pool@{}", "a".repeat(MAX_DATASET_NAME_LEN))).unwrap_err();
+    }
+
+    #[test]
+    fn redacted_secrets() {
+        assert_eq!(
+            redact_secrets("Failed to open 'file:///keys/pool.key': No such file"),
+            "Failed to open '<redacted>': No such file"
+        );
+        assert_eq!(
+            redact_secrets("keylocation=https://keys.example.com/a?b=c, retrying"),
+            "keylocation=<redacted>, retrying"
+        );
+        let key = "0123456789abcdef".repeat(4);
+        assert_eq!(
+            redact_secrets(&format!("key {key}.\nnext line")),
+            "key <redacted>.\nnext line"
+        );
+        assert_eq!(
+            redact_secrets("cannot open 'pool/dataset': dataset does not exist"),
+            "cannot open 'pool/dataset': dataset does not exist"
+        );
+        assert_eq!(redact_secrets("deadbeef cafe"), "deadbeef cafe");
+
+        let error = ZfsError::LoadKeyCmdFailed(
+            "pool/dataset".to_string(),
+            CommandFailure {
+                code: Some(255),
+                stderr: "Failed to open key material file at file:///keys/pool.key\n".to_string(),
+            },
+        );
+        assert_eq!(
+            error.redacted_display(),
+            "Load key command for dataset pool/dataset failed: \
+             Failed to open key material file at <redacted> (exit code 255)"
+        );
+        assert!(error.stderr().unwrap().contains("file:///keys/pool.key"));
+    }
+
+    #[test]
+    fn mountpoint_datasets() {
+        let mountpoints = parse_datasets_mountpoints(
+            "tank\t/tank\n\
+             tank/home\t/tank/home\n\
+             tank/home/alice\t/tank/home/alice\n\
+             rpool/ROOT/a\t/\n\
+             rpool/ROOT/b\t/\n\
+             tank/legacy\tlegacy\n",
+        );
+        let f = |path: &str| datasets_at_mountpoint(&mountpoints, Path::new(path));
+
+        assert_eq!(f("/tank/home"), ["tank/home"]);
+        assert_eq!(f("/tank/home/"), ["tank/home"]);
+        assert_eq!(f("/"), ["rpool/ROOT/a", "rpool/ROOT/b"]);
+        assert!(f("/tank/home/alice/docs").is_empty());
+        assert!(f("/tank/ho").is_empty());
+        assert!(f("legacy").is_empty());
+    }
+
+    #[test]
+    fn holds_result() {
+        let f = parse_holds_result;
+
+        let output = "pool/a@snap1\tsyncoid\tThu Jan  4 10:00 2024\n\
+                      pool/a@snap1\tkeep\tThu Jan  4 11:00 2024\n\
+                      pool/a/b@snap1\tzrepl_step\tFri Jan  5 10:00 2024\n";
+        let holds = f("pool/a", true, output, String::new()).unwrap();
+        assert_eq!(holds["pool/a@snap1"], ["syncoid", "keep"]);
+        assert_eq!(holds["pool/a/b@snap1"], ["zrepl_step"]);
+        assert_eq!(holds.len(), 2);
+
+        assert!(f("pool/a", true, "", String::new()).unwrap().is_empty());
+        assert!(matches!(
+            f(
+                "pool/a@snap1",
+                false,
+                "",
+                "cannot open 'pool/a@snap1': dataset does not exist\n".to_string()
+            ),
+            Err(ZfsError::DatasetNotFound(_))
+        ));
+        assert!(matches!(
+            f("pool/a", false, "", "permission denied\n".to_string()),
+            Err(ZfsError::ListHoldsCallFailed(..))
+        ));
+    }
+
+    #[test]
+    fn datasets_table() {
+        let state = |name: &str, is_mounted: bool, is_key_loaded: bool| {
+            (
+                name.to_string(),
+                DatasetMountedState {
+                    dataset_name: name.to_string(),
+                    is_mounted,
+                    is_key_loaded,
+                    mountpoint: None,
+                    is_encryption_root: true,
+                },
+            )
+        };
+
+        let datasets = BTreeMap::from([
+            state("pool/enc", true, true),
+            state("pool/enc/a-longer-name", false, true),
+            state("tank", false, false),
+        ]);
+        assert_eq!(
+            format_datasets_table(&datasets),
+            "NAME                    MOUNTED  KEY LOADED\n\
+             pool/enc                yes      yes\n\
+             pool/enc/a-longer-name  no       yes\n\
+             tank                    no       no\n"
+        );
+
+        assert_eq!(
+            format_datasets_table(&BTreeMap::from([state("p", false, false)])),
+            "NAME  MOUNTED  KEY LOADED\n\
+             p     no       no\n"
+        );
+        assert_eq!(
+            format_datasets_table(&BTreeMap::new()),
+            "NAME  MOUNTED  KEY LOADED\n"
+        );
+    }
+
+    #[test]
+    fn sudoers_lines() {
+        assert_eq!(
+            required_sudoers("zfsunlocker"),
+            "zfsunlocker ALL=(root) NOPASSWD: /usr/sbin/zfs load-key *\n\
+             zfsunlocker ALL=(root) NOPASSWD: /usr/sbin/zfs unload-key *\n\
+             zfsunlocker ALL=(root) NOPASSWD: /usr/sbin/zfs change-key *\n\
+             zfsunlocker ALL=(root) NOPASSWD: /usr/sbin/zfs mount *\n\
+             zfsunlocker ALL=(root) NOPASSWD: /usr/sbin/zfs umount *\n\
+             zfsunlocker ALL=(root) NOPASSWD: /usr/sbin/zfs create *\n\
+             zfsunlocker ALL=(root) NOPASSWD: /usr/sbin/zfs set *\n\
+             zfsunlocker ALL=(root) NOPASSWD: /usr/sbin/zfs send *\n\
+             zfsunlocker ALL=(root) NOPASSWD: /usr/sbin/zpool import *\n\
+             zfsunlocker ALL=(root) NOPASSWD: /usr/sbin/zpool export *\n"
+        );
+    }
+
+    #[test]
+    fn sudo_list() {
+        let zfs = "/usr/sbin/zfs";
+        let header = "Matching Defaults entries for alice on host:\n    \
+                      env_reset, secure_path=/usr/local/sbin\\:/usr/sbin\\:/usr/bin\n\n\
+                      User alice may run the following commands on host:\n";
+
+        assert_eq!(
+            parse_sudo_list(
+                &format!("{header}    (root) NOPASSWD: /usr/sbin/zfs load-key *, /usr/sbin/zfs mount *\n"),
+                zfs
+            ),
+            SudoCapabilities {
+                load_key: true,
+                unload_key: false,
+                mount: true,
+                umount: false,
+            }
+        );
+        assert!(parse_sudo_list(
+            &format!(
+                "{header}    (root) NOPASSWD: /usr/sbin/zfs load-key *\n    \
+                 (root) NOPASSWD: /usr/sbin/zfs unload-key *\n    \
+                 (root, admin) NOPASSWD: /usr/sbin/zfs mount *, /usr/sbin/zfs umount *\n"
+            ),
+            zfs
+        )
+        .all());
+        assert!(parse_sudo_list(&format!("{header}    (ALL : ALL) NOPASSWD: ALL\n"), zfs).all());
+        assert!(parse_sudo_list(
+            &format!("{header}    (root) NOPASSWD: /usr/sbin/zfs\n"),
+            zfs
+        )
+        .all());
+
+        // Needs a password, runs as another user, another binary, or before the rules
+        assert_eq!(
+            parse_sudo_list(
+                &format!(
+                    "{header}    (ALL : ALL) ALL\n    \
+                     (bob) NOPASSWD: /usr/sbin/zfs mount *\n    \
+                     (root) NOPASSWD: /usr/bin/zfs umount *\n"
+                ),
+                zfs
+            ),
+            SudoCapabilities::default()
+        );
+        assert_eq!(
+            parse_sudo_list("(root) NOPASSWD: /usr/sbin/zfs mount *\n", zfs),
+            SudoCapabilities::default()
+        );
+
+        // Later rules and negated commands override earlier ones
+        let capabilities = parse_sudo_list(
+            &format!(
+                "{header}    (root) NOPASSWD: /usr/sbin/zfs, !/usr/sbin/zfs umount *\n    \
+                 (root) PASSWD: /usr/sbin/zfs unload-key *\n"
+            ),
+            zfs,
+        );
+        assert_eq!(
+            capabilities,
+            SudoCapabilities {
+                load_key: true,
+                unload_key: false,
+                mount: true,
+                umount: false,
+            }
+        );
+    }
+
+    #[test]
+    fn key_for_keyformat() {
+        let f = check_key_for_keyformat;
+        let ds = "pool/dataset";
+
+        assert!(f(ds, "passphrase", b"abcdefghijklmnop").unwrap());
+        assert!(f(ds, " passphrase ", b"abcdefghijklmnop").unwrap());
+        assert!(!f(ds, "raw", &[0xAB; 32]).unwrap());
+        assert!(!f(ds, "hex", &[b'a'; 64]).unwrap());
+        assert!(!f(
+            ds,
+            "hex",
+            "0123456789abcdefABCDEF0123456789abcdefABCDEF0123456789abcdef0123".as_bytes()
+        )
+        .unwrap());
+
+        f(ds, "raw", &[0xAB; 31]).unwrap_err();
+        f(ds, "raw", &[0xAB; 33]).unwrap_err();
+        f(ds, "raw", b"").unwrap_err();
+        f(ds, "hex", &[b'a'; 63]).unwrap_err();
+        f(ds, "hex", &[b'a'; 65]).unwrap_err();
+        f(ds, "hex", &[b'g'; 64]).unwrap_err();
+        f(ds, "hex", &[0xAB; 32]).unwrap_err();
+        f(ds, "none", b"abcdefghijklmnop").unwrap_err();
+        f(ds, "-", b"abcdefghijklmnop").unwrap_err();
+        assert!(matches!(
+            f(ds, "passphrase", b""),
+            Err(ZfsError::EmptyPassphrase(_))
+        ));
+        assert!(matches!(
+            f(ds, "passphrase", b" \t\n"),
+            Err(ZfsError::EmptyPassphrase(_))
+        ));
+    }
+
+    #[test]
+    fn empty_passphrase() {
+        let ds = "pool/dataset";
+
+        check_passphrase(ds, "abcdefghijklmnop").unwrap();
+        check_passphrase(ds, " abcdefghijklmnop ").unwrap();
+        assert!(matches!(
+            check_passphrase(ds, ""),
+            Err(ZfsError::EmptyPassphrase(_))
+        ));
+        assert!(matches!(
+            check_passphrase(ds, "  \t "),
+            Err(ZfsError::EmptyPassphrase(_))
+        ));
+        assert!(matches!(
+            zfs_load_key(ds, ""),
+            Err(ZfsError::EmptyPassphrase(_))
+        ));
+        assert!(matches!(
+            zfs_change_key(ds, " "),
+            Err(ZfsError::EmptyPassphrase(_))
+        ));
+        assert!(matches!(
+            zfs_load_key_bytes(ds, b"", false),
+            Err(ZfsError::EmptyPassphrase(_))
+        ));
+    }
+
+    #[test]
+    fn unavailable_key_file() {
+        let ds = "pool/dataset";
+
+        assert!(matches!(
+            zfs_load_key_from_file(ds, "/some/non/existent/key/file"),
+            Err(ZfsError::KeyFileUnavailable(_, _))
+        ));
+        assert!(matches!(
+            zfs_load_key_from_file(ds, std::env::temp_dir()),
+            Err(ZfsError::KeyFileUnavailable(_, _))
+        ));
+        assert!(matches!(
+            zfs_load_key_from_file("pool/dataset name", "/some/non/existent/key/file"),
+            Err(ZfsError::DatasetNameIsInvalid(_))
+        ));
+    }
+
+    #[test]
+    fn command_output() {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("cat >&2; exit 3");
+        let output = run_command(
+            command,
+            b"some input",
+            WaitLimit::NONE,
+            "pool/dataset",
+            ZfsError::SystemError,
+        )
+        .unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stderr, "some input");
+
+        let failure = output.failure();
+        assert_eq!(
+            failure,
+            CommandFailure {
+                code: Some(3),
+                stderr: "some input".to_string()
+            }
+        );
+        assert_eq!(failure.to_string(), "some input (exit code 3)");
+        assert_eq!(
+            ZfsError::MountCmdFailed("pool/dataset".to_string(), failure).to_string(),
+            "Mount command for dataset pool/dataset failed: some input (exit code 3)"
+        );
+
+        let command = Command::new("/some/non/existent/binary");
+        assert!(matches!(
+            run_command(
+                command,
+                &[],
+                WaitLimit::NONE,
+                "pool/dataset",
+                ZfsError::SystemError
+            ),
+            Err(ZfsError::ZfsBinaryNotFound(path)) if path == Path::new("/some/non/existent/binary")
+        ));
+    }
+
+    #[test]
+    fn stderr_summaries() {
+        assert_eq!(summarize_stderr(""), "");
+        assert_eq!(summarize_stderr("permission denied\n"), "permission denied");
+        assert_eq!(
+            summarize_stderr(
+                "\ncannot mount 'pool/dataset': directory is not empty\nuse 'zfs mount -O'\n"
+            ),
+            "cannot mount 'pool/dataset': directory is not empty [...]"
+        );
+        let long_line = "é".repeat(MAX_STDERR_SUMMARY_LEN + 1);
+        assert_eq!(
+            summarize_stderr(&long_line),
+            format!("{} [...]", "é".repeat(MAX_STDERR_SUMMARY_LEN))
+        );
+
+        let stderr = "cannot open 'pool/dataset': permission denied\nmore details\n";
+        let error =
+            ZfsError::DatasetStateCallFailed("pool/dataset".to_string(), stderr.to_string());
+        assert_eq!(
+            error.to_string(),
+            "Command to get the state of dataset pool/dataset failed: \
+             cannot open 'pool/dataset': permission denied [...]"
+        );
+        assert_eq!(error.stderr(), Some(stderr));
+
+        let error = ZfsError::MountCmdFailed(
+            "pool/dataset".to_string(),
+            CommandFailure {
+                code: Some(1),
+                stderr: stderr.to_string(),
+            },
+        );
+        assert_eq!(
+            error.to_string(),
+            "Mount command for dataset pool/dataset failed: \
+             cannot open 'pool/dataset': permission denied [...] (exit code 1)"
+        );
+        assert_eq!(error.stderr(), Some(stderr));
+        assert_eq!(
+            ZfsError::DatasetBusy("pool/dataset".to_string()).stderr(),
+            None
+        );
+    }
+
+    #[test]
+    fn command_lines() {
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg("printf 'a\\nb b\\n'; echo failure >&2; exit 2");
+        let mut lines = spawn_command_lines(
+            command,
+            ZfsError::SystemError,
+            ZfsError::ListUnmountedDatasetsCallFailed,
+        )
+        .unwrap();
+        assert_eq!(lines.next().unwrap().unwrap(), "a");
+        assert_eq!(lines.next().unwrap().unwrap(), "b b");
+        assert!(matches!(
+            lines.next(),
+            Some(Err(ZfsError::ListUnmountedDatasetsCallFailed(stderr))) if stderr == "failure\n"
+        ));
+        assert!(lines.next().is_none());
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("echo a; echo b");
+        let lines = spawn_command_lines(command, ZfsError::SystemError, ZfsError::SystemError)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(lines, ["a", "b"]);
+
+        // Stopping early kills the command, instead of waiting for it forever
+        let command = Command::new("yes");
+        let lines = spawn_command_lines(command, ZfsError::SystemError, ZfsError::SystemError)
+            .unwrap()
+            .take(3)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(lines, ["y", "y", "y"]);
+
+        let command = Command::new("/some/non/existent/binary");
+        assert!(matches!(
+            spawn_command_lines(command, ZfsError::SystemError, ZfsError::SystemError),
+            Err(ZfsError::ZfsBinaryNotFound(_))
+        ));
+
+        // Not a binary, so it can't be run, but it exists
+        let command = Command::new("/");
+        assert!(matches!(
+            spawn_command_lines(command, ZfsError::SystemError, ZfsError::SystemError),
+            Err(ZfsError::SystemError(_))
+        ));
+    }
+
+    #[test]
+    fn command_timeout() {
+        let mut command = Command::new("sleep");
+        command.arg("10");
+        let start = Instant::now();
+        let result = run_command(
+            command,
+            &[],
+            WaitLimit::timeout(Duration::from_millis(100)),
+            "pool/dataset",
+            ZfsError::SystemError,
+        );
+        assert!(matches!(result, Err(ZfsError::CommandTimedOut(_, _))));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("echo done >&2");
+        let output = run_command(
+            command,
+            &[],
+            WaitLimit::timeout(Duration::from_secs(10)),
+            "pool/dataset",
+            ZfsError::SystemError,
+        )
+        .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stderr, "done\n");
+    }
+
+    #[test]
+    fn command_large_output() {
+        const SIZE: usize = 1 << 20;
+
+        // Fills both pipes many times over, then reads all of its stdin
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(format!(
+            "head -c {SIZE} /dev/zero | tr '\\0' o; \
+             head -c {SIZE} /dev/zero | tr '\\0' e >&2; \
+             wc -c"
+        ));
+        let output = run_command(
+            command,
+            &vec![b'i'; SIZE],
+            WaitLimit::timeout(Duration::from_secs(30)),
+            "pool/dataset",
+            ZfsError::SystemError,
+        )
+        .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stderr.len(), SIZE);
+        assert!(output.stderr.bytes().all(|c| c == b'e'));
+        let (out, count) = output.stdout.split_at(SIZE);
+        assert!(out.bytes().all(|c| c == b'o'));
+        assert_eq!(count.trim(), SIZE.to_string());
+    }
+
+    #[test]
+    fn command_cancel() {
+        let cancel = AtomicBool::new(false);
+        let start = Instant::now();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(100));
+                cancel.store(true, Ordering::SeqCst);
+            });
+
+            let mut command = Command::new("sleep");
+            command.arg("10");
+            let result = run_command(
+                command,
+                &[],
+                WaitLimit::cancel(&cancel),
+                "pool/dataset",
+                ZfsError::SystemError,
+            );
+            assert!(matches!(result, Err(ZfsError::Cancelled(_))));
+        });
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // Already cancelled, so the command doesn't even start
+        let command = Command::new("/some/non/existent/command");
+        let result = run_command(
+            command,
+            &[],
+            WaitLimit::cancel(&cancel),
+            "pool/dataset",
+            ZfsError::SystemError,
+        );
+        assert!(matches!(result, Err(ZfsError::Cancelled(_))));
+
+        let cancel = AtomicBool::new(false);
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("echo done");
+        let output = run_command(
+            command,
+            &[],
+            WaitLimit::cancel(&cancel),
+            "pool/dataset",
+            ZfsError::SystemError,
+        )
+        .unwrap();
+        assert_eq!(output.stdout, "done\n");
+    }
+
+    #[test]
+    fn incorrect_key_error() {
+        assert!(is_incorrect_key_error(
+            "Key load error: Incorrect key provided for 'pool/dataset'.\n"
+        ));
+        assert!(!is_incorrect_key_error(
+            "Key load error: Failed to open key material file: No such file or directory\n"
+        ));
+        assert!(!is_incorrect_key_error(
+            "Key load error: Key already loaded for 'pool/dataset'.\n"
+        ));
+    }
+
+    #[test]
+    fn dataset_busy_error() {
+        assert!(is_dataset_busy_error(
+            "umount: /pool/dataset: target is busy.\n"
+        ));
+        assert!(is_dataset_busy_error(
+            "cannot unmount '/pool/dataset': pool or dataset is busy\n"
+        ));
+        assert!(is_dataset_busy_error(
+            "cannot unmount '/pool/dataset': Device or resource busy\n"
+        ));
+        assert!(!is_dataset_busy_error(
+            "cannot unmount 'pool/dataset': not currently mounted\n"
+        ));
+    }
+
+    #[test]
+    fn sudo_password_required_error() {
+        assert!(is_sudo_password_required_error(
+            "sudo: a password is required\n"
+        ));
+        assert!(is_sudo_password_required_error(
+            "sudo: a terminal is required to read the password; either use the -S option to read from standard input or configure an askpass helper\n"
+        ));
+        assert!(is_sudo_password_required_error(
+            "sudo: no tty present and no askpass program specified\n"
+        ));
+        assert!(!is_sudo_password_required_error(
+            "Key load error: Incorrect key provided for 'pool/dataset'.\n"
+        ));
+        assert!(!is_sudo_password_required_error(""));
+    }
 
-    // Read stdout/stderr to a string
-    let mut stdout_string = String::new();
-    stdout
-        .read_to_string(&mut stdout_string)
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
-    let mut stderr_string = String::new();
-    stderr
-        .read_to_string(&mut stderr_string)
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
+    #[test]
+    fn mountpoint_occupied_error() {
+        let stderr = "cannot mount '/pool/dataset': directory is not empty\n";
+        assert!(is_mountpoint_occupied_error(stderr));
+        assert_eq!(
+            mountpoint_from_mount_error(stderr),
+            Some(PathBuf::from("/pool/dataset"))
+        );
 
-    // Wait for the zfs command to complete
-    let status = child
-        .wait()
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
+        let stderr = "cannot mount 'pool/dataset': filesystem already mounted\n";
+        assert!(is_mountpoint_occupied_error(stderr));
+        assert_eq!(mountpoint_from_mount_error(stderr), None);
 
-    // Check if the command was successful
-    if status.success() {
-        let lines = stdout_string.lines();
-        let datasets_results = lines
-            .into_iter()
-            .map(|l| l.split_whitespace().collect::<Vec<_>>())
-            .filter(|v| v.len() >= 2)
-            .map(|v| (v[0], v[1]))
-            .collect::<BTreeMap<&str, &str>>();
-        match datasets_results.get(&*dataset) {
-            Some(is_dataset_mounted) => match *is_dataset_mounted {
-                "yes" => Ok(Some(true)),
-                "no" => Ok(Some(false)),
-                _ => Err(ZfsError::UnexpectedStateForMount(
-                    is_dataset_mounted.to_string(),
-                )),
-            },
-            None => Ok(None),
-        }
-    } else {
-        Err(ZfsError::IsMountedCheckCallFailed(
-            dataset.to_string(),
-            stderr_string,
-        ))
+        let stderr = "cannot mount '/mnt/my data': directory is not empty\n";
+        assert!(is_mountpoint_occupied_error(stderr));
+        assert_eq!(
+            mountpoint_from_mount_error(stderr),
+            Some(PathBuf::from("/mnt/my data"))
+        );
+
+        let stderr = "cannot mount 'pool/dataset': encryption key not loaded\n";
+        assert!(!is_mountpoint_occupied_error(stderr));
+
+        let stderr = "cannot mount '/pool/dataset': Permission denied\n";
+        assert!(!is_mountpoint_occupied_error(stderr));
     }
-}
 
-pub fn zfs_list_datasets_mountpoints() -> Result<BTreeMap<String, PathBuf>, ZfsError> {
-    // Create a command to run zfs load-key
-    let mut child = Command::new("zfs")
-        .arg("list")
-        .arg("-H") // No table header
-        .arg("-o")
-        .arg("name,mountpoint") // Only show two columns, dataset name and mountpoint
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()
-        .map_err(|e| ZfsError::ListDatasetsMountPointsCallFailed(e.to_string()))?;
+    #[test]
+    fn dataset_not_found_error() {
+        assert!(is_dataset_not_found_error(
+            "cannot open 'pool/missing': dataset does not exist\n"
+        ));
+        assert!(!is_dataset_not_found_error(
+            "cannot open 'pool': permission denied\n"
+        ));
+    }
 
-    // Capture the stdout handle of the child process
-    let mut stdout = child.stdout.take().expect("Failed to capture stdout");
-    let mut stderr = child.stderr.take().expect("Failed to capture stderr");
+    #[test]
+    fn pbkdf2iters_values() {
+        let ds = "pool/dataset";
 
-    // Read stdout/stderr to a string
-    let mut stdout_string = String::new();
-    stdout
-        .read_to_string(&mut stdout_string)
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
-    let mut stderr_string = String::new();
-    stderr
-        .read_to_string(&mut stderr_string)
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
+        assert_eq!(parse_pbkdf2iters(ds, "350000").unwrap(), Some(350000));
+        assert_eq!(parse_pbkdf2iters(ds, "1000\n").unwrap(), Some(1000));
+        assert_eq!(parse_pbkdf2iters(ds, "0").unwrap(), None);
+        assert_eq!(parse_pbkdf2iters(ds, "-").unwrap(), None);
+        assert!(matches!(
+            parse_pbkdf2iters(ds, "many"),
+            Err(ZfsError::GetPropertyCallFailed(_, property, _)) if property == "pbkdf2iters"
+        ));
+        parse_pbkdf2iters(ds, "-1").unwrap_err();
+    }
 
-    // Wait for the zfs command to complete
-    let status = child
-        .wait()
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
+    #[test]
+    fn output_line_columns() {
+        assert_eq!(
+            split_output_line("pool/dataset\t/mnt/my data"),
+            vec!["pool/dataset", "/mnt/my data"]
+        );
+        assert_eq!(
+            split_output_line("pool/dataset\tno\tavailable"),
+            vec!["pool/dataset", "no", "available"]
+        );
+        assert_eq!(
+            split_output_line("pool/dataset  no available"),
+            vec!["pool/dataset", "no", "available"]
+        );
+    }
 
-    // Check if the command was successful
-    if status.success() {
-        let lines = stdout_string.lines();
-        let datasets_results = lines
-            .into_iter()
-            .map(|l| l.split_whitespace().collect::<Vec<_>>())
-            .filter(|v| v.len() >= 2)
-            .map(|v| (v[0].to_string(), PathBuf::from(v[1])))
-            .collect::<BTreeMap<String, PathBuf>>();
-        Ok(datasets_results)
-    } else {
-        Err(ZfsError::ListDatasetsMountPointsCallFailed(stderr_string))
+    #[test]
+    fn mount_table() {
+        let mount_table = "proc /proc proc rw,nosuid 0 0\n\
+                           pool/data /mnt/my\\040data zfs rw,xattr 0 0\n\
+                           pool/data /srv zfs rw 0 0\n\
+                           pool/data\\040not /mnt/other zfs rw 0 0\n\
+                           pool/data /mnt/ext4 ext4 rw 0 0\n";
+
+        assert_eq!(
+            parse_mount_table_targets(mount_table, "pool/data"),
+            [PathBuf::from("/mnt/my data"), PathBuf::from("/srv")]
+        );
+        assert!(parse_mount_table_targets(mount_table, "pool").is_empty());
+        assert_eq!(unescape_mount_table_field("a\\134b\\011c\\"), "a\\b\tc\\");
     }
-}
 
-pub fn zfs_list_encrypted_datasets() -> Result<BTreeMap<String, DatasetMountedState>, ZfsError> {
-    // Create a command to run zfs load-key
-    let mut child = Command::new("zfs")
-        .arg("list")
-        .arg("-H") // No table header
-        .arg("-o")
-        .arg("name,mounted,keystatus") // Only show two columns, dataset name and mountpoint
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()
-        .map_err(|e| ZfsError::ListDatasetsMountPointsCallFailed(e.to_string()))?;
+    #[test]
+    fn mountpoints_with_spaces() {
+        let output = "pool\t/pool\npool/dataset\t/mnt/my data\npool/other\t/mnt/a  b \n\
+                      pool/legacy\tlegacy\npool/none\tnone\npool/volume\t-\n";
+        let mountpoints = parse_datasets_mountpoints(output);
 
-    // Capture the stdout handle of the child process
-    let mut stdout = child.stdout.take().expect("Failed to capture stdout");
-    let mut stderr = child.stderr.take().expect("Failed to capture stderr");
+        assert_eq!(mountpoints.len(), 6);
+        assert_eq!(
+            mountpoints["pool"],
+            Mountpoint::Path(PathBuf::from("/pool"))
+        );
+        assert_eq!(
+            mountpoints["pool/dataset"],
+            Mountpoint::Path(PathBuf::from("/mnt/my data"))
+        );
+        assert_eq!(
+            mountpoints["pool/other"],
+            Mountpoint::Path(PathBuf::from("/mnt/a  b "))
+        );
+        assert_eq!(mountpoints["pool/legacy"], Mountpoint::Legacy);
+        assert_eq!(mountpoints["pool/none"], Mountpoint::None);
+        assert_eq!(mountpoints["pool/volume"], Mountpoint::None);
+        assert_eq!(mountpoints["pool/legacy"].path(), None);
+    }
 
-    // Read stdout/stderr to a string
-    let mut stdout_string = String::new();
-    stdout
-        .read_to_string(&mut stdout_string)
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
-    let mut stderr_string = String::new();
-    stderr
-        .read_to_string(&mut stderr_string)
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
+    #[test]
+    fn inconsistent_datasets() {
+        let output = "pool\tyes\t-\t-\n\
+                      pool/enc\tno\tunavailable\tpool/enc\n\
+                      pool/enc/ok\tno\tunavailable\tpool/enc\n\
+                      pool/enc/loaded\tno\tavailable\tpool/enc\n\
+                      pool/enc/ghost\tyes\tunavailable\tpool/enc\n\
+                      pool/enc/orphan\tno\tavailable\tpool/gone\n\
+                      pool/enc/plain\tyes\tavailable\tpool\n\
+                      pool/short\tyes\n";
+        assert_eq!(
+            parse_inconsistent_datasets(output).unwrap(),
+            [
+                Inconsistency::MountedWithoutKey {
+                    dataset: "pool/enc/ghost".to_string()
+                },
+                Inconsistency::KeyStatusDiffersFromRoot {
+                    dataset: "pool/enc/loaded".to_string(),
+                    encryption_root: "pool/enc".to_string(),
+                    is_key_loaded: true,
+                    is_root_key_loaded: false,
+                },
+                Inconsistency::InvalidEncryptionRoot {
+                    dataset: "pool/enc/orphan".to_string(),
+                    encryption_root: "pool/gone".to_string(),
+                },
+                Inconsistency::InvalidEncryptionRoot {
+                    dataset: "pool/enc/plain".to_string(),
+                    encryption_root: "pool".to_string(),
+                },
+            ]
+        );
 
-    // Wait for the zfs command to complete
-    let status = child
-        .wait()
-        .map_err(|e| ZfsError::SystemError(e.to_string()))?;
+        let output = "pool/enc\tyes\tavailable\tpool/enc\npool/enc/a\tyes\tavailable\tpool/enc\n";
+        assert!(parse_inconsistent_datasets(output).unwrap().is_empty());
+        parse_inconsistent_datasets("pool/enc\tmaybe\tavailable\tpool/enc\n").unwrap_err();
+        parse_inconsistent_datasets("pool/enc\tno\tsometimes\tpool/enc\n").unwrap_err();
+    }
 
-    // Check if the command was successful
-    if status.success() {
-        let lines = stdout_string.lines();
-        let datasets_results = lines
-            .into_iter()
-            .map(|l| l.split_whitespace().collect::<Vec<_>>())
-            .filter(|v| v.len() >= 3)
-            .filter(|v| v[2].trim() != "-") // Filter unencrypted datasets
-            .map(|v| {
-                let dataset_name = v[0].to_string();
-                let is_mounted = parse_dataset_mounted_state(v[1])?;
-                let is_key_loaded = parse_key_available_state(v[2])?;
-                Ok((
-                    dataset_name.clone(),
-                    DatasetMountedState {
-                        dataset_name,
-                        is_mounted,
-                        is_key_loaded,
-                    },
-                ))
+    #[test]
+    fn encrypted_datasets_output() {
+        let output = "pool\tyes\t-\npool/enc\tno\tunavailable\npool/enc/child\tyes\tavailable\n";
+        let datasets = parse_encrypted_datasets(output, |_| true).unwrap().0;
+
+        assert_eq!(datasets.len(), 2);
+        assert!(!datasets["pool/enc"].is_mounted);
+        assert!(!datasets["pool/enc"].is_key_loaded);
+        assert!(datasets["pool/enc/child"].is_mounted);
+        assert!(datasets["pool/enc/child"].is_key_loaded);
+
+        parse_encrypted_datasets("pool/enc\tmaybe\tavailable\n", |_| true).unwrap_err();
+
+        let locked = parse_encrypted_datasets(output, |state| !state.is_key_loaded)
+            .unwrap()
+            .0;
+        assert_eq!(locked.keys().collect::<Vec<_>>(), ["pool/enc"]);
+
+        let (datasets, warnings) =
+            parse_encrypted_datasets("pool/gone\tno\npool/enc\tno\tunavailable\n\n", |_| true)
+                .unwrap();
+        assert_eq!(datasets.len(), 1);
+        assert_eq!(warnings.skipped_lines, ["pool/gone\tno"]);
+        assert!(parse_encrypted_datasets(output, |_| true)
+            .unwrap()
+            .1
+            .is_empty());
+
+        let output = "pool/enc\tno\tunavailable\tpool/enc\t/home/enc\n\
+                      pool/enc/legacy\tno\tunavailable\tpool/enc\tlegacy\n\
+                      pool/enc/none\tno\tunavailable\tpool/enc\tnone\n\
+                      pool/enc/spaces\tyes\tavailable\tpool/enc/spaces\t/home/my enc\n";
+        let datasets = parse_encrypted_datasets(output, |_| true).unwrap().0;
+        assert!(datasets["pool/enc"].is_encryption_root);
+        assert!(!datasets["pool/enc/legacy"].is_encryption_root);
+        assert!(datasets["pool/enc/spaces"].is_encryption_root);
+        assert_eq!(
+            datasets["pool/enc"].mountpoint,
+            Some(PathBuf::from("/home/enc"))
+        );
+        assert_eq!(datasets["pool/enc/legacy"].mountpoint, None);
+        assert_eq!(datasets["pool/enc/none"].mountpoint, None);
+        assert_eq!(
+            datasets["pool/enc/spaces"].mountpoint,
+            Some(PathBuf::from("/home/my enc"))
+        );
+    }
+
+    #[test]
+    fn dataset_exists_result() {
+        let f = parse_dataset_exists_result;
+        let ds = "pool/dataset";
+
+        assert!(f(ds, true, String::new()).unwrap());
+        assert!(!f(
+            ds,
+            false,
+            "cannot open 'pool/dataset': dataset does not exist\n".to_string()
+        )
+        .unwrap());
+        assert!(matches!(
+            f(ds, false, "permission denied\n".to_string()),
+            Err(ZfsError::DatasetExistsCheckFailed(_, _))
+        ));
+    }
+
+    #[test]
+    fn encryption_root() {
+        let f = parse_encryption_root;
+        let ds = "pool/home/alice";
+
+        assert_eq!(f(ds, "pool/home").unwrap(), Some("pool/home".to_string()));
+        assert_eq!(f(ds, "pool/home/alice\n").unwrap(), Some(ds.to_string()));
+        assert_eq!(f(ds, "-").unwrap(), None);
+        assert!(matches!(
+            f(ds, "pool/home; rm -rf /"),
+            Err(ZfsError::UnexpectedPropertyValue(_, _, _))
+        ));
+    }
+
+    #[test]
+    fn dataset_usage_result() {
+        let f = parse_dataset_usage_result;
+        let ds = "pool/dataset";
+
+        let output = "used\t1073741824\navailable\t53687091200\nreferenced\t524288\n";
+        assert_eq!(
+            f(ds, true, output, String::new()).unwrap(),
+            Some(DatasetUsage {
+                used: 1073741824,
+                available: 53687091200,
+                referenced: 524288,
             })
-            .collect::<Result<BTreeMap<String, DatasetMountedState>, _>>()?;
-        Ok(datasets_results)
-    } else {
-        Err(ZfsError::ListUnmountedDatasetsCallFailed(stderr_string))
+        );
+
+        assert_eq!(
+            f(
+                ds,
+                false,
+                "",
+                "cannot open 'pool/dataset': dataset does not exist\n".to_string()
+            )
+            .unwrap(),
+            None
+        );
+
+        f(ds, false, "", "permission denied\n".to_string()).unwrap_err();
+        // Without -p, zfs shows sizes like 1.00G
+        assert!(matches!(
+            f(
+                ds,
+                true,
+                "used\t1.00G\navailable\t50G\nreferenced\t512K\n",
+                String::new()
+            ),
+            Err(ZfsError::UnexpectedPropertyValue(_, _, _))
+        ));
+        assert!(matches!(
+            f(ds, true, "used\t1073741824\n", String::new()),
+            Err(ZfsError::GetPropertyCallFailed(_, _, _))
+        ));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn snapshots_result() {
+        let f = parse_snapshots_result;
+        let ds = "pool/dataset";
+
+        assert_eq!(
+            f(
+                ds,
+                true,
+                "pool/dataset@first\t1024\t1073741824\t1700000000\n\
+                 pool/dataset/child@second\t0\t4096\t1700003600\n",
+                String::new()
+            )
+            .unwrap(),
+            [
+                Snapshot {
+                    name: "pool/dataset@first".to_string(),
+                    used: 1024,
+                    referenced: 1073741824,
+                    creation: 1700000000,
+                },
+                Snapshot {
+                    name: "pool/dataset/child@second".to_string(),
+                    used: 0,
+                    referenced: 4096,
+                    creation: 1700003600,
+                },
+            ]
+        );
+
+        assert_eq!(f(ds, true, "", String::new()).unwrap(), []);
+
+        assert!(matches!(
+            f(
+                ds,
+                false,
+                "",
+                "cannot open 'pool/dataset': dataset does not exist\n".to_string()
+            ),
+            Err(ZfsError::DatasetNotFound(_))
+        ));
+        assert!(matches!(
+            f(ds, false, "", "permission denied\n".to_string()),
+            Err(ZfsError::ListSnapshotsCallFailed(_, _))
+        ));
+        // Without -p, zfs shows sizes like 1.00G
+        assert!(matches!(
+            f(
+                ds,
+                true,
+                "pool/dataset@first\t1K\t1.00G\tTue Nov 14 22:13 2023\n",
+                String::new()
+            ),
+            Err(ZfsError::ListSnapshotsCallFailed(_, _))
+        ));
+        assert!(matches!(
+            f(ds, true, "pool/dataset@first\t1024\n", String::new()),
+            Err(ZfsError::ListSnapshotsCallFailed(_, _))
+        ));
+    }
 
     #[test]
-    fn basic() {
-        // Feel free to update these entries to your machine's entries to test
-        let hostname = "pitests";
-        let ds_name = "SamRandomPool/EncryptedDataset1";
-        let passphrase = "abcdefghijklmnop";
-        let mount_point = "/SamRandomPoolEncryptedDS1";
+    fn key_status_result() {
+        let f = parse_key_status_result;
+        let ds = "pool/dataset";
 
-        if hostname::get().unwrap().to_string_lossy().to_lowercase() == hostname.to_lowercase() {
-            // Try with a non-existent database
-            assert_eq!(zfs_is_key_loaded("some_random_stuff").unwrap(), None);
+        assert_eq!(
+            f(ds, true, "pool/dataset\tavailable\n", String::new()).unwrap(),
+            KeyStatus::Available
+        );
+        assert_eq!(
+            f(ds, true, "pool/dataset\tunavailable\n", String::new()).unwrap(),
+            KeyStatus::Unavailable
+        );
+        assert_eq!(
+            f(ds, true, "pool/dataset\t-\n", String::new()).unwrap(),
+            KeyStatus::NotEncrypted
+        );
+        assert_eq!(
+            f(ds, true, "pool/dataset\tnone\n", String::new()).unwrap(),
+            KeyStatus::NotEncrypted
+        );
+        assert_eq!(
+            f(ds, true, "", String::new()).unwrap(),
+            KeyStatus::DatasetNotFound
+        );
+        assert_eq!(
+            f(
+                ds,
+                false,
+                "",
+                "cannot open 'pool/dataset': dataset does not exist\n".to_string()
+            )
+            .unwrap(),
+            KeyStatus::DatasetNotFound
+        );
 
-            // Unmount, before messing with the key
-            zfs_unmount_dataset(ds_name).unwrap();
+        f(ds, true, "pool/dataset\tyes\n", String::new()).unwrap_err();
+        f(ds, false, "", "permission denied\n".to_string()).unwrap_err();
 
-            // Ensure the key is unloaded and db is unmounted, load it, then unload it
-            zfs_unload_key(ds_name).unwrap();
-            assert_eq!(zfs_is_key_loaded(ds_name).unwrap(), Some(false));
-            assert_eq!(
-                zfs_list_encrypted_datasets()
-                    .unwrap()
-                    .get(ds_name)
-                    .unwrap()
-                    .is_key_loaded,
-                false
-            );
-            zfs_load_key(ds_name, passphrase).unwrap();
-            assert_eq!(zfs_is_key_loaded(ds_name).unwrap(), Some(true));
-            assert_eq!(
-                zfs_list_encrypted_datasets()
-                    .unwrap()
-                    .get(ds_name)
-                    .unwrap()
-                    .is_key_loaded,
-                true
-            );
-            zfs_unload_key(ds_name).unwrap();
-            assert_eq!(
-                zfs_list_encrypted_datasets()
-                    .unwrap()
-                    .get(ds_name)
-                    .unwrap()
-                    .is_key_loaded,
-                false
-            );
-            assert_eq!(zfs_is_key_loaded(ds_name).unwrap(), Some(false));
+        assert_eq!(KeyStatus::Available.is_loaded(), Some(true));
+        assert_eq!(KeyStatus::Unavailable.is_loaded(), Some(false));
+        assert_eq!(KeyStatus::NotEncrypted.is_loaded(), Some(true));
+        assert_eq!(KeyStatus::DatasetNotFound.is_loaded(), None);
+    }
 
-            zfs_load_key(ds_name, passphrase).unwrap();
-            assert_eq!(
-                zfs_list_encrypted_datasets()
-                    .unwrap()
-                    .get(ds_name)
-                    .unwrap()
-                    .is_key_loaded,
-                true
-            );
-            assert_eq!(zfs_is_key_loaded(ds_name).unwrap(), Some(true));
+    #[test]
+    fn mount_status_result() {
+        let f = parse_mount_status_result;
+        let ds = "pool/dataset";
+        let output = "pool\tyes\npool/dataset\tno\npool/dataset/child\tyes\n";
 
-            zfs_unmount_dataset(ds_name).unwrap();
-            assert_eq!(zfs_is_dataset_mounted(ds_name).unwrap(), Some(false));
-            assert_eq!(
-                zfs_list_encrypted_datasets()
-                    .unwrap()
-                    .get(ds_name)
-                    .unwrap()
-                    .is_mounted,
-                false
-            );
-            zfs_mount_dataset(ds_name).unwrap();
-            assert_eq!(zfs_is_dataset_mounted(ds_name).unwrap(), Some(true));
-            assert_eq!(
-                zfs_list_encrypted_datasets()
-                    .unwrap()
-                    .get(ds_name)
-                    .unwrap()
-                    .is_mounted,
-                true
-            );
-            zfs_unmount_dataset(ds_name).unwrap();
-            assert_eq!(zfs_is_dataset_mounted(ds_name).unwrap(), Some(false));
-            assert_eq!(
-                zfs_list_encrypted_datasets()
-                    .unwrap()
-                    .get(ds_name)
-                    .unwrap()
-                    .is_mounted,
-                false
-            );
+        assert_eq!(
+            f(ds, true, output, String::new()).unwrap(),
+            MountStatus::Unmounted
+        );
+        assert_eq!(
+            f("pool/dataset/child", true, output, String::new()).unwrap(),
+            MountStatus::Mounted
+        );
+        assert_eq!(
+            f("pool/other", true, output, String::new()).unwrap(),
+            MountStatus::DatasetNotFound
+        );
 
-            zfs_unload_key(ds_name).unwrap();
-            assert_eq!(zfs_is_key_loaded(ds_name).unwrap(), Some(false));
+        f(ds, true, "pool/dataset\tavailable\n", String::new()).unwrap_err();
+        f(ds, false, "", "permission denied\n".to_string()).unwrap_err();
 
-            let mount_points = zfs_list_datasets_mountpoints().unwrap();
-            assert_eq!(
-                mount_points.get(ds_name).unwrap().to_string_lossy(),
-                mount_point,
-            );
-        } else {
-            let err = "WARNING: No tests were run. Update the tests to test on your machine.";
-            println!("{}", err);
-            eprintln!("{}", err);
+        assert_eq!(MountStatus::Mounted.is_mounted(), Some(true));
+        assert_eq!(MountStatus::Unmounted.is_mounted(), Some(false));
+        assert_eq!(MountStatus::DatasetNotFound.is_mounted(), None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialized_states() {
+        let state = DatasetMountedState {
+            dataset_name: "pool/dataset".to_string(),
+            is_mounted: true,
+            is_key_loaded: true,
+            mountpoint: Some(PathBuf::from("/mnt/dataset")),
+            is_encryption_root: false,
+        };
+
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(
+            json,
+            r#"{"dataset_name":"pool/dataset","is_mounted":true,"is_key_loaded":true,"mountpoint":"/mnt/dataset","is_encryption_root":false}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<DatasetMountedState>(&json).unwrap(),
+            state
+        );
+
+        for status in [
+            KeyStatus::Available,
+            KeyStatus::Unavailable,
+            KeyStatus::NotEncrypted,
+            KeyStatus::DatasetNotFound,
+        ] {
+            let json = serde_json::to_string(&status).unwrap();
+            assert_eq!(serde_json::from_str::<KeyStatus>(&json).unwrap(), status);
         }
+        for status in [
+            MountStatus::Mounted,
+            MountStatus::Unmounted,
+            MountStatus::DatasetNotFound,
+        ] {
+            let json = serde_json::to_string(&status).unwrap();
+            assert_eq!(serde_json::from_str::<MountStatus>(&json).unwrap(), status);
+        }
+
+        let change = DatasetStateChange {
+            dataset: "pool/dataset".to_string(),
+            old_state: None,
+            new_state: Some(state),
+        };
+        let json = serde_json::to_string(&change).unwrap();
+        assert_eq!(
+            serde_json::from_str::<DatasetStateChange>(&json).unwrap(),
+            change
+        );
     }
 
     #[test]
-    fn test_valid_zfs_dataset_names() {
-        let f = check_and_sanitize_zfs_dataset_name;
+    fn properties_result() {
+        let properties = ["name".to_string(), "mountpoint".to_string()];
+        let f = |success, stdout, stderr: &str| {
+            parse_properties_result(success, stdout, stderr.to_string(), &properties)
+        };
 
-        f("pool/dataset1").unwrap();
-        f("pool/dataset_2").unwrap();
-        f("pool.dataset/dataset-3").unwrap();
-        f("pool.dataset/dataset:3").unwrap();
-        f("pool:1/dataset.with.multiple.levels").unwrap();
-        f(" pool:1/dataset.with.multiple.levels").unwrap();
-        f(" pool:1/dataset.with.multiple.levels  ").unwrap();
+        let rows = f(true, "pool\t/pool\npool/data\t/mnt/my data\npool/bad\n", "").unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1]["name"], "pool/data");
+        assert_eq!(rows[1]["mountpoint"], "/mnt/my data");
+
+        let rows = f(
+            false,
+            "pool\t/pool\n",
+            "cannot open 'pool/missing': dataset does not exist\n",
+        )
+        .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert!(matches!(
+            f(false, "", "bad property list: invalid property 'x'\n"),
+            Err(ZfsError::ListPropertiesCallFailed(_))
+        ));
+        assert!(matches!(
+            f(false, "pool\t/pool\n", ""),
+            Err(ZfsError::ListPropertiesCallFailed(_))
+        ));
+
+        check_and_sanitize_property_name(" com.example:prop ").unwrap();
+        check_and_sanitize_property_name("name,mounted").unwrap_err();
+        check_and_sanitize_property_name("").unwrap_err();
     }
 
     #[test]
-    fn test_invalid_zfs_dataset_names() {
-        let f = check_and_sanitize_zfs_dataset_name;
+    fn unlock_all_roots() {
+        let locked = parse_encrypted_datasets(
+            "pool/a\tno\tunavailable\tpool/a\t/a\n\
+             pool/a/child\tno\tunavailable\tpool/a\t/a/child\n\
+             pool/a/child/own\tno\tunavailable\tpool/a/child/own\t/a/own\n\
+             pool/b\tno\tunavailable\tpool/b\t/b\n\
+             pool/b/child\tno\tunavailable\tpool/b\t/b/child\n\
+             pool/c\tno\tunavailable\tpool/c\t/c\n",
+            |_| true,
+        )
+        .unwrap()
+        .0;
+        let passphrases = BTreeMap::from([
+            ("pool/a".to_string(), "root passphrase".to_string()),
+            ("pool/a/child".to_string(), "child passphrase".to_string()),
+            (" pool/b/child ".to_string(), "b passphrase".to_string()),
+        ]);
 
-        f("").unwrap_err();
-        f("_R").unwrap_err();
-        f("-R").unwrap_err();
-        f(":R").unwrap_err();
-        f(".R").unwrap_err();
-        f(" _R").unwrap_err();
-        f(" -R").unwrap_err();
-        f(" :R").unwrap_err();
-        f(" .R").unwrap_err();
-        f("pool/_R").unwrap_err();
-        f("pool/-R").unwrap_err();
-        f("pool/:R").unwrap_err();
-        f("pool/.R").unwrap_err();
-        f("pool/ _R").unwrap_err();
-        f("pool/ -R").unwrap_err();
-        f("pool/ :R").unwrap_err();
-        f("pool/ .R").unwrap_err();
-        f("pool/dataset name").unwrap_err();
-        f("pool/dataset!").unwrap_err();
-        f("pool/dataset@name").unwrap_err();
-        f("pool//dataset").unwrap_err();
-        f("pool/ dataset").unwrap_err();
+        assert_eq!(
+            unlock_all_passphrases(&locked, &passphrases),
+            BTreeMap::from([
+                ("pool/a".to_string(), Some("root passphrase")),
+                ("pool/a/child/own".to_string(), None),
+                ("pool/b".to_string(), Some("b passphrase")),
+                ("pool/c".to_string(), None),
+            ])
+        );
+    }
+
+    #[test]
+    fn dataset_states_result() {
+        let f = parse_dataset_states_result;
+
+        let states = f(
+            false,
+            "pool/a\tyes\tavailable\tpool/a\t/mnt/a\n\
+             pool/b\tno\t-\t-\t/mnt/b\n",
+            "cannot open 'pool/missing': dataset does not exist\n".to_string(),
+        )
+        .unwrap();
+        assert_eq!(states.keys().collect::<Vec<_>>(), ["pool/a", "pool/b"]);
+        assert!(states["pool/a"].is_mounted && states["pool/a"].is_key_loaded);
+        assert!(!states["pool/b"].is_mounted && states["pool/b"].is_key_loaded);
+
+        assert!(f(
+            false,
+            "",
+            "cannot open 'pool/x': dataset does not exist\n\
+             cannot open 'pool/y': dataset does not exist\n"
+                .to_string()
+        )
+        .unwrap()
+        .is_empty());
+        assert!(matches!(
+            f(false, "", "permission denied\n".to_string()),
+            Err(ZfsError::DatasetStatesCallFailed(_))
+        ));
+        // A failure that doesn't say why, e.g., zfs killed by a signal, isn't a missing dataset
+        assert!(matches!(
+            f(
+                false,
+                "pool/a\tyes\tavailable\tpool/a\t/mnt/a\n",
+                String::new()
+            ),
+            Err(ZfsError::DatasetStatesCallFailed(_))
+        ));
+        assert!(matches!(
+            f(false, "", "\n".to_string()),
+            Err(ZfsError::DatasetStatesCallFailed(_))
+        ));
+        f(true, "pool/a\tmaybe\tavailable\n", String::new()).unwrap_err();
+    }
+
+    #[test]
+    fn dataset_state_result() {
+        let f = parse_dataset_state_result;
+        let ds = "pool/dataset";
+        let state = |is_mounted, is_key_loaded| DatasetMountedState {
+            dataset_name: ds.to_string(),
+            is_mounted,
+            is_key_loaded,
+            mountpoint: None,
+            is_encryption_root: false,
+        };
+
+        assert_eq!(
+            f(ds, true, "pool/dataset\tyes\tavailable\n", String::new()).unwrap(),
+            Some(state(true, true))
+        );
+        assert_eq!(
+            f(ds, true, "pool/dataset\tno\tunavailable\n", String::new()).unwrap(),
+            Some(state(false, false))
+        );
+        assert_eq!(
+            f(ds, true, "pool/dataset\tno\t-\n", String::new()).unwrap(),
+            Some(state(false, true))
+        );
+        assert_eq!(f(ds, true, "", String::new()).unwrap(), None);
+        assert_eq!(
+            f(
+                ds,
+                false,
+                "",
+                "cannot open 'pool/dataset': dataset does not exist\n".to_string()
+            )
+            .unwrap(),
+            None
+        );
+
+        assert_eq!(
+            f(
+                ds,
+                true,
+                "pool/dataset\tyes\tavailable\tpool/dataset\t/mnt/my data\n",
+                String::new()
+            )
+            .unwrap(),
+            Some(DatasetMountedState {
+                mountpoint: Some(PathBuf::from("/mnt/my data")),
+                is_encryption_root: true,
+                ..state(true, true)
+            })
+        );
+        assert!(
+            !f(
+                ds,
+                true,
+                "pool/dataset\tyes\tavailable\tpool\t/mnt/data\n",
+                String::new()
+            )
+            .unwrap()
+            .unwrap()
+            .is_encryption_root
+        );
+
+        f(ds, true, "pool/dataset\tmaybe\tavailable\n", String::new()).unwrap_err();
+        f(ds, false, "", "permission denied\n".to_string()).unwrap_err();
+    }
+
+    #[test]
+    fn mountpoints() {
+        assert_eq!(
+            parse_mountpoint("/mnt/data"),
+            Some(PathBuf::from("/mnt/data"))
+        );
+        assert_eq!(
+            parse_mountpoint("/mnt/my data"),
+            Some(PathBuf::from("/mnt/my data"))
+        );
+        assert_eq!(parse_mountpoint("none"), None);
+        assert_eq!(parse_mountpoint("legacy"), None);
+        assert_eq!(parse_mountpoint("-"), None);
+        assert_eq!(parse_mountpoint(""), None);
+    }
+
+    #[test]
+    fn unmounted_filesystems_output() {
+        let output = "pool\tyes\ton\t/pool\n\
+                      pool/home\tno\ton\t/home\n\
+                      pool/home/alice\tno\ton\t/home/alice smith\n\
+                      pool/noauto\tno\tnoauto\t/noauto\n\
+                      pool/off\tno\toff\t/off\n\
+                      pool/legacy\tno\ton\tlegacy\n\
+                      pool/none\tno\ton\tnone\n";
+        assert_eq!(
+            parse_unmounted_filesystems(output).unwrap(),
+            BTreeSet::from(["pool/home".to_string(), "pool/home/alice".to_string()])
+        );
+
+        parse_unmounted_filesystems("pool\tmaybe\ton\t/pool\n").unwrap_err();
+    }
+
+    #[test]
+    fn lockable_roots_output() {
+        let output = "pool\t-\t-\n\
+                      pool/home\tpool/home\tunavailable\n\
+                      pool/home/alice\tpool/home\tunavailable\n\
+                      pool/home/bob\tpool/home/bob\tunavailable\n\
+                      pool/backup\tpool/backup\tavailable\n\
+                      pool/backup/daily\tpool/backup\tavailable\n";
+        assert_eq!(
+            parse_lockable_roots(output).unwrap(),
+            ["pool/home", "pool/home/bob"]
+        );
+        assert_eq!(parse_lockable_roots("").unwrap(), Vec::<String>::new());
+        assert!(matches!(
+            parse_lockable_roots("pool/home\tpool/home\tmaybe\n"),
+            Err(ZfsError::UnexpectedStateForKey(_))
+        ));
+    }
+
+    #[test]
+    fn key_states_output() {
+        let output = "pool/enc\tavailable\npool/enc/plain\t-\npool/enc/child\tunavailable\n";
+        let key_states = parse_key_states(output).unwrap();
+
+        assert_eq!(key_states.len(), 2);
+        assert!(key_states["pool/enc"]);
+        assert!(!key_states["pool/enc/child"]);
+        assert_eq!(
+            locked_datasets(&key_states),
+            BTreeSet::from(["pool/enc/child".to_string()])
+        );
+        assert_eq!(
+            unlocked_datasets(&key_states),
+            BTreeSet::from(["pool/enc".to_string()])
+        );
+
+        parse_key_states("pool/enc\tyes\n").unwrap_err();
     }
 
     #[test]
     fn key_loaded_state() {
-        assert_eq!(parse_key_available_state("available").unwrap(), true);
-        assert_eq!(parse_key_available_state("unavailable").unwrap(), false);
-        assert_eq!(parse_key_available_state(" available").unwrap(), true);
-        assert_eq!(parse_key_available_state(" unavailable").unwrap(), false);
-        assert_eq!(parse_key_available_state("available ").unwrap(), true);
-        assert_eq!(parse_key_available_state("unavailable ").unwrap(), false);
-        assert_eq!(parse_key_available_state(" available ").unwrap(), true);
-        assert_eq!(parse_key_available_state(" unavailable ").unwrap(), false);
+        assert!(parse_key_available_state("available").unwrap());
+        assert!(!parse_key_available_state("unavailable").unwrap());
+        assert!(parse_key_available_state(" available").unwrap());
+        assert!(!parse_key_available_state(" unavailable").unwrap());
+        assert!(parse_key_available_state("available ").unwrap());
+        assert!(!parse_key_available_state("unavailable ").unwrap());
+        assert!(parse_key_available_state(" available ").unwrap());
+        assert!(!parse_key_available_state(" unavailable ").unwrap());
 
         parse_key_available_state("yes").unwrap_err();
         parse_key_available_state("no").unwrap_err();
@@ -737,14 +5200,14 @@ mod tests {
 
     #[test]
     fn is_mounted_state() {
-        assert_eq!(parse_dataset_mounted_state("yes").unwrap(), true);
-        assert_eq!(parse_dataset_mounted_state("no").unwrap(), false);
-        assert_eq!(parse_dataset_mounted_state(" yes").unwrap(), true);
-        assert_eq!(parse_dataset_mounted_state(" no").unwrap(), false);
-        assert_eq!(parse_dataset_mounted_state("yes ").unwrap(), true);
-        assert_eq!(parse_dataset_mounted_state("no ").unwrap(), false);
-        assert_eq!(parse_dataset_mounted_state(" yes ").unwrap(), true);
-        assert_eq!(parse_dataset_mounted_state(" no ").unwrap(), false);
+        assert!(parse_dataset_mounted_state("yes").unwrap());
+        assert!(!parse_dataset_mounted_state("no").unwrap());
+        assert!(parse_dataset_mounted_state(" yes").unwrap());
+        assert!(!parse_dataset_mounted_state(" no").unwrap());
+        assert!(parse_dataset_mounted_state("yes ").unwrap());
+        assert!(!parse_dataset_mounted_state("no ").unwrap());
+        assert!(parse_dataset_mounted_state(" yes ").unwrap());
+        assert!(!parse_dataset_mounted_state(" no ").unwrap());
 
         parse_dataset_mounted_state("available").unwrap_err();
         parse_dataset_mounted_state("unavailable").unwrap_err();
@@ -752,4 +5215,61 @@ mod tests {
         parse_dataset_mounted_state(".").unwrap_err();
         parse_dataset_mounted_state("2222").unwrap_err();
     }
+
+    /// Runs f with a subscriber that records the events of this thread, and returns them as they're formatted
+    #[cfg(feature = "tracing")]
+    pub(crate) fn captured_events(f: impl FnOnce()) -> String {
+        #[derive(Clone, Default)]
+        struct Events(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+        impl Write for Events {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let events = Events::default();
+        let writer = events.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, f);
+
+        let events = events.0.lock().unwrap().clone();
+        String::from_utf8(events).unwrap()
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn command_events() {
+        let events = captured_events(|| {
+            let mut command = Command::new("sh");
+            command
+                .arg("-c")
+                .arg("read -r key; echo 'Key load error: Incorrect key provided' >&2; exit 255");
+            let output = run_command(
+                command,
+                b"the secret passphrase\n",
+                WaitLimit::NONE,
+                "pool/dataset",
+                ZfsError::SystemError,
+            )
+            .unwrap();
+            assert_eq!(output.status.code(), Some(255));
+        });
+
+        assert!(events.contains("Running command"));
+        assert!(events.contains("read -r key"));
+        assert!(events.contains("stdin_len=22"));
+        assert!(events.contains("Command failed"));
+        assert!(events.contains("Incorrect key provided"));
+        assert!(!events.contains("secret"));
+    }
 }
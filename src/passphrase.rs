@@ -0,0 +1,139 @@
+use std::io::Read;
+
+/// Memory that's overwritten with zeros, including its unused capacity, when it's dropped
+#[cfg(feature = "zeroize")]
+type Secret<T> = zeroize::Zeroizing<T>;
+
+/// Without the `zeroize` feature, secrets are freed like any other memory
+#[cfg(not(feature = "zeroize"))]
+type Secret<T> = T;
+
+#[cfg(feature = "zeroize")]
+fn secret<T: zeroize::Zeroize>(value: T) -> Secret<T> {
+    zeroize::Zeroizing::new(value)
+}
+
+#[cfg(not(feature = "zeroize"))]
+fn secret<T>(value: T) -> Secret<T> {
+    value
+}
+
+/// A passphrase that's overwritten with zeros when it's dropped, with the `zeroize` feature, which is the default
+/// It can be given to every function that takes a passphrase, as it's `AsRef<str>`.
+/// The functions of this crate wipe their own copies of passphrases, like the data written to zfs, after use.
+#[derive(Clone, Default, Eq, PartialEq)]
+pub struct Passphrase(Secret<String>);
+
+impl Passphrase {
+    pub fn new(passphrase: impl Into<String>) -> Self {
+        Self(secret(passphrase.into()))
+    }
+}
+
+impl From<String> for Passphrase {
+    fn from(passphrase: String) -> Self {
+        Self(secret(passphrase))
+    }
+}
+
+impl From<&str> for Passphrase {
+    fn from(passphrase: &str) -> Self {
+        Self(secret(passphrase.to_string()))
+    }
+}
+
+impl AsRef<str> for Passphrase {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Doesn't show the passphrase, so that it can't end up in logs
+impl std::fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Passphrase(<redacted>)")
+    }
+}
+
+/// The bytes written to the stdin of a command, which are overwritten with zeros when they're dropped, like Passphrase
+pub(crate) struct SecretBuffer(Secret<Vec<u8>>);
+
+impl SecretBuffer {
+    /// Concatenates the parts in a buffer that's allocated once, so no copy is left behind by reallocations
+    pub(crate) fn concat(parts: &[&[u8]]) -> Self {
+        let mut buffer = secret(Vec::with_capacity(
+            parts.iter().map(|part| part.len()).sum(),
+        ));
+        for part in parts {
+            buffer.extend_from_slice(part);
+        }
+        Self(buffer)
+    }
+
+    /// Reads everything from the reader into a buffer that's allocated once, like concat
+    /// Returns: Ok(None) if there's more than max_len bytes to read
+    pub(crate) fn read_from(
+        mut reader: impl Read,
+        max_len: usize,
+    ) -> std::io::Result<Option<Self>> {
+        let mut buffer = Self(secret(vec![0; max_len + 1]));
+        let mut len = 0;
+        while len < buffer.0.len() {
+            match reader.read(&mut buffer.0[len..]) {
+                Ok(0) => break,
+                Ok(read) => len += read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        if len > max_len {
+            return Ok(None);
+        }
+        buffer.0.truncate(len);
+        Ok(Some(buffer))
+    }
+}
+
+impl std::ops::Deref for SecretBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_buffer() {
+        let buffer = SecretBuffer::concat(&[b"secret", b"\n", b"secret", b"\n"]);
+        assert_eq!(&*buffer, b"secret\nsecret\n");
+        assert_eq!(buffer.0.capacity(), buffer.len());
+    }
+
+    #[test]
+    fn read_secret() {
+        let buffer = SecretBuffer::read_from(&b"secret\n"[..], 7)
+            .unwrap()
+            .unwrap();
+        assert_eq!(&*buffer, b"secret\n");
+        assert!(SecretBuffer::read_from(&b"secret\n"[..], 6)
+            .unwrap()
+            .is_none());
+        assert!(SecretBuffer::read_from(&b""[..], 6)
+            .unwrap()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn passphrase() {
+        let passphrase = Passphrase::new("abcdefghijklmnop");
+        assert_eq!(passphrase.as_ref(), "abcdefghijklmnop");
+        assert_eq!(format!("{passphrase:?}"), "Passphrase(<redacted>)");
+        assert_eq!(Passphrase::from("a"), Passphrase::from("a".to_string()));
+    }
+}
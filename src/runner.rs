@@ -0,0 +1,165 @@
+use std::ffi::OsString;
+use std::io::Write;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+use crate::config::COMMAND_ENV;
+
+/// Runs the commands of a ZfsConfig in place of the system, e.g., to test code that uses this crate without zfs
+/// Every command is given as its program followed by its arguments, exactly as it would be run, including sudo.
+/// Timeouts and cancellation aren't applied to commands run this way.
+pub trait CommandRunner: std::fmt::Debug + Send + Sync {
+    /// Runs the command to completion, writing stdin to it if given, and returns its exit status and outputs
+    fn run(&self, argv: &[OsString], stdin: Option<&[u8]>) -> std::io::Result<Output>;
+}
+
+/// Runs the commands on another host with ssh, e.g., to unlock the datasets of many machines from one process
+/// The command is passed to the remote shell with every argument quoted, so that it gets exactly the arguments
+/// that would be run locally. Passphrases and keys are written to the stdin of ssh, never to its arguments.
+/// ssh runs in batch mode, so it must be able to authenticate without asking anything, e.g., with a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshRunner {
+    /// Where to connect, as given to ssh, e.g., `admin@nas1.example.com` or a host of the ssh config
+    pub destination: String,
+    /// The ssh binary, looked up in PATH if it's not a path
+    pub ssh_path: PathBuf,
+    /// Other options for ssh, put before the destination, e.g., `["-p", "2222"]`
+    pub ssh_options: Vec<String>,
+}
+
+impl SshRunner {
+    pub fn new(destination: impl Into<String>) -> Self {
+        Self {
+            destination: destination.into(),
+            ssh_path: PathBuf::from("ssh"),
+            ssh_options: Vec::new(),
+        }
+    }
+
+    /// The ssh command that runs the command on the remote host
+    fn command(&self, argv: &[OsString]) -> Command {
+        let mut command = Command::new(&self.ssh_path);
+        command
+            .arg("-T") // No terminal, so stdin is passed as is
+            .arg("-o")
+            .arg("BatchMode=yes") // ssh never asks for a password
+            .args(&self.ssh_options)
+            .arg("--") // The destination can't be taken for an option
+            .arg(&self.destination)
+            .arg(remote_command(argv));
+        command
+    }
+}
+
+impl CommandRunner for SshRunner {
+    fn run(&self, argv: &[OsString], stdin: Option<&[u8]>) -> std::io::Result<Output> {
+        let mut child = self
+            .command(argv)
+            .stdin(if stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // stdin is written while the outputs are read, so that neither side can block on a full pipe
+        let child_stdin = child.stdin.take();
+        std::thread::scope(|scope| {
+            let writer = scope.spawn(move || match (child_stdin, stdin) {
+                (Some(mut child_stdin), Some(data)) => child_stdin.write_all(data),
+                _ => Ok(()),
+            });
+            let output = child.wait_with_output();
+            // A command that exits without reading its stdin closes the pipe, which isn't an error of ssh
+            match writer.join().expect("Thread writing to ssh panicked") {
+                Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => Err(e),
+                _ => output,
+            }
+        })
+    }
+}
+
+/// The command line that runs the command in a POSIX shell, in the C locale like the local commands
+/// Every argument is quoted in single quotes, in which the shell expands nothing, so no argument can inject
+/// anything into the command line, whatever the sanitization of the names in it.
+fn remote_command(argv: &[OsString]) -> OsString {
+    let mut line = Vec::new();
+    for (key, value) in COMMAND_ENV {
+        line.extend_from_slice(format!("{key}={value} ").as_bytes());
+    }
+    for (index, arg) in argv.iter().enumerate() {
+        if index > 0 {
+            line.push(b' ');
+        }
+        shell_quote(arg.as_bytes(), &mut line);
+    }
+    OsString::from_vec(line)
+}
+
+/// Quotes the argument in single quotes, with single quotes in it closed, escaped and reopened, like `'a'\''b'`
+fn shell_quote(arg: &[u8], line: &mut Vec<u8>) {
+    line.push(b'\'');
+    for &byte in arg {
+        if byte == b'\'' {
+            line.extend_from_slice(br"'\''");
+        } else {
+            line.push(byte);
+        }
+    }
+    line.push(b'\'');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_commands() {
+        let argv = ["sudo", "-n", "zfs", "load-key", "pool/data set"].map(OsString::from);
+        assert_eq!(
+            remote_command(&argv),
+            "LC_ALL=C LANG=C 'sudo' '-n' 'zfs' 'load-key' 'pool/data set'"
+        );
+
+        let command = SshRunner {
+            ssh_options: vec!["-p".to_string(), "2222".to_string()],
+            ..SshRunner::new("admin@nas1")
+        }
+        .command(&argv);
+        assert_eq!(command.get_program(), "ssh");
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            [
+                "-T",
+                "-o",
+                "BatchMode=yes",
+                "-p",
+                "2222",
+                "--",
+                "admin@nas1",
+                "LC_ALL=C LANG=C 'sudo' '-n' 'zfs' 'load-key' 'pool/data set'",
+            ]
+        );
+
+        // The shell gets the arguments back exactly, without expanding or running anything in them
+        let args = ["a'b", "$(touch /tmp/injected)", "`id`", "x; y", "'", "\\n*"];
+        let argv = std::iter::once("printf")
+            .chain(std::iter::once("%s\\n"))
+            .chain(args)
+            .map(OsString::from)
+            .collect::<Vec<_>>();
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(remote_command(&argv))
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            args.map(|arg| format!("{arg}\n")).concat()
+        );
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::{DatasetMountedState, ZfsConfig};
+
+/// A change of the state of an encrypted dataset, seen by a watcher between two polls
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DatasetStateChange {
+    pub dataset: String,
+    /// None if the dataset appeared, i.e., it wasn't listed by the previous poll
+    pub old_state: Option<DatasetMountedState>,
+    /// None if the dataset disappeared, e.g., it was destroyed or its pool was exported
+    pub new_state: Option<DatasetMountedState>,
+}
+
+/// Stops the watcher that sends the changes to its receiver, when stop is called or when the handle is dropped
+/// The watcher also stops by itself once the receiver is dropped, at its next poll.
+#[derive(Debug)]
+pub struct WatchHandle {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl WatchHandle {
+    /// Stops the watcher and waits for its thread to exit, which interrupts the wait for the next poll
+    /// A poll that's already running is finished first, and its changes are sent.
+    pub fn stop(mut self) {
+        self.stop_and_join();
+    }
+
+    fn stop_and_join(&mut self) {
+        let (stopped, wakeup) = &*self.stopped;
+        *stopped.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        wakeup.notify_all();
+        if let Some(thread) = self.thread.take() {
+            // A panic of the thread was already reported by it, and there's nothing left to stop
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for WatchHandle {
+    fn drop(&mut self) {
+        self.stop_and_join();
+    }
+}
+
+/// Starts the thread that polls the encrypted datasets with the config every poll_interval
+pub(crate) fn spawn_watcher(
+    config: ZfsConfig,
+    poll_interval: Duration,
+) -> (Receiver<DatasetStateChange>, WatchHandle) {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+    let thread = {
+        let stopped = Arc::clone(&stopped);
+        std::thread::spawn(move || watch_loop(&config, poll_interval, &sender, &stopped))
+    };
+    (
+        receiver,
+        WatchHandle {
+            stopped,
+            thread: Some(thread),
+        },
+    )
+}
+
+fn watch_loop(
+    config: &ZfsConfig,
+    poll_interval: Duration,
+    sender: &Sender<DatasetStateChange>,
+    stopped: &(Mutex<bool>, Condvar),
+) {
+    let mut previous = None;
+    loop {
+        // A poll that fails, e.g., because zfs is busy, is skipped, and the next one is compared with the last
+        // that succeeded, so no change is lost or sent twice
+        if let Ok(current) = config.list_encrypted_datasets() {
+            if let Some(previous) = &previous {
+                for change in diff_dataset_states(previous, &current) {
+                    if sender.send(change).is_err() {
+                        return;
+                    }
+                }
+            }
+            previous = Some(current);
+        }
+
+        let (stopped, wakeup) = stopped;
+        let stopped = wakeup
+            .wait_timeout_while(
+                stopped.lock().unwrap_or_else(|e| e.into_inner()),
+                poll_interval,
+                |stopped| !*stopped,
+            )
+            .unwrap_or_else(|e| e.into_inner())
+            .0;
+        if *stopped {
+            return;
+        }
+    }
+}
+
+/// The changes between two listings of the encrypted datasets, by dataset name
+/// A dataset changed if it's mounted or its key is loaded in one listing and not in the other; other changes,
+/// like of its mountpoint, aren't reported.
+pub(crate) fn diff_dataset_states(
+    old: &BTreeMap<String, DatasetMountedState>,
+    new: &BTreeMap<String, DatasetMountedState>,
+) -> Vec<DatasetStateChange> {
+    let changed = |old: &DatasetMountedState, new: &DatasetMountedState| {
+        old.is_mounted != new.is_mounted || old.is_key_loaded != new.is_key_loaded
+    };
+    let names = old
+        .keys()
+        .chain(new.keys().filter(|name| !old.contains_key(*name)));
+    let mut changes = names
+        .filter_map(|name| {
+            let (old_state, new_state) = (old.get(name), new.get(name));
+            match (old_state, new_state) {
+                (Some(old_state), Some(new_state)) if !changed(old_state, new_state) => None,
+                _ => Some(DatasetStateChange {
+                    dataset: name.clone(),
+                    old_state: old_state.cloned(),
+                    new_state: new_state.cloned(),
+                }),
+            }
+        })
+        .collect::<Vec<_>>();
+    changes.sort_by(|a, b| a.dataset.cmp(&b.dataset));
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(name: &str, is_mounted: bool, is_key_loaded: bool) -> DatasetMountedState {
+        DatasetMountedState {
+            dataset_name: name.to_string(),
+            is_mounted,
+            is_key_loaded,
+            mountpoint: Some(format!("/{name}").into()),
+            is_encryption_root: true,
+        }
+    }
+
+    fn states(states: &[DatasetMountedState]) -> BTreeMap<String, DatasetMountedState> {
+        states
+            .iter()
+            .map(|state| (state.dataset_name.clone(), state.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn dataset_state_changes() {
+        let old = states(&[
+            state("pool/a", false, false),
+            state("pool/b", true, true),
+            state("pool/c", true, true),
+            state("pool/gone", false, true),
+        ]);
+        let new = states(&[
+            state("pool/a", false, true),
+            state("pool/b", true, true),
+            DatasetMountedState {
+                mountpoint: None,
+                ..state("pool/c", true, true)
+            },
+            state("pool/added", true, true),
+        ]);
+
+        assert_eq!(
+            diff_dataset_states(&old, &new),
+            [
+                DatasetStateChange {
+                    dataset: "pool/a".to_string(),
+                    old_state: Some(state("pool/a", false, false)),
+                    new_state: Some(state("pool/a", false, true)),
+                },
+                DatasetStateChange {
+                    dataset: "pool/added".to_string(),
+                    old_state: None,
+                    new_state: Some(state("pool/added", true, true)),
+                },
+                DatasetStateChange {
+                    dataset: "pool/gone".to_string(),
+                    old_state: Some(state("pool/gone", false, true)),
+                    new_state: None,
+                },
+            ]
+        );
+        assert!(diff_dataset_states(&old, &old).is_empty());
+        assert!(diff_dataset_states(&BTreeMap::new(), &BTreeMap::new()).is_empty());
+    }
+}
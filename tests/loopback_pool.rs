@@ -0,0 +1,165 @@
+//! End to end tests against a real zfs, on a pool backed by a file that's created and destroyed by the test
+//! They need zfs, and either root or sudo without a password for zpool and zfs, e.g., in a privileged container,
+//! so they're ignored by default. Run them with `cargo test --test loopback_pool -- --ignored`.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use sam_zfs_unlocker::{CreateOpts, SudoPrompt, ZfsConfig, ZfsError};
+
+const PASSPHRASE: &str = "abcdefghijklmnop";
+
+/// A pool on a sparse file in a temporary directory, which is destroyed with its file when dropped,
+/// including when the test panics
+struct LoopbackPool {
+    name: String,
+    dir: PathBuf,
+    config: ZfsConfig,
+}
+
+impl LoopbackPool {
+    fn create() -> Self {
+        let name = format!("samzfsunlockertest{}", std::process::id());
+        let dir = std::env::temp_dir().join(&name);
+        std::fs::create_dir_all(&dir).unwrap();
+        let pool = Self {
+            name,
+            dir,
+            config: ZfsConfig::for_current_user(),
+        };
+
+        let file = pool.dir.join("vdev");
+        run(Command::new("truncate").arg("-s").arg("128M").arg(&file));
+        // The altroot keeps the mountpoints in the temporary directory, and the pool out of the cache file
+        run(pool
+            .privileged_zpool()
+            .arg("create")
+            .arg("-R")
+            .arg(pool.dir.join("root"))
+            .arg(&pool.name)
+            .arg(&file));
+        pool
+    }
+
+    /// The zpool command with the privileges of the config, like it runs the privileged commands
+    fn privileged_zpool(&self) -> Command {
+        let config = &self.config;
+        let zpool = &config.zpool_path;
+        match config.escalation_command.as_deref() {
+            _ if !config.use_sudo => Command::new(zpool),
+            Some([program, options @ ..]) => {
+                let mut command = Command::new(program);
+                command.args(options).arg(zpool);
+                command
+            }
+            _ => {
+                let mut command = Command::new(&config.sudo_path);
+                match &config.sudo_prompt {
+                    SudoPrompt::NonInteractive => {
+                        command.arg("-n");
+                    }
+                    SudoPrompt::Terminal => (),
+                    SudoPrompt::Askpass(askpass) => {
+                        command.arg("-A").env("SUDO_ASKPASS", askpass);
+                    }
+                }
+                command.arg(zpool);
+                command
+            }
+        }
+    }
+
+    fn dataset(&self, name: &str) -> String {
+        format!("{}/{name}", self.name)
+    }
+}
+
+impl Drop for LoopbackPool {
+    fn drop(&mut self) {
+        // Errors are ignored, so that a failed teardown doesn't hide the failure of the test
+        let _ = self
+            .privileged_zpool()
+            .arg("destroy")
+            .arg("-f")
+            .arg(&self.name)
+            .status();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn run(command: &mut Command) {
+    let status = command
+        .status()
+        .unwrap_or_else(|e| panic!("{command:?} couldn't run: {e}"));
+    assert!(status.success(), "{command:?} failed with {status}");
+}
+
+fn is_mounted_at(config: &ZfsConfig, dataset: &str, dir: &Path) -> bool {
+    config
+        .get_mountpoint(dataset)
+        .unwrap()
+        .is_some_and(|mountpoint| mountpoint.starts_with(dir))
+        && config.is_dataset_mounted(dataset).unwrap() == Some(true)
+}
+
+#[test]
+#[ignore = "needs zfs, and root or sudo for zpool and zfs"]
+fn lock_cycle() {
+    let pool = LoopbackPool::create();
+    let config = &pool.config;
+    let ds = pool.dataset("encrypted");
+
+    config
+        .create_encrypted_dataset(&ds, PASSPHRASE, CreateOpts::default())
+        .unwrap();
+    assert_eq!(
+        config.is_unlocked_and_mounted(&ds).unwrap(),
+        Some((true, true))
+    );
+    assert!(is_mounted_at(config, &ds, &pool.dir));
+    assert!(config
+        .list_encrypted_datasets_under(&pool.name)
+        .unwrap()
+        .contains_key(&ds));
+
+    config.unmount_dataset(&ds).unwrap();
+    assert_eq!(config.is_dataset_mounted(&ds).unwrap(), Some(false));
+    config.unload_key(&ds).unwrap();
+    assert_eq!(config.is_key_loaded(&ds).unwrap(), Some(false));
+
+    assert!(matches!(
+        config.load_key(&ds, "the wrong passphrase"),
+        Err(ZfsError::IncorrectPassphrase(_))
+    ));
+    assert_eq!(config.is_key_loaded(&ds).unwrap(), Some(false));
+    config.load_key(&ds, PASSPHRASE).unwrap();
+    assert_eq!(config.is_key_loaded(&ds).unwrap(), Some(true));
+    // Loading a loaded key succeeds
+    config.load_key(&ds, PASSPHRASE).unwrap();
+
+    config.mount_dataset(&ds).unwrap();
+    assert!(is_mounted_at(config, &ds, &pool.dir));
+    config.unmount_and_unload(&ds).unwrap();
+    assert_eq!(
+        config.is_unlocked_and_mounted(&ds).unwrap(),
+        Some((false, false))
+    );
+
+    config.unlock_and_mount(&ds, PASSPHRASE).unwrap();
+    assert_eq!(
+        config.is_unlocked_and_mounted(&ds).unwrap(),
+        Some((true, true))
+    );
+    config.unmount_and_unload(&ds).unwrap();
+    assert_eq!(
+        config.is_unlocked_and_mounted(&ds).unwrap(),
+        Some((false, false))
+    );
+
+    assert_eq!(
+        config
+            .is_unlocked_and_mounted(pool.dataset("missing"))
+            .unwrap(),
+        None
+    );
+}